@@ -1,29 +1,217 @@
-/// That's a hack.
-use std::{fs, sync::LazyLock};
+//! Xen ABI detection
+//!
+//! The layout and interface versions of the sysctl/domctl structures depend on the Xen version.
+//! The ABI is (in order of precedence):
+//!   * explicitly overridden (e.g by a command line option),
+//!   * given by the [ABI_ENV] environment variable (e.g `XEN_ABI=4.19`),
+//!   * deduced from the hypervisor version (`/sys/hypervisor/version/*`),
+//!   * probed, by issuing harmless hypercalls with the interface versions of each known ABI.
+use std::{env, fmt, fs, io, str::FromStr};
 
-#[derive(Clone, Copy)]
+use crate::{
+    domctl::DomctlGetDomainInfo,
+    hypercall::{XenConstBuffer, XenHypercall, XenMutBuffer},
+    sysctl::SysctlPhysInfo,
+    DomId,
+};
+
+/// Environment variable overriding the detected ABI.
+pub const ABI_ENV: &str = "XEN_ABI";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum XenAbi {
     /// Xen 4.17 (XCP-ng 8.3)
     Xen417,
+    /// Xen 4.18
+    Xen418,
     /// Xen 4.19 (upstream Xen)
     Xen419,
+    /// Xen 4.20
+    Xen420,
 }
 
-static ONCE_ABI: LazyLock<XenAbi> = LazyLock::new(|| {
-    let major = fs::read_to_string("/sys/hypervisor/version/major").expect("Not running under Xen");
-    let minor = fs::read_to_string("/sys/hypervisor/version/minor")
-        .expect("Unable to read minor Xen version.");
+impl XenAbi {
+    /// All the supported ABIs, newest first.
+    pub const ALL: [XenAbi; 4] = [
+        XenAbi::Xen420,
+        XenAbi::Xen419,
+        XenAbi::Xen418,
+        XenAbi::Xen417,
+    ];
+
+    /// Xen (major, minor) version.
+    pub fn version(self) -> (u32, u32) {
+        match self {
+            XenAbi::Xen417 => (4, 17),
+            XenAbi::Xen418 => (4, 18),
+            XenAbi::Xen419 => (4, 19),
+            XenAbi::Xen420 => (4, 20),
+        }
+    }
+
+    /// ABI of a Xen version, [None] if it is not supported.
+    pub fn from_version(major: u32, minor: u32) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|abi| abi.version() == (major, minor))
+    }
+
+    /// `XEN_SYSCTL_INTERFACE_VERSION`
+    pub fn sysctl_interface_version(self) -> u32 {
+        match self {
+            XenAbi::Xen417 | XenAbi::Xen418 | XenAbi::Xen419 | XenAbi::Xen420 => 0x15,
+        }
+    }
+
+    /// `XEN_DOMCTL_INTERFACE_VERSION`
+    pub fn domctl_interface_version(self) -> u32 {
+        match self {
+            XenAbi::Xen417 => 0x15,
+            XenAbi::Xen418 => 0x16,
+            XenAbi::Xen419 | XenAbi::Xen420 => 0x17,
+        }
+    }
+}
+
+impl fmt::Display for XenAbi {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (major, minor) = self.version();
+        write!(f, "{major}.{minor}")
+    }
+}
+
+impl FromStr for XenAbi {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (major, minor) = s
+            .trim()
+            .split_once('.')
+            .ok_or_else(|| anyhow::anyhow!("Invalid Xen version '{s}' (expected e.g 4.19)"))?;
+
+        Self::from_version(major.parse()?, minor.parse()?)
+            .ok_or_else(|| anyhow::anyhow!("Unsupported Xen version {s}"))
+    }
+}
+
+/// How the ABI has been chosen.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AbiSource {
+    Override,
+    Environment,
+    Hypervisor,
+    Probe,
+}
+
+/// ABI of the running hypervisor version, [None] if the version is not supported.
+pub fn hypervisor_xen_abi() -> anyhow::Result<Option<XenAbi>> {
+    let read = |name| match fs::read_to_string(format!("/sys/hypervisor/version/{name}")) {
+        Ok(value) => Ok(value.trim().to_string()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            Err(anyhow::anyhow!("Not running under Xen"))
+        }
+        Err(e) => Err(anyhow::anyhow!("Unable to read Xen {name} version: {e}")),
+    };
+
+    let (major, minor) = (read("major")?, read("minor")?);
+
+    Ok(XenAbi::from_version(major.parse()?, minor.parse()?))
+}
 
-    let major = major.trim();
-    let minor = minor.trim();
+/// [XenHypercall] using another ABI, to probe it.
+struct ProbeAbi<'a, H: XenHypercall> {
+    hypercall: &'a H,
+    abi: XenAbi,
+}
+
+impl<H: XenHypercall> XenHypercall for ProbeAbi<'_, H> {
+    type Error = H::Error;
+
+    fn abi(&self) -> XenAbi {
+        self.abi
+    }
+
+    unsafe fn hypercall5(&self, cmd: usize, param: [usize; 5]) -> usize {
+        self.hypercall.hypercall5(cmd, param)
+    }
+
+    fn make_const_object<T: Copy>(
+        &self,
+        buffer: &T,
+    ) -> Result<impl XenConstBuffer<T>, Self::Error> {
+        self.hypercall.make_const_object(buffer)
+    }
+
+    fn make_mut_buffer<T: Copy>(
+        &self,
+        buffer: &mut T,
+    ) -> Result<impl XenMutBuffer<T>, Self::Error> {
+        self.hypercall.make_mut_buffer(buffer)
+    }
+
+    fn make_const_slice<T: Copy>(
+        &self,
+        slice: &[T],
+    ) -> Result<impl XenConstBuffer<T>, Self::Error> {
+        self.hypercall.make_const_slice(slice)
+    }
+
+    fn make_mut_slice<T: Copy>(
+        &self,
+        slice: &mut [T],
+    ) -> Result<impl XenMutBuffer<T>, Self::Error> {
+        self.hypercall.make_mut_slice(slice)
+    }
+}
+
+/// Find the ABI whose interface versions the hypervisor accepts (Xen rejects the sysctls and
+/// domctls of other interface versions).
+///
+/// ABIs with the same interface versions can't be told apart, the newest one is chosen.
+pub fn probe_xen_abi(hypercall: &impl XenHypercall) -> anyhow::Result<XenAbi> {
+    XenAbi::ALL
+        .into_iter()
+        .find(|&abi| {
+            let probe = ProbeAbi { hypercall, abi };
+
+            probe.physinfo().is_ok() && probe.get_domain_info(DomId(0)).is_ok()
+        })
+        .ok_or_else(|| anyhow::anyhow!("No supported Xen ABI is accepted by the hypervisor"))
+}
+
+/// Choose the ABI to use with `hypercall` (see the module documentation).
+pub fn detect_xen_abi(
+    hypercall: &impl XenHypercall,
+    override_abi: Option<XenAbi>,
+) -> anyhow::Result<(XenAbi, AbiSource)> {
+    // Probe if the version is unsupported, or can't be read (e.g /sys/hypervisor isn't mounted).
+    choose_xen_abi(
+        hypercall,
+        override_abi,
+        env::var(ABI_ENV).ok().as_deref(),
+        hypervisor_xen_abi().ok().flatten(),
+    )
+}
+
+/// Choose the ABI to use with `hypercall`, from the override, the [ABI_ENV] environment
+/// variable and the hypervisor version ABI, probing it if none of them is given.
+pub fn choose_xen_abi(
+    hypercall: &impl XenHypercall,
+    override_abi: Option<XenAbi>,
+    env_abi: Option<&str>,
+    hypervisor_abi: Option<XenAbi>,
+) -> anyhow::Result<(XenAbi, AbiSource)> {
+    if let Some(abi) = override_abi {
+        return Ok((abi, AbiSource::Override));
+    }
+
+    if let Some(value) = env_abi {
+        return Ok((value.parse()?, AbiSource::Environment));
+    }
 
-    match (major, minor) {
-        ("4", "19") => XenAbi::Xen419,
-        ("4", "17") => XenAbi::Xen417,
-        _ => panic!("Unsupported Xen version {major}.{minor}"),
+    if let Some(abi) = hypervisor_abi {
+        return Ok((abi, AbiSource::Hypervisor));
     }
-});
 
-pub fn get_xen_abi() -> XenAbi {
-    *ONCE_ABI
+    Ok((probe_xen_abi(hypercall)?, AbiSource::Probe))
 }
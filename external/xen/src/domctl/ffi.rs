@@ -27,6 +27,72 @@ bitflags! {
   }
 }
 
+/// The shutdown reason of a [XenDomctlDominf::SHUTDOWN] domain is in the flags, at this shift.
+pub const XEN_DOMINF_SHUTDOWN_SHIFT: u32 = 16;
+pub const XEN_DOMINF_SHUTDOWN_MASK: u32 = 0xff;
+
+/// `SHUTDOWN_*` reasons of `sched.h`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum XenShutdownReason {
+    /// Domain exited normally. Clean up and kill.
+    Poweroff = 0,
+    /// Clean up, kill, and then restart.
+    Reboot = 1,
+    /// Clean up, save suspend info, kill.
+    Suspend = 2,
+    /// Tell controller we've crashed.
+    Crash = 3,
+    /// Restart because watchdog time expired.
+    Watchdog = 4,
+    /// Domain asked to perform 'soft reset' for it.
+    SoftReset = 5,
+}
+
+impl XenShutdownReason {
+    pub const ALL: [XenShutdownReason; 6] = [
+        XenShutdownReason::Poweroff,
+        XenShutdownReason::Reboot,
+        XenShutdownReason::Suspend,
+        XenShutdownReason::Crash,
+        XenShutdownReason::Watchdog,
+        XenShutdownReason::SoftReset,
+    ];
+
+    pub fn from_code(code: u32) -> Option<Self> {
+        Self::ALL.into_iter().find(|&reason| reason as u32 == code)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            XenShutdownReason::Poweroff => "poweroff",
+            XenShutdownReason::Reboot => "reboot",
+            XenShutdownReason::Suspend => "suspend",
+            XenShutdownReason::Crash => "crash",
+            XenShutdownReason::Watchdog => "watchdog",
+            XenShutdownReason::SoftReset => "soft_reset",
+        }
+    }
+}
+
+/// Kind of guest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum XenGuestType {
+    Pv,
+    Hvm,
+    /// HVM guest without emulated devices (besides the local APICs).
+    Pvh,
+}
+
+impl XenGuestType {
+    pub fn name(self) -> &'static str {
+        match self {
+            XenGuestType::Pv => "pv",
+            XenGuestType::Hvm => "hvm",
+            XenGuestType::Pvh => "pvh",
+        }
+    }
+}
+
 bitflags! {
   /// Content of the `emulation_flags` field of the domain creation hypercall.
   #[repr(C)]
@@ -136,4 +202,4 @@ pub struct XenDomctl {
     pub domain: DomId,
     pub pad: [u16; 3],
     pub param: XenDomctlParam,
-}
\ No newline at end of file
+}
@@ -2,22 +2,14 @@ mod ffi;
 pub use ffi::*;
 
 use crate::{
-    abi::get_xen_abi,
     hypercall::{XenHypercall, XenMutBuffer},
     DomId,
 };
 
-fn domctl_interface_version() -> u32 {
-    match get_xen_abi() {
-        crate::abi::XenAbi::Xen417 => 0x15,
-        crate::abi::XenAbi::Xen419 => 0x17,
-    }
-}
-
-const HYPERVISOR_DOMCTL: usize = 36;
+pub const HYPERVISOR_DOMCTL: usize = 36;
 
-const XEN_DOMCTL_GETDOMAININFO: u32 = 5;
-const XEN_DOMCTL_GETVCPUINFO: u32 = 14;
+pub const XEN_DOMCTL_GETDOMAININFO: u32 = 5;
+pub const XEN_DOMCTL_GETVCPUINFO: u32 = 14;
 
 pub trait DomctlGetDomainInfo
 where
@@ -26,7 +18,7 @@ where
     fn get_domain_info(&self, domain: DomId) -> anyhow::Result<XenDomctlGetDomainInfo> {
         let mut domctl: XenDomctl = XenDomctl {
             cmd: XEN_DOMCTL_GETDOMAININFO,
-            interface_version: domctl_interface_version(),
+            interface_version: self.abi().domctl_interface_version(),
             domain,
             pad: [0; 3],
             param: XenDomctlParam {
@@ -50,6 +42,34 @@ where
 
 impl<T: XenHypercall> DomctlGetDomainInfo for T {}
 
+impl XenDomctlGetDomainInfo {
+    /// Why the domain has shut down, [None] if it hasn't (or the reason is unknown).
+    pub fn shutdown_reason(&self) -> Option<XenShutdownReason> {
+        if !self.flags.contains(XenDomctlDominf::SHUTDOWN) {
+            return None;
+        }
+
+        XenShutdownReason::from_code(
+            (self.flags.bits() >> XEN_DOMINF_SHUTDOWN_SHIFT) & XEN_DOMINF_SHUTDOWN_MASK,
+        )
+    }
+
+    pub fn guest_type(&self) -> XenGuestType {
+        if !self.flags.contains(XenDomctlDominf::HVM_GUEST) {
+            XenGuestType::Pv
+        } else if self
+            .arch_config
+            .emulation_flags
+            .difference(XenX86Emu::Lapic | XenX86Emu::Vpci)
+            .is_empty()
+        {
+            XenGuestType::Pvh
+        } else {
+            XenGuestType::Hvm
+        }
+    }
+}
+
 pub trait DomctlGetVCpuInfo
 where
     Self: XenHypercall,
@@ -57,7 +77,7 @@ where
     fn get_vcpu_info(&self, domain: DomId, vcpu: u32) -> anyhow::Result<XenDomctlGetVCpuInfo> {
         let mut domctl: XenDomctl = XenDomctl {
             cmd: XEN_DOMCTL_GETVCPUINFO,
-            interface_version: domctl_interface_version(),
+            interface_version: self.abi().domctl_interface_version(),
             domain,
             pad: [0; 3],
             param: XenDomctlParam {
@@ -0,0 +1,608 @@
+//! Fake hypervisor
+//!
+//! [MockXenHypercall] answers the sysctl and domctl hypercalls from a [MockXen] model instead of
+//! Xen, so that users of this crate can be tested without a hypervisor. Tests can change the model
+//! between hypercalls (e.g create or destroy a domain, or [MockXen::advance] the time).
+//!
+//! Hypercall buffers are not bounced, the fake hypervisor directly uses the original memory.
+
+use core::{convert::Infallible, marker::PhantomData, time::Duration};
+use std::{
+    collections::BTreeMap,
+    sync::{Mutex, MutexGuard},
+};
+
+use nix::errno::Errno;
+use uuid::Uuid;
+
+use super::{XenConstBuffer, XenHypercall, XenMutBuffer};
+use crate::{
+    abi::XenAbi,
+    domctl::{
+        XenArchDomainconfig, XenDomctl, XenDomctlDominf, XenDomctlGetDomainInfo,
+        XenDomctlGetVCpuInfo, XenShutdownReason, XenX86Emu, HYPERVISOR_DOMCTL,
+        XEN_DOMCTL_GETDOMAININFO, XEN_DOMCTL_GETVCPUINFO, XEN_DOMINF_SHUTDOWN_SHIFT,
+    },
+    sysctl::{
+        XenPmPxVal, XenSysctl, XenSysctlCpuTopo, XenSysctlCpuinfo, XenSysctlMeminfo,
+        XenSysctlPhysCap, XenSysctlPhysInfo, CPUFREQ_NAME_LEN, HYPERVISOR_SYSCTL,
+        PMSTAT_GET_CXSTAT, PMSTAT_GET_MAX_CX, PMSTAT_GET_MAX_PX, PMSTAT_GET_PXSTAT,
+        XEN_SYSCTL_CPUTOPOINFO, XEN_SYSCTL_GETCPUINFO, XEN_SYSCTL_GETDOMAININFOLIST,
+        XEN_SYSCTL_GET_PMSTAT, XEN_SYSCTL_NUMAINFO, XEN_SYSCTL_PHYSINFO, XEN_SYSCTL_PM_OP,
+        XEN_SYSCTL_PM_OP_CPUFREQ_AVG, XEN_SYSCTL_PM_OP_GET_CPUFREQ_PARA,
+    },
+    Align64, DomId,
+};
+
+/// A P-state of a [MockPCpu].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MockPState {
+    /// Core frequency (MHz).
+    pub freq: u64,
+    /// Time spent in this state (ns).
+    pub residency: u64,
+    /// Number of transitions to this state.
+    pub count: u64,
+}
+
+/// A C-state of a [MockPCpu].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MockCState {
+    /// Time spent in this state (ns).
+    pub residency: u64,
+    /// Number of entries in this state.
+    pub usage: u64,
+}
+
+/// cpufreq policy of a [MockPCpu].
+#[derive(Clone, Debug, Default)]
+pub struct MockCpufreq {
+    pub driver: String,
+    pub governor: String,
+}
+
+/// A physical CPU of a [MockXen].
+#[derive(Clone, Debug, Default)]
+pub struct MockPCpu {
+    /// Time spent idle (ns).
+    pub idletime: u64,
+    /// Average frequency (as reported by cpufreq), [None] if cpufreq is not available.
+    pub avgfreq: Option<u64>,
+    /// Fraction of the time this CPU is busy, used by [MockXen::advance].
+    pub busy: f64,
+    pub core: u32,
+    pub socket: u32,
+    pub node: u32,
+    /// P-states (none if P-states are not managed by Xen).
+    pub pstates: Vec<MockPState>,
+    /// Current P-state.
+    pub pstate: usize,
+    /// Transitions between each pair of P-states (`from * pstates.len() + to`).
+    pub pstate_transitions: Vec<u64>,
+    /// C-states, C0 first (none if C-states are not managed by Xen). The idle time is spent in
+    /// the deepest one.
+    pub cstates: Vec<MockCState>,
+    pub cpufreq: Option<MockCpufreq>,
+}
+
+impl MockPCpu {
+    /// Switch to another P-state.
+    pub fn set_pstate(&mut self, pstate: usize) {
+        let nr = self.pstates.len();
+
+        self.pstate_transitions.resize(nr * nr, 0);
+        self.pstate_transitions[self.pstate * nr + pstate] += 1;
+        self.pstates[pstate].count += 1;
+        self.pstate = pstate;
+    }
+}
+
+/// A NUMA node of a [MockXen].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MockNode {
+    /// Memory of the node (bytes).
+    pub memsize: u64,
+    /// Free memory of the node (bytes).
+    pub memfree: u64,
+}
+
+/// A virtual CPU of a [MockDomain].
+#[derive(Clone, Copy, Debug)]
+pub struct MockVCpu {
+    pub online: bool,
+    pub blocked: bool,
+    pub running: bool,
+    /// Time spent running (ns).
+    pub cpu_time: u64,
+    /// Physical CPU this vCPU is running on.
+    pub cpu: u32,
+    /// Fraction of the time this vCPU is running, used by [MockXen::advance].
+    pub busy: f64,
+}
+
+impl Default for MockVCpu {
+    fn default() -> Self {
+        Self {
+            online: true,
+            blocked: false,
+            running: true,
+            cpu_time: 0,
+            cpu: 0,
+            busy: 0.0,
+        }
+    }
+}
+
+/// A domain of a [MockXen].
+#[derive(Clone, Debug, Default)]
+pub struct MockDomain {
+    pub handle: Uuid,
+    pub flags: XenDomctlDominf,
+    /// Shutdown reason, sets [XenDomctlDominf::SHUTDOWN].
+    pub shutdown_reason: Option<XenShutdownReason>,
+    pub emulation_flags: XenX86Emu,
+    pub tot_pages: u64,
+    pub max_pages: u64,
+    pub outstanding_pages: u64,
+    pub shr_pages: u64,
+    pub paged_pages: u64,
+    pub cpupool: u32,
+    pub gpaddr_bits: u8,
+    pub vcpus: Vec<MockVCpu>,
+}
+
+impl MockDomain {
+    /// A running domain with `nr_vcpus` online vCPUs.
+    pub fn new(handle: Uuid, nr_vcpus: u32) -> Self {
+        Self {
+            handle,
+            flags: XenDomctlDominf::RUNNING,
+            vcpus: vec![MockVCpu::default(); nr_vcpus as usize],
+            ..Default::default()
+        }
+    }
+
+    fn info(&self, domain: DomId) -> XenDomctlGetDomainInfo {
+        let flags = match self.shutdown_reason {
+            Some(reason) => XenDomctlDominf::from_bits_retain(
+                (self.flags | XenDomctlDominf::SHUTDOWN).bits()
+                    | (reason as u32) << XEN_DOMINF_SHUTDOWN_SHIFT,
+            ),
+            None => self.flags,
+        };
+
+        XenDomctlGetDomainInfo {
+            domain,
+            flags,
+            tot_pages: Align64(self.tot_pages),
+            max_pages: Align64(self.max_pages),
+            outstanding_pages: Align64(self.outstanding_pages),
+            shr_pages: Align64(self.shr_pages),
+            paged_pages: Align64(self.paged_pages),
+            cpu_time: Align64(self.vcpus.iter().map(|vcpu| vcpu.cpu_time).sum()),
+            nr_online_vcpus: self.vcpus.iter().filter(|vcpu| vcpu.online).count() as u32,
+            max_vcpu_id: self.vcpus.len().saturating_sub(1) as u32,
+            handle: self.handle,
+            cpupool: self.cpupool,
+            gpaddr_bits: self.gpaddr_bits,
+            arch_config: XenArchDomainconfig {
+                emulation_flags: self.emulation_flags,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+}
+
+/// Model of the hypervisor state.
+#[derive(Clone, Debug)]
+pub struct MockXen {
+    /// ABI expected from the hypercalls (wrong interface versions are rejected).
+    pub abi: XenAbi,
+    pub threads_per_core: u32,
+    pub cores_per_socket: u32,
+    pub cpu_khz: u32,
+    pub capabilities: XenSysctlPhysCap,
+    pub total_pages: u64,
+    pub free_pages: u64,
+    pub scrub_pages: u64,
+    pub outstanding_pages: u64,
+    pub pcpus: Vec<MockPCpu>,
+    pub nodes: Vec<MockNode>,
+    pub domains: BTreeMap<u16, MockDomain>,
+}
+
+impl MockXen {
+    /// A host with `nr_cpus` idle CPUs (being the cores of a socket), a NUMA node and no domain.
+    pub fn new(abi: XenAbi, nr_cpus: u32) -> Self {
+        Self {
+            abi,
+            threads_per_core: 1,
+            cores_per_socket: nr_cpus,
+            cpu_khz: 2_000_000,
+            capabilities: XenSysctlPhysCap::HVM | XenSysctlPhysCap::PV,
+            total_pages: 0,
+            free_pages: 0,
+            scrub_pages: 0,
+            outstanding_pages: 0,
+            pcpus: (0..nr_cpus)
+                .map(|core| MockPCpu {
+                    core,
+                    ..Default::default()
+                })
+                .collect(),
+            nodes: vec![MockNode::default()],
+            domains: BTreeMap::new(),
+        }
+    }
+
+    /// Let `elapsed` time pass, accounting idle and running times according to the `busy`
+    /// fractions of the CPUs.
+    pub fn advance(&mut self, elapsed: Duration) {
+        let elapsed = elapsed.as_nanos() as f64;
+
+        for pcpu in &mut self.pcpus {
+            let idle = (elapsed * (1.0 - pcpu.busy)) as u64;
+
+            pcpu.idletime += idle;
+
+            if let Some(pstate) = pcpu.pstates.get_mut(pcpu.pstate) {
+                pstate.residency += elapsed as u64;
+            }
+
+            if let [c0, .., deepest] = pcpu.cstates.as_mut_slice() {
+                c0.residency += elapsed as u64 - idle;
+                deepest.residency += idle;
+                deepest.usage += 1;
+            }
+        }
+
+        for vcpu in self
+            .domains
+            .values_mut()
+            .flat_map(|domain| &mut domain.vcpus)
+            .filter(|vcpu| vcpu.online)
+        {
+            vcpu.cpu_time += (elapsed * vcpu.busy) as u64;
+        }
+    }
+
+    fn physinfo(&self) -> XenSysctlPhysInfo {
+        XenSysctlPhysInfo {
+            threads_per_core: self.threads_per_core,
+            cores_per_socket: self.cores_per_socket,
+            nr_cpus: self.pcpus.len() as u32,
+            max_cpu_id: self.pcpus.len().saturating_sub(1) as u32,
+            nr_nodes: self.nodes.len() as u32,
+            max_node_id: self.nodes.len().saturating_sub(1) as u32,
+            cpu_khz: self.cpu_khz,
+            capabilities: self.capabilities,
+            total_pages: Align64(self.total_pages),
+            free_pages: Align64(self.free_pages),
+            scrub_pages: Align64(self.scrub_pages),
+            outstanding_pages: Align64(self.outstanding_pages),
+            ..Default::default()
+        }
+    }
+
+    /// # Safety
+    ///
+    /// The guest buffers of `sysctl` must be valid for their advertised length.
+    unsafe fn sysctl(&self, sysctl: &mut XenSysctl) -> Result<(), Errno> {
+        if sysctl.interface_version != self.abi.sysctl_interface_version() {
+            return Err(Errno::EACCES);
+        }
+
+        match sysctl.cmd {
+            XEN_SYSCTL_PHYSINFO => sysctl.param.physinfo = self.physinfo(),
+            XEN_SYSCTL_GETDOMAININFOLIST => {
+                let list = &mut sysctl.param.getdomaininfolist;
+                let infos = self
+                    .domains
+                    .range(list.first_domain.0..)
+                    .take(list.max_domains as usize)
+                    .map(|(&domid, domain)| domain.info(DomId(domid)));
+
+                list.num_domains = 0;
+
+                for (i, info) in infos.enumerate() {
+                    list.buffer.0.add(i).write(info);
+                    list.num_domains += 1;
+                }
+            }
+            XEN_SYSCTL_GETCPUINFO => {
+                let getcpuinfo = &mut sysctl.param.getcpuinfo;
+                let nr_cpus = self.pcpus.len().min(getcpuinfo.max_cpus as usize);
+
+                for (i, pcpu) in self.pcpus[..nr_cpus].iter().enumerate() {
+                    getcpuinfo.info.0.add(i).write(XenSysctlCpuinfo {
+                        idletime: Align64(pcpu.idletime),
+                    });
+                }
+
+                getcpuinfo.nr_cpus = nr_cpus as u32;
+            }
+            XEN_SYSCTL_CPUTOPOINFO => {
+                let cputopoinfo = &mut sysctl.param.cputopoinfo;
+                let num_cpus = self.pcpus.len().min(cputopoinfo.num_cpus as usize);
+
+                if !cputopoinfo.cputopo.0.is_null() {
+                    for (i, pcpu) in self.pcpus[..num_cpus].iter().enumerate() {
+                        cputopoinfo.cputopo.0.add(i).write(XenSysctlCpuTopo {
+                            core: pcpu.core,
+                            socket: pcpu.socket,
+                            node: pcpu.node,
+                        });
+                    }
+                }
+
+                cputopoinfo.num_cpus = self.pcpus.len() as u32;
+            }
+            XEN_SYSCTL_NUMAINFO => {
+                let numainfo = &mut sysctl.param.numainfo;
+                let num_nodes = self.nodes.len().min(numainfo.num_nodes as usize);
+
+                if !numainfo.meminfo.0.is_null() {
+                    for (i, node) in self.nodes[..num_nodes].iter().enumerate() {
+                        numainfo.meminfo.0.add(i).write(XenSysctlMeminfo {
+                            memsize: Align64(node.memsize),
+                            memfree: Align64(node.memfree),
+                        });
+                    }
+                }
+
+                numainfo.num_nodes = self.nodes.len() as u32;
+            }
+            XEN_SYSCTL_PM_OP => {
+                let pm_op = &mut sysctl.param.pm_op;
+
+                match pm_op.cmd {
+                    XEN_SYSCTL_PM_OP_CPUFREQ_AVG => {
+                        let pcpu = self.pcpus.get(pm_op.cpuid as usize).ok_or(Errno::EINVAL)?;
+
+                        pm_op.param.get_avgfreq = Align64(pcpu.avgfreq.ok_or(Errno::ENODEV)?);
+                    }
+                    XEN_SYSCTL_PM_OP_GET_CPUFREQ_PARA => {
+                        let pcpu = self.pcpus.get(pm_op.cpuid as usize).ok_or(Errno::EINVAL)?;
+                        let cpufreq = pcpu.cpufreq.as_ref().ok_or(Errno::ENODEV)?;
+                        let para = &mut pm_op.param.get_para;
+                        let freq_num = pcpu.pstates.len() as u32;
+
+                        if (para.cpu_num, para.freq_num, para.gov_num) != (1, freq_num, 1) {
+                            (para.cpu_num, para.freq_num, para.gov_num) = (1, freq_num, 1);
+                            return Err(Errno::EAGAIN);
+                        }
+
+                        let name = |name: &str| {
+                            let mut buffer = [0u8; CPUFREQ_NAME_LEN];
+                            let len = name.len().min(CPUFREQ_NAME_LEN - 1);
+
+                            buffer[..len].copy_from_slice(&name.as_bytes()[..len]);
+                            buffer
+                        };
+                        let freqs = pcpu.pstates.iter().map(|pstate| pstate.freq as u32 * 1000);
+
+                        para.affected_cpus.0.write(pm_op.cpuid);
+                        for (i, freq) in freqs.clone().enumerate() {
+                            para.scaling_available_frequencies.0.add(i).write(freq);
+                        }
+                        (para.scaling_available_governors.0 as *mut [u8; CPUFREQ_NAME_LEN])
+                            .write(name(&cpufreq.governor));
+
+                        para.scaling_driver = name(&cpufreq.driver);
+                        para.scaling_governor = name(&cpufreq.governor);
+                        para.cpuinfo_min_freq = freqs.clone().min().unwrap_or_default();
+                        para.cpuinfo_max_freq = freqs.clone().max().unwrap_or_default();
+                        para.scaling_cur_freq = freqs.clone().nth(pcpu.pstate).unwrap_or_default();
+                        para.cpuinfo_cur_freq = para.scaling_cur_freq;
+                    }
+                    _ => return Err(Errno::ENOSYS),
+                }
+            }
+            XEN_SYSCTL_GET_PMSTAT => {
+                let pmstat = &mut sysctl.param.get_pmstat;
+                let pcpu = self.pcpus.get(pmstat.cpuid as usize).ok_or(Errno::EINVAL)?;
+
+                match pmstat.stat_type {
+                    PMSTAT_GET_MAX_PX | PMSTAT_GET_PXSTAT if pcpu.pstates.is_empty() => {
+                        return Err(Errno::ENODEV)
+                    }
+                    PMSTAT_GET_MAX_CX | PMSTAT_GET_CXSTAT if pcpu.cstates.is_empty() => {
+                        return Err(Errno::ENODEV)
+                    }
+                    PMSTAT_GET_MAX_PX => pmstat.param.getpx.total = pcpu.pstates.len() as u8,
+                    PMSTAT_GET_PXSTAT => {
+                        let getpx = &mut pmstat.param.getpx;
+                        let nr = pcpu.pstates.len();
+
+                        // Xen doesn't check it, but writing out of the buffers would be UB here.
+                        if (getpx.total as usize) < nr {
+                            return Err(Errno::EINVAL);
+                        }
+
+                        for (i, pstate) in pcpu.pstates.iter().enumerate() {
+                            getpx.pt.0.add(i).write(XenPmPxVal {
+                                freq: Align64(pstate.freq),
+                                residency: Align64(pstate.residency),
+                                count: Align64(pstate.count),
+                            });
+
+                            for j in 0..nr {
+                                getpx.trans_pt.0.add(i * nr + j).write(
+                                    pcpu.pstate_transitions
+                                        .get(i * nr + j)
+                                        .copied()
+                                        .unwrap_or_default(),
+                                );
+                            }
+                        }
+
+                        getpx.total = nr as u8;
+                        getpx.usable = nr as u8;
+                        getpx.cur = pcpu.pstate as u8;
+                        getpx.last = pcpu.pstate as u8;
+                    }
+                    PMSTAT_GET_MAX_CX => pmstat.param.getcx.nr = pcpu.cstates.len() as u32,
+                    PMSTAT_GET_CXSTAT => {
+                        let getcx = &mut pmstat.param.getcx;
+                        let nr = pcpu.cstates.len().min(getcx.nr as usize);
+
+                        for (i, cstate) in pcpu.cstates[..nr].iter().enumerate() {
+                            getcx.triggers.0.add(i).write(cstate.usage);
+                            getcx.residencies.0.add(i).write(cstate.residency);
+                        }
+
+                        getcx.nr = nr as u32;
+                        getcx.last = 0;
+                        getcx.idle_time = Align64(pcpu.idletime);
+                        getcx.nr_pc = 0;
+                        getcx.nr_cc = 0;
+                    }
+                    _ => return Err(Errno::EINVAL),
+                }
+            }
+            _ => return Err(Errno::ENOSYS),
+        }
+
+        Ok(())
+    }
+
+    fn domctl(&self, domctl: &mut XenDomctl) -> Result<(), Errno> {
+        if domctl.interface_version != self.abi.domctl_interface_version() {
+            return Err(Errno::EACCES);
+        }
+
+        let domain = self.domains.get(&domctl.domain.0).ok_or(Errno::ESRCH)?;
+
+        match domctl.cmd {
+            XEN_DOMCTL_GETDOMAININFO => domctl.param.getdomaininfo = domain.info(domctl.domain),
+            XEN_DOMCTL_GETVCPUINFO => {
+                // SAFETY: The vCPU is the input of this domctl.
+                let vcpu_id = unsafe { domctl.param.getvcpuinfo.vcpu };
+                let vcpu = domain.vcpus.get(vcpu_id as usize).ok_or(Errno::EINVAL)?;
+
+                domctl.param.getvcpuinfo = XenDomctlGetVCpuInfo {
+                    vcpu: vcpu_id,
+                    online: vcpu.online.into(),
+                    blocked: vcpu.blocked.into(),
+                    running: vcpu.running.into(),
+                    cpu_time: Align64(vcpu.cpu_time),
+                    cpu: vcpu.cpu,
+                };
+            }
+            _ => return Err(Errno::ENOSYS),
+        }
+
+        Ok(())
+    }
+}
+
+/// [XenHypercall] backed by a [MockXen].
+#[derive(Debug)]
+pub struct MockXenHypercall {
+    model: Mutex<MockXen>,
+}
+
+impl MockXenHypercall {
+    pub fn new(model: MockXen) -> Self {
+        Self {
+            model: Mutex::new(model),
+        }
+    }
+
+    /// Access the model, e.g to change it between two hypercalls.
+    pub fn model(&self) -> MutexGuard<'_, MockXen> {
+        self.model.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Buffer of [MockXenHypercall], pointing to the original value.
+pub struct MockConstBuffer<'a, T> {
+    ptr: *const T,
+    original: PhantomData<&'a T>,
+}
+
+impl<T> XenConstBuffer<T> for MockConstBuffer<'_, T> {
+    fn as_hypercall_ptr(&self) -> *const T {
+        self.ptr
+    }
+}
+
+/// Mutable buffer of [MockXenHypercall], pointing to the original value.
+pub struct MockMutBuffer<'a, T> {
+    ptr: *mut T,
+    original: PhantomData<&'a mut T>,
+}
+
+impl<T> XenMutBuffer<T> for MockMutBuffer<'_, T> {
+    fn as_hypercall_ptr(&mut self) -> *mut T {
+        self.ptr
+    }
+
+    unsafe fn update(&mut self) {
+        // The original value has been directly updated.
+    }
+}
+
+impl XenHypercall for MockXenHypercall {
+    type Error = Infallible;
+
+    fn abi(&self) -> XenAbi {
+        self.model().abi
+    }
+
+    unsafe fn hypercall5(&self, cmd: usize, param: [usize; 5]) -> usize {
+        let model = self.model();
+
+        let result = match cmd {
+            HYPERVISOR_SYSCTL => model.sysctl(&mut *(param[0] as *mut XenSysctl)),
+            HYPERVISOR_DOMCTL => model.domctl(&mut *(param[0] as *mut XenDomctl)),
+            _ => Err(Errno::ENOSYS),
+        };
+
+        match result {
+            Ok(()) => 0,
+            Err(errno) => -(errno as isize) as usize,
+        }
+    }
+
+    fn make_const_object<'a, T: Copy>(
+        &self,
+        buffer: &'a T,
+    ) -> Result<impl XenConstBuffer<T>, Self::Error> {
+        Ok(MockConstBuffer {
+            ptr: buffer,
+            original: PhantomData::<&'a T>,
+        })
+    }
+
+    fn make_mut_buffer<'a, T: Copy>(
+        &self,
+        buffer: &'a mut T,
+    ) -> Result<impl XenMutBuffer<T>, Self::Error> {
+        Ok(MockMutBuffer {
+            ptr: buffer,
+            original: PhantomData::<&'a mut T>,
+        })
+    }
+
+    fn make_const_slice<'a, T: Copy>(
+        &self,
+        slice: &'a [T],
+    ) -> Result<impl XenConstBuffer<T>, Self::Error> {
+        Ok(MockConstBuffer {
+            ptr: slice.as_ptr(),
+            original: PhantomData::<&'a T>,
+        })
+    }
+
+    fn make_mut_slice<'a, T: Copy>(
+        &self,
+        slice: &'a mut [T],
+    ) -> Result<impl XenMutBuffer<T>, Self::Error> {
+        Ok(MockMutBuffer {
+            ptr: slice.as_mut_ptr(),
+            original: PhantomData::<&'a mut T>,
+        })
+    }
+}
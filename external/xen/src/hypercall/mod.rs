@@ -4,9 +4,14 @@
 //!
 //! If hosted: `/dev/xen/privcmd` is used via `ioctl()`.
 //! If freestanding: A direct hypercall is issued.
+//! For testing (`mock` feature): a fake hypervisor answers the hypercalls.
 
 use std::error::Error;
 
+use crate::abi::XenAbi;
+
+#[cfg(feature = "mock")]
+pub mod mock;
 pub mod unix;
 
 /// Wrapper of a reference into a hypercall-safe buffer.
@@ -27,6 +32,9 @@ pub trait XenMutBuffer<T> {
 pub trait XenHypercall: Sized {
     type Error: Error + Send + Sync + 'static;
 
+    /// ABI of the hypervisor, which the hypercall structures must follow.
+    fn abi(&self) -> XenAbi;
+
     unsafe fn hypercall5(&self, cmd: usize, param: [usize; 5]) -> usize;
 
     unsafe fn hypercall4(&self, cmd: usize, param: [usize; 4]) -> usize {
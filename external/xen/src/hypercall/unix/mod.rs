@@ -7,18 +7,21 @@
 pub mod buffer;
 
 use core::{marker::PhantomData, ptr::addr_of_mut};
-use std::{fs::File, io, os::fd::AsRawFd};
+use std::{fs::File, os::fd::AsRawFd};
 
 use buffer::{UnixConstXenBuffer, UnixConstXenSlice, UnixMutXenBuffer, UnixMutXenSlice};
 use nix::errno::Errno;
 
 use super::{XenConstBuffer, XenHypercall, XenMutBuffer};
+use crate::abi::{detect_xen_abi, AbiSource, XenAbi};
 
 /// An abstraction over Xen privcmd device.
 #[derive(Debug)]
 pub struct UnixXenHypercall {
     privcmd_device: File,
     hypercall_device: File,
+    abi: XenAbi,
+    abi_source: AbiSource,
 }
 
 #[cfg(target_os = "linux")]
@@ -54,20 +57,43 @@ const PATH_PRIVCMD: &str = "/dev/xen/privcmd";
 const PATH_HYPERCALL: &str = "/dev/xen/hypercall";
 
 impl UnixXenHypercall {
-    pub fn new() -> Result<Self, io::Error> {
-        Ok(Self {
+    /// Open the privcmd device, and detect the Xen ABI.
+    pub fn new() -> anyhow::Result<Self> {
+        Self::with_abi(None)
+    }
+
+    /// Open the privcmd device, using `override_abi` if provided, otherwise detecting the Xen ABI
+    /// (see [crate::abi]).
+    pub fn with_abi(override_abi: Option<XenAbi>) -> anyhow::Result<Self> {
+        let mut hypercall = Self {
             privcmd_device: File::options().read(true).write(true).open(PATH_PRIVCMD)?,
             hypercall_device: File::options()
                 .read(true)
                 .write(true)
                 .open(PATH_HYPERCALL)?,
-        })
+            // Placeholder until detected.
+            abi: XenAbi::Xen417,
+            abi_source: AbiSource::Probe,
+        };
+
+        (hypercall.abi, hypercall.abi_source) = detect_xen_abi(&hypercall, override_abi)?;
+
+        Ok(hypercall)
+    }
+
+    /// How the ABI has been chosen.
+    pub fn abi_source(&self) -> AbiSource {
+        self.abi_source
     }
 }
 
 impl XenHypercall for UnixXenHypercall {
     type Error = Errno;
 
+    fn abi(&self) -> XenAbi {
+        self.abi
+    }
+
     unsafe fn hypercall5(&self, cmd: usize, param: [usize; 5]) -> usize {
         let mut privcmd_arg = PrivCmdArg {
             op: cmd as _,
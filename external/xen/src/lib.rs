@@ -9,6 +9,9 @@ pub mod sysctl;
 pub mod domctl;
 pub mod abi;
 
+#[cfg(all(test, feature = "mock"))]
+mod test;
+
 /// Abstraction of a domain ID. This is the number used by Xen to identify a
 /// single domain at runtime.
 #[repr(transparent)]
@@ -1,3 +1,5 @@
+use bitflags::bitflags;
+
 use crate::{domctl::XenDomctlGetDomainInfo, Align64, DomId};
 
 #[repr(C)]
@@ -13,6 +15,34 @@ pub struct XenSysctlGetDomainInfoList {
     pub num_domains: u32,
 }
 
+bitflags! {
+  /// Content of the `capabilities` field of physinfo.
+  #[repr(C)]
+  #[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+  pub struct XenSysctlPhysCap: u32 {
+      /// The platform supports HVM guests.
+      const HVM = 1 << 0;
+      /// The platform supports PV guests.
+      const PV = 1 << 1;
+      /// The platform supports direct access to I/O devices with VT-d or AMD-V.
+      const DIRECTIO = 1 << 2;
+      /// The platform supports Hardware Assisted Paging.
+      const HAP = 1 << 3;
+      /// The platform supports software paging.
+      const SHADOW = 1 << 4;
+      /// The platform supports sharing of HAP page tables with the IOMMU.
+      const IOMMU_HAP_PT_SHARE = 1 << 5;
+      /// The platform supports processor tracing.
+      const VMTRACE = 1 << 6;
+      /// The platform supports vPMU.
+      const VPMU = 1 << 7;
+      /// The platform supports grant tables v1.
+      const GNTTAB_V1 = 1 << 8;
+      /// The platform supports grant tables v2.
+      const GNTTAB_V2 = 1 << 9;
+  }
+}
+
 #[repr(C)]
 #[derive(Clone, Copy, Debug, Default)]
 pub struct XenSysctlPhysInfo {
@@ -23,7 +53,7 @@ pub struct XenSysctlPhysInfo {
     pub nr_nodes: u32,
     pub max_node_id: u32,
     pub cpu_khz: u32,
-    pub capabilities: u32,
+    pub capabilities: XenSysctlPhysCap,
     pub arch_capabilities: u32,
     pub pad: u32,
     pub total_pages: Align64<u64>,
@@ -51,10 +81,81 @@ pub struct XenSysctlGetCpuInfo {
     pub nr_cpus: u32,
 }
 
+/// Invalid `core`, `socket` or `node` of a [XenSysctlCpuTopo] (e.g offline CPU).
+pub const XEN_INVALID_TOPOLOGY_ID: u32 = !0;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct XenSysctlCpuTopo {
+    pub core: u32,
+    pub socket: u32,
+    pub node: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct XenSysctlCpuTopoInfo {
+    /// IN: size of the buffer, OUT: number of CPUs
+    pub num_cpus: u32,
+    /// OUT (optional)
+    pub cputopo: Align64<*mut XenSysctlCpuTopo>,
+}
+
+/// Invalid `memsize` or `memfree` of a [XenSysctlMeminfo] (e.g node without memory).
+pub const XEN_INVALID_MEM_SZ: u64 = !0;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct XenSysctlMeminfo {
+    /// Memory of the node (bytes)
+    pub memsize: Align64<u64>,
+    /// Free memory of the node (bytes)
+    pub memfree: Align64<u64>,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct XenSysctlNumaInfo {
+    /// IN: size of the buffers, OUT: number of nodes
+    pub num_nodes: u32,
+    /// OUT (optional)
+    pub meminfo: Align64<*mut XenSysctlMeminfo>,
+    /// OUT (optional): distance between each pair of nodes (`num_nodes * num_nodes` entries)
+    pub distance: Align64<*mut u32>,
+}
+
+/// Length of the cpufreq driver and governor names.
+pub const CPUFREQ_NAME_LEN: usize = 16;
+
+/// `xen_get_cpufreq_para`, up to the fields whose layout depends on the Xen version.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct XenGetCpufreqPara {
+    /// IN/OUT: size of `affected_cpus`
+    pub cpu_num: u32,
+    /// IN/OUT: size of `scaling_available_frequencies`
+    pub freq_num: u32,
+    /// IN/OUT: number of governors in `scaling_available_governors`
+    pub gov_num: u32,
+    /// OUT
+    pub affected_cpus: Align64<*mut u32>,
+    /// OUT
+    pub scaling_available_frequencies: Align64<*mut u32>,
+    /// OUT: `gov_num` names of [CPUFREQ_NAME_LEN] bytes
+    pub scaling_available_governors: Align64<*mut u8>,
+    pub scaling_driver: [u8; CPUFREQ_NAME_LEN],
+    pub cpuinfo_cur_freq: u32,
+    pub cpuinfo_max_freq: u32,
+    pub cpuinfo_min_freq: u32,
+    pub scaling_cur_freq: u32,
+    pub scaling_governor: [u8; CPUFREQ_NAME_LEN],
+}
+
 #[repr(C)]
 #[derive(Clone, Copy)]
 pub union XenSysctlPmOpParam {
     pub get_avgfreq: Align64<u64>,
+    pub get_para: XenGetCpufreqPara,
     _pad: [u8; 128], // Just to make sure we are large enough
 }
 
@@ -66,9 +167,83 @@ pub struct XenSysctlPmOp {
     pub param: XenSysctlPmOpParam,
 }
 
+// GET_CPUFREQ_PARA = CPUFREQ_PARA | 0x01
+pub const XEN_SYSCTL_PM_OP_GET_CPUFREQ_PARA: u32 = 0x10 | 0x01;
 // GET_CPUFREQ_AVGFREQ = CPUFREQ_PARA | 0x04
 pub const XEN_SYSCTL_PM_OP_CPUFREQ_AVG: u32 = 0x10 | 0x04;
 
+/// Statistics of a P-state.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct XenPmPxVal {
+    /// Core frequency (MHz)
+    pub freq: Align64<u64>,
+    /// Time spent in this state (ns)
+    pub residency: Align64<u64>,
+    /// Number of transitions to this state
+    pub count: Align64<u64>,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct XenPmPxStat {
+    /// Total number of P-states
+    pub total: u8,
+    /// Number of usable P-states
+    pub usable: u8,
+    /// Last P-state
+    pub last: u8,
+    /// Current P-state
+    pub cur: u8,
+    /// OUT: transitions between each pair of P-states (`total * total` entries)
+    pub trans_pt: Align64<*mut u64>,
+    /// OUT: `total` entries
+    pub pt: Align64<*mut XenPmPxVal>,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct XenPmCxStat {
+    /// IN/OUT: size of `triggers` and `residencies` (including C0)
+    pub nr: u32,
+    /// Last C-state
+    pub last: u32,
+    /// Idle time since boot (ns)
+    pub idle_time: Align64<u64>,
+    /// OUT: number of entries in each C-state
+    pub triggers: Align64<*mut u64>,
+    /// OUT: time spent in each C-state (ns)
+    pub residencies: Align64<*mut u64>,
+    /// IN/OUT: size of `pc`
+    pub nr_pc: u32,
+    /// IN/OUT: size of `cc`
+    pub nr_cc: u32,
+    /// OUT (optional): package C-state residencies
+    pub pc: Align64<*mut u64>,
+    /// OUT (optional): core C-state residencies
+    pub cc: Align64<*mut u64>,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub union XenSysctlGetPmStatParam {
+    pub getpx: XenPmPxStat,
+    pub getcx: XenPmCxStat,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct XenSysctlGetPmStat {
+    pub stat_type: u32,
+    pub cpuid: u32,
+    pub param: XenSysctlGetPmStatParam,
+}
+
+pub const PMSTAT_GET_MAX_PX: u32 = 0x10 | 0x1;
+pub const PMSTAT_GET_PXSTAT: u32 = 0x10 | 0x2;
+pub const PMSTAT_GET_MAX_CX: u32 = 0x20 | 0x1;
+pub const PMSTAT_GET_CXSTAT: u32 = 0x20 | 0x2;
+
 #[repr(C)]
 #[derive(Clone, Copy)]
 pub union XenSysctlParam {
@@ -76,6 +251,9 @@ pub union XenSysctlParam {
     pub physinfo: XenSysctlPhysInfo,
     pub getcpuinfo: XenSysctlGetCpuInfo,
     pub pm_op: XenSysctlPmOp,
+    pub cputopoinfo: XenSysctlCpuTopoInfo,
+    pub numainfo: XenSysctlNumaInfo,
+    pub get_pmstat: XenSysctlGetPmStat,
     _pad: [u8; 128],
 }
 
@@ -91,5 +269,8 @@ pub const HYPERVISOR_SYSCTL: usize = 35;
 
 pub const XEN_SYSCTL_PHYSINFO: u32 = 3;
 pub const XEN_SYSCTL_GETDOMAININFOLIST: u32 = 6;
+pub const XEN_SYSCTL_CPUTOPOINFO: u32 = 16;
 pub const XEN_SYSCTL_GETCPUINFO: u32 = 8;
+pub const XEN_SYSCTL_GET_PMSTAT: u32 = 10;
 pub const XEN_SYSCTL_PM_OP: u32 = 12;
+pub const XEN_SYSCTL_NUMAINFO: u32 = 17;
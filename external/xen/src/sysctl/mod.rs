@@ -1,20 +1,14 @@
 mod ffi;
 pub use ffi::*;
 
+use nix::errno::Errno;
+
 use crate::{
-    abi::get_xen_abi,
     domctl::XenDomctlGetDomainInfo,
     hypercall::{XenHypercall, XenMutBuffer},
     Align64, DomId,
 };
 
-fn sysctl_interface_version() -> u32 {
-    match get_xen_abi() {
-        crate::abi::XenAbi::Xen417 => 0x15,
-        crate::abi::XenAbi::Xen419 => 0x15,
-    }
-}
-
 pub trait SysctlGetDomainInfoList
 where
     Self: XenHypercall,
@@ -25,7 +19,7 @@ where
     ) -> anyhow::Result<Vec<XenDomctlGetDomainInfo>> {
         let mut sysctl = XenSysctl {
             cmd: XEN_SYSCTL_GETDOMAININFOLIST,
-            interface_version: sysctl_interface_version(),
+            interface_version: self.abi().sysctl_interface_version(),
             param: XenSysctlParam {
                 getdomaininfolist: XenSysctlGetDomainInfoList::default(),
             },
@@ -118,7 +112,7 @@ where
     fn physinfo(&self) -> anyhow::Result<XenSysctlPhysInfo> {
         let mut sysctl = XenSysctl {
             cmd: XEN_SYSCTL_PHYSINFO,
-            interface_version: sysctl_interface_version(),
+            interface_version: self.abi().sysctl_interface_version(),
             param: XenSysctlParam {
                 physinfo: XenSysctlPhysInfo::default(),
             },
@@ -153,7 +147,7 @@ where
 
         let mut sysctl = XenSysctl {
             cmd: XEN_SYSCTL_GETCPUINFO,
-            interface_version: sysctl_interface_version(),
+            interface_version: self.abi().sysctl_interface_version(),
             param: XenSysctlParam {
                 getcpuinfo: XenSysctlGetCpuInfo {
                     max_cpus,
@@ -191,7 +185,7 @@ where
     fn get_cpufreq_avgfreq(&self, cpuid: u32) -> anyhow::Result<u64> {
         let mut sysctl = XenSysctl {
             cmd: XEN_SYSCTL_PM_OP,
-            interface_version: sysctl_interface_version(),
+            interface_version: self.abi().sysctl_interface_version(),
             param: XenSysctlParam {
                 pm_op: XenSysctlPmOp {
                     cmd: XEN_SYSCTL_PM_OP_CPUFREQ_AVG,
@@ -216,6 +210,389 @@ where
             Ok(sysctl.param.pm_op.param.get_avgfreq.0)
         }
     }
+
+    /// Get the cpufreq driver and governor of a CPU.
+    ///
+    /// The buffers are allocated with `sizes`, which are updated to the ones Xen expects: keep
+    /// them between calls to avoid a round trip.
+    fn get_cpufreq_policy(
+        &self,
+        cpuid: u32,
+        sizes: &mut CpufreqParaSizes,
+    ) -> anyhow::Result<CpufreqPolicy> {
+        // Xen answers -EAGAIN with the sizes it expects until the buffers are large enough.
+        for _ in 0..3 {
+            let mut para = XenGetCpufreqPara {
+                cpu_num: sizes.cpu_num,
+                freq_num: sizes.freq_num,
+                gov_num: sizes.gov_num,
+                ..Default::default()
+            };
+            let mut affected_cpus = vec![0u32; para.cpu_num as usize];
+            let mut frequencies = vec![0u32; para.freq_num as usize];
+            let mut governors = vec![0u8; para.gov_num as usize * CPUFREQ_NAME_LEN];
+
+            let mut affected_cpus_buffer = self.make_mut_slice(&mut affected_cpus)?;
+            let mut frequencies_buffer = self.make_mut_slice(&mut frequencies)?;
+            let mut governors_buffer = self.make_mut_slice(&mut governors)?;
+
+            para.affected_cpus = Align64(affected_cpus_buffer.as_hypercall_ptr());
+            para.scaling_available_frequencies = Align64(frequencies_buffer.as_hypercall_ptr());
+            para.scaling_available_governors = Align64(governors_buffer.as_hypercall_ptr());
+
+            let mut sysctl = XenSysctl {
+                cmd: XEN_SYSCTL_PM_OP,
+                interface_version: self.abi().sysctl_interface_version(),
+                param: XenSysctlParam {
+                    pm_op: XenSysctlPmOp {
+                        cmd: XEN_SYSCTL_PM_OP_GET_CPUFREQ_PARA,
+                        cpuid,
+                        param: XenSysctlPmOpParam { get_para: para },
+                    },
+                },
+            };
+
+            unsafe {
+                let mut sysctl_buffer = self.make_mut_buffer(&mut sysctl)?;
+                let res = self.hypercall1(HYPERVISOR_SYSCTL, sysctl_buffer.as_hypercall_ptr() as _);
+
+                sysctl_buffer.update();
+                affected_cpus_buffer.update();
+                frequencies_buffer.update();
+                governors_buffer.update();
+                drop(sysctl_buffer);
+
+                let returned = sysctl.param.pm_op.param.get_para;
+
+                match res as isize {
+                    0 => {
+                        return Ok(CpufreqPolicy {
+                            driver: cpufreq_name(&returned.scaling_driver),
+                            governor: cpufreq_name(&returned.scaling_governor),
+                            cur_freq: returned.scaling_cur_freq,
+                            min_freq: returned.cpuinfo_min_freq,
+                            max_freq: returned.cpuinfo_max_freq,
+                        })
+                    }
+                    res if res == -(Errno::EAGAIN as isize) => {
+                        *sizes = CpufreqParaSizes {
+                            cpu_num: returned.cpu_num,
+                            freq_num: returned.freq_num,
+                            gov_num: returned.gov_num,
+                        };
+                    }
+                    res => anyhow::bail!("sysctl_pm_op:get_cpufreq_para failed {res}"),
+                }
+            }
+        }
+
+        anyhow::bail!("sysctl_pm_op:get_cpufreq_para failed: the buffer sizes keep changing")
+    }
 }
 
 impl<H: XenHypercall> SysctlGetPmOp for H {}
+
+pub trait SysctlCpuTopoInfo
+where
+    Self: XenHypercall,
+{
+    /// Get the core, socket and node of each CPU, returns the number of CPUs.
+    fn cputopoinfo(&self, buffer: &mut [XenSysctlCpuTopo]) -> anyhow::Result<usize> {
+        let num_cpus = buffer.len() as _;
+        let mut cputopo_buffer = self.make_mut_slice(buffer)?;
+
+        let mut sysctl = XenSysctl {
+            cmd: XEN_SYSCTL_CPUTOPOINFO,
+            interface_version: self.abi().sysctl_interface_version(),
+            param: XenSysctlParam {
+                cputopoinfo: XenSysctlCpuTopoInfo {
+                    num_cpus,
+                    cputopo: Align64(cputopo_buffer.as_hypercall_ptr()),
+                },
+            },
+        };
+
+        let mut sysctl_buffer = self.make_mut_buffer(&mut sysctl)?;
+
+        unsafe {
+            let res = self.hypercall1(HYPERVISOR_SYSCTL, sysctl_buffer.as_hypercall_ptr() as usize);
+
+            if res != 0 {
+                anyhow::bail!("sysctl_cputopoinfo failed {}", res as isize)
+            }
+
+            sysctl_buffer.update();
+            cputopo_buffer.update();
+            drop(sysctl_buffer);
+            drop(cputopo_buffer);
+
+            Ok(sysctl.param.cputopoinfo.num_cpus as _)
+        }
+    }
+}
+
+impl<H: XenHypercall> SysctlCpuTopoInfo for H {}
+
+pub trait SysctlNumaInfo
+where
+    Self: XenHypercall,
+{
+    /// Get the memory of each NUMA node, returns the number of nodes.
+    fn numainfo(&self, buffer: &mut [XenSysctlMeminfo]) -> anyhow::Result<usize> {
+        let num_nodes = buffer.len() as _;
+        let mut meminfo_buffer = self.make_mut_slice(buffer)?;
+
+        let mut sysctl = XenSysctl {
+            cmd: XEN_SYSCTL_NUMAINFO,
+            interface_version: self.abi().sysctl_interface_version(),
+            param: XenSysctlParam {
+                numainfo: XenSysctlNumaInfo {
+                    num_nodes,
+                    meminfo: Align64(meminfo_buffer.as_hypercall_ptr()),
+                    // Distances are not needed.
+                    distance: Align64::default(),
+                },
+            },
+        };
+
+        let mut sysctl_buffer = self.make_mut_buffer(&mut sysctl)?;
+
+        unsafe {
+            let res = self.hypercall1(HYPERVISOR_SYSCTL, sysctl_buffer.as_hypercall_ptr() as usize);
+
+            if res != 0 {
+                anyhow::bail!("sysctl_numainfo failed {}", res as isize)
+            }
+
+            sysctl_buffer.update();
+            meminfo_buffer.update();
+            drop(sysctl_buffer);
+            drop(meminfo_buffer);
+
+            Ok(sysctl.param.numainfo.num_nodes as _)
+        }
+    }
+}
+
+impl<H: XenHypercall> SysctlNumaInfo for H {}
+
+/// cpufreq policy of a CPU.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CpufreqPolicy {
+    pub driver: String,
+    pub governor: String,
+    /// Current frequency (kHz)
+    pub cur_freq: u32,
+    /// Minimum frequency (kHz)
+    pub min_freq: u32,
+    /// Maximum frequency (kHz)
+    pub max_freq: u32,
+}
+
+/// Buffer sizes of [SysctlGetPmOp::get_cpufreq_policy].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CpufreqParaSizes {
+    /// Number of affected CPUs
+    pub cpu_num: u32,
+    /// Number of available frequencies
+    pub freq_num: u32,
+    /// Number of available governors
+    pub gov_num: u32,
+}
+
+/// Parse a NUL-padded cpufreq name.
+fn cpufreq_name(name: &[u8; CPUFREQ_NAME_LEN]) -> String {
+    let len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+
+    String::from_utf8_lossy(&name[..len]).into_owned()
+}
+
+/// P-state statistics of a CPU.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PxStat {
+    /// Number of usable P-states
+    pub usable: u8,
+    /// Last P-state
+    pub last: u8,
+    /// Current P-state
+    pub cur: u8,
+    /// Frequency, residency and transition count of each P-state
+    pub states: Vec<XenPmPxVal>,
+    /// Transitions between each pair of P-states (`from * states.len() + to`)
+    pub transitions: Vec<u64>,
+}
+
+/// C-state statistics of a CPU.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CxStat {
+    /// Last C-state
+    pub last: u32,
+    /// Idle time since boot (ns)
+    pub idle_time: u64,
+    /// Number of entries in each C-state (C0 first)
+    pub usage: Vec<u64>,
+    /// Time spent in each C-state (ns)
+    pub residencies: Vec<u64>,
+}
+
+fn get_pmstat<H: XenHypercall>(
+    hypercall: &H,
+    stat_type: u32,
+    cpuid: u32,
+    param: XenSysctlGetPmStatParam,
+) -> anyhow::Result<XenSysctlGetPmStatParam> {
+    let mut sysctl = XenSysctl {
+        cmd: XEN_SYSCTL_GET_PMSTAT,
+        interface_version: hypercall.abi().sysctl_interface_version(),
+        param: XenSysctlParam {
+            get_pmstat: XenSysctlGetPmStat {
+                stat_type,
+                cpuid,
+                param,
+            },
+        },
+    };
+
+    unsafe {
+        let mut sysctl_buffer = hypercall.make_mut_buffer(&mut sysctl)?;
+        let res = hypercall.hypercall1(HYPERVISOR_SYSCTL, sysctl_buffer.as_hypercall_ptr() as _);
+
+        if res != 0 {
+            anyhow::bail!("sysctl_get_pmstat({stat_type:#x}) failed {}", res as isize)
+        }
+
+        sysctl_buffer.update();
+        drop(sysctl_buffer);
+
+        Ok(sysctl.param.get_pmstat.param)
+    }
+}
+
+pub trait SysctlGetPmStat
+where
+    Self: XenHypercall,
+{
+    /// Get the P-state residencies and transitions of a CPU.
+    fn get_pxstat(&self, cpuid: u32) -> anyhow::Result<PxStat> {
+        let total = unsafe {
+            get_pmstat(
+                self,
+                PMSTAT_GET_MAX_PX,
+                cpuid,
+                XenSysctlGetPmStatParam {
+                    getpx: XenPmPxStat::default(),
+                },
+            )?
+            .getpx
+            .total
+        };
+
+        let mut states = vec![XenPmPxVal::default(); total as usize];
+        let mut transitions = vec![0u64; total as usize * total as usize];
+
+        let mut states_buffer = self.make_mut_slice(&mut states)?;
+        let mut transitions_buffer = self.make_mut_slice(&mut transitions)?;
+
+        let getpx = unsafe {
+            let getpx = get_pmstat(
+                self,
+                PMSTAT_GET_PXSTAT,
+                cpuid,
+                XenSysctlGetPmStatParam {
+                    getpx: XenPmPxStat {
+                        total,
+                        trans_pt: Align64(transitions_buffer.as_hypercall_ptr()),
+                        pt: Align64(states_buffer.as_hypercall_ptr()),
+                        ..Default::default()
+                    },
+                },
+            )?
+            .getpx;
+
+            states_buffer.update();
+            transitions_buffer.update();
+            getpx
+        };
+        drop(states_buffer);
+        drop(transitions_buffer);
+
+        // Xen fills the tables with its current number of P-states (which is also the stride of
+        // the transitions), it must not have grown since it has been queried.
+        let returned = getpx.total as usize;
+
+        if returned > total as usize {
+            anyhow::bail!(
+                "sysctl_get_pmstat: the number of P-states changed ({total} to {returned})"
+            );
+        }
+
+        states.truncate(returned);
+        transitions.truncate(returned * returned);
+
+        Ok(PxStat {
+            usable: getpx.usable,
+            last: getpx.last,
+            cur: getpx.cur,
+            states,
+            transitions,
+        })
+    }
+
+    /// Get the C-state residencies and usage of a CPU.
+    fn get_cxstat(&self, cpuid: u32) -> anyhow::Result<CxStat> {
+        let nr = unsafe {
+            get_pmstat(
+                self,
+                PMSTAT_GET_MAX_CX,
+                cpuid,
+                XenSysctlGetPmStatParam {
+                    getcx: XenPmCxStat::default(),
+                },
+            )?
+            .getcx
+            .nr
+        };
+
+        let mut usage = vec![0u64; nr as usize];
+        let mut residencies = vec![0u64; nr as usize];
+
+        let mut usage_buffer = self.make_mut_slice(&mut usage)?;
+        let mut residencies_buffer = self.make_mut_slice(&mut residencies)?;
+
+        let getcx = unsafe {
+            // Package and core C-states are not requested.
+            let getcx = get_pmstat(
+                self,
+                PMSTAT_GET_CXSTAT,
+                cpuid,
+                XenSysctlGetPmStatParam {
+                    getcx: XenPmCxStat {
+                        nr,
+                        triggers: Align64(usage_buffer.as_hypercall_ptr()),
+                        residencies: Align64(residencies_buffer.as_hypercall_ptr()),
+                        ..Default::default()
+                    },
+                },
+            )?
+            .getcx;
+
+            usage_buffer.update();
+            residencies_buffer.update();
+            getcx
+        };
+        drop(usage_buffer);
+        drop(residencies_buffer);
+
+        usage.truncate(getcx.nr.min(nr) as usize);
+        residencies.truncate(getcx.nr.min(nr) as usize);
+
+        Ok(CxStat {
+            last: getcx.last,
+            idle_time: getcx.idle_time.0,
+            usage,
+            residencies,
+        })
+    }
+}
+
+impl<H: XenHypercall> SysctlGetPmStat for H {}
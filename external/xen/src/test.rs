@@ -0,0 +1,93 @@
+//! Interface constants, and ABI detection tests against a fake hypervisor
+
+use uuid::Uuid;
+
+use crate::{
+    abi::{choose_xen_abi, probe_xen_abi, AbiSource, XenAbi},
+    hypercall::mock::{MockDomain, MockXen, MockXenHypercall},
+    sysctl::{
+        PMSTAT_GET_CXSTAT, PMSTAT_GET_MAX_CX, PMSTAT_GET_MAX_PX, PMSTAT_GET_PXSTAT,
+        XEN_SYSCTL_CPUTOPOINFO, XEN_SYSCTL_GETCPUINFO, XEN_SYSCTL_GETDOMAININFOLIST,
+        XEN_SYSCTL_GET_PMSTAT, XEN_SYSCTL_NUMAINFO, XEN_SYSCTL_PHYSINFO, XEN_SYSCTL_PM_OP,
+        XEN_SYSCTL_PM_OP_CPUFREQ_AVG, XEN_SYSCTL_PM_OP_GET_CPUFREQ_PARA,
+    },
+};
+
+/// The commands match Xen's public `sysctl.h`: the fake hypervisor uses the same constants, so
+/// it can't catch a wrong number.
+#[test]
+fn sysctl_commands() {
+    for (cmd, expected) in [
+        (XEN_SYSCTL_PHYSINFO, 3),
+        (XEN_SYSCTL_GETDOMAININFOLIST, 6),
+        (XEN_SYSCTL_GETCPUINFO, 8),
+        (XEN_SYSCTL_GET_PMSTAT, 10),
+        (XEN_SYSCTL_PM_OP, 12),
+        (XEN_SYSCTL_CPUTOPOINFO, 16),
+        (XEN_SYSCTL_NUMAINFO, 17),
+        (PMSTAT_GET_MAX_PX, 0x11),
+        (PMSTAT_GET_PXSTAT, 0x12),
+        (PMSTAT_GET_MAX_CX, 0x21),
+        (PMSTAT_GET_CXSTAT, 0x22),
+        (XEN_SYSCTL_PM_OP_GET_CPUFREQ_PARA, 0x11),
+        (XEN_SYSCTL_PM_OP_CPUFREQ_AVG, 0x14),
+    ] {
+        assert_eq!(cmd, expected);
+    }
+}
+
+fn mock_xen(abi: XenAbi) -> MockXenHypercall {
+    let mut xen = MockXen::new(abi, 1);
+    xen.domains.insert(0, MockDomain::new(Uuid::nil(), 1));
+
+    MockXenHypercall::new(xen)
+}
+
+/// ABIs are parsed from Xen versions, and probed from the accepted interface versions.
+#[test]
+fn abi_detection() {
+    assert_eq!("4.18".parse::<XenAbi>().unwrap(), XenAbi::Xen418);
+    assert_eq!(XenAbi::Xen420.to_string(), "4.20");
+    assert!("4.16".parse::<XenAbi>().is_err());
+    assert!("4".parse::<XenAbi>().is_err());
+
+    for (abi, probed) in [
+        (XenAbi::Xen417, XenAbi::Xen417),
+        (XenAbi::Xen418, XenAbi::Xen418),
+        // Same interface versions, the newest is chosen.
+        (XenAbi::Xen419, XenAbi::Xen420),
+        (XenAbi::Xen420, XenAbi::Xen420),
+    ] {
+        assert_eq!(probe_xen_abi(&mock_xen(abi)).unwrap(), probed);
+    }
+
+    // Probing needs a domain 0.
+    let hyp = MockXenHypercall::new(MockXen::new(XenAbi::Xen418, 1));
+    assert!(probe_xen_abi(&hyp).is_err());
+
+    // In order of precedence: override, environment, hypervisor version, then probing.
+    let hyp = mock_xen(XenAbi::Xen417);
+    assert_eq!(
+        choose_xen_abi(
+            &hyp,
+            Some(XenAbi::Xen418),
+            Some("4.19"),
+            Some(XenAbi::Xen420)
+        )
+        .unwrap(),
+        (XenAbi::Xen418, AbiSource::Override)
+    );
+    assert_eq!(
+        choose_xen_abi(&hyp, None, Some("4.19"), Some(XenAbi::Xen420)).unwrap(),
+        (XenAbi::Xen419, AbiSource::Environment)
+    );
+    assert!(choose_xen_abi(&hyp, None, Some("4.16"), Some(XenAbi::Xen420)).is_err());
+    assert_eq!(
+        choose_xen_abi(&hyp, None, None, Some(XenAbi::Xen420)).unwrap(),
+        (XenAbi::Xen420, AbiSource::Hypervisor)
+    );
+    assert_eq!(
+        choose_xen_abi(&hyp, None, None, None).unwrap(),
+        (XenAbi::Xen417, AbiSource::Probe)
+    );
+}
@@ -1,10 +1,15 @@
 mod plugin;
+#[cfg(feature = "xenstore")]
+mod watch;
 
 use std::{os::unix::net::UnixStream, path::PathBuf};
 
 use argh::FromArgs;
 use xcp_metrics_common::protocol::METRICS_SOCKET_PATH;
-use xen::hypercall::unix::UnixXenHypercall;
+use xen::{
+    abi::XenAbi,
+    hypercall::{unix::UnixXenHypercall, XenHypercall},
+};
 
 /// xcp-metrics XenStore plugin.
 #[derive(Clone, FromArgs, Debug)]
@@ -16,6 +21,10 @@ struct Args {
     /// target daemon.
     #[argh(option, short = 'd')]
     target: Option<PathBuf>,
+
+    /// xen ABI to use (e.g 4.19), detected by default.
+    #[argh(option)]
+    xen_abi: Option<XenAbi>,
 }
 
 fn main() {
@@ -38,7 +47,7 @@ fn main() {
             }
         };
 
-    let hyp = match UnixXenHypercall::new() {
+    let hyp = match UnixXenHypercall::with_abi(args.xen_abi) {
         Ok(xs) => xs,
         Err(e) => {
             tracing::error!("Unable to initialize xen privcmd: {e}");
@@ -46,7 +55,19 @@ fn main() {
         }
     };
 
-    if let Err(e) = plugin::run_plugin(&mut rpc_stream, &hyp) {
+    tracing::info!("Using Xen {} ABI ({:?})", hyp.abi(), hyp.abi_source());
+
+    // Without xenstore, domain changes are only seen at each collection round.
+    #[cfg(feature = "xenstore")]
+    let domain_changes = {
+        let (sender, receiver) = std::sync::mpsc::channel();
+        std::thread::spawn(move || watch::watch_domains(sender));
+        Some(receiver)
+    };
+    #[cfg(not(feature = "xenstore"))]
+    let domain_changes = None;
+
+    if let Err(e) = plugin::run_plugin(&mut rpc_stream, &hyp, domain_changes) {
         tracing::error!("Plugin failure {e}");
     }
 }
@@ -1,8 +1,21 @@
+mod abi;
 mod cpu;
+mod domain;
 mod memory;
+mod pm;
+mod topology;
 mod vcpu;
 
-use std::{collections::HashMap, os::unix::net::UnixStream, thread, time::Duration};
+#[cfg(test)]
+mod test;
+
+use std::{
+    collections::HashMap,
+    os::unix::net::UnixStream,
+    sync::mpsc::{self, Receiver, Sender},
+    thread,
+    time::{Duration, SystemTime},
+};
 
 use compact_str::{CompactString, ToCompactString};
 use enum_dispatch::enum_dispatch;
@@ -11,18 +24,25 @@ use uuid::Uuid;
 
 use xcp_metrics_common::{
     metrics::{Label, Metric},
-    protocol::{ProtocolMessage, RemoveMetric, UpdateMetric, XcpMetricsStream},
+    protocol::{
+        CollectionDone, ProtocolMessage, RemoveMetric, SubscribeTicks, UpdateMetric,
+        XcpMetricsStream,
+    },
 };
 use xen::{
-    domctl::XenDomctlGetDomainInfo,
-    hypercall::unix::UnixXenHypercall,
+    domctl::{DomctlGetVCpuInfo, XenDomctlGetDomainInfo, XenDomctlGetVCpuInfo},
+    hypercall::XenHypercall,
     sysctl::{SysctlGetDomainInfoList, SysctlPhysInfo, XenSysctlPhysInfo},
     DomId,
 };
 
-use cpu::{PCpuFreq, PCpuUsage};
-use memory::DomainMemory;
-use vcpu::VCpuUsage;
+use abi::XenAbiInfo;
+use cpu::{PCpuFreq, PCpuUsage, PCpuVCpus};
+use domain::DomainState;
+use memory::{DomainMemory, HostMemory, NodeMemory};
+use pm::PCpuPmStats;
+use topology::HostTopology;
+use vcpu::{DomainCpuUsage, VCpuState, VCpuUsage};
 
 #[derive(Default)]
 struct PluginState {
@@ -36,37 +56,124 @@ pub(crate) struct PluginMetricKind {
     submetric: Option<CompactString>,
 }
 
+/// Time between two collection rounds, to compute rates.
+#[derive(Debug, Default)]
+pub(crate) struct RoundClock {
+    latest: Option<SystemTime>,
+    elapsed: Option<Duration>,
+}
+
+impl RoundClock {
+    pub fn advance(&mut self, timestamp: SystemTime) {
+        self.elapsed = self
+            .latest
+            .and_then(|latest| timestamp.duration_since(latest).ok())
+            .filter(|elapsed| !elapsed.is_zero());
+        self.latest = Some(timestamp);
+    }
+
+    /// Time since the previous round, [None] for the first round.
+    pub fn elapsed(&self) -> Option<Duration> {
+        self.elapsed
+    }
+}
+
+/// What is read from the hypervisor once per collection round, and shared by the metrics.
+pub(crate) struct RoundSnapshot {
+    pub physinfo: XenSysctlPhysInfo,
+    /// Labels of each pCPU (see [cpu::pcpu_labels]), indexed by CPU id.
+    pub pcpu_labels: Vec<Box<[Label]>>,
+    pub domains: Vec<DomainSnapshot>,
+}
+
+impl RoundSnapshot {
+    pub fn read(hyp: &impl XenHypercall) -> anyhow::Result<Self> {
+        let physinfo = hyp
+            .physinfo()
+            .inspect_err(|e| tracing::error!("physinfo hypercall failure {e}"))?;
+
+        Ok(Self {
+            physinfo,
+            pcpu_labels: cpu::pcpu_labels(hyp, physinfo),
+            domains: hyp
+                .iter_domains()
+                .map(|info| DomainSnapshot::read(hyp, info))
+                .collect(),
+        })
+    }
+}
+
+/// A domain and its vCPUs, as read at the beginning of a collection round.
+pub(crate) struct DomainSnapshot {
+    pub info: XenDomctlGetDomainInfo,
+    /// Information of each vCPU (up to `max_vcpu_id`), [None] if it couldn't be read.
+    pub vcpus: SmallVec<[Option<XenDomctlGetVCpuInfo>; 8]>,
+}
+
+impl DomainSnapshot {
+    pub fn read(hyp: &impl XenHypercall, info: XenDomctlGetDomainInfo) -> Self {
+        Self {
+            info,
+            vcpus: (0..=info.max_vcpu_id)
+                .map(|vcpu_id| {
+                    hyp.get_vcpu_info(info.domain, vcpu_id)
+                        .inspect_err(|e| tracing::error!("get_vcpu_info failure: {e}"))
+                        .ok()
+                })
+                .collect(),
+        }
+    }
+}
+
 #[enum_dispatch]
 pub(crate) trait XenMetric {
+    /// A collection round of the metrics sampled at `timestamp` begins.
+    fn begin_round(&mut self, _timestamp: SystemTime) {}
+
     fn make_families(&self, stream: &mut UnixStream) -> anyhow::Result<()>;
 
-    fn read_host_metrics(
+    fn read_host_metrics<H: XenHypercall>(
         &mut self,
-        _physinfo: XenSysctlPhysInfo,
-        _hyp: &UnixXenHypercall,
+        _round: &RoundSnapshot,
+        _hyp: &H,
     ) -> SmallVec<[(PluginMetricKind, Metric); 3]> {
         smallvec![]
     }
 
-    fn read_domain_metrics(
+    fn read_domain_metrics<H: XenHypercall>(
         &mut self,
-        _dominfo: XenDomctlGetDomainInfo,
+        _domain: &DomainSnapshot,
         // impl XenHypercall doesn't work due to enum_dispatch bug.
-        _hyp: &UnixXenHypercall,
+        _hyp: &H,
     ) -> SmallVec<[(PluginMetricKind, Metric); 3]> {
         smallvec![]
     }
 
     /// For cleaning up internal metric informations.
     fn clear_domain_metrics(&mut self, _domid: u16) {}
+
+    /// Whether the domain metrics are also read when a domain changes of state (they must not
+    /// depend on the collection rounds).
+    fn on_domain_change(&self) -> bool {
+        false
+    }
 }
 
 #[enum_dispatch(XenMetric)]
 pub(crate) enum XenMetricEnum {
+    Abi(XenAbiInfo),
     Memory(DomainMemory),
+    DomainState(DomainState),
+    HostMemory(HostMemory),
+    NodeMemory(NodeMemory),
+    Topology(HostTopology),
     PCpu(PCpuUsage),
     VCpu(VCpuUsage),
+    VCpuState(VCpuState),
+    DomainCpu(DomainCpuUsage),
     CpuFreq(PCpuFreq),
+    PCpuVCpus(PCpuVCpus),
+    PmStats(PCpuPmStats),
 }
 
 impl PluginState {
@@ -116,40 +223,178 @@ impl PluginState {
 
         Ok(())
     }
+
+    /// Remove the metrics of the domains that no longer exist.
+    pub fn remove_domains(
+        &mut self,
+        stream: &mut UnixStream,
+        metrics: &mut [XenMetricEnum],
+        found_domain: &[u16],
+    ) -> anyhow::Result<()> {
+        let orphans = self
+            .domid_metrics
+            .keys()
+            .filter(|domid| !found_domain.contains(domid))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for domid in orphans {
+            tracing::debug!("{domid} disappaered");
+
+            metrics
+                .iter_mut()
+                .for_each(|xen_metric| xen_metric.clear_domain_metrics(domid));
+
+            let Some(domain_metrics) = self.domid_metrics.remove(&domid) else {
+                continue;
+            };
+
+            for (PluginMetricKind { family_name, .. }, uuid) in domain_metrics {
+                stream.send_message(ProtocolMessage::RemoveMetric(RemoveMetric {
+                    family_name: family_name.into(),
+                    uuid,
+                }))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// What the plugin reacts to.
+enum PluginEvent {
+    Message(std::io::Result<ProtocolMessage>),
+    /// A domain has been introduced to xenstored, has shut down or has been destroyed.
+    DomainChange,
 }
 
-pub fn run_plugin(stream: &mut UnixStream, hyp: &UnixXenHypercall) -> anyhow::Result<()> {
+/// Forward the messages of the daemon.
+fn forward_messages(mut stream: UnixStream, sender: Sender<PluginEvent>) {
+    loop {
+        let message = stream.recv_message();
+        let failed = message.is_err();
+
+        if sender.send(PluginEvent::Message(message)).is_err() || failed {
+            return;
+        }
+    }
+}
+
+/// Forward the domain changes, until they are no longer watched.
+fn forward_domain_changes(changes: Receiver<()>, sender: Sender<PluginEvent>) {
+    for () in changes {
+        if sender.send(PluginEvent::DomainChange).is_err() {
+            return;
+        }
+    }
+
+    tracing::warn!("Domain changes are no longer watched, they are only seen at each round");
+}
+
+/// Read the metrics that follow the domain state, and remove the metrics of the dead domains
+/// without waiting for the next round.
+fn refresh_domains(
+    state: &mut PluginState,
+    stream: &mut UnixStream,
+    metrics: &mut [XenMetricEnum],
+    hyp: &impl XenHypercall,
+) -> anyhow::Result<()> {
+    let mut found_domain = vec![];
+
+    for info in hyp.iter_domains() {
+        let (domid, dom_uuid) = (info.domain, info.handle);
+        found_domain.push(domid.0);
+        let domain = DomainSnapshot::read(hyp, info);
+
+        for metric in metrics
+            .iter_mut()
+            .filter(|xen_metric| xen_metric.on_domain_change())
+            .flat_map(|xen_metric| xen_metric.read_domain_metrics(&domain, hyp))
+        {
+            tracing::debug!("Pushing {metric:?}");
+            state.push_domain_metric((domid, dom_uuid), stream, metric)?;
+        }
+    }
+
+    state.remove_domains(stream, metrics, &found_domain)
+}
+
+/// Run the plugin, reacting to the domain changes signaled through `domain_changes` (if provided).
+pub fn run_plugin(
+    stream: &mut UnixStream,
+    hyp: &impl XenHypercall,
+    domain_changes: Option<Receiver<()>>,
+) -> anyhow::Result<()> {
     let mut state = PluginState::default();
     let metrics: &mut [XenMetricEnum] = &mut [
         DomainMemory.into(),
+        DomainState.into(),
         VCpuUsage::new().into(),
+        VCpuState.into(),
+        DomainCpuUsage::new().into(),
         PCpuUsage::new().into(),
         PCpuFreq.into(),
+        PCpuVCpus.into(),
+        PCpuPmStats::new().into(),
+        XenAbiInfo.into(),
+        HostMemory.into(),
+        NodeMemory.into(),
+        HostTopology.into(),
     ];
 
     for xen_metric in metrics.iter() {
         xen_metric.make_families(stream)?;
     }
 
+    // Collect the metrics when the daemon asks for it, so that they line up with other plugins.
+    stream.send_message(ProtocolMessage::SubscribeTicks(SubscribeTicks))?;
+
+    let (sender, receiver) = mpsc::channel();
+
+    thread::spawn({
+        let (stream, sender) = (stream.try_clone()?, sender.clone());
+        move || forward_messages(stream, sender)
+    });
+
+    if let Some(changes) = domain_changes {
+        thread::spawn(move || forward_domain_changes(changes, sender));
+    }
+
     loop {
+        let tick = match receiver.recv()? {
+            PluginEvent::Message(message) => match message? {
+                ProtocolMessage::CollectionTick(tick) => tick,
+                message => {
+                    tracing::warn!("Unexpected message {message:?}");
+                    continue;
+                }
+            },
+            PluginEvent::DomainChange => {
+                refresh_domains(&mut state, stream, metrics, hyp)?;
+                continue;
+            }
+        };
+
+        metrics
+            .iter_mut()
+            .for_each(|xen_metric| xen_metric.begin_round(tick.timestamp));
+
         // Track what domains (still) exists.
         let mut found_domain = vec![0; 0];
 
-        let physinfo = hyp
-            .physinfo()
-            .inspect_err(|e| tracing::error!("physinfo hypercall failure {e}"))?;
+        let round = RoundSnapshot::read(hyp)?;
 
         // Get host metrics
         for metric in metrics
             .iter_mut()
-            .flat_map(|xen_metric| xen_metric.read_host_metrics(physinfo, hyp))
+            .flat_map(|xen_metric| xen_metric.read_host_metrics(&round, hyp))
         {
             tracing::debug!("Pushing {metric:?}");
             state.push_host_metric(stream, metric)?;
         }
 
-        for domain in hyp.iter_domains() {
-            let (domid, dom_uuid) = (domain.domain, domain.handle);
+        for domain in &round.domains {
+            let (domid, dom_uuid) = (domain.info.domain, domain.info.handle);
             found_domain.push(domid.0);
 
             for metric in metrics
@@ -162,32 +407,10 @@ pub fn run_plugin(stream: &mut UnixStream, hyp: &UnixXenHypercall) -> anyhow::Re
         }
 
         // For all domains that no longer exists, remove all their related metrics.
-        let orphans = state
-            .domid_metrics
-            .keys()
-            .filter(|domid| !found_domain.contains(domid))
-            .cloned()
-            .collect::<Vec<_>>();
+        state.remove_domains(stream, metrics, &found_domain)?;
 
-        for domid in orphans {
-            tracing::debug!("{domid} disappaered");
-
-            metrics
-                .iter_mut()
-                .for_each(|xen_metric| xen_metric.clear_domain_metrics(domid));
-
-            let Some(domain_metrics) = state.domid_metrics.remove(&domid) else {
-                continue;
-            };
-
-            for (PluginMetricKind { family_name, .. }, uuid) in domain_metrics {
-                stream.send_message(ProtocolMessage::RemoveMetric(RemoveMetric {
-                    family_name: family_name.into(),
-                    uuid,
-                }))?;
-            }
-        }
-
-        thread::sleep(Duration::from_secs(1));
+        stream.send_message(ProtocolMessage::CollectionDone(CollectionDone {
+            sequence: tick.sequence,
+        }))?;
     }
 }
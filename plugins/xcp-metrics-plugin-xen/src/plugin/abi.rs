@@ -0,0 +1,64 @@
+use std::os::unix::net::UnixStream;
+
+use compact_str::format_compact;
+use smallvec::{smallvec, SmallVec};
+
+use xcp_metrics_common::{
+    metrics::{Label, Metric, MetricType, MetricValue},
+    protocol::{CreateFamily, ProtocolMessage, XcpMetricsStream},
+};
+use xen::hypercall::XenHypercall;
+
+use super::{PluginMetricKind, RoundSnapshot, XenMetric};
+
+/// Xen ABI used by the plugin.
+pub struct XenAbiInfo;
+
+impl XenMetric for XenAbiInfo {
+    fn make_families(&self, stream: &mut UnixStream) -> anyhow::Result<()> {
+        stream.send_message(ProtocolMessage::CreateFamily(CreateFamily {
+            help: "Xen ABI used to communicate with the hypervisor".into(),
+            name: "xen_abi".into(),
+            metric_type: MetricType::Info,
+            unit: "".into(),
+        }))?;
+
+        Ok(())
+    }
+
+    fn read_host_metrics<H: XenHypercall>(
+        &mut self,
+        _: &RoundSnapshot,
+        hyp: &H,
+    ) -> SmallVec<[(PluginMetricKind, Metric); 3]> {
+        let abi = hyp.abi();
+        let label = |name: &str, value| Label {
+            name: name.into(),
+            value,
+        };
+
+        smallvec![(
+            PluginMetricKind {
+                family_name: "xen_abi",
+                submetric: None,
+            },
+            Metric {
+                labels: vec![].into_boxed_slice(),
+                value: MetricValue::Info(
+                    vec![
+                        label("version", format_compact!("{abi}")),
+                        label(
+                            "sysctl_interface_version",
+                            format_compact!("{:#x}", abi.sysctl_interface_version()),
+                        ),
+                        label(
+                            "domctl_interface_version",
+                            format_compact!("{:#x}", abi.domctl_interface_version()),
+                        ),
+                    ]
+                    .into_boxed_slice(),
+                ),
+            },
+        )]
+    }
+}
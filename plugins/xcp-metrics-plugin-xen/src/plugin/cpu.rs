@@ -1,4 +1,8 @@
-use std::{iter, os::unix::net::UnixStream, time::Instant};
+use std::{
+    iter,
+    os::unix::net::UnixStream,
+    time::{Duration, SystemTime},
+};
 
 use compact_str::ToCompactString;
 use smallvec::{smallvec, SmallVec};
@@ -8,31 +12,78 @@ use xcp_metrics_common::{
     protocol::{CreateFamily, ProtocolMessage, XcpMetricsStream},
 };
 use xen::{
-    hypercall::unix::UnixXenHypercall,
-    sysctl::{SysctlGetCpuInfo, SysctlGetPmOp, XenSysctlCpuinfo, XenSysctlPhysInfo},
+    hypercall::XenHypercall,
+    sysctl::{
+        SysctlCpuTopoInfo, SysctlGetCpuInfo, SysctlGetPmOp, XenSysctlCpuTopo, XenSysctlCpuinfo,
+        XenSysctlPhysInfo, XEN_INVALID_TOPOLOGY_ID,
+    },
 };
 
-use super::{PluginMetricKind, XenMetric};
+use super::{PluginMetricKind, RoundClock, RoundSnapshot, XenMetric};
 
 // TODO: use a passed physinfo
 pub struct PCpuUsage {
-    latest_instant: Instant,
+    clock: RoundClock,
     prev_pcpu_infos: Option<Box<[XenSysctlCpuinfo]>>,
 }
 
 impl PCpuUsage {
     pub fn new() -> Self {
         Self {
-            latest_instant: Instant::now(),
+            clock: RoundClock::default(),
             prev_pcpu_infos: None,
         }
     }
 }
 
+/// Labels of each pCPU: `cpu_id`, and its `node`, `socket` and `core` when they are known.
+pub(super) fn pcpu_labels<H: XenHypercall>(
+    hyp: &H,
+    physinfo: XenSysctlPhysInfo,
+) -> Vec<Box<[Label]>> {
+    let mut topology = vec![XenSysctlCpuTopo::default(); (physinfo.max_cpu_id + 1) as _];
+
+    match hyp.cputopoinfo(&mut topology) {
+        Ok(count) => topology.truncate(count),
+        Err(e) => {
+            tracing::warn!("cputopoinfo failure: {e}");
+            topology.clear();
+        }
+    }
+
+    (0..=physinfo.max_cpu_id as usize)
+        .map(|cpu_id| {
+            let mut labels = vec![Label {
+                name: "cpu_id".into(),
+                value: cpu_id.to_compact_string(),
+            }];
+
+            if let Some(topo) = topology.get(cpu_id) {
+                labels.extend(
+                    [
+                        ("node", topo.node),
+                        ("socket", topo.socket),
+                        ("core", topo.core),
+                    ]
+                    .into_iter()
+                    .filter(|&(_, id)| id != XEN_INVALID_TOPOLOGY_ID)
+                    .map(|(name, id)| Label {
+                        name: name.into(),
+                        value: id.to_compact_string(),
+                    }),
+                );
+            }
+
+            labels.into_boxed_slice()
+        })
+        .collect()
+}
+
 fn generate_pcpu_usage(
     cpu_id: usize,
+    labels: Box<[Label]>,
     (pcpu_info, prev_pcpu_info): (&XenSysctlCpuinfo, &XenSysctlCpuinfo),
-    latest_instant: Instant,
+    elapsed: Duration,
 ) -> (PluginMetricKind, Metric) {
     (
         PluginMetricKind {
@@ -40,18 +91,14 @@ fn generate_pcpu_usage(
             submetric: Some(cpu_id.to_compact_string()),
         },
         Metric {
-            labels: vec![Label {
-                name: "cpu_id".into(),
-                value: cpu_id.to_compact_string(),
-            }]
-            .into_boxed_slice(),
+            labels,
             value: MetricValue::Gauge(NumberValue::Double(f64::max(
                 0.0,
                 1.0 - f64::max(
                     0.0,
                     ((pcpu_info.idletime.0 - prev_pcpu_info.idletime.0) as f64)
                         / 1.0e9
-                        / latest_instant.elapsed().as_secs_f64(),
+                        / elapsed.as_secs_f64(),
                 ),
             ))),
         },
@@ -59,6 +106,10 @@ fn generate_pcpu_usage(
 }
 
 impl XenMetric for PCpuUsage {
+    fn begin_round(&mut self, timestamp: SystemTime) {
+        self.clock.advance(timestamp);
+    }
+
     fn make_families(&self, stream: &mut UnixStream) -> anyhow::Result<()> {
         stream.send_message(ProtocolMessage::CreateFamily(CreateFamily {
             help: "Time taken running a CPU core".into(),
@@ -70,13 +121,13 @@ impl XenMetric for PCpuUsage {
         Ok(())
     }
 
-    fn read_host_metrics(
+    fn read_host_metrics<H: XenHypercall>(
         &mut self,
-        physinfo: XenSysctlPhysInfo,
-        hyp: &UnixXenHypercall,
+        round: &RoundSnapshot,
+        hyp: &H,
     ) -> SmallVec<[(PluginMetricKind, Metric); 3]> {
         let mut new_pcpu_infos: Vec<XenSysctlCpuinfo> =
-            vec![XenSysctlCpuinfo::default(); (physinfo.max_cpu_id + 1) as _];
+            vec![XenSysctlCpuinfo::default(); (round.physinfo.max_cpu_id + 1) as _];
 
         match hyp.get_cpu_info(&mut new_pcpu_infos) {
             Ok(count) => new_pcpu_infos.truncate(count),
@@ -86,11 +137,14 @@ impl XenMetric for PCpuUsage {
             }
         }
 
-        let metrics = if let Some(previous_pcpu_infos) = &self.prev_pcpu_infos {
+        let metrics = if let Some((previous_pcpu_infos, elapsed)) =
+            self.prev_pcpu_infos.as_ref().zip(self.clock.elapsed())
+        {
             iter::zip(&new_pcpu_infos, previous_pcpu_infos)
+                .zip(round.pcpu_labels.iter().cloned())
                 .enumerate()
-                .map(|(cpu_id, pcpus_info)| {
-                    generate_pcpu_usage(cpu_id, pcpus_info, self.latest_instant)
+                .map(|(cpu_id, (pcpus_info, labels))| {
+                    generate_pcpu_usage(cpu_id, labels, pcpus_info, elapsed)
                 })
                 .collect()
         } else {
@@ -98,7 +152,6 @@ impl XenMetric for PCpuUsage {
         };
 
         self.prev_pcpu_infos = Some(new_pcpu_infos.into_boxed_slice());
-        self.latest_instant = Instant::now();
         metrics
     }
 }
@@ -117,12 +170,12 @@ impl XenMetric for PCpuFreq {
         Ok(())
     }
 
-    fn read_host_metrics(
+    fn read_host_metrics<H: XenHypercall>(
         &mut self,
-        physinfo: XenSysctlPhysInfo,
-        hyp: &UnixXenHypercall,
+        round: &RoundSnapshot,
+        hyp: &H,
     ) -> SmallVec<[(PluginMetricKind, Metric); 3]> {
-        (0..=physinfo.max_cpu_id)
+        (0..=round.physinfo.max_cpu_id)
             .filter_map(|cpuid| {
                 // Ignore all failing reads.
                 hyp.get_cpufreq_avgfreq(cpuid)
@@ -139,11 +192,7 @@ impl XenMetric for PCpuFreq {
                         submetric: Some(cpuid.to_compact_string()),
                     },
                     Metric {
-                        labels: vec![Label {
-                            name: "cpu_id".into(),
-                            value: cpuid.to_compact_string(),
-                        }]
-                        .into_boxed_slice(),
+                        labels: round.pcpu_labels[cpuid as usize].clone(),
                         value: MetricValue::Gauge(NumberValue::Int64(freq as i64)),
                     },
                 )
@@ -151,3 +200,55 @@ impl XenMetric for PCpuFreq {
             .collect()
     }
 }
+
+/// Number of online vCPUs placed on each pCPU, to spot oversubscribed cores.
+pub struct PCpuVCpus;
+
+impl XenMetric for PCpuVCpus {
+    fn make_families(&self, stream: &mut UnixStream) -> anyhow::Result<()> {
+        stream.send_message(ProtocolMessage::CreateFamily(CreateFamily {
+            help: "Number of online VCPUs placed on a CPU core".into(),
+            name: "xen_cpu_vcpus".into(),
+            metric_type: MetricType::Gauge,
+            unit: "".into(),
+        }))?;
+
+        Ok(())
+    }
+
+    fn read_host_metrics<H: XenHypercall>(
+        &mut self,
+        round: &RoundSnapshot,
+        _: &H,
+    ) -> SmallVec<[(PluginMetricKind, Metric); 3]> {
+        let mut vcpus = vec![0; (round.physinfo.max_cpu_id + 1) as _];
+
+        for vcpu_info in round
+            .domains
+            .iter()
+            .flat_map(|domain| domain.vcpus.iter().flatten())
+        {
+            if vcpu_info.online != 0 {
+                if let Some(count) = vcpus.get_mut(vcpu_info.cpu as usize) {
+                    *count += 1;
+                }
+            }
+        }
+
+        iter::zip(vcpus, round.pcpu_labels.iter().cloned())
+            .enumerate()
+            .map(|(cpu_id, (count, labels))| {
+                (
+                    PluginMetricKind {
+                        family_name: "xen_cpu_vcpus",
+                        submetric: Some(cpu_id.to_compact_string()),
+                    },
+                    Metric {
+                        labels,
+                        value: MetricValue::Gauge(NumberValue::Int64(count)),
+                    },
+                )
+            })
+            .collect()
+    }
+}
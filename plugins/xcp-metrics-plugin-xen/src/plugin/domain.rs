@@ -0,0 +1,143 @@
+use std::os::unix::net::UnixStream;
+
+use compact_str::{CompactString, ToCompactString};
+use smallvec::{smallvec, SmallVec};
+
+use xcp_metrics_common::{
+    metrics::{Label, Metric, MetricType, MetricValue, State},
+    protocol::{CreateFamily, ProtocolMessage, XcpMetricsStream},
+};
+use xen::{
+    domctl::{XenDomctlDominf, XenShutdownReason},
+    hypercall::XenHypercall,
+};
+
+use super::{DomainSnapshot, PluginMetricKind, XenMetric};
+
+/// Run state flags of a domain, and the name of their state.
+const RUN_STATES: [(XenDomctlDominf, &str); 5] = [
+    (XenDomctlDominf::RUNNING, "running"),
+    (XenDomctlDominf::BLOCKED, "blocked"),
+    (XenDomctlDominf::PAUSED, "paused"),
+    (XenDomctlDominf::SHUTDOWN, "shutdown"),
+    (XenDomctlDominf::DYING, "dying"),
+];
+
+/// Run state and shutdown reason (as state sets) and traits of a domain.
+pub struct DomainState;
+
+impl XenMetric for DomainState {
+    fn make_families(&self, stream: &mut UnixStream) -> anyhow::Result<()> {
+        for (name, help, metric_type) in [
+            (
+                "xen_domain_state",
+                "Run state of a guest.",
+                MetricType::StateSet,
+            ),
+            (
+                "xen_domain_shutdown_reason",
+                "Why a guest has shut down.",
+                MetricType::StateSet,
+            ),
+            (
+                "xen_domain",
+                "Type, vCPUs and cpupool of a guest.",
+                MetricType::Info,
+            ),
+        ] {
+            stream.send_message(ProtocolMessage::CreateFamily(CreateFamily {
+                help: help.into(),
+                name: name.into(),
+                metric_type,
+                unit: "".into(),
+            }))?;
+        }
+
+        Ok(())
+    }
+
+    fn read_domain_metrics<H: XenHypercall>(
+        &mut self,
+        domain: &DomainSnapshot,
+        _: &H,
+    ) -> SmallVec<[(PluginMetricKind, Metric); 3]> {
+        let dominfo = domain.info;
+        let state = |name: &str, enabled| State {
+            name: name.into(),
+            enabled,
+        };
+        let label = |name: &str, value: CompactString| Label {
+            name: name.into(),
+            value,
+        };
+        let metric = |family_name, value| {
+            (
+                PluginMetricKind {
+                    family_name,
+                    submetric: None,
+                },
+                Metric {
+                    labels: vec![].into_boxed_slice(),
+                    value,
+                },
+            )
+        };
+
+        let shutdown_reason = dominfo.shutdown_reason();
+
+        smallvec![
+            metric(
+                "xen_domain_state",
+                MetricValue::StateSet(
+                    RUN_STATES
+                        .iter()
+                        .map(|&(flag, name)| state(name, dominfo.flags.contains(flag)))
+                        .collect()
+                )
+            ),
+            metric(
+                "xen_domain_shutdown_reason",
+                MetricValue::StateSet(
+                    XenShutdownReason::ALL
+                        .iter()
+                        .map(|&reason| state(reason.name(), shutdown_reason == Some(reason)))
+                        .collect()
+                )
+            ),
+            metric(
+                "xen_domain",
+                MetricValue::Info(
+                    vec![
+                        label("guest_type", dominfo.guest_type().name().into()),
+                        label(
+                            "hap",
+                            dominfo
+                                .flags
+                                .contains(XenDomctlDominf::HAP)
+                                .to_compact_string()
+                        ),
+                        label(
+                            "xenstore_domain",
+                            dominfo
+                                .flags
+                                .contains(XenDomctlDominf::XS_DOMAIN)
+                                .to_compact_string()
+                        ),
+                        label(
+                            "nr_online_vcpus",
+                            dominfo.nr_online_vcpus.to_compact_string()
+                        ),
+                        label("max_vcpu_id", dominfo.max_vcpu_id.to_compact_string()),
+                        label("cpupool", dominfo.cpupool.to_compact_string()),
+                        label("gpaddr_bits", dominfo.gpaddr_bits.to_compact_string()),
+                    ]
+                    .into_boxed_slice()
+                )
+            ),
+        ]
+    }
+
+    fn on_domain_change(&self) -> bool {
+        true
+    }
+}
@@ -1,47 +1,224 @@
 use std::os::unix::net::UnixStream;
 
-use smallvec::smallvec;
+use compact_str::ToCompactString;
+use smallvec::{smallvec, SmallVec};
 
 use xcp_metrics_common::{
-    metrics::{Metric, MetricType, MetricValue, NumberValue},
+    metrics::{Label, Metric, MetricType, MetricValue, NumberValue},
     protocol::{CreateFamily, ProtocolMessage, XcpMetricsStream},
 };
-use xen::{domctl::XenDomctlGetDomainInfo, hypercall::unix::UnixXenHypercall};
+use xen::{
+    hypercall::XenHypercall,
+    sysctl::{SysctlNumaInfo, XenSysctlMeminfo, XEN_INVALID_MEM_SZ},
+};
 
-use super::{PluginMetricKind, XenMetric};
+use super::{DomainSnapshot, PluginMetricKind, RoundSnapshot, XenMetric};
 
 const PAGE_SIZE: u64 = 4096;
 
+/// Domain memory families: (name, help).
+const DOMAIN_MEMORY_FAMILIES: [(&str, &str); 5] = [
+    ("xen_domain_memory", "Memory reserved to a guest."),
+    (
+        "xen_domain_memory_max",
+        "Maximum memory a guest can be given.",
+    ),
+    (
+        "xen_domain_memory_outstanding",
+        "Memory claimed by a guest being built.",
+    ),
+    (
+        "xen_domain_memory_shared",
+        "Memory of a guest shared with other guests.",
+    ),
+    ("xen_domain_memory_paged", "Memory of a guest paged out."),
+];
+
+/// `max_pages` of domains without limit.
+const UNLIMITED_PAGES: u64 = u32::MAX as u64;
+
 pub struct DomainMemory;
 
 impl XenMetric for DomainMemory {
     fn make_families(&self, stream: &mut UnixStream) -> anyhow::Result<()> {
-        stream.send_message(ProtocolMessage::CreateFamily(CreateFamily {
-            help: "Memory reserved to a guest.".into(),
-            name: "xen_domain_memory".into(),
-            metric_type: MetricType::Gauge,
-            unit: "bytes".into(),
-        }))?;
+        for (name, help) in DOMAIN_MEMORY_FAMILIES {
+            stream.send_message(ProtocolMessage::CreateFamily(CreateFamily {
+                help: help.into(),
+                name: name.into(),
+                metric_type: MetricType::Gauge,
+                unit: "bytes".into(),
+            }))?;
+        }
 
         Ok(())
     }
 
-    fn read_domain_metrics(
+    fn read_domain_metrics<H: XenHypercall>(
         &mut self,
-        dominfo: XenDomctlGetDomainInfo,
-        _: &UnixXenHypercall,
+        domain: &DomainSnapshot,
+        _: &H,
     ) -> smallvec::SmallVec<[(PluginMetricKind, Metric); 3]> {
-        smallvec![(
-            PluginMetricKind {
-                family_name: "xen_domain_memory",
-                submetric: None,
-            },
-            Metric {
-                labels: vec![].into_boxed_slice(),
-                value: MetricValue::Gauge(NumberValue::Int64(
-                    (dominfo.tot_pages.0 * PAGE_SIZE) as i64
-                )),
-            },
-        )]
+        let dominfo = domain.info;
+        let pages = [
+            Some(dominfo.tot_pages.0),
+            Some(dominfo.max_pages.0).filter(|&pages| pages < UNLIMITED_PAGES),
+            Some(dominfo.outstanding_pages.0),
+            Some(dominfo.shr_pages.0),
+            Some(dominfo.paged_pages.0),
+        ];
+
+        DOMAIN_MEMORY_FAMILIES
+            .iter()
+            .zip(pages)
+            .filter_map(|(&(family_name, _), pages)| {
+                Some((
+                    PluginMetricKind {
+                        family_name,
+                        submetric: None,
+                    },
+                    Metric {
+                        labels: vec![].into_boxed_slice(),
+                        value: MetricValue::Gauge(NumberValue::Int64((pages? * PAGE_SIZE) as i64)),
+                    },
+                ))
+            })
+            .collect()
+    }
+
+    fn on_domain_change(&self) -> bool {
+        true
+    }
+}
+
+/// Host memory families: (name, help).
+const HOST_MEMORY_FAMILIES: [(&str, &str); 4] = [
+    ("xen_host_memory_total", "Total memory of the host."),
+    ("xen_host_memory_free", "Free memory of the host."),
+    (
+        "xen_host_memory_scrub",
+        "Free memory of the host that is pending scrubbing.",
+    ),
+    (
+        "xen_host_memory_outstanding",
+        "Memory of the host claimed by domains being built.",
+    ),
+];
+
+pub struct HostMemory;
+
+impl XenMetric for HostMemory {
+    fn make_families(&self, stream: &mut UnixStream) -> anyhow::Result<()> {
+        for (name, help) in HOST_MEMORY_FAMILIES {
+            stream.send_message(ProtocolMessage::CreateFamily(CreateFamily {
+                help: help.into(),
+                name: name.into(),
+                metric_type: MetricType::Gauge,
+                unit: "bytes".into(),
+            }))?;
+        }
+
+        Ok(())
+    }
+
+    fn read_host_metrics<H: XenHypercall>(
+        &mut self,
+        round: &RoundSnapshot,
+        _: &H,
+    ) -> SmallVec<[(PluginMetricKind, Metric); 3]> {
+        let physinfo = round.physinfo;
+        let pages = [
+            physinfo.total_pages,
+            physinfo.free_pages,
+            physinfo.scrub_pages,
+            physinfo.outstanding_pages,
+        ];
+
+        HOST_MEMORY_FAMILIES
+            .iter()
+            .zip(pages)
+            .map(|(&(family_name, _), pages)| {
+                (
+                    PluginMetricKind {
+                        family_name,
+                        submetric: None,
+                    },
+                    Metric {
+                        labels: vec![].into_boxed_slice(),
+                        value: MetricValue::Gauge(NumberValue::Int64((pages.0 * PAGE_SIZE) as i64)),
+                    },
+                )
+            })
+            .collect()
+    }
+}
+
+/// NUMA node memory families: (name, help).
+const NODE_MEMORY_FAMILIES: [(&str, &str); 2] = [
+    ("xen_host_node_memory_total", "Total memory of a NUMA node."),
+    ("xen_host_node_memory_free", "Free memory of a NUMA node."),
+];
+
+pub struct NodeMemory;
+
+impl XenMetric for NodeMemory {
+    fn make_families(&self, stream: &mut UnixStream) -> anyhow::Result<()> {
+        for (name, help) in NODE_MEMORY_FAMILIES {
+            stream.send_message(ProtocolMessage::CreateFamily(CreateFamily {
+                help: help.into(),
+                name: name.into(),
+                metric_type: MetricType::Gauge,
+                unit: "bytes".into(),
+            }))?;
+        }
+
+        Ok(())
+    }
+
+    fn read_host_metrics<H: XenHypercall>(
+        &mut self,
+        round: &RoundSnapshot,
+        hyp: &H,
+    ) -> SmallVec<[(PluginMetricKind, Metric); 3]> {
+        let mut meminfos = vec![XenSysctlMeminfo::default(); (round.physinfo.max_node_id + 1) as _];
+
+        match hyp.numainfo(&mut meminfos) {
+            Ok(count) => meminfos.truncate(count),
+            Err(e) => {
+                tracing::error!("numainfo failure: {e}");
+                return smallvec![];
+            }
+        }
+
+        meminfos
+            .iter()
+            .enumerate()
+            .flat_map(|(node, meminfo)| {
+                let [(total_family, _), (free_family, _)] = NODE_MEMORY_FAMILIES;
+
+                [
+                    (total_family, meminfo.memsize.0),
+                    (free_family, meminfo.memfree.0),
+                ]
+                .into_iter()
+                // Nodes without memory.
+                .filter(|&(_, bytes)| bytes != XEN_INVALID_MEM_SZ)
+                .map(move |(family_name, bytes)| {
+                    (
+                        PluginMetricKind {
+                            family_name,
+                            submetric: Some(node.to_compact_string()),
+                        },
+                        Metric {
+                            labels: vec![Label {
+                                name: "node".into(),
+                                value: node.to_compact_string(),
+                            }]
+                            .into_boxed_slice(),
+                            value: MetricValue::Gauge(NumberValue::Int64(bytes as i64)),
+                        },
+                    )
+                })
+            })
+            .collect()
     }
 }
@@ -0,0 +1,203 @@
+use std::os::unix::net::UnixStream;
+
+use compact_str::{format_compact, CompactString};
+use smallvec::SmallVec;
+
+use xcp_metrics_common::{
+    metrics::{Label, Metric, MetricType, MetricValue, NumberValue},
+    protocol::{CreateFamily, ProtocolMessage, XcpMetricsStream},
+};
+use xen::{
+    hypercall::XenHypercall,
+    sysctl::{CpufreqParaSizes, SysctlGetPmOp, SysctlGetPmStat},
+};
+
+use super::{PluginMetricKind, RoundSnapshot, XenMetric};
+
+/// P-state and C-state residencies, and cpufreq governor of each pCPU (like xcp-rrdd-xenpm).
+///
+/// These statistics are only available when Xen manages the power states of the CPUs, their
+/// failures are only logged as debug.
+pub struct PCpuPmStats {
+    /// cpufreq buffer sizes of each pCPU, from the previous round.
+    cpufreq_sizes: Vec<CpufreqParaSizes>,
+}
+
+impl PCpuPmStats {
+    pub fn new() -> Self {
+        Self {
+            cpufreq_sizes: vec![],
+        }
+    }
+}
+
+fn counter(
+    family_name: &'static str,
+    cpuid: u32,
+    labels: &[Label],
+    state: CompactString,
+    total: NumberValue,
+) -> (PluginMetricKind, Metric) {
+    (
+        PluginMetricKind {
+            family_name,
+            submetric: Some(format_compact!("{cpuid}-{state}")),
+        },
+        Metric {
+            labels: labels
+                .iter()
+                .cloned()
+                .chain([Label {
+                    name: "state".into(),
+                    value: state,
+                }])
+                .collect(),
+            value: MetricValue::Counter {
+                total,
+                created: None,
+                exemplar: None,
+            },
+        },
+    )
+}
+
+/// Nanoseconds to seconds.
+fn seconds(ns: u64) -> NumberValue {
+    NumberValue::Double(ns as f64 / 1.0e9)
+}
+
+impl XenMetric for PCpuPmStats {
+    fn make_families(&self, stream: &mut UnixStream) -> anyhow::Result<()> {
+        for (name, help, metric_type, unit) in [
+            (
+                "xen_cpu_pstate_residency",
+                "Time spent by a CPU core in each P-state",
+                MetricType::Counter,
+                "seconds",
+            ),
+            (
+                "xen_cpu_pstate_transitions",
+                "Number of transitions of a CPU core to each P-state",
+                MetricType::Counter,
+                "",
+            ),
+            (
+                "xen_cpu_cstate_residency",
+                "Time spent by a CPU core in each C-state",
+                MetricType::Counter,
+                "seconds",
+            ),
+            (
+                "xen_cpu_cstate_usage",
+                "Number of entries of a CPU core in each C-state",
+                MetricType::Counter,
+                "",
+            ),
+            (
+                "xen_cpu_governor",
+                "cpufreq driver and governor of a CPU core",
+                MetricType::Info,
+                "",
+            ),
+        ] {
+            stream.send_message(ProtocolMessage::CreateFamily(CreateFamily {
+                help: help.into(),
+                name: name.into(),
+                metric_type,
+                unit: unit.into(),
+            }))?;
+        }
+
+        Ok(())
+    }
+
+    fn read_host_metrics<H: XenHypercall>(
+        &mut self,
+        round: &RoundSnapshot,
+        hyp: &H,
+    ) -> SmallVec<[(PluginMetricKind, Metric); 3]> {
+        let mut metrics = SmallVec::new();
+
+        self.cpufreq_sizes.resize(
+            (round.physinfo.max_cpu_id + 1) as usize,
+            CpufreqParaSizes::default(),
+        );
+
+        for cpuid in 0..=round.physinfo.max_cpu_id {
+            let labels = &round.pcpu_labels[cpuid as usize];
+
+            match hyp.get_pxstat(cpuid) {
+                Ok(pxstat) => {
+                    for (i, pstate) in pxstat.states.iter().enumerate() {
+                        let state = format_compact!("P{i}");
+
+                        metrics.push(counter(
+                            "xen_cpu_pstate_residency",
+                            cpuid,
+                            labels,
+                            state.clone(),
+                            seconds(pstate.residency.0),
+                        ));
+                        metrics.push(counter(
+                            "xen_cpu_pstate_transitions",
+                            cpuid,
+                            labels,
+                            state,
+                            NumberValue::Int64(pstate.count.0 as i64),
+                        ));
+                    }
+                }
+                Err(e) => tracing::debug!("get_pxstat failure for cpuid:{cpuid}: {e}"),
+            }
+
+            match hyp.get_cxstat(cpuid) {
+                Ok(cxstat) => {
+                    for (i, (&residency, &usage)) in
+                        cxstat.residencies.iter().zip(&cxstat.usage).enumerate()
+                    {
+                        let state = format_compact!("C{i}");
+
+                        metrics.push(counter(
+                            "xen_cpu_cstate_residency",
+                            cpuid,
+                            labels,
+                            state.clone(),
+                            seconds(residency),
+                        ));
+                        metrics.push(counter(
+                            "xen_cpu_cstate_usage",
+                            cpuid,
+                            labels,
+                            state,
+                            NumberValue::Int64(usage as i64),
+                        ));
+                    }
+                }
+                Err(e) => tracing::debug!("get_cxstat failure for cpuid:{cpuid}: {e}"),
+            }
+
+            match hyp.get_cpufreq_policy(cpuid, &mut self.cpufreq_sizes[cpuid as usize]) {
+                Ok(policy) => metrics.push((
+                    PluginMetricKind {
+                        family_name: "xen_cpu_governor",
+                        submetric: Some(format_compact!("{cpuid}")),
+                    },
+                    Metric {
+                        labels: labels.clone(),
+                        value: MetricValue::Info(
+                            [("driver", policy.driver), ("governor", policy.governor)]
+                                .map(|(name, value)| Label {
+                                    name: name.into(),
+                                    value: value.into(),
+                                })
+                                .into(),
+                        ),
+                    },
+                )),
+                Err(e) => tracing::debug!("get_cpufreq_policy failure for cpuid:{cpuid}: {e}"),
+            }
+        }
+
+        metrics
+    }
+}
@@ -0,0 +1,675 @@
+//! Collector tests, against a fake hypervisor
+
+use std::{
+    os::unix::net::UnixStream,
+    sync::{mpsc, Arc},
+    thread,
+    time::{Duration, SystemTime},
+};
+
+use uuid::Uuid;
+use xcp_metrics_common::{
+    metrics::{Label, Metric, MetricValue, NumberValue},
+    protocol::{CollectionTick, ProtocolMessage, UpdateMetric, XcpMetricsStream},
+};
+use xen::{
+    abi::XenAbi,
+    domctl::{XenDomctlDominf, XenShutdownReason, XenX86Emu},
+    hypercall::mock::{
+        MockCState, MockCpufreq, MockDomain, MockNode, MockPState, MockXen, MockXenHypercall,
+    },
+    sysctl::{
+        CpufreqParaSizes, CpufreqPolicy, SysctlGetDomainInfoList, SysctlGetPmOp, SysctlGetPmStat,
+    },
+};
+
+use super::{
+    abi::XenAbiInfo,
+    cpu::{PCpuFreq, PCpuUsage, PCpuVCpus},
+    domain::DomainState,
+    memory::{DomainMemory, HostMemory, NodeMemory},
+    pm::PCpuPmStats,
+    run_plugin,
+    topology::HostTopology,
+    vcpu::{DomainCpuUsage, VCpuState, VCpuUsage},
+    DomainSnapshot, PluginMetricKind, RoundSnapshot, XenMetric,
+};
+
+const STEP: Duration = Duration::from_secs(5);
+
+fn mock_xen() -> MockXenHypercall {
+    let mut xen = MockXen::new(XenAbi::Xen417, 2);
+
+    xen.total_pages = 1024;
+    xen.free_pages = 512;
+    xen.scrub_pages = 16;
+    xen.outstanding_pages = 8;
+
+    // Dual-socket host, with a NUMA node per socket.
+    xen.cores_per_socket = 1;
+    xen.pcpus[1].core = 0;
+    xen.pcpus[1].socket = 1;
+    xen.pcpus[1].node = 1;
+    xen.nodes = vec![
+        MockNode {
+            memsize: 1 << 30,
+            memfree: 1 << 29,
+        },
+        MockNode {
+            memsize: 1 << 30,
+            memfree: 1 << 28,
+        },
+    ];
+
+    xen.pcpus[0].busy = 0.25;
+    xen.pcpus[1].busy = 0.5;
+    xen.pcpus[1].avgfreq = Some(2_400_000);
+
+    let mut domain = MockDomain::new(Uuid::new_v4(), 2);
+    domain.tot_pages = 256;
+    domain.max_pages = 512;
+    domain.shr_pages = 4;
+    domain.paged_pages = 2;
+    domain.vcpus[0].busy = 0.5;
+    domain.vcpus[1].busy = 1.0;
+    xen.domains.insert(1, domain);
+
+    MockXenHypercall::new(xen)
+}
+
+fn values(metrics: impl IntoIterator<Item = (PluginMetricKind, Metric)>) -> Vec<(String, f64)> {
+    let mut values: Vec<(String, f64)> = metrics
+        .into_iter()
+        .map(|(kind, metric)| {
+            let value = match metric.value {
+                MetricValue::Gauge(NumberValue::Double(value)) => value,
+                MetricValue::Gauge(NumberValue::Int64(value)) => value as f64,
+                MetricValue::Counter {
+                    total: NumberValue::Double(value),
+                    ..
+                } => value,
+                MetricValue::Counter {
+                    total: NumberValue::Int64(value),
+                    ..
+                } => value as f64,
+                value => panic!("unexpected value {value:?}"),
+            };
+
+            (
+                format!(
+                    "{}{{{}}}",
+                    kind.family_name,
+                    kind.submetric.unwrap_or_default()
+                ),
+                value,
+            )
+        })
+        .collect();
+
+    values.sort_by(|a, b| a.0.cmp(&b.0));
+    values
+}
+
+/// Host metrics: CPU usage between two rounds, frequency of the CPUs that have cpufreq, memory
+/// and topology.
+#[test]
+fn host_metrics() {
+    let hyp = mock_xen();
+    let start = SystemTime::now();
+    let mut usage = PCpuUsage::new();
+
+    usage.begin_round(start);
+    let round = RoundSnapshot::read(&hyp).unwrap();
+    assert!(usage.read_host_metrics(&round, &hyp).is_empty());
+
+    hyp.model().advance(STEP);
+    usage.begin_round(start + STEP);
+    assert_eq!(
+        values(usage.read_host_metrics(&round, &hyp)),
+        [
+            ("xen_cpu_time{0}".into(), 0.25),
+            ("xen_cpu_time{1}".into(), 0.5)
+        ]
+    );
+
+    assert_eq!(
+        values(PCpuFreq.read_host_metrics(&round, &hyp)),
+        [("xen_cpu_freq{1}".into(), 2_400_000.0)]
+    );
+
+    assert_eq!(
+        values(HostMemory.read_host_metrics(&round, &hyp)),
+        [
+            ("xen_host_memory_free{}".into(), (512 * 4096) as f64),
+            ("xen_host_memory_outstanding{}".into(), (8 * 4096) as f64),
+            ("xen_host_memory_scrub{}".into(), (16 * 4096) as f64),
+            ("xen_host_memory_total{}".into(), (1024 * 4096) as f64),
+        ]
+    );
+
+    assert_eq!(
+        values(NodeMemory.read_host_metrics(&round, &hyp)),
+        [
+            ("xen_host_node_memory_free{0}".into(), (1 << 29) as f64),
+            ("xen_host_node_memory_free{1}".into(), (1 << 28) as f64),
+            ("xen_host_node_memory_total{0}".into(), (1 << 30) as f64),
+            ("xen_host_node_memory_total{1}".into(), (1 << 30) as f64),
+        ]
+    );
+
+    // pCPU series are labelled with their place in the topology.
+    let freq = PCpuFreq.read_host_metrics(&round, &hyp);
+    let labels: Vec<_> = freq[0]
+        .1
+        .labels
+        .iter()
+        .map(|label| format!("{}={}", label.name, label.value))
+        .collect();
+    assert_eq!(labels, ["cpu_id=1", "node=1", "socket=1", "core=0"]);
+
+    let topology = HostTopology.read_host_metrics(&round, &hyp);
+    let MetricValue::Info(labels) = &topology[0].1.value else {
+        panic!("unexpected value {:?}", topology[0].1.value);
+    };
+    let labels: Vec<_> = labels
+        .iter()
+        .map(|label| format!("{}={}", label.name, label.value))
+        .collect();
+    assert_eq!(
+        labels,
+        [
+            "nr_cpus=2",
+            "cores_per_socket=1",
+            "threads_per_core=1",
+            "nr_nodes=2",
+            "cpu_khz=2000000",
+            "capabilities=hvm,pv"
+        ]
+    );
+}
+
+/// Power management: P-state and C-state residencies, and cpufreq governor.
+#[test]
+fn pm_metrics() {
+    let hyp = mock_xen();
+
+    {
+        let pcpu = &mut hyp.model().pcpus[0];
+        pcpu.pstates = vec![
+            MockPState {
+                freq: 2000,
+                ..Default::default()
+            },
+            MockPState {
+                freq: 1000,
+                ..Default::default()
+            },
+        ];
+        pcpu.cstates = vec![MockCState::default(); 3];
+        pcpu.cpufreq = Some(MockCpufreq {
+            driver: "acpi-cpufreq".into(),
+            governor: "ondemand".into(),
+        });
+    }
+
+    hyp.model().advance(STEP);
+    hyp.model().pcpus[0].set_pstate(1);
+    hyp.model().advance(STEP);
+
+    let pxstat = hyp.get_pxstat(0).unwrap();
+    assert_eq!(pxstat.cur, 1);
+    assert_eq!(pxstat.transitions, [0, 1, 0, 0]);
+
+    // The buffer sizes expected by Xen are learned by the first call, and reused by the next.
+    let mut sizes = CpufreqParaSizes::default();
+    let policy = CpufreqPolicy {
+        driver: "acpi-cpufreq".into(),
+        governor: "ondemand".into(),
+        cur_freq: 1_000_000,
+        min_freq: 1_000_000,
+        max_freq: 2_000_000,
+    };
+    assert_eq!(hyp.get_cpufreq_policy(0, &mut sizes).unwrap(), policy);
+    let learned = CpufreqParaSizes {
+        cpu_num: 1,
+        freq_num: 2,
+        gov_num: 1,
+    };
+    assert_eq!(sizes, learned);
+    assert_eq!(hyp.get_cpufreq_policy(0, &mut sizes).unwrap(), policy);
+    assert_eq!(sizes, learned);
+
+    // CPU 1 has no power management statistics.
+    assert!(hyp.get_pxstat(1).is_err());
+    assert!(hyp.get_cxstat(1).is_err());
+
+    let round = RoundSnapshot::read(&hyp).unwrap();
+    let (governor, counters): (Vec<_>, Vec<_>) = PCpuPmStats::new()
+        .read_host_metrics(&round, &hyp)
+        .into_iter()
+        .partition(|(kind, _)| kind.family_name == "xen_cpu_governor");
+
+    assert_eq!(
+        values(counters),
+        [
+            ("xen_cpu_cstate_residency{0-C0}".into(), 2.5),
+            ("xen_cpu_cstate_residency{0-C1}".into(), 0.0),
+            ("xen_cpu_cstate_residency{0-C2}".into(), 7.5),
+            ("xen_cpu_cstate_usage{0-C0}".into(), 0.0),
+            ("xen_cpu_cstate_usage{0-C1}".into(), 0.0),
+            ("xen_cpu_cstate_usage{0-C2}".into(), 2.0),
+            ("xen_cpu_pstate_residency{0-P0}".into(), 5.0),
+            ("xen_cpu_pstate_residency{0-P1}".into(), 5.0),
+            ("xen_cpu_pstate_transitions{0-P0}".into(), 0.0),
+            ("xen_cpu_pstate_transitions{0-P1}".into(), 1.0),
+        ]
+    );
+
+    assert_eq!(governor.len(), 1);
+    let MetricValue::Info(labels) = &governor[0].1.value else {
+        panic!("unexpected value {:?}", governor[0].1.value);
+    };
+    let labels: Vec<_> = labels
+        .iter()
+        .map(|label| format!("{}={}", label.name, label.value))
+        .collect();
+    assert_eq!(labels, ["driver=acpi-cpufreq", "governor=ondemand"]);
+}
+
+/// Domain metrics: memory, vCPU and domain usage between two rounds, and CPU time counters
+/// (whose `created` timestamp is left to the daemon).
+#[test]
+fn domain_metrics() {
+    let hyp = mock_xen();
+    let start = SystemTime::now();
+    let mut usage = VCpuUsage::new();
+    let mut domain_usage = DomainCpuUsage::new();
+
+    let domains: Vec<_> = hyp.iter_domains().collect();
+    assert_eq!(domains.len(), 1);
+    let dominfo = domains[0];
+    assert_eq!(dominfo.nr_online_vcpus, 2);
+    let domain = DomainSnapshot::read(&hyp, dominfo);
+
+    assert_eq!(
+        values(DomainMemory.read_domain_metrics(&domain, &hyp)),
+        [
+            ("xen_domain_memory_max{}".into(), (512 * 4096) as f64),
+            ("xen_domain_memory_outstanding{}".into(), 0.0),
+            ("xen_domain_memory_paged{}".into(), (2 * 4096) as f64),
+            ("xen_domain_memory_shared{}".into(), (4 * 4096) as f64),
+            ("xen_domain_memory{}".into(), (256 * 4096) as f64),
+        ]
+    );
+
+    // Domains without limit have no maximum.
+    hyp.model().domains.get_mut(&1).unwrap().max_pages = u32::MAX as u64;
+    let unlimited = DomainSnapshot::read(&hyp, hyp.iter_domains().next().unwrap());
+    assert!(DomainMemory
+        .read_domain_metrics(&unlimited, &hyp)
+        .iter()
+        .all(|(kind, _)| kind.family_name != "xen_domain_memory_max"));
+
+    usage.begin_round(start);
+    domain_usage.begin_round(start);
+    assert_eq!(
+        values(usage.read_domain_metrics(&domain, &hyp)),
+        [
+            ("xen_vcpu_cpu_seconds{0}".into(), 0.0),
+            ("xen_vcpu_cpu_seconds{1}".into(), 0.0)
+        ]
+    );
+    assert_eq!(
+        values(domain_usage.read_domain_metrics(&domain, &hyp)),
+        [("xen_domain_cpu_seconds{}".into(), 0.0)]
+    );
+
+    hyp.model().advance(STEP);
+    usage.begin_round(start + STEP);
+    domain_usage.begin_round(start + STEP);
+    let domain = DomainSnapshot::read(&hyp, hyp.iter_domains().next().unwrap());
+
+    let vcpu_metrics = usage.read_domain_metrics(&domain, &hyp);
+    assert!(vcpu_metrics.iter().all(|(kind, metric)| {
+        kind.family_name != "xen_vcpu_cpu_seconds"
+            || matches!(metric.value, MetricValue::Counter { created: None, .. })
+    }));
+    assert_eq!(
+        values(vcpu_metrics),
+        [
+            ("xen_vcpu_cpu_seconds{0}".into(), 2.5),
+            ("xen_vcpu_cpu_seconds{1}".into(), 5.0),
+            ("xen_vcpu_time{0}".into(), 0.5),
+            ("xen_vcpu_time{1}".into(), 1.0)
+        ]
+    );
+
+    // 7.5s of the 10s of the 2 vCPUs.
+    assert_eq!(
+        values(domain_usage.read_domain_metrics(&domain, &hyp)),
+        [
+            ("xen_domain_cpu_seconds{}".into(), 7.5),
+            ("xen_domain_cpu_usage{}".into(), 0.75)
+        ]
+    );
+}
+
+/// vCPU scheduling state and placement, and vCPUs per pCPU.
+#[test]
+fn vcpu_placement() {
+    let hyp = mock_xen();
+
+    {
+        let mut xen = hyp.model();
+        let vcpus = &mut xen.domains.get_mut(&1).unwrap().vcpus;
+        vcpus[1].cpu = 1;
+        vcpus[1].running = false;
+        vcpus[1].blocked = true;
+
+        let mut domain = MockDomain::new(Uuid::new_v4(), 2);
+        domain.vcpus[1].online = false;
+        xen.domains.insert(2, domain);
+    }
+
+    // Each vCPU is read once per round, and shared by the metrics.
+    let round = RoundSnapshot::read(&hyp).unwrap();
+    assert_eq!(round.domains.len(), 2);
+    let domain = round
+        .domains
+        .iter()
+        .find(|domain| domain.info.domain.0 == 1)
+        .unwrap();
+    let (states, placement): (Vec<_>, Vec<_>) = VCpuState
+        .read_domain_metrics(domain, &hyp)
+        .into_iter()
+        .partition(|(kind, _)| kind.family_name == "xen_vcpu_state");
+
+    assert_eq!(
+        values(placement),
+        [
+            ("xen_vcpu_cpu{0}".into(), 0.0),
+            ("xen_vcpu_cpu{1}".into(), 1.0)
+        ]
+    );
+
+    let states: Vec<_> = states
+        .into_iter()
+        .flat_map(|(kind, metric)| {
+            let MetricValue::StateSet(states) = metric.value else {
+                panic!("unexpected value {:?}", metric.value);
+            };
+
+            states
+                .iter()
+                .filter(|state| state.enabled)
+                .map(|state| format!("{}={}", kind.submetric.clone().unwrap(), state.name))
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    assert_eq!(states, ["0=running", "1=blocked"]);
+
+    // The offline vCPU of domain 2 is not counted.
+    assert_eq!(
+        values(PCpuVCpus.read_host_metrics(&round, &hyp)),
+        [
+            ("xen_cpu_vcpus{0}".into(), 2.0),
+            ("xen_cpu_vcpus{1}".into(), 1.0)
+        ]
+    );
+}
+
+/// Enabled states of each state set, and labels of the info.
+fn domain_state(hyp: &MockXenHypercall) -> Vec<String> {
+    let domain = DomainSnapshot::read(hyp, hyp.iter_domains().next().unwrap());
+
+    DomainState
+        .read_domain_metrics(&domain, hyp)
+        .into_iter()
+        .flat_map(|(kind, metric)| match metric.value {
+            MetricValue::StateSet(states) => states
+                .iter()
+                .filter(|state| state.enabled)
+                .map(|state| format!("{}={}", kind.family_name, state.name))
+                .collect::<Vec<_>>(),
+            MetricValue::Info(labels) => labels
+                .iter()
+                .map(|label| format!("{}={}", label.name, label.value))
+                .collect(),
+            value => panic!("unexpected value {value:?}"),
+        })
+        .collect()
+}
+
+/// Domain run state, shutdown reason and guest type.
+#[test]
+fn domain_states() {
+    let hyp = mock_xen();
+
+    assert_eq!(
+        domain_state(&hyp),
+        [
+            "xen_domain_state=running",
+            "guest_type=pv",
+            "hap=false",
+            "xenstore_domain=false",
+            "nr_online_vcpus=2",
+            "max_vcpu_id=1",
+            "cpupool=0",
+            "gpaddr_bits=0",
+        ]
+    );
+
+    {
+        let mut xen = hyp.model();
+        let domain = xen.domains.get_mut(&1).unwrap();
+        domain.flags = XenDomctlDominf::HVM_GUEST | XenDomctlDominf::HAP;
+        domain.emulation_flags = XenX86Emu::Lapic;
+        domain.gpaddr_bits = 52;
+    }
+    assert!(domain_state(&hyp).contains(&"guest_type=pvh".into()));
+    assert!(domain_state(&hyp).contains(&"hap=true".into()));
+
+    hyp.model().domains.get_mut(&1).unwrap().emulation_flags = XenX86Emu::all();
+    assert!(domain_state(&hyp).contains(&"guest_type=hvm".into()));
+
+    {
+        let mut xen = hyp.model();
+        let domain = xen.domains.get_mut(&1).unwrap();
+        domain.flags |= XenDomctlDominf::PAUSED;
+        domain.shutdown_reason = Some(XenShutdownReason::Crash);
+    }
+    assert_eq!(
+        domain_state(&hyp)[..3],
+        [
+            "xen_domain_state=paused",
+            "xen_domain_state=shutdown",
+            "xen_domain_shutdown_reason=crash",
+        ]
+    );
+}
+
+/// The ABI in use is published as an Info family.
+#[test]
+fn abi_info() {
+    let hyp = mock_xen();
+    hyp.model().abi = XenAbi::Xen418;
+    let round = RoundSnapshot::read(&hyp).unwrap();
+
+    let metrics = XenAbiInfo.read_host_metrics(&round, &hyp);
+    assert_eq!(
+        metrics[0].1.value,
+        MetricValue::Info(
+            [
+                ("version", "4.18"),
+                ("sysctl_interface_version", "0x15"),
+                ("domctl_interface_version", "0x16"),
+            ]
+            .map(|(name, value)| Label {
+                name: name.into(),
+                value: value.into(),
+            })
+            .into()
+        )
+    );
+}
+
+/// Collect the messages of a round, until `CollectionDone`.
+fn recv_round(stream: &mut UnixStream, sequence: u64) -> Vec<ProtocolMessage> {
+    stream
+        .send_message(ProtocolMessage::CollectionTick(CollectionTick {
+            sequence,
+            timestamp: SystemTime::UNIX_EPOCH + STEP * sequence as u32,
+        }))
+        .unwrap();
+
+    let mut messages = vec![];
+
+    loop {
+        match stream.recv_message().unwrap() {
+            ProtocolMessage::CollectionDone(done) => {
+                assert_eq!(done.sequence, sequence);
+                return messages;
+            }
+            message => messages.push(message),
+        }
+    }
+}
+
+/// The plugin collects at each tick, and removes the metrics of the destroyed domains.
+#[test]
+fn plugin_rounds() {
+    let (mut stream, mut plugin_stream) = UnixStream::pair().unwrap();
+    let hyp = Arc::new(mock_xen());
+
+    let plugin = thread::spawn({
+        let hyp = hyp.clone();
+        move || run_plugin(&mut plugin_stream, hyp.as_ref(), None)
+    });
+
+    // Families, then tick subscription.
+    let mut families = 0;
+
+    loop {
+        match stream.recv_message().unwrap() {
+            ProtocolMessage::CreateFamily(_) => families += 1,
+            ProtocolMessage::SubscribeTicks(_) => break,
+            message => panic!("unexpected message {message:?}"),
+        }
+    }
+    assert_eq!(families, 30);
+
+    let updated = |messages: &[ProtocolMessage], family: &str| {
+        messages
+            .iter()
+            .filter(|message| {
+                matches!(message, ProtocolMessage::UpdateMetric(update) if update.family_name == family)
+            })
+            .count()
+    };
+    let removed = |messages: &[ProtocolMessage], family: &str| {
+        messages
+            .iter()
+            .filter(|message| {
+                matches!(message, ProtocolMessage::RemoveMetric(remove) if remove.family_name == family)
+            })
+            .count()
+    };
+
+    // Rates need two rounds.
+    let messages = recv_round(&mut stream, 1);
+    assert_eq!(updated(&messages, "xen_domain_memory"), 1);
+    assert_eq!(updated(&messages, "xen_vcpu_time"), 0);
+
+    hyp.model().advance(STEP);
+    let messages = recv_round(&mut stream, 2);
+    assert_eq!(updated(&messages, "xen_cpu_time"), 2);
+    assert_eq!(updated(&messages, "xen_vcpu_time"), 2);
+
+    hyp.model().domains.remove(&1);
+    let messages = recv_round(&mut stream, 3);
+    assert_eq!(updated(&messages, "xen_domain_memory"), 0);
+    assert_eq!(removed(&messages, "xen_domain_memory"), 1);
+    assert_eq!(removed(&messages, "xen_vcpu_time"), 2);
+
+    // The plugin stops with the daemon.
+    drop(stream);
+    assert!(plugin.join().unwrap().is_err());
+}
+
+/// Wait for the update of a domain metric matching `f`, skipping the other messages.
+fn recv_update(stream: &mut UnixStream, f: impl Fn(&UpdateMetric) -> bool) {
+    loop {
+        match stream.recv_message().unwrap() {
+            ProtocolMessage::UpdateMetric(update) if f(&update) => return,
+            ProtocolMessage::UpdateMetric(_) | ProtocolMessage::RemoveMetric(_) => {}
+            message => panic!("unexpected message {message:?}"),
+        }
+    }
+}
+
+/// Whether `update` is a domain state with `state` enabled, of the domain `uuid`.
+fn is_domain_state(update: &UpdateMetric, uuid: Uuid, state: &str) -> bool {
+    let MetricValue::StateSet(states) = &update.metric.value else {
+        return false;
+    };
+
+    update.family_name == "xen_domain_state"
+        && update
+            .metric
+            .labels
+            .iter()
+            .any(|label| label.name == "domain" && label.value == uuid.to_string())
+        && states.iter().any(|s| s.enabled && s.name == state)
+}
+
+/// Domain changes (e.g domains introduced to and released by xenstored) are handled without
+/// waiting for the next round.
+#[test]
+fn plugin_domain_changes() {
+    let (mut stream, mut plugin_stream) = UnixStream::pair().unwrap();
+    let hyp = Arc::new(mock_xen());
+    let (changes, receiver) = mpsc::channel();
+
+    let plugin = thread::spawn({
+        let hyp = hyp.clone();
+        move || run_plugin(&mut plugin_stream, hyp.as_ref(), Some(receiver))
+    });
+
+    while !matches!(
+        stream.recv_message().unwrap(),
+        ProtocolMessage::SubscribeTicks(_)
+    ) {}
+
+    // New domains are seen once introduced.
+    let uuid = Uuid::new_v4();
+    hyp.model().domains.insert(2, MockDomain::new(uuid, 1));
+    changes.send(()).unwrap();
+    recv_update(&mut stream, |update| {
+        is_domain_state(update, uuid, "running")
+    });
+
+    // The state of the shut down domain is updated.
+    hyp.model().domains.get_mut(&2).unwrap().flags = XenDomctlDominf::SHUTDOWN;
+    changes.send(()).unwrap();
+    recv_update(&mut stream, |update| {
+        is_domain_state(update, uuid, "shutdown")
+    });
+
+    // The metrics of the destroyed domain are removed.
+    hyp.model().domains.remove(&2);
+    changes.send(()).unwrap();
+
+    loop {
+        match stream.recv_message().unwrap() {
+            ProtocolMessage::RemoveMetric(remove) if remove.family_name == "xen_domain_state" => {
+                break
+            }
+            ProtocolMessage::UpdateMetric(_) | ProtocolMessage::RemoveMetric(_) => {}
+            message => panic!("unexpected message {message:?}"),
+        }
+    }
+
+    drop(stream);
+    drop(changes);
+    assert!(plugin.join().unwrap().is_err());
+}
@@ -0,0 +1,73 @@
+use std::os::unix::net::UnixStream;
+
+use compact_str::{CompactString, ToCompactString};
+use smallvec::{smallvec, SmallVec};
+
+use xcp_metrics_common::{
+    metrics::{Label, Metric, MetricType, MetricValue},
+    protocol::{CreateFamily, ProtocolMessage, XcpMetricsStream},
+};
+use xen::hypercall::XenHypercall;
+
+use super::{PluginMetricKind, RoundSnapshot, XenMetric};
+
+/// Host topology (CPUs and NUMA nodes) and capabilities.
+pub struct HostTopology;
+
+impl XenMetric for HostTopology {
+    fn make_families(&self, stream: &mut UnixStream) -> anyhow::Result<()> {
+        stream.send_message(ProtocolMessage::CreateFamily(CreateFamily {
+            help: "Topology and capabilities of the host".into(),
+            name: "xen_host_topology".into(),
+            metric_type: MetricType::Info,
+            unit: "".into(),
+        }))?;
+
+        Ok(())
+    }
+
+    fn read_host_metrics<H: XenHypercall>(
+        &mut self,
+        round: &RoundSnapshot,
+        _: &H,
+    ) -> SmallVec<[(PluginMetricKind, Metric); 3]> {
+        let label = |name: &str, value: CompactString| Label {
+            name: name.into(),
+            value,
+        };
+
+        let physinfo = round.physinfo;
+        let capabilities: Vec<_> = physinfo
+            .capabilities
+            .iter_names()
+            .map(|(name, _)| name.to_lowercase())
+            .collect();
+
+        smallvec![(
+            PluginMetricKind {
+                family_name: "xen_host_topology",
+                submetric: None,
+            },
+            Metric {
+                labels: vec![].into_boxed_slice(),
+                value: MetricValue::Info(
+                    vec![
+                        label("nr_cpus", physinfo.nr_cpus.to_compact_string()),
+                        label(
+                            "cores_per_socket",
+                            physinfo.cores_per_socket.to_compact_string()
+                        ),
+                        label(
+                            "threads_per_core",
+                            physinfo.threads_per_core.to_compact_string()
+                        ),
+                        label("nr_nodes", physinfo.nr_nodes.to_compact_string()),
+                        label("cpu_khz", physinfo.cpu_khz.to_compact_string()),
+                        label("capabilities", capabilities.join(",").into()),
+                    ]
+                    .into_boxed_slice(),
+                ),
+            },
+        )]
+    }
+}
@@ -1,46 +1,66 @@
-use std::{collections::HashMap, iter, os::unix::net::UnixStream, time::Instant};
+use std::{
+    collections::HashMap,
+    iter,
+    os::unix::net::UnixStream,
+    time::{Duration, SystemTime},
+};
 
 use compact_str::ToCompactString;
 use smallvec::{smallvec, SmallVec};
 
 use xcp_metrics_common::{
-    metrics::{Label, Metric, MetricType, MetricValue, NumberValue},
+    metrics::{Label, Metric, MetricType, MetricValue, NumberValue, State},
     protocol::{CreateFamily, ProtocolMessage, XcpMetricsStream},
 };
-use xen::{
-    domctl::{DomctlGetVCpuInfo, XenDomctlGetDomainInfo, XenDomctlGetVCpuInfo},
-    hypercall::unix::UnixXenHypercall,
-    sysctl::XenSysctlPhysInfo,
-};
+use xen::{domctl::XenDomctlGetVCpuInfo, hypercall::XenHypercall};
+
+use super::{DomainSnapshot, PluginMetricKind, RoundClock, XenMetric};
 
-use super::{PluginMetricKind, XenMetric};
+/// CPU time (ns) to seconds.
+fn cpu_time_seconds(cpu_time: u64) -> f64 {
+    // xcp-rrdd: Workaround for Xen leaking the flag XEN_RUNSTATE_UPDATE; using a mask of its complement ~(1 << 63)
+    (cpu_time & !(1u64 << 63)) as f64 / 1.0e9
+}
 
+/// CPU time counter, whose `created` timestamp is left to the hub (which keeps it across plugin
+/// restarts).
+fn cpu_time_counter(seconds: f64) -> MetricValue {
+    MetricValue::Counter {
+        total: NumberValue::Double(seconds),
+        created: None,
+        exemplar: None,
+    }
+}
+
+/// Usage (as a fraction of time) and CPU time counter of each vCPU.
 pub struct VCpuUsage {
-    // We need to keep track of the previous instant as latest_instant is ~now.
-    previous_instant: Option<Instant>,
-    latest_instant: Instant,
+    clock: RoundClock,
     prev_vcpu_infos: HashMap<u16, SmallVec<[XenDomctlGetVCpuInfo; 8]>>,
 }
 
 impl VCpuUsage {
     pub fn new() -> Self {
         Self {
-            previous_instant: None,
-            latest_instant: Instant::now(),
+            clock: RoundClock::default(),
             prev_vcpu_infos: HashMap::new(),
         }
     }
 }
 
+fn vcpu_labels(vcpu_info: &XenDomctlGetVCpuInfo) -> Box<[Label]> {
+    vec![Label {
+        name: "vcpu_id".into(),
+        value: vcpu_info.vcpu.to_compact_string(),
+    }]
+    .into_boxed_slice()
+}
+
 fn generate_vcpu_usage(
     (vcpu_info, prev_vcpu_info): (&XenDomctlGetVCpuInfo, &XenDomctlGetVCpuInfo),
-    latest_instant: Instant,
+    elapsed: Duration,
 ) -> (PluginMetricKind, Metric) {
-    // xcp-rrdd: Workaround for Xen leaking the flag XEN_RUNSTATE_UPDATE; using a mask of its complement ~(1 << 63)
-    // Then convert from nanoseconds to seconds
-    let cputime = (vcpu_info.cpu_time.0 & !(1u64 << 63)) as f64 / 1.0e9;
-    // Do the same for previous cpu time.
-    let prev_cputime = (prev_vcpu_info.cpu_time.0 & !(1u64 << 63)) as f64 / 1.0e9;
+    let cputime = cpu_time_seconds(vcpu_info.cpu_time.0);
+    let prev_cputime = cpu_time_seconds(prev_vcpu_info.cpu_time.0);
 
     (
         PluginMetricKind {
@@ -48,19 +68,28 @@ fn generate_vcpu_usage(
             submetric: Some(vcpu_info.vcpu.to_compact_string()),
         },
         Metric {
-            labels: vec![Label {
-                name: "vcpu_id".into(),
-                value: vcpu_info.vcpu.to_compact_string(),
-            }]
-            .into_boxed_slice(),
+            labels: vcpu_labels(vcpu_info),
             value: MetricValue::Gauge(NumberValue::Double(f64::max(
                 0.0,
-                (cputime - prev_cputime) / latest_instant.elapsed().as_secs_f64(),
+                (cputime - prev_cputime) / elapsed.as_secs_f64(),
             ))),
         },
     )
 }
 
+fn generate_vcpu_cpu_seconds(vcpu_info: &XenDomctlGetVCpuInfo) -> (PluginMetricKind, Metric) {
+    (
+        PluginMetricKind {
+            family_name: "xen_vcpu_cpu_seconds",
+            submetric: Some(vcpu_info.vcpu.to_compact_string()),
+        },
+        Metric {
+            labels: vcpu_labels(vcpu_info),
+            value: cpu_time_counter(cpu_time_seconds(vcpu_info.cpu_time.0)),
+        },
+    )
+}
+
 impl XenMetric for VCpuUsage {
     fn make_families(&self, stream: &mut UnixStream) -> anyhow::Result<()> {
         stream.send_message(ProtocolMessage::CreateFamily(CreateFamily {
@@ -70,51 +99,211 @@ impl XenMetric for VCpuUsage {
             unit: "".into(),
         }))?;
 
+        stream.send_message(ProtocolMessage::CreateFamily(CreateFamily {
+            help: "CPU time consumed by a VCPU".into(),
+            name: "xen_vcpu_cpu_seconds".into(),
+            metric_type: MetricType::Counter,
+            unit: "seconds".into(),
+        }))?;
+
         Ok(())
     }
 
-    fn read_domain_metrics(
+    fn read_domain_metrics<H: XenHypercall>(
         &mut self,
-        dominfo: XenDomctlGetDomainInfo,
-        hyp: &UnixXenHypercall,
+        domain: &DomainSnapshot,
+        _: &H,
     ) -> SmallVec<[(PluginMetricKind, Metric); 3]> {
-        let new_vcpu_infos: SmallVec<[_; 8]> = (0..=dominfo.max_vcpu_id)
-            .map(|vcpu_id| {
-                hyp.get_vcpu_info(dominfo.domain, vcpu_id)
-                    .inspect_err(|e| tracing::error!("get_vcpu_info failure: {e}"))
-                    .unwrap_or_default()
-            })
+        let new_vcpu_infos: SmallVec<[_; 8]> = domain
+            .vcpus
+            .iter()
+            .map(|vcpu_info| vcpu_info.unwrap_or_default())
             .collect();
 
-        let metrics = if let Some((previous_vcpu_infos, previous_instant)) = self
+        let mut metrics: SmallVec<_> = if let Some((previous_vcpu_infos, elapsed)) = self
             .prev_vcpu_infos
-            .get(&dominfo.domain.0)
-            .zip(self.previous_instant)
+            .get(&domain.info.domain.0)
+            .zip(self.clock.elapsed())
         {
             iter::zip(&new_vcpu_infos, previous_vcpu_infos)
-                .map(|vcpus_infos| generate_vcpu_usage(vcpus_infos, previous_instant))
+                .map(|vcpus_infos| generate_vcpu_usage(vcpus_infos, elapsed))
                 .collect()
         } else {
             smallvec![]
         };
 
+        metrics.extend(new_vcpu_infos.iter().map(generate_vcpu_cpu_seconds));
+
         self.prev_vcpu_infos
-            .insert(dominfo.domain.0, new_vcpu_infos);
+            .insert(domain.info.domain.0, new_vcpu_infos);
         metrics
     }
 
-    fn read_host_metrics(
+    fn begin_round(&mut self, timestamp: SystemTime) {
+        self.clock.advance(timestamp);
+    }
+
+    fn clear_domain_metrics(&mut self, domid: u16) {
+        self.prev_vcpu_infos.remove(&domid);
+    }
+}
+
+/// Scheduling state and placement (current pCPU) of each vCPU, like `xl vcpu-list`.
+pub struct VCpuState;
+
+fn generate_vcpu_state(vcpu_info: &XenDomctlGetVCpuInfo) -> [(PluginMetricKind, Metric); 2] {
+    let state = |name: &str, enabled| State {
+        name: name.into(),
+        enabled,
+    };
+
+    [
+        (
+            PluginMetricKind {
+                family_name: "xen_vcpu_state",
+                submetric: Some(vcpu_info.vcpu.to_compact_string()),
+            },
+            Metric {
+                labels: vcpu_labels(vcpu_info),
+                value: MetricValue::StateSet(
+                    [
+                        state("running", vcpu_info.running != 0),
+                        state("blocked", vcpu_info.blocked != 0),
+                        state("offline", vcpu_info.online == 0),
+                    ]
+                    .into(),
+                ),
+            },
+        ),
+        (
+            PluginMetricKind {
+                family_name: "xen_vcpu_cpu",
+                submetric: Some(vcpu_info.vcpu.to_compact_string()),
+            },
+            Metric {
+                labels: vcpu_labels(vcpu_info),
+                value: MetricValue::Gauge(NumberValue::Int64(vcpu_info.cpu as i64)),
+            },
+        ),
+    ]
+}
+
+impl XenMetric for VCpuState {
+    fn make_families(&self, stream: &mut UnixStream) -> anyhow::Result<()> {
+        stream.send_message(ProtocolMessage::CreateFamily(CreateFamily {
+            help: "Scheduling state of a VCPU".into(),
+            name: "xen_vcpu_state".into(),
+            metric_type: MetricType::StateSet,
+            unit: "".into(),
+        }))?;
+
+        stream.send_message(ProtocolMessage::CreateFamily(CreateFamily {
+            help: "Physical CPU a VCPU is (or has last been) running on".into(),
+            name: "xen_vcpu_cpu".into(),
+            metric_type: MetricType::Gauge,
+            unit: "".into(),
+        }))?;
+
+        Ok(())
+    }
+
+    fn read_domain_metrics<H: XenHypercall>(
         &mut self,
-        _physinfo: XenSysctlPhysInfo,
-        _hyp: &UnixXenHypercall,
+        domain: &DomainSnapshot,
+        _: &H,
     ) -> SmallVec<[(PluginMetricKind, Metric); 3]> {
-        self.previous_instant = Some(self.latest_instant);
-        self.latest_instant = Instant::now();
+        domain
+            .vcpus
+            .iter()
+            .flatten()
+            .flat_map(generate_vcpu_state)
+            .collect()
+    }
+}
+
+/// Usage (as a fraction of the time of its online vCPUs) and CPU time counter of each domain.
+pub struct DomainCpuUsage {
+    clock: RoundClock,
+    prev_cpu_time: HashMap<u16, u64>,
+}
 
-        smallvec![]
+impl DomainCpuUsage {
+    pub fn new() -> Self {
+        Self {
+            clock: RoundClock::default(),
+            prev_cpu_time: HashMap::new(),
+        }
+    }
+}
+
+impl XenMetric for DomainCpuUsage {
+    fn make_families(&self, stream: &mut UnixStream) -> anyhow::Result<()> {
+        stream.send_message(ProtocolMessage::CreateFamily(CreateFamily {
+            help: "Time taken running the VCPUs of a domain, relative to its online VCPUs".into(),
+            name: "xen_domain_cpu_usage".into(),
+            metric_type: MetricType::Gauge,
+            unit: "".into(),
+        }))?;
+
+        stream.send_message(ProtocolMessage::CreateFamily(CreateFamily {
+            help: "CPU time consumed by all the VCPUs of a domain".into(),
+            name: "xen_domain_cpu_seconds".into(),
+            metric_type: MetricType::Counter,
+            unit: "seconds".into(),
+        }))?;
+
+        Ok(())
+    }
+
+    fn read_domain_metrics<H: XenHypercall>(
+        &mut self,
+        domain: &DomainSnapshot,
+        _: &H,
+    ) -> SmallVec<[(PluginMetricKind, Metric); 3]> {
+        let dominfo = domain.info;
+        let domid = dominfo.domain.0;
+        let cpu_time = dominfo.cpu_time.0;
+        let mut metrics = smallvec![];
+
+        if let Some((&prev_cpu_time, elapsed)) =
+            self.prev_cpu_time.get(&domid).zip(self.clock.elapsed())
+        {
+            let usage = (cpu_time_seconds(cpu_time) - cpu_time_seconds(prev_cpu_time))
+                / elapsed.as_secs_f64()
+                / dominfo.nr_online_vcpus.max(1) as f64;
+
+            metrics.push((
+                PluginMetricKind {
+                    family_name: "xen_domain_cpu_usage",
+                    submetric: None,
+                },
+                Metric {
+                    labels: vec![].into_boxed_slice(),
+                    value: MetricValue::Gauge(NumberValue::Double(usage.max(0.0))),
+                },
+            ));
+        }
+
+        metrics.push((
+            PluginMetricKind {
+                family_name: "xen_domain_cpu_seconds",
+                submetric: None,
+            },
+            Metric {
+                labels: vec![].into_boxed_slice(),
+                value: cpu_time_counter(cpu_time_seconds(cpu_time)),
+            },
+        ));
+
+        self.prev_cpu_time.insert(domid, cpu_time);
+        metrics
+    }
+
+    fn begin_round(&mut self, timestamp: SystemTime) {
+        self.clock.advance(timestamp);
     }
 
     fn clear_domain_metrics(&mut self, domid: u16) {
-        self.prev_vcpu_infos.remove(&domid);
+        self.prev_cpu_time.remove(&domid);
     }
 }
@@ -0,0 +1,39 @@
+//! Domain changes, from the `@introduceDomain` and `@releaseDomain` xenstore watches.
+
+use std::sync::mpsc::Sender;
+
+use futures::{stream, StreamExt};
+use smol::Executor;
+use xenstore_rs::{smol::XsSmol, AsyncWatch};
+
+/// Fired by xenstored when a domain is introduced to it (once created by the toolstack).
+const INTRODUCE_DOMAIN: &str = "@introduceDomain";
+
+/// Fired by xenstored when a domain has shut down or has been destroyed.
+const RELEASE_DOMAIN: &str = "@releaseDomain";
+
+/// Signal each domain change to `changes`, until it is closed.
+pub fn watch_domains(changes: Sender<()>) {
+    let executor = Executor::new();
+
+    let result = smol::block_on(executor.run(async {
+        let xs = XsSmol::new(&executor).await?;
+
+        let mut events = Box::pin(stream::select(
+            xs.watch(INTRODUCE_DOMAIN).await?,
+            xs.watch(RELEASE_DOMAIN).await?,
+        ));
+
+        while events.next().await.is_some() {
+            if changes.send(()).is_err() {
+                break;
+            }
+        }
+
+        anyhow::Ok(())
+    }));
+
+    if let Err(e) = result {
+        tracing::warn!("Unable to watch the domains in xenstore: {e}");
+    }
+}
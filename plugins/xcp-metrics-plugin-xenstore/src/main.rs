@@ -5,7 +5,10 @@ use std::path::PathBuf;
 use argh::FromArgs;
 use smol::{net::unix::UnixStream, Executor};
 use xcp_metrics_common::protocol::METRICS_SOCKET_PATH;
-use xen::hypercall::unix::UnixXenHypercall;
+use xen::{
+    abi::XenAbi,
+    hypercall::{unix::UnixXenHypercall, XenHypercall},
+};
 use xenstore_rs::smol::XsSmol;
 
 /// xcp-metrics XenStore plugin.
@@ -18,6 +21,10 @@ struct Args {
     /// target daemon.
     #[argh(option, short = 'd')]
     target: Option<PathBuf>,
+
+    /// xen ABI to use (e.g 4.19), detected by default.
+    #[argh(option)]
+    xen_abi: Option<XenAbi>,
 }
 
 fn main() {
@@ -50,7 +57,7 @@ fn main() {
             }
         };
 
-        let hyp = match UnixXenHypercall::new() {
+        let hyp = match UnixXenHypercall::with_abi(args.xen_abi) {
             Ok(xs) => xs,
             Err(e) => {
                 tracing::error!("Unable to initialize privcmd: {e}");
@@ -58,6 +65,8 @@ fn main() {
             }
         };
 
+        tracing::info!("Using Xen {} ABI ({:?})", hyp.abi(), hyp.abi_source());
+
         if let Err(e) = plugin::run_plugin(rpc_stream, hyp, xs).await {
             tracing::error!("Plugin failure {e}");
         }
@@ -16,7 +16,9 @@ use xcp_metrics_common::{
 use xen::{domctl::DomctlGetDomainInfo, hypercall::XenHypercall};
 use xenstore_rs::{smol::XsSmol, AsyncWatch, AsyncXs};
 
-use metrics::{MemInfoFree, MemInfoTotal, MetricHandler, MetricHandlerEnum};
+use metrics::{
+    MemInfoFree, MemInfoTotal, MemoryStaticMax, MemoryTarget, MetricHandler, MetricHandlerEnum,
+};
 
 #[derive(Default)]
 struct PluginState {
@@ -64,6 +66,24 @@ async fn initialize_families(stream: &mut UnixStream) -> anyhow::Result<()> {
         }))
         .await?;
 
+    stream
+        .send_message_async(ProtocolMessage::CreateFamily(CreateFamily {
+            help: "Balloon target of the guest".into(),
+            name: "xen_domain_memory_target".into(),
+            metric_type: MetricType::Gauge,
+            unit: "bytes".into(),
+        }))
+        .await?;
+
+    stream
+        .send_message_async(ProtocolMessage::CreateFamily(CreateFamily {
+            help: "Static maximum memory of the guest".into(),
+            name: "xen_domain_memory_static_max".into(),
+            metric_type: MetricType::Gauge,
+            unit: "bytes".into(),
+        }))
+        .await?;
+
     Ok(())
 }
 
@@ -101,6 +121,12 @@ pub async fn run_plugin(
     let meminfo_free = MemInfoFree;
     handlers.insert(meminfo_free.subpath(), meminfo_free.into());
 
+    let memory_target = MemoryTarget;
+    handlers.insert(memory_target.subpath(), memory_target.into());
+
+    let memory_static_max = MemoryStaticMax;
+    handlers.insert(memory_static_max.subpath(), memory_static_max.into());
+
     let mut state = PluginState::default();
 
     while let Some(path) = domain_watcher.next().await {
@@ -182,16 +208,16 @@ pub async fn run_plugin(
                     }))
                     .await?;
             } else {
-                // Remove the related metric (if there is)
+                // Remove the related metric (if there is), metrics are keyed by family.
+                let Some(handler) = handlers.get(subpath) else {
+                    continue;
+                };
+
                 if let Some(uuid) = state
                     .metrics_map
                     .get_mut(&domid)
-                    .and_then(|map| map.remove(subpath))
+                    .and_then(|map| map.remove(handler.family_name()))
                 {
-                    let Some(handler) = handlers.get(subpath) else {
-                        continue;
-                    };
-
                     stream
                         .send_message_async(ProtocolMessage::RemoveMetric(RemoveMetric {
                             family_name: handler.family_name().into(),
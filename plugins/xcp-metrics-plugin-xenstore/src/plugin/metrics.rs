@@ -10,6 +10,17 @@ pub(crate) trait MetricHandler {
     async fn read_metric(&self, xs: &impl AsyncXs, path: &str, subpath: &str) -> Option<Metric>;
 }
 
+/// Read an amount of memory in KiB as bytes.
+async fn read_kib_metric(xs: &impl AsyncXs, path: &str) -> Option<Metric> {
+    let mut bytes: i64 = xs.read(path).await.ok()?.trim().parse().ok()?;
+    bytes *= 1024; // KiB to bytes
+
+    Some(Metric {
+        labels: vec![].into_boxed_slice(),
+        value: MetricValue::Gauge(NumberValue::Int64(bytes)),
+    })
+}
+
 #[derive(Default)]
 pub struct MemInfoTotal;
 
@@ -27,13 +38,7 @@ impl MetricHandler for MemInfoTotal {
             return None;
         }
 
-        let mut mem_total = xs.read(path).await.ok()?.parse().ok()?;
-        mem_total *= 1024; // KiB to bytes
-
-        Some(Metric {
-            labels: vec![].into_boxed_slice(),
-            value: MetricValue::Gauge(NumberValue::Int64(mem_total)),
-        })
+        read_kib_metric(xs, path).await
     }
 }
 
@@ -54,13 +59,50 @@ impl MetricHandler for MemInfoFree {
             return None;
         }
 
-        let mut mem_total = xs.read(path).await.ok()?.parse().ok()?;
-        mem_total *= 1024; // KiB to bytes
+        read_kib_metric(xs, path).await
+    }
+}
+
+/// Balloon target of the guest, set by the toolstack.
+#[derive(Default)]
+pub struct MemoryTarget;
+
+impl MetricHandler for MemoryTarget {
+    fn subpath(&self) -> &'static str {
+        "memory/target"
+    }
+
+    fn family_name(&self) -> &'static str {
+        "xen_domain_memory_target"
+    }
+
+    async fn read_metric(&self, xs: &impl AsyncXs, path: &str, subpath: &str) -> Option<Metric> {
+        if subpath != self.subpath() {
+            return None;
+        }
+
+        read_kib_metric(xs, path).await
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryStaticMax;
+
+impl MetricHandler for MemoryStaticMax {
+    fn subpath(&self) -> &'static str {
+        "memory/static-max"
+    }
+
+    fn family_name(&self) -> &'static str {
+        "xen_domain_memory_static_max"
+    }
+
+    async fn read_metric(&self, xs: &impl AsyncXs, path: &str, subpath: &str) -> Option<Metric> {
+        if subpath != self.subpath() {
+            return None;
+        }
 
-        Some(Metric {
-            labels: vec![].into_boxed_slice(),
-            value: MetricValue::Gauge(NumberValue::Int64(mem_total)),
-        })
+        read_kib_metric(xs, path).await
     }
 }
 
@@ -68,4 +110,6 @@ impl MetricHandler for MemInfoFree {
 pub enum MetricHandlerEnum {
     MemInfoTotal(MemInfoTotal),
     MemInfoFree(MemInfoFree),
+    MemoryTarget(MemoryTarget),
+    MemoryStaticMax(MemoryStaticMax),
 }
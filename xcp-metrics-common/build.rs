@@ -1,4 +1,10 @@
 fn main() {
     #[cfg(feature = "openmetrics")]
     prost_build::compile_protos(&["src/openmetrics_data_model.proto"], &["src/"]).unwrap();
+
+    #[cfg(feature = "remote_write")]
+    prost_build::compile_protos(&["src/remote_write.proto"], &["src/"]).unwrap();
+
+    #[cfg(feature = "otlp")]
+    prost_build::compile_protos(&["src/otlp.proto"], &["src/"]).unwrap();
 }
@@ -1,5 +1,10 @@
 //! OpenMetrics conversion and text export.
 pub mod convert;
+#[cfg(feature = "otlp")]
+pub mod otlp;
+pub mod parse;
+#[cfg(feature = "remote_write")]
+pub mod remote_write;
 pub mod text;
 
 pub use convert::openmetrics::*;
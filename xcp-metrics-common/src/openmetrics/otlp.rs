@@ -0,0 +1,302 @@
+//! OpenTelemetry (OTLP) metrics conversion.
+//!
+//! Families are converted following the OpenTelemetry Prometheus/OpenMetrics compatibility
+//! rules:
+//! - gauges (and unknown metrics) are gauges,
+//! - counters are monotonic cumulative sums,
+//! - histograms and summaries are (cumulative) histograms and summaries,
+//! - state sets are non-monotonic sums with a point per state (1 if enabled, 0 otherwise),
+//!   the state being in the attribute named after the family,
+//! - info metrics are non-monotonic sums of 1, with their labels as attributes.
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use prost::Message;
+
+use crate::{
+    metrics::{Label, Metric, MetricFamily, MetricSet, MetricValue, NumberValue},
+    utils::flatten::number_to_f64,
+};
+
+pub mod opentelemetry {
+    include!(concat!(env!("OUT_DIR"), "/opentelemetry.rs"));
+}
+
+use opentelemetry::{
+    any_value, metric, number_data_point, summary_data_point::ValueAtQuantile,
+    AggregationTemporality, AnyValue, DataPointFlags, ExportMetricsServiceRequest, Gauge,
+    Histogram, HistogramDataPoint, InstrumentationScope, KeyValue, NumberDataPoint, Resource,
+    ResourceMetrics, ScopeMetrics, Sum, Summary, SummaryDataPoint,
+};
+
+/// Content type of OTLP/HTTP protobuf requests.
+pub const CONTENT_TYPE: &str = "application/x-protobuf";
+
+/// Name of the instrumentation scope of the exported metrics.
+pub const SCOPE_NAME: &str = "xcp-metrics";
+
+/// Make a string attribute.
+pub fn key_value(key: &str, value: &str) -> KeyValue {
+    KeyValue {
+        key: key.to_string(),
+        value: Some(AnyValue {
+            value: Some(any_value::Value::StringValue(value.to_string())),
+        }),
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}
+
+fn attributes<'a>(labels: impl Iterator<Item = &'a Label>) -> Vec<KeyValue> {
+    labels
+        .map(|label| key_value(&label.name, &label.value))
+        .collect()
+}
+
+/// Timestamps of the data points.
+#[derive(Clone, Copy)]
+struct Times {
+    /// Start of cumulative data points without creation time.
+    start: u64,
+    now: u64,
+}
+
+impl Times {
+    /// Start time from a creation time, [UNIX_EPOCH] meaning unknown.
+    fn start_from(&self, created: SystemTime) -> u64 {
+        match unix_nanos(created) {
+            0 => self.start,
+            created => created,
+        }
+    }
+}
+
+fn number_point(
+    attributes: Vec<KeyValue>,
+    value: NumberValue,
+    start_time_unix_nano: u64,
+    time_unix_nano: u64,
+) -> NumberDataPoint {
+    let (value, flags) = match value {
+        NumberValue::Double(value) => (Some(number_data_point::Value::AsDouble(value)), 0),
+        NumberValue::Int64(value) => (Some(number_data_point::Value::AsInt(value)), 0),
+        NumberValue::Undefined => (None, DataPointFlags::NoRecordedValueMask as u32),
+    };
+
+    NumberDataPoint {
+        attributes,
+        start_time_unix_nano,
+        time_unix_nano,
+        value,
+        flags,
+    }
+}
+
+/// Data points of a metric, one of each kind being used depending on the family type.
+#[derive(Default)]
+struct DataPoints {
+    gauge: Vec<NumberDataPoint>,
+    counter: Vec<NumberDataPoint>,
+    /// State sets and info.
+    non_monotonic: Vec<NumberDataPoint>,
+    histogram: Vec<HistogramDataPoint>,
+    summary: Vec<SummaryDataPoint>,
+}
+
+impl DataPoints {
+    fn add(&mut self, name: &str, metric: &Metric, times: Times) {
+        let labels = metric.labels.iter();
+
+        match &metric.value {
+            MetricValue::Unknown(value) | MetricValue::Gauge(value) => self
+                .gauge
+                .push(number_point(attributes(labels), *value, 0, times.now)),
+            MetricValue::Counter { total, created, .. } => self.counter.push(number_point(
+                attributes(labels),
+                *total,
+                created.map_or(times.start, |created| times.start_from(created)),
+                times.now,
+            )),
+            MetricValue::Histogram {
+                sum,
+                count,
+                created,
+                buckets,
+            } => {
+                // OpenMetrics buckets are cumulative, OTLP ones aren't.
+                let mut explicit_bounds = vec![];
+                let mut bucket_counts = vec![];
+                let mut previous = 0;
+
+                for bucket in buckets
+                    .iter()
+                    .filter(|bucket| bucket.upper_bound.is_finite())
+                {
+                    explicit_bounds.push(bucket.upper_bound);
+                    bucket_counts.push(bucket.count.saturating_sub(previous));
+                    previous = bucket.count;
+                }
+                bucket_counts.push(count.saturating_sub(previous));
+
+                self.histogram.push(HistogramDataPoint {
+                    attributes: attributes(labels),
+                    start_time_unix_nano: times.start_from(*created),
+                    time_unix_nano: times.now,
+                    count: *count,
+                    sum: number_to_f64(*sum),
+                    bucket_counts,
+                    explicit_bounds,
+                    flags: 0,
+                })
+            }
+            MetricValue::Summary {
+                sum,
+                count,
+                created,
+                quantile,
+            } => self.summary.push(SummaryDataPoint {
+                attributes: attributes(labels),
+                start_time_unix_nano: times.start_from(*created),
+                time_unix_nano: times.now,
+                count: *count,
+                sum: number_to_f64(*sum),
+                quantile_values: quantile
+                    .iter()
+                    .map(|quantile| ValueAtQuantile {
+                        quantile: quantile.quantile,
+                        value: quantile.value,
+                    })
+                    .collect(),
+                flags: 0,
+            }),
+            MetricValue::StateSet(states) => {
+                for state in states.iter() {
+                    let mut attributes = attributes(labels.clone());
+                    attributes.push(key_value(name, &state.name));
+
+                    self.non_monotonic.push(number_point(
+                        attributes,
+                        NumberValue::Int64(state.enabled.into()),
+                        times.start,
+                        times.now,
+                    ));
+                }
+            }
+            MetricValue::Info(info) => self.non_monotonic.push(number_point(
+                attributes(labels.chain(info.iter())),
+                NumberValue::Int64(1),
+                times.start,
+                times.now,
+            )),
+        }
+    }
+
+    fn into_data(self) -> Option<metric::Data> {
+        let cumulative = AggregationTemporality::Cumulative as i32;
+
+        if !self.gauge.is_empty() {
+            Some(metric::Data::Gauge(Gauge {
+                data_points: self.gauge,
+            }))
+        } else if !self.counter.is_empty() {
+            Some(metric::Data::Sum(Sum {
+                data_points: self.counter,
+                aggregation_temporality: cumulative,
+                is_monotonic: true,
+            }))
+        } else if !self.non_monotonic.is_empty() {
+            Some(metric::Data::Sum(Sum {
+                data_points: self.non_monotonic,
+                aggregation_temporality: cumulative,
+                is_monotonic: false,
+            }))
+        } else if !self.histogram.is_empty() {
+            Some(metric::Data::Histogram(Histogram {
+                data_points: self.histogram,
+                aggregation_temporality: cumulative,
+            }))
+        } else if !self.summary.is_empty() {
+            Some(metric::Data::Summary(Summary {
+                data_points: self.summary,
+            }))
+        } else {
+            None
+        }
+    }
+}
+
+fn family_to_metric(name: &str, family: &MetricFamily, times: Times) -> Option<metric::Data> {
+    let mut points = DataPoints::default();
+
+    for metric in family.metrics.values() {
+        points.add(name, metric, times);
+    }
+
+    points.into_data()
+}
+
+/// Convert `metrics` into an OTLP export request, sampled at `timestamp`.
+///
+/// `resource` are the attributes of the resource (e.g host identity), and `start_time` the
+/// start of cumulative data points that don't have a creation time.
+pub fn metrics_to_request(
+    metrics: &MetricSet,
+    resource: &[KeyValue],
+    start_time: SystemTime,
+    timestamp: SystemTime,
+) -> ExportMetricsServiceRequest {
+    let times = Times {
+        start: unix_nanos(start_time),
+        now: unix_nanos(timestamp),
+    };
+
+    let metrics = metrics
+        .families
+        .iter()
+        .filter_map(|(name, family)| {
+            Some(opentelemetry::Metric {
+                name: name.to_string(),
+                description: family.help.to_string(),
+                unit: family.unit.to_string(),
+                data: Some(family_to_metric(name, family, times)?),
+            })
+        })
+        .collect();
+
+    ExportMetricsServiceRequest {
+        resource_metrics: vec![ResourceMetrics {
+            resource: Some(Resource {
+                attributes: resource.to_vec(),
+            }),
+            scope_metrics: vec![ScopeMetrics {
+                scope: Some(InstrumentationScope {
+                    name: SCOPE_NAME.to_string(),
+                    version: env!("CARGO_PKG_VERSION").to_string(),
+                }),
+                metrics,
+            }],
+        }],
+    }
+}
+
+/// Encode an OTLP export request (protobuf).
+pub fn encode_request(request: &ExportMetricsServiceRequest) -> Vec<u8> {
+    request.encode_to_vec()
+}
+
+/// Decode an OTLP export request (protobuf).
+pub fn decode_request(payload: &[u8]) -> anyhow::Result<ExportMetricsServiceRequest> {
+    Ok(ExportMetricsServiceRequest::decode(payload)?)
+}
+
+/// Decode an OTLP export response (protobuf).
+pub fn decode_response(
+    payload: &[u8],
+) -> anyhow::Result<opentelemetry::ExportMetricsServiceResponse> {
+    Ok(opentelemetry::ExportMetricsServiceResponse::decode(
+        payload,
+    )?)
+}
@@ -0,0 +1,412 @@
+//! OpenMetrics (and Prometheus) text format parser.
+//!
+//! Families are named without their suffixes (`_total` of counters, unit declared with `# UNIT`),
+//! like the families of [crate::metrics::MetricSet]. Samples without a `# TYPE` are unknown
+//! metrics, timestamps and exemplars are ignored.
+use std::{collections::HashMap, time::UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Context};
+use compact_str::CompactString;
+
+use crate::metrics::{
+    Bucket, Label, Metric, MetricFamily, MetricSet, MetricType, MetricValue, NumberValue, Quantile,
+    State,
+};
+
+fn parse_type(s: &str) -> anyhow::Result<MetricType> {
+    Ok(match s {
+        "unknown" | "untyped" => MetricType::Unknown,
+        "gauge" => MetricType::Gauge,
+        "counter" => MetricType::Counter,
+        "stateset" => MetricType::StateSet,
+        "info" => MetricType::Info,
+        "histogram" => MetricType::Histogram,
+        "gaugehistogram" => MetricType::GaugeHistogram,
+        "summary" => MetricType::Summary,
+        _ => bail!("Unknown metric type '{s}'"),
+    })
+}
+
+fn parse_float(s: &str) -> anyhow::Result<f64> {
+    s.parse().with_context(|| format!("Invalid value '{s}'"))
+}
+
+fn parse_number(s: &str) -> anyhow::Result<NumberValue> {
+    match s.parse() {
+        Ok(value) => Ok(NumberValue::Int64(value)),
+        Err(_) => parse_float(s).map(NumberValue::Double),
+    }
+}
+
+fn unescape(s: &str) -> String {
+    let mut unescaped = String::with_capacity(s.len());
+    let mut chars = s.chars();
+
+    while let Some(c) = chars.next() {
+        match (c, c == '\\') {
+            (_, true) => match chars.next() {
+                Some('n') => unescaped.push('\n'),
+                Some(c) => unescaped.push(c),
+                None => unescaped.push('\\'),
+            },
+            (c, false) => unescaped.push(c),
+        }
+    }
+
+    unescaped
+}
+
+/// Parse a `{name="value",...}` label set, returns the labels and the rest of the line.
+fn parse_labels(s: &str) -> anyhow::Result<(Vec<Label>, &str)> {
+    let mut labels = vec![];
+    let mut rest = s.trim_start();
+
+    loop {
+        if let Some(after) = rest.strip_prefix('}') {
+            return Ok((labels, after));
+        }
+
+        let (name, after) = rest
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Invalid labels '{s}'"))?;
+        let after = after
+            .trim_start()
+            .strip_prefix('"')
+            .ok_or_else(|| anyhow!("Unquoted label value in '{s}'"))?;
+
+        // Find the closing quote, skipping escaped characters.
+        let mut end = None;
+        let mut escaped = false;
+
+        for (i, c) in after.char_indices() {
+            match c {
+                _ if escaped => escaped = false,
+                '\\' => escaped = true,
+                '"' => {
+                    end = Some(i);
+                    break;
+                }
+                _ => (),
+            }
+        }
+
+        let end = end.ok_or_else(|| anyhow!("Unclosed label value in '{s}'"))?;
+
+        labels.push(Label {
+            name: name.trim().into(),
+            value: unescape(&after[..end]).into(),
+        });
+
+        rest = after[end + 1..].trim_start();
+        rest = rest.strip_prefix(',').unwrap_or(rest).trim_start();
+    }
+}
+
+/// Split a sample line into its name, labels and value.
+fn parse_sample(line: &str) -> anyhow::Result<(&str, Vec<Label>, &str)> {
+    let name_end = line
+        .find(|c: char| c == '{' || c.is_whitespace())
+        .ok_or_else(|| anyhow!("Missing value in '{line}'"))?;
+    let (name, rest) = line.split_at(name_end);
+
+    let (labels, rest) = match rest.strip_prefix('{') {
+        Some(rest) => parse_labels(rest)?,
+        None => (vec![], rest),
+    };
+
+    let value = rest
+        .split_whitespace()
+        .next()
+        .ok_or_else(|| anyhow!("Missing value in '{line}'"))?;
+
+    Ok((name, labels, value))
+}
+
+/// Family being parsed.
+#[derive(Default)]
+struct FamilyBuilder {
+    metric_type: MetricType,
+    unit: CompactString,
+    help: CompactString,
+    /// Metrics (by labels, excluding `le` and `quantile`) in order of appearance.
+    metrics: Vec<(Vec<Label>, MetricValue)>,
+}
+
+impl FamilyBuilder {
+    fn metric(
+        &mut self,
+        labels: Vec<Label>,
+        default: impl FnOnce() -> MetricValue,
+    ) -> &mut MetricValue {
+        let index = match self.metrics.iter().position(|(other, _)| *other == labels) {
+            Some(index) => index,
+            None => {
+                self.metrics.push((labels, default()));
+                self.metrics.len() - 1
+            }
+        };
+
+        &mut self.metrics[index].1
+    }
+
+    /// Add a sample, `suffix` being the part of the sample name after the family name.
+    fn add_sample(
+        &mut self,
+        family: &str,
+        suffix: &str,
+        mut labels: Vec<Label>,
+        value: &str,
+    ) -> anyhow::Result<()> {
+        let mut take_label = |name: &str| {
+            let index = labels.iter().position(|label| label.name == name)?;
+            Some(labels.remove(index).value)
+        };
+
+        match (self.metric_type, suffix) {
+            (MetricType::Unknown | MetricType::Gauge, "")
+            | (MetricType::Counter, "" | "_total") => {
+                let value = parse_number(value)?;
+                let value = match self.metric_type {
+                    MetricType::Unknown => MetricValue::Unknown(value),
+                    MetricType::Gauge => MetricValue::Gauge(value),
+                    _ => MetricValue::Counter {
+                        total: value,
+                        created: None,
+                        exemplar: None,
+                    },
+                };
+
+                let default = value.clone();
+                *self.metric(labels, || default) = value;
+            }
+            (MetricType::StateSet, "") => {
+                let state =
+                    take_label(family).ok_or_else(|| anyhow!("Missing state label '{family}'"))?;
+                let enabled = parse_float(value)? != 0.0;
+
+                if let MetricValue::StateSet(states) =
+                    self.metric(labels, || MetricValue::StateSet([].into()))
+                {
+                    let mut updated = states.to_vec();
+                    updated.push(State {
+                        enabled,
+                        name: state,
+                    });
+                    *states = updated.into();
+                }
+            }
+            (MetricType::Info, "" | "_info") => {
+                self.metric(labels, || MetricValue::Info([].into()));
+            }
+            (MetricType::Histogram | MetricType::GaugeHistogram, "_bucket") => {
+                let upper_bound = take_label("le")
+                    .ok_or_else(|| anyhow!("Missing 'le' label"))
+                    .and_then(|le| parse_float(&le))?;
+                let count = parse_float(value)? as u64;
+
+                if let MetricValue::Histogram { buckets, .. } = self.metric(labels, empty_histogram)
+                {
+                    let mut updated = buckets.to_vec();
+                    updated.push(Bucket {
+                        count,
+                        upper_bound,
+                        exemplar: None,
+                    });
+                    updated.sort_by(|a, b| a.upper_bound.total_cmp(&b.upper_bound));
+                    *buckets = updated.into();
+                }
+            }
+            (MetricType::Summary, "") => {
+                let quantile = take_label("quantile")
+                    .ok_or_else(|| anyhow!("Missing 'quantile' label"))
+                    .and_then(|quantile| parse_float(&quantile))?;
+                let value = parse_float(value)?;
+
+                if let MetricValue::Summary {
+                    quantile: quantiles,
+                    ..
+                } = self.metric(labels, empty_summary)
+                {
+                    let mut updated = quantiles.to_vec();
+                    updated.push(Quantile { quantile, value });
+                    *quantiles = updated.into();
+                }
+            }
+            (
+                MetricType::Histogram | MetricType::GaugeHistogram | MetricType::Summary,
+                "_count" | "_gcount" | "_sum" | "_gsum",
+            ) => {
+                let metric_type = self.metric_type;
+                let default = match metric_type {
+                    MetricType::Summary => empty_summary,
+                    _ => empty_histogram,
+                };
+
+                match self.metric(labels, default) {
+                    MetricValue::Histogram { sum, count, .. }
+                    | MetricValue::Summary { sum, count, .. } => match suffix {
+                        "_count" | "_gcount" => *count = parse_float(value)? as u64,
+                        _ => *sum = parse_number(value)?,
+                    },
+                    _ => (),
+                }
+            }
+            // Creation timestamps are ignored.
+            (_, "_created") => (),
+            _ => bail!("Unexpected sample '{family}{suffix}'"),
+        }
+
+        Ok(())
+    }
+}
+
+fn empty_histogram() -> MetricValue {
+    MetricValue::Histogram {
+        sum: NumberValue::Undefined,
+        count: 0,
+        created: UNIX_EPOCH,
+        buckets: [].into(),
+    }
+}
+
+fn empty_summary() -> MetricValue {
+    MetricValue::Summary {
+        sum: NumberValue::Undefined,
+        count: 0,
+        created: UNIX_EPOCH,
+        quantile: [].into(),
+    }
+}
+
+/// Suffixes of the samples of each family type.
+fn sample_suffixes(metric_type: MetricType) -> &'static [&'static str] {
+    match metric_type {
+        MetricType::Counter => &["_total", "_created"],
+        MetricType::Info => &["_info"],
+        MetricType::Histogram => &["_bucket", "_count", "_sum", "_created"],
+        MetricType::GaugeHistogram => &["_bucket", "_gcount", "_gsum"],
+        MetricType::Summary => &["_count", "_sum", "_created"],
+        _ => &[],
+    }
+}
+
+/// Parse a text exposition into a [MetricSet].
+pub fn parse_metrics_set_text(text: &str) -> anyhow::Result<MetricSet> {
+    // Families in order of declaration.
+    let mut families: Vec<(CompactString, FamilyBuilder)> = vec![];
+    let mut family_indices: HashMap<CompactString, usize> = HashMap::new();
+
+    let mut family = |name: &str, families: &mut Vec<(CompactString, FamilyBuilder)>| {
+        let index = *family_indices.entry(name.into()).or_insert_with(|| {
+            families.push((name.into(), FamilyBuilder::default()));
+            families.len() - 1
+        });
+
+        index
+    };
+
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+
+        if line.is_empty() {
+            continue;
+        }
+
+        let result = if let Some(comment) = line.strip_prefix('#') {
+            let mut parts = comment.trim_start().splitn(3, char::is_whitespace);
+
+            match (
+                parts.next(),
+                parts.next(),
+                parts.next().unwrap_or("").trim(),
+            ) {
+                (Some("EOF"), _, _) => break,
+                (Some("TYPE"), Some(name), metric_type) => {
+                    parse_type(metric_type).map(|metric_type| {
+                        let index = family(name, &mut families);
+                        families[index].1.metric_type = metric_type;
+                    })
+                }
+                (Some("UNIT"), Some(name), unit) => {
+                    let index = family(name, &mut families);
+                    families[index].1.unit = unit.into();
+                    Ok(())
+                }
+                (Some("HELP"), Some(name), help) => {
+                    let index = family(name, &mut families);
+                    families[index].1.help = unescape(help).into();
+                    Ok(())
+                }
+                // Other comments.
+                _ => Ok(()),
+            }
+        } else {
+            parse_sample(line).and_then(|(name, labels, value)| {
+                // Find the family of the sample, using the suffixes of its type.
+                let (index, suffix) = families
+                    .iter()
+                    .enumerate()
+                    .find_map(|(index, (family_name, builder))| {
+                        let suffix = name.strip_prefix(family_name.as_str())?;
+
+                        (suffix.is_empty()
+                            || sample_suffixes(builder.metric_type).contains(&suffix))
+                        .then_some((index, suffix))
+                    })
+                    .unwrap_or_else(|| (family(name, &mut families), ""));
+
+                let (family_name, builder) = &mut families[index];
+                builder.add_sample(family_name, suffix, labels, value)
+            })
+        };
+
+        result.with_context(|| format!("Line {}", number + 1))?;
+    }
+
+    let families = families
+        .into_iter()
+        .filter(|(_, builder)| !builder.metrics.is_empty())
+        .map(|(name, builder)| {
+            // Remove the suffixes that exporters add back.
+            let mut name = name.as_str();
+
+            if builder.metric_type == MetricType::Counter {
+                name = name.strip_suffix("_total").unwrap_or(name);
+            }
+
+            if !builder.unit.is_empty() {
+                name = name
+                    .strip_suffix(builder.unit.as_str())
+                    .and_then(|name| name.strip_suffix('_'))
+                    .unwrap_or(name);
+            }
+
+            let metrics = builder
+                .metrics
+                .into_iter()
+                .map(|(labels, value)| {
+                    (
+                        uuid::Uuid::new_v4(),
+                        Metric {
+                            labels: labels.into(),
+                            value,
+                        },
+                    )
+                })
+                .collect();
+
+            (
+                name.into(),
+                MetricFamily {
+                    reference_count: 1,
+                    metric_type: builder.metric_type,
+                    unit: builder.unit,
+                    help: builder.help,
+                    metrics,
+                },
+            )
+        })
+        .collect();
+
+    Ok(MetricSet { families })
+}
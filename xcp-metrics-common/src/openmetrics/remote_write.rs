@@ -0,0 +1,108 @@
+//! Prometheus remote-write (v1) conversion.
+//!
+//! Metrics are flattened into Prometheus series using [flatten].
+use prost::Message;
+
+use crate::{
+    metrics::{MetricSet, MetricType},
+    utils::flatten,
+};
+
+use super::text::format_name;
+
+#[allow(non_snake_case)]
+pub mod prometheus {
+    include!(concat!(env!("OUT_DIR"), "/prometheus.rs"));
+}
+
+/// Content type of remote-write requests.
+pub const CONTENT_TYPE: &str = "application/x-protobuf";
+/// Value of the `X-Prometheus-Remote-Write-Version` header.
+pub const VERSION: &str = "0.1.0";
+
+impl From<MetricType> for prometheus::metric_metadata::MetricType {
+    fn from(value: MetricType) -> Self {
+        match value {
+            MetricType::Unknown => Self::Unknown,
+            MetricType::Gauge => Self::Gauge,
+            MetricType::Counter => Self::Counter,
+            MetricType::StateSet => Self::Stateset,
+            MetricType::Info => Self::Info,
+            MetricType::Histogram => Self::Histogram,
+            MetricType::GaugeHistogram => Self::Gaugehistogram,
+            MetricType::Summary => Self::Summary,
+        }
+    }
+}
+
+/// Convert `metrics` into remote-write series, sampled at `timestamp` (in milliseconds).
+pub fn metrics_to_series(metrics: &MetricSet, timestamp: i64) -> Vec<prometheus::TimeSeries> {
+    let mut series = vec![];
+
+    for (name, family) in &metrics.families {
+        let name = format_name(&flatten::family_name(name, family), true, true);
+
+        for metric in family.metrics.values() {
+            for sample in flatten::flatten(&name, &metric.value) {
+                let mut labels: Vec<prometheus::Label> = metric
+                    .labels
+                    .iter()
+                    .map(|label| (&label.name, &label.value))
+                    .chain(
+                        sample
+                            .extra_labels
+                            .iter()
+                            .map(|(name, value)| (name, value)),
+                    )
+                    .map(|(name, value)| prometheus::Label {
+                        name: format_name(name, false, true),
+                        value: value.to_string(),
+                    })
+                    .chain([prometheus::Label {
+                        name: "__name__".into(),
+                        value: format!("{name}{}", sample.suffix),
+                    }])
+                    .collect();
+
+                labels.sort_by(|a, b| a.name.cmp(&b.name));
+                labels.dedup_by(|a, b| a.name == b.name);
+
+                series.push(prometheus::TimeSeries {
+                    labels,
+                    samples: vec![prometheus::Sample {
+                        value: sample.value,
+                        timestamp,
+                    }],
+                });
+            }
+        }
+    }
+
+    series
+}
+
+/// Metadata (type, unit and help) of the families of `metrics`.
+pub fn metrics_metadata(metrics: &MetricSet) -> Vec<prometheus::MetricMetadata> {
+    metrics
+        .families
+        .iter()
+        .map(|(name, family)| prometheus::MetricMetadata {
+            r#type: prometheus::metric_metadata::MetricType::from(family.metric_type).into(),
+            metric_family_name: format_name(&flatten::family_name(name, family), true, true),
+            help: family.help.to_string(),
+            unit: family.unit.to_string(),
+        })
+        .collect()
+}
+
+/// Encode a remote-write request (snappy-compressed protobuf).
+pub fn encode_write_request(request: &prometheus::WriteRequest) -> Result<Vec<u8>, snap::Error> {
+    snap::raw::Encoder::new().compress_vec(&request.encode_to_vec())
+}
+
+/// Decode a remote-write request (snappy-compressed protobuf).
+pub fn decode_write_request(payload: &[u8]) -> anyhow::Result<prometheus::WriteRequest> {
+    let buffer = snap::raw::Decoder::new().decompress_vec(payload)?;
+
+    Ok(prometheus::WriteRequest::decode(buffer.as_slice())?)
+}
@@ -154,3 +154,82 @@ fn metrics_to_openmetrics_histogram() {
 
     assert_eq!(metric_point, decoded_metric_point);
 }
+
+/// Test parsing of a Prometheus text exposition.
+#[test]
+fn parse_text() {
+    use super::parse::parse_metrics_set_text;
+    use crate::metrics::MetricType;
+
+    let text = r#"
+# HELP backup_duration_seconds Duration of the last backup.
+# TYPE backup_duration_seconds gauge
+# UNIT backup_duration_seconds seconds
+backup_duration_seconds{vm="web 1"} 12.5
+# TYPE backup_runs_total counter
+backup_runs_total{status="ok"} 42
+backup_runs_total{status="failed"} 1
+# TYPE sr_scrub_latency histogram
+sr_scrub_latency_bucket{sr="local",le="0.5"} 2
+sr_scrub_latency_bucket{sr="local",le="+Inf"} 3
+sr_scrub_latency_sum{sr="local"} 2.5
+sr_scrub_latency_count{sr="local"} 3
+# TYPE rpc_latency summary
+rpc_latency{quantile="0.5"} 0.1
+rpc_latency_sum 4
+rpc_latency_count 20
+# A comment.
+untyped_metric{path="C:\\tmp\"x\""} 1 1700000000000
+"#;
+
+    let metrics = parse_metrics_set_text(text).unwrap();
+    let metric = |name: &str| {
+        let family = &metrics.families[name];
+        assert_eq!(family.reference_count, 1);
+        (family, family.metrics.values().next().unwrap())
+    };
+
+    let (family, duration) = metric("backup_duration");
+    assert_eq!(family.metric_type, MetricType::Gauge);
+    assert_eq!(family.unit, "seconds");
+    assert_eq!(family.help, "Duration of the last backup.");
+    assert_eq!(duration.labels[0].value, "web 1");
+    assert_eq!(
+        duration.value,
+        MetricValue::Gauge(NumberValue::Double(12.5))
+    );
+
+    let (family, _) = metric("backup_runs");
+    assert_eq!(family.metric_type, MetricType::Counter);
+    assert_eq!(family.metrics.len(), 2);
+
+    let (_, latency) = metric("sr_scrub_latency");
+    let MetricValue::Histogram {
+        sum,
+        count,
+        buckets,
+        ..
+    } = &latency.value
+    else {
+        panic!("Not a histogram");
+    };
+    assert_eq!(latency.labels.len(), 1);
+    assert_eq!((*sum, *count), (NumberValue::Double(2.5), 3));
+    assert_eq!(buckets.len(), 2);
+    assert_eq!(buckets[1].upper_bound, f64::INFINITY);
+
+    let (_, summary) = metric("rpc_latency");
+    assert!(matches!(
+        &summary.value,
+        MetricValue::Summary { count: 20, quantile, .. } if quantile[0] == Quantile { quantile: 0.5, value: 0.1 }
+    ));
+
+    let (family, untyped) = metric("untyped_metric");
+    assert_eq!(family.metric_type, MetricType::Unknown);
+    assert_eq!(untyped.labels[0].value, "C:\\tmp\"x\"");
+    assert_eq!(untyped.value, MetricValue::Unknown(NumberValue::Int64(1)));
+
+    assert!(parse_metrics_set_text("# TYPE a nothing").is_err());
+    assert!(parse_metrics_set_text("a{b=\"c} 1").is_err());
+    assert!(parse_metrics_set_text("a 1\na_bucket 2").is_ok());
+}
@@ -28,7 +28,7 @@ fn escape_string(s: &str) -> String {
     s.escape_default().collect()
 }
 
-fn format_name(s: &str, allow_colon: bool, keep_underscores: bool) -> String {
+pub(crate) fn format_name(s: &str, allow_colon: bool, keep_underscores: bool) -> String {
     s.char_indices()
         .filter_map(|(pos, c)| match c {
             c @ 'A'..='Z' => Some(c),
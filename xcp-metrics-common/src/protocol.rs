@@ -3,8 +3,19 @@
 //! Unix Domain Socket-based protocol based on CBOR.
 //! All CBOR payloads are prefixed with a 4-bytes big-endian length prefix.
 //!
+//! Payloads that can exceed [MAX_PAYLOAD_SIZE] (e.g [FetchMetricsStream] responses) are sent
+//! as a stream of raw chunks terminated by an empty chunk, up to [MAX_STREAM_SIZE].
+//!
+//! Plugins can [SubscribeTicks] to collect their metrics when xcp-metrics asks for it
+//! ([CollectionTick]), so that the samples of all the plugins line up. Once the metrics of a tick
+//! are pushed, the plugin replies with [CollectionDone].
+//!
 //! TODO: Protocol negociation
-use std::io::{self, Read, Write};
+use std::{
+    io::{self, Read, Write},
+    str::FromStr,
+    time::SystemTime,
+};
 
 use compact_str::CompactString;
 use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
@@ -14,6 +25,8 @@ use crate::metrics::{Metric, MetricType};
 
 pub const METRICS_SOCKET_PATH: &str = "/var/lib/xcp/xcp-metrics";
 pub const MAX_PAYLOAD_SIZE: u32 = 512 * 1024; // 512 Ko
+/// Maximum total size of a stream of chunks.
+pub const MAX_STREAM_SIZE: usize = 256 * 1024 * 1024; // 256 Mo
 
 /// Register a new metric family to the hub.
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -45,6 +58,14 @@ pub struct RemoveMetric {
     pub uuid: uuid::Uuid,
 }
 
+/// Prefix all family names used by this plugin with `{prefix}_`.
+///
+/// Must be sent before registering any family.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetNamespace {
+    pub prefix: CompactString,
+}
+
 /// Fetch metrics from xcp-metrics.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum FetchMetrics {
@@ -54,14 +75,116 @@ pub enum FetchMetrics {
     OpenMetrics1Binary,
 }
 
+/// Compression of a metrics stream.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "gzip" => Ok(Self::Gzip),
+            "zstd" => Ok(Self::Zstd),
+            _ => Err(format!(
+                "Unknown compression '{s}' (expected none, gzip or zstd)"
+            )),
+        }
+    }
+}
+
+#[cfg(feature = "compression")]
+impl Compression {
+    pub fn compress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(data.to_vec()),
+            Compression::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+            Compression::Zstd => zstd::encode_all(data, 0),
+        }
+    }
+
+    pub fn decompress(self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut buffer = vec![];
+
+        match self {
+            Compression::None => buffer.extend_from_slice(data),
+            Compression::Gzip => {
+                flate2::read::GzDecoder::new(data).read_to_end(&mut buffer)?;
+            }
+            Compression::Zstd => buffer = zstd::decode_all(data)?,
+        }
+
+        Ok(buffer)
+    }
+}
+
+/// Fetch metrics from xcp-metrics, without being limited by [MAX_PAYLOAD_SIZE].
+///
+/// The metrics are sent back as a stream of chunks (see [XcpMetricsStream::recv_message_stream]),
+/// compressed with `compression`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FetchMetricsStream {
+    pub format: FetchMetrics,
+    pub compression: Compression,
+}
+
+/// Receive a [CollectionTick] at each collection round.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubscribeTicks;
+
+/// Sent by xcp-metrics to the subscribed plugins: collect and push the metrics, then reply with
+/// [CollectionDone].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CollectionTick {
+    pub sequence: u64,
+    /// Time of the collection round, rates should be computed against it.
+    pub timestamp: SystemTime,
+}
+
+/// All the metrics of the `sequence` collection round have been pushed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CollectionDone {
+    pub sequence: u64,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ProtocolMessage {
     CreateFamily(CreateFamily),
     RemoveFamily(RemoveFamily),
     UpdateMetric(UpdateMetric),
     RemoveMetric(RemoveMetric),
+    SetNamespace(SetNamespace),
 
     FetchMetrics(FetchMetrics),
+    FetchMetricsStream(FetchMetricsStream),
+
+    SubscribeTicks(SubscribeTicks),
+    CollectionTick(CollectionTick),
+    CollectionDone(CollectionDone),
+}
+
+/// Append a stream chunk to `payload`, unless the stream gets larger than [MAX_STREAM_SIZE].
+fn append_chunk(payload: &mut Vec<u8>, chunk: &[u8]) -> io::Result<()> {
+    if payload.len() + chunk.len() > MAX_STREAM_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Stream is too large !",
+        ));
+    }
+
+    payload.extend_from_slice(chunk);
+    Ok(())
 }
 
 pub trait XcpMetricsStream {
@@ -83,6 +206,30 @@ pub trait XcpMetricsStream {
         ciborium::from_reader(buffer.as_ref())
             .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
     }
+
+    /// Send a payload of any size as chunks, followed by a empty chunk (end of stream).
+    fn send_message_stream(&mut self, payload: &[u8]) -> io::Result<()> {
+        for chunk in payload.chunks(MAX_PAYLOAD_SIZE as usize) {
+            self.send_message_raw(chunk)?;
+        }
+
+        self.send_message_raw(&[])
+    }
+
+    /// Receive chunks until the end of stream (up to [MAX_STREAM_SIZE]), and concatenate them.
+    fn recv_message_stream(&mut self) -> io::Result<Vec<u8>> {
+        let mut payload = vec![];
+
+        loop {
+            let chunk = self.recv_message_raw()?;
+
+            if chunk.is_empty() {
+                return Ok(payload);
+            }
+
+            append_chunk(&mut payload, &chunk)?;
+        }
+    }
 }
 
 impl<S> XcpMetricsStream for S
@@ -136,6 +283,30 @@ pub trait XcpMetricsAsyncStream {
         ciborium::from_reader(buffer.as_ref())
             .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
     }
+
+    /// Send a payload of any size as chunks, followed by a empty chunk (end of stream).
+    async fn send_message_stream_async(&mut self, payload: &[u8]) -> io::Result<()> {
+        for chunk in payload.chunks(MAX_PAYLOAD_SIZE as usize) {
+            self.send_message_raw_async(chunk).await?;
+        }
+
+        self.send_message_raw_async(&[]).await
+    }
+
+    /// Receive chunks until the end of stream (up to [MAX_STREAM_SIZE]), and concatenate them.
+    async fn recv_message_stream_async(&mut self) -> io::Result<Vec<u8>> {
+        let mut payload = vec![];
+
+        loop {
+            let chunk = self.recv_message_raw_async().await?;
+
+            if chunk.is_empty() {
+                return Ok(payload);
+            }
+
+            append_chunk(&mut payload, &chunk)?;
+        }
+    }
 }
 
 impl<S> XcpMetricsAsyncStream for S
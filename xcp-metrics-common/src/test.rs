@@ -1,9 +1,16 @@
 //! Protocol v3 tests
 
-use std::iter;
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    iter,
+};
 
+#[cfg(feature = "compression")]
+use crate::protocol::Compression;
 use crate::{
     metrics::{Label, Metric, MetricFamily, MetricSet, MetricType, MetricValue, NumberValue},
+    protocol::{XcpMetricsStream, MAX_PAYLOAD_SIZE},
     utils::delta::MetricSetModel,
 };
 
@@ -112,3 +119,229 @@ fn assert_metrics_set_equals(a: &MetricSet, b: &MetricSet) {
             }
         })
 }
+
+/// Check that payloads larger than [MAX_PAYLOAD_SIZE] can be sent as a stream.
+#[test]
+fn protocol_message_stream() {
+    let payload: Vec<u8> = (0..3 * MAX_PAYLOAD_SIZE + 42).map(|i| i as u8).collect();
+    let mut stream = VecDeque::new();
+
+    stream.send_message_stream(&payload).unwrap();
+    // 4 chunks and end of stream.
+    assert_eq!(stream.len(), payload.len() + 5 * 4);
+
+    assert_eq!(stream.recv_message_stream().unwrap(), payload);
+    assert!(stream.is_empty());
+}
+
+/// A stream of full chunks that never ends.
+#[derive(Default)]
+struct EndlessStream {
+    /// Position in the current chunk (including its length prefix).
+    position: usize,
+}
+
+impl Read for EndlessStream {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let prefix = MAX_PAYLOAD_SIZE.to_be_bytes();
+        let chunk_len = prefix.len() + MAX_PAYLOAD_SIZE as usize;
+
+        let len = if self.position < prefix.len() {
+            let len = buffer.len().min(prefix.len() - self.position);
+            buffer[..len].copy_from_slice(&prefix[self.position..self.position + len]);
+            len
+        } else {
+            let len = buffer.len().min(chunk_len - self.position);
+            buffer[..len].fill(0);
+            len
+        };
+
+        self.position = (self.position + len) % chunk_len;
+        Ok(len)
+    }
+}
+
+impl Write for EndlessStream {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        Ok(buffer.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Check that a stream can't grow beyond [crate::protocol::MAX_STREAM_SIZE].
+#[test]
+fn protocol_message_stream_limit() {
+    let error = EndlessStream::default().recv_message_stream().unwrap_err();
+
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+}
+
+#[cfg(feature = "compression")]
+#[test]
+fn protocol_compression() {
+    let payload = b"xen_cpu_time{cpu_id=\"0\"} 0.5".repeat(100);
+
+    for compression in [Compression::None, Compression::Gzip, Compression::Zstd] {
+        let compressed = compression.compress(&payload).unwrap();
+        assert_eq!(compression.decompress(&compressed).unwrap(), payload);
+    }
+}
+
+#[cfg(feature = "relabel")]
+#[test]
+fn relabel_metrics() {
+    use crate::utils::relabel::{relabel_metrics, RelabelConfig};
+
+    let label = |name: &str, value: &str| Label {
+        name: name.into(),
+        value: value.into(),
+    };
+    let family = |labels: Vec<Vec<Label>>| MetricFamily {
+        reference_count: 1,
+        metric_type: MetricType::Gauge,
+        metrics: labels
+            .into_iter()
+            .map(|labels| {
+                (
+                    uuid::Uuid::new_v4(),
+                    Metric {
+                        labels: labels.into(),
+                        value: MetricValue::Gauge(NumberValue::Int64(1)),
+                    },
+                )
+            })
+            .collect(),
+        ..Default::default()
+    };
+
+    let mut metrics = MetricSet::default();
+    metrics.families.insert(
+        "xen_domain_memory".into(),
+        family(vec![vec![label("domain", "1234"), label("owner", "vm")]]),
+    );
+    metrics.families.insert(
+        "xen_cpu_time".into(),
+        family(vec![vec![label("cpu_id", "0"), label("host_os", "xcp")]]),
+    );
+    metrics.families.insert(
+        "internal_stats".into(),
+        family(vec![vec![label("kind", "debug")], vec![]]),
+    );
+
+    let configs: Vec<RelabelConfig> = serde_json::from_str(
+        r#"[
+            { "source_labels": ["__name__"], "regex": "internal_.*", "action": "drop" },
+            { "source_labels": ["domain", "owner"], "regex": "(.+);vm", "target_label": "vm_uuid" },
+            { "regex": "domain|cpu_id", "action": "labeldrop" },
+            { "regex": "host_(.*)", "replacement": "node_$1", "action": "labelmap" }
+        ]"#,
+    )
+    .unwrap();
+
+    relabel_metrics(&mut metrics, &configs);
+
+    assert!(!metrics.families.contains_key("internal_stats"));
+
+    let labels = |name: &str| {
+        metrics.families[name]
+            .metrics
+            .values()
+            .next()
+            .unwrap()
+            .labels
+            .clone()
+    };
+    assert_eq!(
+        *labels("xen_domain_memory"),
+        [label("owner", "vm"), label("vm_uuid", "1234")]
+    );
+    assert_eq!(
+        *labels("xen_cpu_time"),
+        [label("host_os", "xcp"), label("node_os", "xcp")]
+    );
+
+    let keep: Vec<RelabelConfig> = serde_json::from_str(
+        r#"[{ "source_labels": ["owner"], "regex": "vm", "action": "keep" }]"#,
+    )
+    .unwrap();
+    relabel_metrics(&mut metrics, &keep);
+    assert_eq!(metrics.families.len(), 1);
+
+    assert!(serde_json::from_str::<RelabelConfig>(r#"{ "target_label": "__name__" }"#).is_err());
+    assert!(serde_json::from_str::<RelabelConfig>(r#"{ "regex": "(" }"#).is_err());
+}
+
+#[test]
+fn line_protocols() {
+    use std::time::{Duration, SystemTime};
+
+    use crate::utils::{graphite, influx};
+
+    let label = |name: &str, value: &str| Label {
+        name: name.into(),
+        value: value.into(),
+    };
+    let mut metrics = MetricSet::default();
+    metrics.families.insert(
+        "xen_domain_memory".into(),
+        MetricFamily {
+            reference_count: 1,
+            metric_type: MetricType::Gauge,
+            unit: "bytes".into(),
+            metrics: [(
+                uuid::Uuid::new_v4(),
+                Metric {
+                    labels: [label("domain", "vm 1"), label("owner", "")].into(),
+                    value: MetricValue::Gauge(NumberValue::Int64(1024)),
+                },
+            )]
+            .into(),
+            ..Default::default()
+        },
+    );
+    let timestamp = SystemTime::UNIX_EPOCH + Duration::from_secs(1700000000);
+
+    assert_eq!(
+        influx::metrics_to_lines(&metrics, timestamp),
+        ["xen_domain_memory_bytes,domain=vm\\ 1 value=1024i 1700000000000000000"]
+    );
+
+    // Info labels are tags, the ones of the series take precedence.
+    let mut info = MetricSet::default();
+    info.families.insert(
+        "xen_host".into(),
+        MetricFamily {
+            reference_count: 1,
+            metric_type: MetricType::Info,
+            metrics: [(
+                uuid::Uuid::new_v4(),
+                Metric {
+                    labels: [label("host", "a")].into(),
+                    value: MetricValue::Info([label("host", "b"), label("os", "xcp")].into()),
+                },
+            )]
+            .into(),
+            ..Default::default()
+        },
+    );
+    assert_eq!(
+        influx::metrics_to_lines(&info, timestamp),
+        ["xen_host,host=a,os=xcp info=1i 1700000000000000000"]
+    );
+
+    let template: graphite::GraphiteTemplate = "xcp.{owner}.{name}.{labels}".parse().unwrap();
+    assert_eq!(
+        graphite::metrics_to_lines(&metrics, &template, timestamp),
+        ["xcp.xen_domain_memory_bytes.vm_1 1024 1700000000"]
+    );
+    assert_eq!(
+        graphite::metrics_to_lines(&metrics, &Default::default(), timestamp),
+        ["xen_domain_memory_bytes.vm_1 1024 1700000000"]
+    );
+
+    assert!("{name".parse::<graphite::GraphiteTemplate>().is_err());
+    assert!("a.{}".parse::<graphite::GraphiteTemplate>().is_err());
+}
@@ -0,0 +1,119 @@
+//! Flatten metrics into single-value samples, following the OpenMetrics text conventions
+//! (e.g `_total` suffix for counters, `_bucket`/`_count`/`_sum` samples for histograms).
+//!
+//! Used by exporters to formats that only support plain samples (e.g Prometheus remote-write
+//! or Graphite).
+use compact_str::CompactString;
+
+use crate::metrics::{MetricFamily, MetricValue, NumberValue};
+
+/// A single value of a metric.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlatSample {
+    /// Suffix of the family name (e.g `_total`).
+    pub suffix: &'static str,
+    /// Labels to add to the ones of the metric (e.g `le` of histogram buckets).
+    pub extra_labels: Vec<(CompactString, CompactString)>,
+    pub value: f64,
+}
+
+impl FlatSample {
+    fn new(suffix: &'static str, value: f64) -> Self {
+        Self {
+            suffix,
+            extra_labels: vec![],
+            value,
+        }
+    }
+
+    fn with_label(mut self, name: &str, value: &str) -> Self {
+        self.extra_labels.push((name.into(), value.into()));
+        self
+    }
+}
+
+pub fn number_to_f64(value: NumberValue) -> f64 {
+    match value {
+        NumberValue::Double(value) => value,
+        NumberValue::Int64(value) => value as f64,
+        NumberValue::Undefined => f64::NAN,
+    }
+}
+
+/// Format a bucket bound (`le` label).
+pub fn format_bound(bound: f64) -> String {
+    if bound == f64::INFINITY {
+        "+Inf".to_string()
+    } else {
+        bound.to_string()
+    }
+}
+
+/// Name of a family, with its unit suffix.
+pub fn family_name(name: &str, family: &MetricFamily) -> String {
+    if family.unit.is_empty() {
+        name.to_string()
+    } else {
+        format!("{name}_{}", family.unit)
+    }
+}
+
+/// Flatten the value of a metric of the `name` family.
+pub fn flatten(name: &str, value: &MetricValue) -> Vec<FlatSample> {
+    match value {
+        MetricValue::Unknown(value) | MetricValue::Gauge(value) => {
+            vec![FlatSample::new("", number_to_f64(*value))]
+        }
+        MetricValue::Counter { total, .. } => {
+            vec![FlatSample::new("_total", number_to_f64(*total))]
+        }
+        MetricValue::Histogram {
+            sum,
+            count,
+            buckets,
+            ..
+        } => buckets
+            .iter()
+            .map(|bucket| {
+                FlatSample::new("_bucket", bucket.count as f64)
+                    .with_label("le", &format_bound(bucket.upper_bound))
+            })
+            .chain([
+                FlatSample::new("_count", *count as f64),
+                FlatSample::new("_sum", number_to_f64(*sum)),
+            ])
+            .collect(),
+        MetricValue::StateSet(states) => states
+            .iter()
+            .map(|state| {
+                FlatSample::new("", f64::from(u8::from(state.enabled)))
+                    .with_label(name, &state.name)
+            })
+            .collect(),
+        MetricValue::Info(labels) => {
+            let mut sample = FlatSample::new("_info", 1.0);
+            sample.extra_labels = labels
+                .iter()
+                .map(|label| (label.name.clone(), label.value.clone()))
+                .collect();
+
+            vec![sample]
+        }
+        MetricValue::Summary {
+            sum,
+            count,
+            quantile,
+            ..
+        } => quantile
+            .iter()
+            .map(|quantile| {
+                FlatSample::new("", quantile.value)
+                    .with_label("quantile", &quantile.quantile.to_string())
+            })
+            .chain([
+                FlatSample::new("_count", *count as f64),
+                FlatSample::new("_sum", number_to_f64(*sum)),
+            ])
+            .collect(),
+    }
+}
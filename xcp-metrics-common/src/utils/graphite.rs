@@ -0,0 +1,158 @@
+//! Graphite plaintext protocol serialization (`<path> <value> <timestamp>`).
+//!
+//! Metrics are flattened (see [crate::utils::flatten]), and their path is made out of a
+//! [GraphiteTemplate].
+use std::{str::FromStr, time::SystemTime};
+
+use compact_str::CompactString;
+
+use crate::{
+    metrics::MetricSet,
+    utils::flatten::{family_name, flatten},
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum TemplatePart {
+    Literal(String),
+    /// Family name (with suffix).
+    Name,
+    /// Value of a label.
+    Label(CompactString),
+    /// Values of all the labels not used by [TemplatePart::Label].
+    Labels,
+}
+
+/// Template of metric paths, e.g `xcp.{hostname}.{name}.{labels}`.
+///
+/// `{name}` is replaced by the metric name, `{labels}` by the values of the labels that aren't
+/// explicitly used, and `{<label>}` by the value of this label.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GraphiteTemplate(Vec<TemplatePart>);
+
+impl Default for GraphiteTemplate {
+    fn default() -> Self {
+        "{name}.{labels}".parse().unwrap()
+    }
+}
+
+impl FromStr for GraphiteTemplate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = vec![];
+        let mut rest = s;
+
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(TemplatePart::Literal(rest[..start].to_string()));
+            }
+
+            let Some(end) = rest[start..].find('}') else {
+                return Err(format!("Unclosed '{{' in template '{s}'"));
+            };
+
+            parts.push(match &rest[start + 1..start + end] {
+                "" => return Err(format!("Empty placeholder in template '{s}'")),
+                "name" => TemplatePart::Name,
+                "labels" => TemplatePart::Labels,
+                label => TemplatePart::Label(label.into()),
+            });
+
+            rest = &rest[start + end + 1..];
+        }
+
+        if !rest.is_empty() {
+            parts.push(TemplatePart::Literal(rest.to_string()));
+        }
+
+        Ok(Self(parts))
+    }
+}
+
+/// Make a value usable as a path node.
+fn sanitize(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            'A'..='Z' | 'a'..='z' | '0'..='9' | '_' | '-' | ':' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+impl GraphiteTemplate {
+    /// Make the path of a metric.
+    pub fn format(&self, name: &str, labels: &[(&str, &str)]) -> String {
+        let mut path = String::new();
+
+        for part in &self.0 {
+            match part {
+                TemplatePart::Literal(literal) => path.push_str(literal),
+                TemplatePart::Name => path.push_str(&sanitize(name)),
+                TemplatePart::Label(label) => {
+                    if let Some((_, value)) = labels.iter().find(|(name, _)| name == label) {
+                        path.push_str(&sanitize(value));
+                    }
+                }
+                TemplatePart::Labels => {
+                    let values: Vec<String> = labels
+                        .iter()
+                        .filter(|(name, _)| !self.0.contains(&TemplatePart::Label((*name).into())))
+                        .map(|(_, value)| sanitize(value))
+                        .collect();
+
+                    path.push_str(&values.join("."));
+                }
+            }
+        }
+
+        // Remove the empty nodes left by missing labels.
+        path.split('.')
+            .filter(|node| !node.is_empty())
+            .collect::<Vec<_>>()
+            .join(".")
+    }
+}
+
+/// Serialize `metrics` into plaintext lines, sampled at `timestamp`.
+pub fn metrics_to_lines(
+    metrics: &MetricSet,
+    template: &GraphiteTemplate,
+    timestamp: SystemTime,
+) -> Vec<String> {
+    let timestamp = timestamp
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let mut lines = vec![];
+
+    for (name, family) in &metrics.families {
+        let name = family_name(name, family);
+
+        for metric in family.metrics.values() {
+            for sample in flatten(&name, &metric.value) {
+                // Graphite has no representation for NaN and infinities.
+                if !sample.value.is_finite() {
+                    continue;
+                }
+
+                let labels: Vec<(&str, &str)> = metric
+                    .labels
+                    .iter()
+                    .map(|label| (label.name.as_str(), label.value.as_str()))
+                    .chain(
+                        sample
+                            .extra_labels
+                            .iter()
+                            .map(|(name, value)| (name.as_str(), value.as_str())),
+                    )
+                    .collect();
+
+                let path = template.format(&format!("{name}{}", sample.suffix), &labels);
+                lines.push(format!("{path} {} {timestamp}", sample.value));
+            }
+        }
+    }
+
+    lines
+}
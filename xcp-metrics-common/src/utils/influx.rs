@@ -0,0 +1,174 @@
+//! InfluxDB line protocol serialization.
+//!
+//! Each metric is a point of the measurement named after its family, with its labels as tags:
+//! - gauges and counters have a `value` field,
+//! - histograms and summaries have `count` and `sum` fields, and a field per bucket bound
+//!   (or quantile),
+//! - state sets have a boolean field per state,
+//! - info metrics have an `info` field, their labels being added to the tags.
+use std::{fmt::Write, time::SystemTime};
+
+use crate::{
+    metrics::{Label, MetricSet, MetricValue, NumberValue},
+    utils::flatten::{family_name, format_bound},
+};
+
+fn escape(s: &str, special: &[char]) -> String {
+    let mut escaped = String::with_capacity(s.len());
+
+    for c in s.chars() {
+        if special.contains(&c) || c == '\\' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+/// Escape a tag key, tag value or field key.
+fn escape_key(s: &str) -> String {
+    escape(s, &[',', '=', ' '])
+}
+
+enum FieldValue {
+    Float(f64),
+    Integer(i64),
+    Boolean(bool),
+}
+
+impl From<NumberValue> for Option<FieldValue> {
+    fn from(value: NumberValue) -> Self {
+        match value {
+            NumberValue::Double(value) => Some(FieldValue::Float(value)),
+            NumberValue::Int64(value) => Some(FieldValue::Integer(value)),
+            NumberValue::Undefined => None,
+        }
+    }
+}
+
+fn write_field(line: &mut String, name: &str, value: FieldValue) {
+    let separator = if line.ends_with(' ') { "" } else { "," };
+
+    // Line protocol has no representation for NaN and infinities.
+    match value {
+        FieldValue::Float(value) if !value.is_finite() => (),
+        FieldValue::Float(value) => {
+            write!(line, "{separator}{}={value}", escape_key(name)).unwrap()
+        }
+        FieldValue::Integer(value) => {
+            write!(line, "{separator}{}={value}i", escape_key(name)).unwrap()
+        }
+        FieldValue::Boolean(value) => {
+            write!(line, "{separator}{}={value}", escape_key(name)).unwrap()
+        }
+    }
+}
+
+fn write_tags<'a>(line: &mut String, labels: impl Iterator<Item = &'a Label>) {
+    for Label { name, value } in labels {
+        // Empty tag values are not allowed.
+        if !value.is_empty() {
+            write!(line, ",{}={}", escape_key(name), escape_key(value)).unwrap();
+        }
+    }
+}
+
+/// Serialize `metrics` into line protocol (one line per metric), sampled at `timestamp`.
+pub fn metrics_to_lines(metrics: &MetricSet, timestamp: SystemTime) -> Vec<String> {
+    let timestamp = timestamp
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let mut lines = vec![];
+
+    for (name, family) in &metrics.families {
+        let measurement = escape(&family_name(name, family), &[',', ' ']);
+
+        for metric in family.metrics.values() {
+            let mut line = measurement.clone();
+
+            match &metric.value {
+                MetricValue::Info(info) => {
+                    // Labels of the series take precedence.
+                    let info = info
+                        .iter()
+                        .filter(|label| metric.labels.iter().all(|other| other.name != label.name));
+
+                    write_tags(&mut line, metric.labels.iter().chain(info))
+                }
+                _ => write_tags(&mut line, metric.labels.iter()),
+            }
+
+            line.push(' ');
+            let fields_start = line.len();
+
+            match &metric.value {
+                MetricValue::Unknown(value) | MetricValue::Gauge(value) => {
+                    if let Some(value) = Option::<FieldValue>::from(*value) {
+                        write_field(&mut line, "value", value);
+                    }
+                }
+                MetricValue::Counter { total, .. } => {
+                    if let Some(value) = Option::<FieldValue>::from(*total) {
+                        write_field(&mut line, "value", value);
+                    }
+                }
+                MetricValue::Histogram {
+                    sum,
+                    count,
+                    buckets,
+                    ..
+                } => {
+                    write_field(&mut line, "count", FieldValue::Integer(*count as i64));
+
+                    if let Some(sum) = Option::<FieldValue>::from(*sum) {
+                        write_field(&mut line, "sum", sum);
+                    }
+
+                    for bucket in buckets.iter() {
+                        write_field(
+                            &mut line,
+                            &format_bound(bucket.upper_bound),
+                            FieldValue::Integer(bucket.count as i64),
+                        );
+                    }
+                }
+                MetricValue::Summary {
+                    sum,
+                    count,
+                    quantile,
+                    ..
+                } => {
+                    write_field(&mut line, "count", FieldValue::Integer(*count as i64));
+
+                    if let Some(sum) = Option::<FieldValue>::from(*sum) {
+                        write_field(&mut line, "sum", sum);
+                    }
+
+                    for quantile in quantile.iter() {
+                        write_field(
+                            &mut line,
+                            &quantile.quantile.to_string(),
+                            FieldValue::Float(quantile.value),
+                        );
+                    }
+                }
+                MetricValue::StateSet(states) => {
+                    for state in states.iter() {
+                        write_field(&mut line, &state.name, FieldValue::Boolean(state.enabled));
+                    }
+                }
+                MetricValue::Info(_) => write_field(&mut line, "info", FieldValue::Integer(1)),
+            }
+
+            // A point needs at least a field.
+            if line.len() > fields_start {
+                write!(line, " {timestamp}").unwrap();
+                lines.push(line);
+            }
+        }
+    }
+
+    lines
+}
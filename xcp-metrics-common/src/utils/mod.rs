@@ -1,7 +1,12 @@
 //! Various xcp-metrics utilities.
 
 pub mod delta;
+pub mod flatten;
+pub mod graphite;
+pub mod influx;
 #[cfg(feature = "rrdd_compat")]
 pub mod mapping;
+#[cfg(feature = "relabel")]
+pub mod relabel;
 
 pub(crate) mod write_bridge;
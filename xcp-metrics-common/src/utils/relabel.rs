@@ -0,0 +1,211 @@
+//! Prometheus-style relabeling of a [MetricSet].
+//!
+//! Each [RelabelConfig] is applied in order to the labels of every series, the family name
+//! being available (read-only) as the `__name__` source label.
+use std::fmt;
+
+use compact_str::CompactString;
+use regex::Regex;
+use serde::{de, Deserialize, Deserializer};
+
+use crate::metrics::{Label, MetricSet};
+
+/// Pseudo-label holding the family name.
+pub const NAME_LABEL: &str = "__name__";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RelabelAction {
+    /// Set `target_label` to `replacement` if `regex` matches the source labels.
+    #[default]
+    Replace,
+    /// Only keep series whose source labels match `regex`.
+    Keep,
+    /// Drop series whose source labels match `regex`.
+    Drop,
+    /// Copy labels whose name match `regex` to the label named `replacement`.
+    Labelmap,
+    /// Remove labels whose name match `regex`.
+    Labeldrop,
+    /// Remove labels whose name doesn't match `regex`.
+    Labelkeep,
+}
+
+/// A regex matching whole strings.
+#[derive(Clone)]
+pub struct RelabelRegex(Regex);
+
+impl RelabelRegex {
+    pub fn new(regex: &str) -> Result<Self, regex::Error> {
+        Regex::new(&format!("^(?:{regex})$")).map(Self)
+    }
+}
+
+impl fmt::Debug for RelabelRegex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("RelabelRegex")
+            .field(&self.0.as_str())
+            .finish()
+    }
+}
+
+impl Default for RelabelRegex {
+    fn default() -> Self {
+        Self::new("(.*)").unwrap()
+    }
+}
+
+impl<'de> Deserialize<'de> for RelabelRegex {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let regex = String::deserialize(deserializer)?;
+
+        Self::new(&regex).map_err(de::Error::custom)
+    }
+}
+
+fn default_separator() -> CompactString {
+    ";".into()
+}
+
+fn default_replacement() -> CompactString {
+    "$1".into()
+}
+
+fn deserialize_target_label<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<CompactString>, D::Error> {
+    match Option::<CompactString>::deserialize(deserializer)? {
+        Some(target) if target == NAME_LABEL => Err(de::Error::custom(format!(
+            "{NAME_LABEL} can't be a target label"
+        ))),
+        target => Ok(target),
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RelabelConfig {
+    #[serde(default)]
+    pub source_labels: Vec<CompactString>,
+    #[serde(default = "default_separator")]
+    pub separator: CompactString,
+    #[serde(default)]
+    pub regex: RelabelRegex,
+    #[serde(default, deserialize_with = "deserialize_target_label")]
+    pub target_label: Option<CompactString>,
+    #[serde(default = "default_replacement")]
+    pub replacement: CompactString,
+    #[serde(default)]
+    pub action: RelabelAction,
+}
+
+impl RelabelConfig {
+    /// Apply this config to `labels`, returns false if the series must be dropped.
+    pub fn apply(&self, family_name: &str, labels: &mut Vec<Label>) -> bool {
+        let source = || {
+            self.source_labels
+                .iter()
+                .map(|name| {
+                    if name == NAME_LABEL {
+                        return family_name;
+                    }
+
+                    labels
+                        .iter()
+                        .find(|label| label.name == *name)
+                        .map_or("", |label| label.value.as_str())
+                })
+                .collect::<Vec<_>>()
+                .join(&self.separator)
+        };
+
+        match self.action {
+            RelabelAction::Keep => self.regex.0.is_match(&source()),
+            RelabelAction::Drop => !self.regex.0.is_match(&source()),
+            RelabelAction::Replace => {
+                let Some(target) = &self.target_label else {
+                    return true;
+                };
+
+                let source = source();
+                let Some(captures) = self.regex.0.captures(&source) else {
+                    return true;
+                };
+
+                let mut value = String::new();
+                captures.expand(&self.replacement, &mut value);
+
+                labels.retain(|label| label.name != *target);
+
+                if !value.is_empty() {
+                    labels.push(Label {
+                        name: target.clone(),
+                        value: value.into(),
+                    });
+                }
+
+                true
+            }
+            RelabelAction::Labelmap => {
+                let mapped: Vec<Label> = labels
+                    .iter()
+                    .filter_map(|label| {
+                        let captures = self.regex.0.captures(&label.name)?;
+
+                        let mut name = String::new();
+                        captures.expand(&self.replacement, &mut name);
+
+                        Some(Label {
+                            name: name.into(),
+                            value: label.value.clone(),
+                        })
+                    })
+                    .collect();
+
+                for label in mapped {
+                    labels.retain(|other| other.name != label.name);
+                    labels.push(label);
+                }
+
+                true
+            }
+            RelabelAction::Labeldrop => {
+                labels.retain(|label| !self.regex.0.is_match(&label.name));
+                true
+            }
+            RelabelAction::Labelkeep => {
+                labels.retain(|label| self.regex.0.is_match(&label.name));
+                true
+            }
+        }
+    }
+}
+
+/// Apply `configs` to all the series of `metrics`.
+///
+/// Families whose series are all dropped are removed. Note that removing labels can make
+/// several series of a family collide, aggregate them beforehand (e.g using recording rules).
+pub fn relabel_metrics(metrics: &mut MetricSet, configs: &[RelabelConfig]) {
+    if configs.is_empty() {
+        return;
+    }
+
+    metrics.families.retain(|name, family| {
+        let was_empty = family.metrics.is_empty();
+
+        family.metrics.retain(|_, metric| {
+            let mut labels = metric.labels.to_vec();
+
+            if !configs.iter().all(|config| config.apply(name, &mut labels)) {
+                return false;
+            }
+
+            if *metric.labels != *labels {
+                metric.labels = labels.into();
+            }
+
+            true
+        });
+
+        was_empty || !family.metrics.is_empty()
+    });
+}
@@ -1,11 +1,13 @@
 use std::{
-    io::{stdout, Write},
+    io::{self, stdout, Write},
     os::unix::net::UnixStream,
-    path::PathBuf,
+    path::{Path, PathBuf},
 };
 
 use argh::FromArgs;
-use xcp_metrics_common::protocol::{self, FetchMetrics, XcpMetricsStream};
+use xcp_metrics_common::protocol::{
+    self, Compression, FetchMetrics, FetchMetricsStream, ProtocolMessage, XcpMetricsStream,
+};
 
 /// Tool to get metrics from xcp-metrics in OpenMetrics format.
 #[derive(FromArgs, Debug)]
@@ -17,6 +19,35 @@ struct Args {
     /// whether to use protocol buffers binary format.
     #[argh(switch, short = 'b')]
     binary: bool,
+
+    /// compression used to transfer metrics (none, gzip or zstd).
+    #[argh(option, short = 'c', default = "Compression::None")]
+    compression: Compression,
+}
+
+/// Fetch the metrics as a stream of chunks, decompressing them.
+fn fetch_metrics_stream(
+    daemon_path: &Path,
+    format: FetchMetrics,
+    compression: Compression,
+) -> io::Result<Vec<u8>> {
+    let mut client = UnixStream::connect(daemon_path)?;
+
+    client.send_message(ProtocolMessage::FetchMetricsStream(FetchMetricsStream {
+        format,
+        compression,
+    }))?;
+
+    compression.decompress(&client.recv_message_stream()?)
+}
+
+/// Fetch the metrics as a single message (limited by [protocol::MAX_PAYLOAD_SIZE]).
+fn fetch_metrics(daemon_path: &Path, format: FetchMetrics) -> io::Result<Vec<u8>> {
+    let mut client = UnixStream::connect(daemon_path)?;
+
+    client.send_message(ProtocolMessage::FetchMetrics(format))?;
+
+    Ok(client.recv_message_raw()?.into_vec())
 }
 
 fn main() {
@@ -25,19 +56,26 @@ fn main() {
         .daemon_path
         .unwrap_or(protocol::METRICS_SOCKET_PATH.into());
 
-    let mut client = UnixStream::connect(daemon_path).expect("Unable to connect to daemon");
-
-    client
-        .send_message(protocol::ProtocolMessage::FetchMetrics(if args.binary {
-            FetchMetrics::OpenMetrics1Binary
-        } else {
-            FetchMetrics::OpenMetrics1
-        }))
-        .unwrap();
+    let format = if args.binary {
+        FetchMetrics::OpenMetrics1Binary
+    } else {
+        FetchMetrics::OpenMetrics1
+    };
 
-    let data = client
-        .recv_message_raw()
-        .expect("Unable to receive daemon response");
+    let data = match fetch_metrics_stream(&daemon_path, format.clone(), args.compression) {
+        Ok(data) => data,
+        // Daemons without streaming close the connection on unknown messages.
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset
+            ) =>
+        {
+            eprintln!("Daemon rejected FetchMetricsStream ({e}), falling back to FetchMetrics");
+            fetch_metrics(&daemon_path, format).expect("Unable to receive daemon response")
+        }
+        Err(e) => panic!("Unable to receive daemon response: {e}"),
+    };
 
     stdout().write_all(&data).expect("Can't write output");
 }
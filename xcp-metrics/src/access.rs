@@ -0,0 +1,150 @@
+//! Access control of RPC sessions using Unix peer credentials (`SO_PEERCRED`).
+//!
+//! Each connecting process is given a [Role] depending on its uid, gid or executable,
+//! according to the [AccessPolicy] rules. Peers that doesn't match any rule are rejected.
+//!
+//! Only the uid and gid are reliable, the executable is advisory (see [PeerCredentials::exe]).
+use std::{fmt, fs, os::fd::AsFd, path::PathBuf, str::FromStr};
+
+use nix::{
+    sys::socket::{getsockopt, sockopt},
+    unistd::{getuid, Gid, Pid, Uid},
+};
+
+/// What a peer is allowed to do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    /// Only fetch metrics.
+    Read,
+    /// Fetch and push metrics (plugins).
+    ReadWrite,
+}
+
+/// Credentials of a connected process.
+#[derive(Debug, Clone)]
+pub struct PeerCredentials {
+    pub pid: Pid,
+    pub uid: Uid,
+    pub gid: Gid,
+    /// Executable of the process (if it can be read).
+    ///
+    /// It is read from `/proc/<pid>/exe` after the connection is accepted, by then the peer may
+    /// have executed another program or exited (and its PID be reused), it must not be relied
+    /// on as a security boundary.
+    pub exe: Option<PathBuf>,
+}
+
+impl PeerCredentials {
+    /// Get the credentials of the process connected to a Unix socket.
+    pub fn from_socket(socket: &impl AsFd) -> anyhow::Result<Self> {
+        let credentials = getsockopt(socket, sockopt::PeerCredentials)?;
+        let pid = Pid::from_raw(credentials.pid());
+
+        Ok(Self {
+            pid,
+            uid: Uid::from_raw(credentials.uid()),
+            gid: Gid::from_raw(credentials.gid()),
+            exe: fs::read_link(format!("/proc/{pid}/exe")).ok(),
+        })
+    }
+}
+
+impl fmt::Display for PeerCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "pid={} uid={} gid={}", self.pid, self.uid, self.gid)?;
+
+        if let Some(exe) = &self.exe {
+            write!(f, " exe={}", exe.display())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Matches a set of peers.
+///
+/// Parsed from `uid:<uid>`, `gid:<gid>`, `exe:<path>` or `any`, `exe:` is advisory (see
+/// [PeerCredentials::exe]).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerMatcher {
+    Any,
+    Uid(Uid),
+    Gid(Gid),
+    Exe(PathBuf),
+}
+
+impl PeerMatcher {
+    pub fn matches(&self, peer: &PeerCredentials) -> bool {
+        match self {
+            PeerMatcher::Any => true,
+            PeerMatcher::Uid(uid) => peer.uid == *uid,
+            PeerMatcher::Gid(gid) => peer.gid == *gid,
+            PeerMatcher::Exe(exe) => peer.exe.as_ref() == Some(exe),
+        }
+    }
+}
+
+impl FromStr for PeerMatcher {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "any" {
+            return Ok(Self::Any);
+        }
+
+        match s.split_once(':') {
+            Some(("uid", uid)) => uid
+                .parse()
+                .map(|uid| Self::Uid(Uid::from_raw(uid)))
+                .map_err(|e| format!("Invalid uid '{uid}': {e}")),
+            Some(("gid", gid)) => gid
+                .parse()
+                .map(|gid| Self::Gid(Gid::from_raw(gid)))
+                .map_err(|e| format!("Invalid gid '{gid}': {e}")),
+            Some(("exe", exe)) => Ok(Self::Exe(exe.into())),
+            _ => Err(format!(
+                "Invalid peer rule '{s}' (expected uid:<uid>, gid:<gid>, exe:<path> or any)"
+            )),
+        }
+    }
+}
+
+/// Rules deciding the [Role] of each peer.
+#[derive(Debug, Clone, Default)]
+pub struct AccessPolicy {
+    pub rules: Vec<(PeerMatcher, Role)>,
+}
+
+impl AccessPolicy {
+    /// Make a policy out of read-write and read-only rules.
+    ///
+    /// If there is no read-write rule, root and the user running the daemon are allowed to
+    /// push metrics.
+    pub fn new(read_write: Vec<PeerMatcher>, read_only: Vec<PeerMatcher>) -> Self {
+        let read_write = if read_write.is_empty() {
+            vec![
+                PeerMatcher::Uid(Uid::from_raw(0)),
+                PeerMatcher::Uid(getuid()),
+            ]
+        } else {
+            read_write
+        };
+
+        Self {
+            rules: read_write
+                .into_iter()
+                .map(|matcher| (matcher, Role::ReadWrite))
+                .chain(read_only.into_iter().map(|matcher| (matcher, Role::Read)))
+                .collect(),
+        }
+    }
+
+    /// Get the role of a peer (the highest of all matching rules), [None] if it isn't allowed.
+    pub fn authorize(&self, peer: &PeerCredentials) -> Option<Role> {
+        self.rules
+            .iter()
+            .filter(|(matcher, _)| matcher.matches(peer))
+            .map(|&(_, role)| role)
+            .max()
+    }
+}
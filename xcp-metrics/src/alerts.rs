@@ -0,0 +1,317 @@
+//! Threshold alerts, evaluated by the hub along with the recording rules.
+//!
+//! Alerts are loaded from a JSON file (`--alerts`), e.g
+//! ```json
+//! [
+//!   {
+//!     "alert": "xen_guest_memory_low",
+//!     "expr": { "binary": {
+//!       "op": "div",
+//!       "left": { "family": "xen_memory_usage_free" },
+//!       "right": { "family": "xen_memory_usage_total" },
+//!       "on": ["domain"]
+//!     } },
+//!     "condition": { "below": 0.05 },
+//!     "for": 300
+//!   },
+//!   {
+//!     "alert": "xen_plugin_stale",
+//!     "expr": { "family": "xen_cpu_time" },
+//!     "condition": "absent"
+//!   }
+//! ]
+//! ```
+//!
+//! Each series matching the condition is `pending`, then `firing` once it has matched for `for`
+//! seconds, and `resolved` (for one evaluation) when it no longer matches. Alerts are published as
+//! [MetricType::StateSet] families, and state changes are sent as [Notification]s to a webhook
+//! or written in a spool directory.
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use compact_str::{format_compact, CompactString};
+use flume::{Receiver, Sender};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use xcp_metrics_common::metrics::{
+    Metric, MetricFamily, MetricSet, MetricType, MetricValue, State,
+};
+
+use crate::{
+    http::{self, Url},
+    rules::{Expr, SeriesLabels, Value},
+};
+
+/// When an alert series is active.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Condition {
+    Above(f64),
+    Below(f64),
+    /// The expression has no series (e.g the plugin providing a family is gone).
+    Absent,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertStatus {
+    Pending,
+    Firing,
+    Resolved,
+}
+
+impl AlertStatus {
+    const ALL: [AlertStatus; 3] = [Self::Pending, Self::Firing, Self::Resolved];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AlertStatus::Pending => "pending",
+            AlertStatus::Firing => "firing",
+            AlertStatus::Resolved => "resolved",
+        }
+    }
+}
+
+/// A state change of an alert, sent to the webhook or spool directory.
+#[derive(Debug, Clone, Serialize)]
+pub struct Notification {
+    pub alert: CompactString,
+    pub status: AlertStatus,
+    pub labels: BTreeMap<CompactString, CompactString>,
+    pub value: f64,
+    /// UNIX timestamp (in seconds).
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Clone)]
+struct AlertState {
+    status: AlertStatus,
+    /// Start of the current status.
+    since: Instant,
+    value: f64,
+    uuid: Uuid,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AlertRule {
+    pub alert: CompactString,
+    #[serde(default)]
+    pub help: CompactString,
+    pub expr: Expr,
+    pub condition: Condition,
+    /// How long (in seconds) the condition must hold before firing.
+    #[serde(rename = "for", default)]
+    pub pending_for: u64,
+
+    #[serde(skip)]
+    states: HashMap<SeriesLabels, AlertState>,
+}
+
+impl AlertRule {
+    /// Series that match the condition, with their value.
+    fn active_series(&mut self, metrics: &MetricSet, now: Instant) -> HashMap<SeriesLabels, f64> {
+        let series = match self.expr.evaluate(metrics, now) {
+            Value::Scalar(value) => vec![(SeriesLabels::default(), value)],
+            Value::Vector(series) => series,
+        };
+
+        match self.condition {
+            Condition::Above(threshold) => series
+                .into_iter()
+                .filter(|&(_, value)| value > threshold)
+                .collect(),
+            Condition::Below(threshold) => series
+                .into_iter()
+                .filter(|&(_, value)| value < threshold)
+                .collect(),
+            Condition::Absent if series.is_empty() => {
+                HashMap::from([(SeriesLabels::default(), 0.0)])
+            }
+            Condition::Absent => HashMap::new(),
+        }
+    }
+
+    /// Update the alert states, sending state changes to `notifications`, and make the
+    /// alert family.
+    pub fn evaluate(
+        &mut self,
+        metrics: &MetricSet,
+        now: Instant,
+        notifications: Option<&Sender<Notification>>,
+    ) -> MetricFamily {
+        let active = self.active_series(metrics, now);
+        let pending_for = Duration::from_secs(self.pending_for);
+        let mut changes = vec![];
+
+        for (labels, &value) in &active {
+            let state = self
+                .states
+                .entry(labels.clone())
+                .or_insert_with(|| AlertState {
+                    status: AlertStatus::Resolved,
+                    since: now,
+                    value,
+                    uuid: Uuid::new_v4(),
+                });
+
+            state.value = value;
+
+            if state.status == AlertStatus::Resolved {
+                state.status = AlertStatus::Pending;
+                state.since = now;
+            }
+
+            if state.status == AlertStatus::Pending
+                && now.duration_since(state.since) >= pending_for
+            {
+                state.status = AlertStatus::Firing;
+                state.since = now;
+                changes.push((labels.clone(), state.clone()));
+            }
+        }
+
+        self.states.retain(|labels, state| {
+            if active.contains_key(labels) {
+                return true;
+            }
+
+            match state.status {
+                // Never fired, just forget it.
+                AlertStatus::Pending => false,
+                AlertStatus::Firing => {
+                    state.status = AlertStatus::Resolved;
+                    state.since = now;
+                    changes.push((labels.clone(), state.clone()));
+                    true
+                }
+                // Already published (and notified) as resolved.
+                AlertStatus::Resolved => false,
+            }
+        });
+
+        if let Some(notifications) = notifications {
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+
+            for (labels, state) in changes {
+                tracing::info!(
+                    "Alert {} is {} ({labels:?})",
+                    self.alert,
+                    state.status.as_str()
+                );
+
+                let sent = notifications.try_send(Notification {
+                    alert: self.alert.clone(),
+                    status: state.status,
+                    labels: labels
+                        .iter()
+                        .map(|label| (label.name.clone(), label.value.clone()))
+                        .collect(),
+                    value: state.value,
+                    timestamp,
+                });
+
+                if sent.is_err() {
+                    tracing::warn!("Notification queue is full, dropping notification");
+                }
+            }
+        }
+
+        MetricFamily {
+            reference_count: 1,
+            metric_type: MetricType::StateSet,
+            unit: "".into(),
+            help: self.help.clone(),
+            metrics: self
+                .states
+                .iter()
+                .map(|(labels, state)| {
+                    (
+                        state.uuid,
+                        Metric {
+                            labels: labels.clone(),
+                            value: MetricValue::StateSet(
+                                AlertStatus::ALL
+                                    .into_iter()
+                                    .map(|status| State {
+                                        enabled: status == state.status,
+                                        name: status.as_str().into(),
+                                    })
+                                    .collect(),
+                            ),
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Alert rules and where to send their notifications.
+#[derive(Debug, Clone, Default)]
+pub struct Alerts {
+    pub rules: Vec<AlertRule>,
+    pub notifications: Option<Sender<Notification>>,
+}
+
+/// Load alert rules from a JSON file.
+pub fn load_alerts(path: &Path) -> anyhow::Result<Vec<AlertRule>> {
+    Ok(serde_json::from_reader(fs::File::open(path)?)?)
+}
+
+/// Write a notification in the spool directory (atomically, through a hidden temporary file).
+fn spool_notification(
+    spool: &Path,
+    notification: &Notification,
+    payload: &[u8],
+) -> anyhow::Result<()> {
+    let name = format_compact!(
+        "{}-{}-{}.json",
+        notification.timestamp,
+        notification.alert,
+        Uuid::new_v4().simple()
+    );
+    let temporary = spool.join(format!(".{name}.tmp"));
+
+    fs::write(&temporary, payload)?;
+    fs::rename(&temporary, spool.join(name.as_str()))?;
+
+    Ok(())
+}
+
+/// Deliver the notifications to the webhook and/or spool directory.
+pub async fn run_notifier(
+    receiver: Receiver<Notification>,
+    webhook: Option<Url>,
+    spool: Option<PathBuf>,
+) {
+    while let Ok(notification) = receiver.recv_async().await {
+        let payload = match serde_json::to_vec(&notification) {
+            Ok(payload) => payload,
+            Err(e) => {
+                tracing::error!("Unable to serialize notification: {e}");
+                continue;
+            }
+        };
+
+        if let Some(webhook) = &webhook {
+            if let Err(e) =
+                http::post(webhook, &[("Content-Type", "application/json")], &payload).await
+            {
+                tracing::warn!("Unable to send notification to webhook: {e}");
+            }
+        }
+
+        if let Some(spool) = &spool {
+            if let Err(e) = spool_notification(spool, &notification, &payload) {
+                tracing::warn!("Unable to spool notification: {e}");
+            }
+        }
+    }
+}
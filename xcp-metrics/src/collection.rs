@@ -0,0 +1,225 @@
+//! Synchronized collection rounds (like `Plugin.Local.next_reading` of xcp-rrdd).
+//!
+//! Plugins that subscribed to ticks collect their metrics when they receive a [CollectionTick],
+//! then reply with `CollectionDone`. Ticks are sent at each interval, aligned on the wall clock
+//! (so that the samples of all the plugins line up in the RRD steps), and in on-demand mode, each
+//! fetch triggers a round and waits for it to be done (or for its deadline) before answering.
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant, SystemTime},
+};
+
+use flume::{Receiver, Sender, TrySendError};
+use futures::{future, select, FutureExt};
+use smol::Timer;
+use xcp_metrics_common::protocol::CollectionTick;
+
+use crate::hub::OwnerId;
+
+/// Interval between two ticks (the RRD step of xcp-rrdd).
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long a fetch waits for the plugins to collect their metrics in on-demand mode.
+pub const DEFAULT_DEADLINE: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy)]
+pub struct CollectionConfig {
+    pub interval: Duration,
+    /// Trigger a collection round on each fetch.
+    pub on_demand: bool,
+    /// How long a round waits for the plugins.
+    pub deadline: Duration,
+}
+
+impl Default for CollectionConfig {
+    fn default() -> Self {
+        Self {
+            interval: DEFAULT_INTERVAL,
+            on_demand: false,
+            deadline: DEFAULT_DEADLINE,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum CollectionMessage {
+    /// Send the ticks to this owner.
+    Subscribe(OwnerId, Sender<CollectionTick>),
+    Unsubscribe(OwnerId),
+    /// The owner pushed the metrics of a round.
+    Done(OwnerId, u64),
+    /// Metrics are about to be fetched, answer once they are collected (immediately if not in
+    /// on-demand mode).
+    Fetch(Sender<()>),
+}
+
+#[derive(Debug)]
+struct Round {
+    sequence: u64,
+    /// Subscribers that haven't pushed their metrics yet.
+    pending: HashSet<OwnerId>,
+    /// Fetches waiting for this round.
+    waiters: Vec<Sender<()>>,
+    deadline: Instant,
+}
+
+/// Time until the next multiple of `interval` (since the epoch), and its timestamp.
+pub fn next_tick(now: SystemTime, interval: Duration) -> (Duration, SystemTime) {
+    let since_epoch = now
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    let interval_ns = interval.as_nanos().max(1);
+    let next = Duration::from_nanos(
+        ((since_epoch.as_nanos() / interval_ns + 1) * interval_ns)
+            .try_into()
+            .unwrap_or(u64::MAX),
+    );
+
+    (next - since_epoch, SystemTime::UNIX_EPOCH + next)
+}
+
+#[derive(Debug, Default)]
+struct Collector {
+    subscribers: HashMap<OwnerId, Sender<CollectionTick>>,
+    sequence: u64,
+    round: Option<Round>,
+    /// Timestamp of the last periodic tick.
+    last_tick: Option<SystemTime>,
+}
+
+impl Collector {
+    fn finish_round(&mut self) {
+        if let Some(round) = self.round.take() {
+            if !round.pending.is_empty() {
+                tracing::warn!(
+                    "{} plugins didn't collect their metrics before the deadline of round {}",
+                    round.pending.len(),
+                    round.sequence
+                );
+            }
+
+            for waiter in round.waiters {
+                waiter.send(()).ok();
+            }
+        }
+    }
+
+    fn start_round(&mut self, timestamp: SystemTime, deadline: Duration) {
+        self.finish_round();
+        self.sequence += 1;
+
+        let tick = CollectionTick {
+            sequence: self.sequence,
+            timestamp,
+        };
+
+        // Subscribers that are gone are forgotten, the ones still busy skip this tick (and aren't
+        // waited for).
+        let mut pending = HashSet::new();
+        self.subscribers
+            .retain(|&owner, sender| match sender.try_send(tick.clone()) {
+                Ok(()) => {
+                    pending.insert(owner);
+                    true
+                }
+                Err(TrySendError::Full(_)) => true,
+                Err(TrySendError::Disconnected(_)) => false,
+            });
+
+        self.round = Some(Round {
+            sequence: self.sequence,
+            pending,
+            waiters: vec![],
+            deadline: Instant::now() + deadline,
+        });
+    }
+
+    fn process(&mut self, message: CollectionMessage, config: &CollectionConfig) {
+        match message {
+            CollectionMessage::Subscribe(owner, sender) => {
+                self.subscribers.insert(owner, sender);
+            }
+            CollectionMessage::Unsubscribe(owner) => {
+                self.subscribers.remove(&owner);
+
+                if let Some(round) = &mut self.round {
+                    round.pending.remove(&owner);
+                }
+            }
+            CollectionMessage::Done(owner, sequence) => match &mut self.round {
+                Some(round) if round.sequence == sequence => {
+                    round.pending.remove(&owner);
+                }
+                _ => tracing::debug!("{owner:?} finished round {sequence} late"),
+            },
+            CollectionMessage::Fetch(waiter) => {
+                if !config.on_demand {
+                    waiter.send(()).ok();
+                    return;
+                }
+
+                // Fetches during a round wait for it.
+                if self.round.is_none() {
+                    self.start_round(SystemTime::now(), config.deadline);
+                }
+
+                if let Some(round) = &mut self.round {
+                    round.waiters.push(waiter);
+                }
+            }
+        }
+
+        if self
+            .round
+            .as_ref()
+            .is_some_and(|round| round.pending.is_empty())
+        {
+            self.finish_round();
+        }
+    }
+}
+
+/// Run the collection rounds, until all the senders of `receiver` are dropped.
+pub async fn run(config: CollectionConfig, receiver: Receiver<CollectionMessage>) {
+    let mut collector = Collector::default();
+
+    loop {
+        // Don't tick twice the same step if the clocks disagree.
+        let now = SystemTime::now().max(collector.last_tick.unwrap_or(SystemTime::UNIX_EPOCH));
+        let (until_tick, tick_timestamp) = next_tick(now, config.interval);
+        let deadline = collector.round.as_ref().map(|round| round.deadline);
+
+        select! {
+            message = receiver.recv_async().fuse() => match message {
+                Ok(message) => collector.process(message, &config),
+                Err(_) => return,
+            },
+            _ = FutureExt::fuse(Timer::after(until_tick)) => {
+                collector.last_tick = Some(tick_timestamp);
+                collector.start_round(tick_timestamp, config.deadline.min(config.interval));
+            }
+            _ = async {
+                match deadline {
+                    Some(deadline) => {
+                        Timer::at(deadline).await;
+                    }
+                    None => future::pending().await,
+                }
+            }
+            .fuse() => collector.finish_round(),
+        }
+    }
+}
+
+/// Wait for the metrics to be collected before fetching them.
+pub async fn before_fetch(collection: &Sender<CollectionMessage>) {
+    let (sender, receiver) = flume::bounded(1);
+
+    if collection
+        .send_async(CollectionMessage::Fetch(sender))
+        .await
+        .is_ok()
+    {
+        receiver.recv_async().await.ok();
+    }
+}
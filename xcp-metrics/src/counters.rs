@@ -0,0 +1,160 @@
+//! Counter reset detection and created timestamps.
+//!
+//! The hub tracks each counter series (identified by its family and labels, so that a restarted
+//! plugin is recognized even if it uses new UUIDs). A counter that decreases (or whose `created`
+//! timestamp moves forward) has been reset: its `created` timestamp is updated, and the reset is
+//! counted in the [COUNTER_RESETS_FAMILY] family. Series without `created` get the time they
+//! were first seen (or reset).
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant, SystemTime},
+};
+
+use compact_str::CompactString;
+use uuid::Uuid;
+use xcp_metrics_common::metrics::{
+    Label, Metric, MetricFamily, MetricType, MetricValue, NumberValue,
+};
+
+/// Family counting the counter resets of each family.
+pub const COUNTER_RESETS_FAMILY: &str = "xcp_metrics_counter_resets";
+
+/// How long the state of a removed series is kept (to detect the reset of a restarted plugin).
+pub const REMOVED_RETENTION: Duration = Duration::from_secs(3600);
+
+#[derive(Debug, Clone)]
+struct CounterState {
+    total: f64,
+    created: SystemTime,
+    /// When the series has been removed.
+    removed: Option<Instant>,
+}
+
+type SeriesKey = (CompactString, Box<[Label]>);
+
+fn series_key(family_name: &CompactString, metric: &Metric) -> SeriesKey {
+    let mut labels = metric.labels.to_vec();
+    labels.sort_by(|a, b| a.name.cmp(&b.name));
+
+    (family_name.clone(), labels.into())
+}
+
+/// Minimum interval between two prunings of the removed series.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Default)]
+pub struct CounterTracker {
+    series: HashMap<SeriesKey, CounterState>,
+    /// UUID and number of resets of each family.
+    resets: HashMap<CompactString, (Uuid, u64)>,
+    last_prune: Option<Instant>,
+}
+
+impl CounterTracker {
+    /// Track a counter update, setting its `created` timestamp.
+    ///
+    /// Returns true if the counter has been reset.
+    pub fn track(&mut self, family_name: &CompactString, metric: &mut Metric) -> bool {
+        let MetricValue::Counter { total, created, .. } = metric.value else {
+            return false;
+        };
+
+        let total = match total {
+            NumberValue::Double(value) => value,
+            NumberValue::Int64(value) => value as f64,
+            NumberValue::Undefined => return false,
+        };
+
+        let key = series_key(family_name, metric);
+        let provided = created;
+        let mut reset = false;
+
+        let state = self
+            .series
+            .entry(key)
+            .and_modify(|state| {
+                reset = total < state.total || provided.is_some_and(|c| c > state.created);
+
+                if reset {
+                    state.created = provided.unwrap_or_else(SystemTime::now);
+                }
+
+                state.total = total;
+                state.removed = None;
+            })
+            .or_insert_with(|| CounterState {
+                total,
+                created: provided.unwrap_or_else(SystemTime::now),
+                removed: None,
+            });
+
+        let MetricValue::Counter { created, .. } = &mut metric.value else {
+            unreachable!()
+        };
+        *created = Some(state.created);
+
+        if reset {
+            tracing::info!("Counter '{family_name}' {:?} has been reset", metric.labels);
+            self.resets
+                .entry(family_name.clone())
+                .or_insert_with(|| (Uuid::new_v4(), 0))
+                .1 += 1;
+        }
+
+        reset
+    }
+
+    /// A series has been removed, keep its state for a while in case it comes back.
+    pub fn forget(&mut self, family_name: &CompactString, metric: &Metric) {
+        let now = Instant::now();
+
+        if let Some(state) = self.series.get_mut(&series_key(family_name, metric)) {
+            state.removed = Some(now);
+        }
+
+        // Pruning goes through all the series, don't do it for each removal (e.g when a plugin
+        // with many series stops).
+        if self
+            .last_prune
+            .is_none_or(|last_prune| now.duration_since(last_prune) >= PRUNE_INTERVAL)
+        {
+            self.series.retain(|_, state| {
+                state
+                    .removed
+                    .is_none_or(|removed| now.duration_since(removed) < REMOVED_RETENTION)
+            });
+            self.last_prune = Some(now);
+        }
+    }
+
+    /// Make the [COUNTER_RESETS_FAMILY] family.
+    pub fn resets_family(&self) -> MetricFamily {
+        MetricFamily {
+            reference_count: 1,
+            metric_type: MetricType::Counter,
+            unit: "".into(),
+            help: "Number of counter resets (e.g plugin restarts) of each family".into(),
+            metrics: self
+                .resets
+                .iter()
+                .map(|(family_name, &(uuid, count))| {
+                    (
+                        uuid,
+                        Metric {
+                            labels: [Label {
+                                name: "family".into(),
+                                value: family_name.clone(),
+                            }]
+                            .into(),
+                            value: MetricValue::Counter {
+                                total: NumberValue::Int64(count as i64),
+                                created: None,
+                                exemplar: None,
+                            },
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+}
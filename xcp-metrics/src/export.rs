@@ -0,0 +1,259 @@
+//! Transformations applied to the hub metrics before exporting them.
+//!
+//! Each exporter (e.g RPC) has its own [ExportPipeline]: it exports either the local metrics or
+//! the federated ones ([MetricsView]), the host identity is exposed first, then the relabel configs
+//! are applied.
+//!
+//! Exporters that periodically push metrics implement [PushExporter], and are run with
+//! [run_push_exporter].
+use std::{
+    fs,
+    future::Future,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::Path,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use compact_str::CompactString;
+use flume::Sender;
+use futures::AsyncWriteExt;
+use smol::{
+    net::{TcpStream, UdpSocket},
+    Timer,
+};
+use xcp_metrics_common::{
+    metrics::{Metric, MetricSet},
+    utils::relabel::{self, RelabelConfig},
+};
+
+use crate::{
+    federation,
+    host::{HostIdentity, HostLabelsMode},
+    http::{self, Url},
+    hub::{HubPullResponse, HubPushMessage, PullMetrics},
+};
+
+/// Which metrics an exporter exports.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MetricsView {
+    /// Metrics of this host.
+    #[default]
+    Local,
+    /// Metrics of this host and of the federated peers (see [crate::federation]).
+    Federated,
+}
+
+impl FromStr for MetricsView {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "local" => Ok(Self::Local),
+            "federated" => Ok(Self::Federated),
+            _ => Err(format!(
+                "Invalid metrics view '{s}' (expected local or federated)"
+            )),
+        }
+    }
+}
+
+/// Transformations applied to the hub metrics by an exporter.
+#[derive(Debug, Clone, Default)]
+pub struct ExportPipeline {
+    pub view: MetricsView,
+    pub host: Arc<HostIdentity>,
+    pub host_labels: HostLabelsMode,
+    pub relabel: Vec<RelabelConfig>,
+}
+
+impl ExportPipeline {
+    pub fn apply(&self, metrics: Arc<MetricSet>) -> Arc<MetricSet> {
+        if self.view == MetricsView::Local
+            && self.host_labels == HostLabelsMode::None
+            && self.relabel.is_empty()
+        {
+            return metrics;
+        }
+
+        let mut metrics = Arc::unwrap_or_clone(metrics);
+
+        // The peer series are already tagged with the identity of their host (and `instance`).
+        let is_local = |metric: &Metric| {
+            self.view == MetricsView::Local
+                || metric
+                    .labels
+                    .iter()
+                    .all(|label| label.name != federation::INSTANCE_LABEL)
+        };
+
+        if self.view == MetricsView::Federated {
+            // Tag the local series like the peer ones, to tell the hosts apart.
+            HostLabelsMode::Labels.apply_matching(&mut metrics, &self.host, is_local);
+        }
+
+        self.host_labels
+            .apply_matching(&mut metrics, &self.host, is_local);
+        relabel::relabel_metrics(&mut metrics, &self.relabel);
+
+        Arc::new(metrics)
+    }
+}
+
+/// Load relabel configs from a JSON file.
+pub fn load_relabel_configs(path: &Path) -> anyhow::Result<Vec<RelabelConfig>> {
+    Ok(serde_json::from_reader(fs::File::open(path)?)?)
+}
+
+/// Get the current hub metrics of `view`, [None] if the hub is stopped.
+pub async fn pull_metrics(
+    hub: &Sender<HubPushMessage>,
+    view: MetricsView,
+) -> Option<Arc<MetricSet>> {
+    let (sender, receiver) = flume::bounded(1);
+
+    hub.send_async(match view {
+        MetricsView::Local => HubPushMessage::PullMetrics(PullMetrics(sender)),
+        MetricsView::Federated => HubPushMessage::PullFederatedMetrics(PullMetrics(sender)),
+    })
+    .await
+    .ok()?;
+
+    let HubPullResponse::Metrics(metrics) = receiver.recv_async().await.ok()?;
+    Some(metrics)
+}
+
+/// An exporter that periodically pushes the metrics somewhere.
+pub trait PushExporter {
+    /// Push `metrics`, sampled at `timestamp`.
+    fn push(&mut self, metrics: &MetricSet, timestamp: SystemTime) -> impl Future<Output = ()>;
+}
+
+/// When and what a [PushExporter] pushes.
+#[derive(Debug, Clone)]
+pub struct PushSchedule {
+    pub interval: Duration,
+    pub export: ExportPipeline,
+}
+
+/// Push the hub metrics with `exporter` at each interval, until the hub stops.
+pub async fn run_push_exporter(
+    mut exporter: impl PushExporter,
+    schedule: PushSchedule,
+    hub: Sender<HubPushMessage>,
+) {
+    loop {
+        Timer::after(schedule.interval).await;
+
+        let Some(metrics) = pull_metrics(&hub, schedule.export.view).await else {
+            return;
+        };
+
+        let metrics = schedule.export.apply(metrics);
+        exporter.push(&metrics, SystemTime::now()).await;
+    }
+}
+
+/// Maximum size of a UDP datagram sent by [PushTarget::Udp] (to fit in the MTU).
+pub const MAX_DATAGRAM_SIZE: usize = 1400;
+
+/// Where text-based exporters send their lines.
+///
+/// Parsed from `tcp://host:port`, `udp://host:port` or `http://host[:port][/path]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PushTarget {
+    Tcp(CompactString),
+    Udp(CompactString),
+    Http(Url),
+}
+
+impl FromStr for PushTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(address) = s.strip_prefix("tcp://") {
+            Ok(Self::Tcp(address.into()))
+        } else if let Some(address) = s.strip_prefix("udp://") {
+            Ok(Self::Udp(address.into()))
+        } else if s.starts_with("http://") {
+            s.parse().map(Self::Http)
+        } else {
+            Err(format!(
+                "Invalid target '{s}' (expected tcp://host:port, udp://host:port or http://host[:port][/path])"
+            ))
+        }
+    }
+}
+
+impl PushTarget {
+    /// Send newline-terminated lines.
+    pub async fn send(&self, lines: &[String]) -> anyhow::Result<()> {
+        match self {
+            PushTarget::Tcp(address) => {
+                smol::future::or(
+                    async {
+                        let mut stream = TcpStream::connect(address.as_str()).await?;
+
+                        for line in lines {
+                            stream.write_all(line.as_bytes()).await?;
+                            stream.write_all(b"\n").await?;
+                        }
+
+                        stream.flush().await?;
+                        anyhow::Ok(())
+                    },
+                    async {
+                        Timer::after(http::REQUEST_TIMEOUT).await;
+                        anyhow::bail!("Sending to {address} timed out")
+                    },
+                )
+                .await?;
+            }
+            PushTarget::Udp(address) => {
+                let Some(target) = smol::net::resolve(address.as_str())
+                    .await?
+                    .into_iter()
+                    .next()
+                else {
+                    anyhow::bail!("Unable to resolve {address}");
+                };
+
+                // Bind to the address family of the target.
+                let local: SocketAddr = match target {
+                    SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+                    SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+                };
+                let socket = UdpSocket::bind(local).await?;
+                socket.connect(target).await?;
+
+                // Pack as many lines as possible in each datagram.
+                let mut datagram = String::new();
+
+                for line in lines {
+                    if !datagram.is_empty() && datagram.len() + line.len() + 1 > MAX_DATAGRAM_SIZE {
+                        socket.send(datagram.as_bytes()).await?;
+                        datagram.clear();
+                    }
+
+                    datagram += line;
+                    datagram.push('\n');
+                }
+
+                if !datagram.is_empty() {
+                    socket.send(datagram.as_bytes()).await?;
+                }
+            }
+            PushTarget::Http(url) => {
+                http::post(
+                    url,
+                    &[("Content-Type", "text/plain; charset=utf-8")],
+                    (lines.join("\n") + "\n").as_bytes(),
+                )
+                .await?;
+            }
+        }
+
+        Ok(())
+    }
+}
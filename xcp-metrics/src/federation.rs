@@ -0,0 +1,263 @@
+//! Federation of the metrics of other xcp-metrics daemons (e.g on a pool master).
+//!
+//! Peers are fetched at each interval through their TCP listener (`--tcp-listen`), using the
+//! xcp-metrics protocol. Each series of a peer is tagged with the peer identity (from its
+//! [HOST_INFO_FAMILY] family, which the TCP listener exposes by default) and an `instance` label,
+//! then the metrics are given to the hub, which merges them into the federated view
+//! ([crate::export::MetricsView::Federated]).
+//!
+//! If a peer can't be fetched (or doesn't answer before the timeout), its previous metrics are kept
+//! until they are stale. `federation_peer_up` and `federation_peer_last_success_timestamp_seconds`
+//! are registered (as local metrics) for each peer.
+use std::{
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+use compact_str::CompactString;
+use flume::Sender;
+use futures::future;
+use smol::{net::TcpStream, Timer};
+use uuid::Uuid;
+use xcp_metrics_common::{
+    metrics::{Label, Metric, MetricFamily, MetricSet, MetricType, MetricValue, NumberValue},
+    openmetrics::{self, prost::Message},
+    protocol::{
+        Compression, FetchMetrics, FetchMetricsStream, ProtocolMessage, XcpMetricsAsyncStream,
+    },
+};
+
+use crate::{host::HOST_INFO_FAMILY, hub::HubPushMessage, ingest::HubSync};
+
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(15);
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Number of intervals without successful fetch after which the metrics of a peer are dropped.
+pub const DEFAULT_STALE_INTERVALS: u32 = 3;
+
+/// Label of the peer address.
+pub const INSTANCE_LABEL: &str = "instance";
+
+#[derive(Debug, Clone)]
+pub struct FederationConfig {
+    /// Peer addresses (host:port).
+    pub peers: Vec<CompactString>,
+    pub interval: Duration,
+    pub timeout: Duration,
+    /// How long the metrics of an unreachable peer are kept.
+    pub stale_after: Duration,
+}
+
+/// Fetch the metrics of a peer.
+pub async fn fetch_peer(address: &str) -> anyhow::Result<MetricSet> {
+    let mut stream = TcpStream::connect(address).await?;
+    let compression = Compression::Zstd;
+
+    stream
+        .send_message_async(ProtocolMessage::FetchMetricsStream(FetchMetricsStream {
+            format: FetchMetrics::OpenMetrics1Binary,
+            compression,
+        }))
+        .await?;
+
+    let payload = compression.decompress(&stream.recv_message_stream_async().await?)?;
+
+    Ok(openmetrics::MetricSet::decode(payload.as_slice())?.into())
+}
+
+/// Tag the series of a peer with its identity and `instance`, the labels of the series taking
+/// precedence.
+///
+/// The identity comes from the peer [HOST_INFO_FAMILY] family, which is removed (the identity of
+/// each host being on its series).
+pub fn tag_peer_metrics(metrics: &mut MetricSet, instance: &str) {
+    let mut tags: Vec<Label> = metrics
+        .families
+        .remove(HOST_INFO_FAMILY)
+        .and_then(|family| {
+            family
+                .metrics
+                .into_values()
+                .find_map(|metric| match metric.value {
+                    MetricValue::Info(labels) => Some(labels.into_vec()),
+                    _ => None,
+                })
+        })
+        .unwrap_or_default();
+
+    tags.push(Label {
+        name: INSTANCE_LABEL.into(),
+        value: instance.into(),
+    });
+
+    for metric in metrics
+        .families
+        .values_mut()
+        .flat_map(|family| family.metrics.values_mut())
+    {
+        let extra: Vec<Label> = tags
+            .iter()
+            .filter(|label| metric.labels.iter().all(|other| other.name != label.name))
+            .cloned()
+            .collect();
+
+        if !extra.is_empty() {
+            metric.labels = metric.labels.iter().cloned().chain(extra).collect();
+        }
+    }
+}
+
+/// Merge the metrics of `peer` into `metrics`, skipping the families of conflicting types.
+pub fn merge_metrics(metrics: &mut MetricSet, peer_metrics: &MetricSet, peer: &str) {
+    for (name, family) in &peer_metrics.families {
+        match metrics.families.get_mut(name) {
+            Some(existing) if existing.metric_type != family.metric_type => {
+                tracing::debug!("Family '{name}' of {peer} has a conflicting type, ignoring it");
+            }
+            Some(existing) => existing.metrics.extend(
+                family
+                    .metrics
+                    .iter()
+                    .map(|(&uuid, metric)| (uuid, metric.clone())),
+            ),
+            None => {
+                metrics.families.insert(name.clone(), family.clone());
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct PeerState {
+    /// Whether the last fetch succeeded.
+    up: bool,
+    last_success: Option<(Instant, SystemTime)>,
+    /// Whether the hub has metrics of this peer.
+    published: bool,
+}
+
+fn status_family(
+    help: &str,
+    values: impl IntoIterator<Item = (CompactString, f64)>,
+) -> MetricFamily {
+    MetricFamily {
+        reference_count: 1,
+        metric_type: MetricType::Gauge,
+        help: help.into(),
+        metrics: values
+            .into_iter()
+            .map(|(instance, value)| {
+                (
+                    Uuid::new_v4(),
+                    Metric {
+                        labels: [Label {
+                            name: INSTANCE_LABEL.into(),
+                            value: instance,
+                        }]
+                        .into(),
+                        value: MetricValue::Gauge(NumberValue::Double(value)),
+                    },
+                )
+            })
+            .collect(),
+        ..Default::default()
+    }
+}
+
+/// Fetch the peers of `config` at each interval, and give their metrics to the hub.
+pub async fn run(config: FederationConfig, hub: Sender<HubPushMessage>) {
+    let mut states: Vec<PeerState> = config.peers.iter().map(|_| Default::default()).collect();
+    let mut sync = HubSync::new(hub.clone());
+
+    loop {
+        let results = future::join_all(config.peers.iter().map(|address| {
+            smol::future::or(async { fetch_peer(address).await }, async {
+                Timer::after(config.timeout).await;
+                Err(anyhow::anyhow!("timed out"))
+            })
+        }))
+        .await;
+
+        for ((address, state), result) in config.peers.iter().zip(&mut states).zip(results) {
+            state.up = result.is_ok();
+
+            let update = match result {
+                Ok(mut metrics) => {
+                    state.last_success = Some((Instant::now(), SystemTime::now()));
+                    tag_peer_metrics(&mut metrics, address);
+
+                    Some(Arc::new(metrics))
+                }
+                Err(e) => {
+                    tracing::warn!("Unable to fetch metrics of {address}: {e}");
+
+                    if state.published
+                        && state
+                            .last_success
+                            .is_some_and(|(instant, _)| instant.elapsed() >= config.stale_after)
+                    {
+                        tracing::warn!("Metrics of {address} are stale, dropping them");
+                        None
+                    } else {
+                        // Keep the previous metrics until they are stale.
+                        continue;
+                    }
+                }
+            };
+
+            state.published = update.is_some();
+
+            if hub
+                .send_async(HubPushMessage::UpdatePeer(address.clone(), update))
+                .await
+                .is_err()
+            {
+                return;
+            }
+        }
+
+        let status = MetricSet {
+            families: [
+                (
+                    "federation_peer_up".into(),
+                    status_family(
+                        "1 if the last fetch of the peer succeeded",
+                        config
+                            .peers
+                            .iter()
+                            .zip(&states)
+                            .map(|(address, state)| (address.clone(), state.up.into())),
+                    ),
+                ),
+                (
+                    "federation_peer_last_success_timestamp_seconds".into(),
+                    status_family(
+                        "Time of the last successful fetch of the peer",
+                        config
+                            .peers
+                            .iter()
+                            .zip(&states)
+                            .filter_map(|(address, state)| {
+                                let (_, time) = state.last_success?;
+
+                                Some((
+                                    address.clone(),
+                                    time.duration_since(SystemTime::UNIX_EPOCH)
+                                        .unwrap_or_default()
+                                        .as_secs_f64(),
+                                ))
+                            }),
+                    ),
+                ),
+            ]
+            .into(),
+        };
+
+        if let Err(e) = sync.sync(&status).await {
+            tracing::error!("Unable to push federation metrics: {e}");
+            return;
+        }
+
+        Timer::after(config.interval).await;
+    }
+}
@@ -0,0 +1,216 @@
+//! Host identity, to tell hosts apart once metrics leave them.
+//!
+//! The identity is read from `/etc/xensource-inventory` (`INSTALLATION_UUID`),
+//! `/etc/xensource/pool.conf` (pool role) and the hostname, along with the static labels
+//! given with `--host-label`.
+use std::{collections::HashMap, fs, str::FromStr};
+
+use compact_str::CompactString;
+use uuid::Uuid;
+use xcp_metrics_common::metrics::{
+    Label, Metric, MetricFamily, MetricSet, MetricType, MetricValue,
+};
+
+pub const INVENTORY_PATH: &str = "/etc/xensource-inventory";
+pub const POOL_CONF_PATH: &str = "/etc/xensource/pool.conf";
+
+/// Name of the [HostLabelsMode::Info] family.
+pub const HOST_INFO_FAMILY: &str = "xcp_host";
+
+/// A `name=value` label.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StaticLabel(pub Label);
+
+impl FromStr for StaticLabel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((name, value)) if !name.is_empty() => Ok(Self(Label {
+                name: name.into(),
+                value: value.into(),
+            })),
+            _ => Err(format!("Invalid label '{s}' (expected name=value)")),
+        }
+    }
+}
+
+/// Parse the `KEY='value'` lines of the XenServer inventory.
+pub fn parse_inventory(inventory: &str) -> HashMap<CompactString, CompactString> {
+    inventory
+        .lines()
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| {
+            (
+                key.trim().into(),
+                value.trim().trim_matches(|c| c == '\'' || c == '"').into(),
+            )
+        })
+        .collect()
+}
+
+/// Labels identifying the host.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct HostIdentity {
+    pub labels: Vec<Label>,
+}
+
+impl HostIdentity {
+    /// Make the host identity out of the inventory, pool configuration, hostname and static
+    /// labels (which take precedence).
+    pub fn new(
+        inventory: Option<&str>,
+        pool_conf: Option<&str>,
+        hostname: Option<&str>,
+        static_labels: Vec<Label>,
+    ) -> Self {
+        let mut labels: Vec<Label> = vec![];
+        let mut add = |name: &str, value: &str| {
+            if !value.is_empty() && labels.iter().all(|label| label.name != name) {
+                labels.push(Label {
+                    name: name.into(),
+                    value: value.into(),
+                });
+            }
+        };
+
+        for Label { name, value } in &static_labels {
+            add(name, value);
+        }
+
+        if let Some(uuid) = inventory
+            .map(parse_inventory)
+            .and_then(|inventory| inventory.get("INSTALLATION_UUID").cloned())
+        {
+            add("host_uuid", &uuid);
+        }
+
+        if let Some(hostname) = hostname {
+            add("hostname", hostname.trim());
+        }
+
+        // Either "master" or "slave:<master address>".
+        match pool_conf.map(str::trim) {
+            Some("master") => add("pool_role", "master"),
+            Some(conf) => {
+                if let Some(master) = conf.strip_prefix("slave:") {
+                    add("pool_role", "slave");
+                    add("pool_master", master);
+                }
+            }
+            None => (),
+        }
+
+        Self { labels }
+    }
+
+    /// Read the host identity of this host.
+    pub fn load(static_labels: Vec<Label>) -> Self {
+        let read = |path: &str| match fs::read_to_string(path) {
+            Ok(content) => Some(content),
+            Err(e) => {
+                tracing::debug!("Unable to read {path}: {e}");
+                None
+            }
+        };
+
+        let hostname = nix::unistd::gethostname()
+            .ok()
+            .and_then(|hostname| hostname.into_string().ok());
+
+        Self::new(
+            read(INVENTORY_PATH).as_deref(),
+            read(POOL_CONF_PATH).as_deref(),
+            hostname.as_deref(),
+            static_labels,
+        )
+    }
+}
+
+/// How an exporter exposes the host identity.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HostLabelsMode {
+    #[default]
+    None,
+    /// Attach the host labels to every series.
+    Labels,
+    /// Add a [HOST_INFO_FAMILY] Info family.
+    Info,
+}
+
+impl FromStr for HostLabelsMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "labels" => Ok(Self::Labels),
+            "info" => Ok(Self::Info),
+            _ => Err(format!(
+                "Invalid host labels mode '{s}' (expected none, labels or info)"
+            )),
+        }
+    }
+}
+
+impl HostLabelsMode {
+    /// Expose `host` in `metrics`.
+    pub fn apply(self, metrics: &mut MetricSet, host: &HostIdentity) {
+        self.apply_matching(metrics, host, |_| true)
+    }
+
+    /// Expose `host` in `metrics`, only labelling the series matching `filter`.
+    pub fn apply_matching(
+        self,
+        metrics: &mut MetricSet,
+        host: &HostIdentity,
+        filter: impl Fn(&Metric) -> bool,
+    ) {
+        match self {
+            HostLabelsMode::None => (),
+            HostLabelsMode::Labels => {
+                for metric in metrics
+                    .families
+                    .values_mut()
+                    .flat_map(|family| family.metrics.values_mut())
+                    .filter(|metric| filter(metric))
+                {
+                    let extra: Vec<Label> = host
+                        .labels
+                        .iter()
+                        // Labels of the series take precedence.
+                        .filter(|label| metric.labels.iter().all(|other| other.name != label.name))
+                        .cloned()
+                        .collect();
+
+                    if !extra.is_empty() {
+                        metric.labels = metric.labels.iter().cloned().chain(extra).collect();
+                    }
+                }
+            }
+            HostLabelsMode::Info => {
+                if metrics.families.contains_key(HOST_INFO_FAMILY) {
+                    tracing::warn!("'{HOST_INFO_FAMILY}' family already exists");
+                    return;
+                }
+
+                metrics.families.insert(
+                    HOST_INFO_FAMILY.into(),
+                    MetricFamily {
+                        reference_count: 1,
+                        metric_type: MetricType::Info,
+                        unit: "".into(),
+                        help: "Host identity".into(),
+                        metrics: HashMap::from([(
+                            Uuid::nil(),
+                            Metric {
+                                labels: Box::default(),
+                                value: MetricValue::Info(host.labels.clone().into()),
+                            },
+                        )]),
+                    },
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,132 @@
+//! Minimal HTTP/1.1 client, used to push data to remote endpoints (e.g alert webhooks).
+//!
+//! Only plain `http://` URLs are supported, use a local proxy to reach TLS endpoints.
+use std::{fmt, str::FromStr, time::Duration};
+
+use compact_str::CompactString;
+use futures::{AsyncReadExt, AsyncWriteExt};
+use smol::{net::TcpStream, Timer};
+
+/// Maximum duration of a request.
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A `http://host[:port][/path]` URL.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Url {
+    pub host: CompactString,
+    pub port: u16,
+    pub path: CompactString,
+}
+
+impl FromStr for Url {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some(rest) = s.strip_prefix("http://") else {
+            return Err(format!(
+                "Unsupported URL '{s}' (expected http://host[:port][/path])"
+            ));
+        };
+
+        let (authority, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, "/"),
+        };
+
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => (
+                host,
+                port.parse()
+                    .map_err(|e| format!("Invalid port '{port}': {e}"))?,
+            ),
+            None => (authority, 80),
+        };
+
+        if host.is_empty() {
+            return Err(format!("Missing host in URL '{s}'"));
+        }
+
+        Ok(Self {
+            host: host.into(),
+            port,
+            path: path.into(),
+        })
+    }
+}
+
+impl fmt::Display for Url {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "http://{}:{}{}", self.host, self.port, self.path)
+    }
+}
+
+/// A non-2xx HTTP status.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StatusError(pub u16);
+
+impl StatusError {
+    /// Whether the request may succeed later (server errors and rate limiting).
+    pub fn is_retryable(self) -> bool {
+        self.0 >= 500 || self.0 == 429
+    }
+}
+
+impl fmt::Display for StatusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "HTTP status {}", self.0)
+    }
+}
+
+impl std::error::Error for StatusError {}
+
+async fn do_post(url: &Url, headers: &[(&str, &str)], body: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut stream = TcpStream::connect((url.host.as_str(), url.port)).await?;
+
+    let mut request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\nConnection: close\r\n",
+        url.path,
+        url.host,
+        body.len()
+    );
+
+    for (name, value) in headers {
+        request += &format!("{name}: {value}\r\n");
+    }
+    request += "\r\n";
+
+    stream.write_all(request.as_bytes()).await?;
+    stream.write_all(body).await?;
+    stream.flush().await?;
+
+    let mut response = vec![];
+    stream.read_to_end(&mut response).await?;
+
+    // HTTP/1.1 <status> <reason>
+    let status: u16 = response
+        .split(|&b| b == b' ')
+        .nth(1)
+        .and_then(|status| std::str::from_utf8(status).ok())
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| anyhow::anyhow!("Invalid HTTP response from {url}"))?;
+
+    if !(200..300).contains(&status) {
+        return Err(StatusError(status).into());
+    }
+
+    let body_start = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .map_or(response.len(), |i| i + 4);
+
+    Ok(response.split_off(body_start))
+}
+
+/// Send a POST request, returning the response body (fails with [StatusError] if the status
+/// isn't 2xx).
+pub async fn post(url: &Url, headers: &[(&str, &str)], body: &[u8]) -> anyhow::Result<Vec<u8>> {
+    smol::future::or(do_post(url, headers, body), async {
+        Timer::after(REQUEST_TIMEOUT).await;
+        anyhow::bail!("Request to {url} timed out")
+    })
+    .await
+}
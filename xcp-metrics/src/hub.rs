@@ -7,15 +7,75 @@ a [`oneshot::Sender<HubPullResponse>`].
 
 All metrics are uniquely identified using a [uuid::Uuid] to ease updating, this identifier
 must be generated by the provider (using [uuid::Uuid::new_v4]).
+
+# Ownership
+
+Each message that alters metrics is tagged with the [OwnerId] of its sender (e.g a RPC session).
+The hub keeps track of which owner registered each family and created each metric, and rejects
+updates or removals of metrics that belong to another owner, so that two providers sharing a family
+can't overwrite each other's metrics.
+
+# Recording rules
+
+On [HubPushMessage::EvaluateRules], the hub evaluates its [RecordingRule]s then its
+[crate::alerts::AlertRule]s, and replaces the recorded families with the results, owned by
+[OwnerId::RULES]. A family registered by a provider is no longer recorded, its recorded series
+being dropped.
+
+# Counter resets
+
+Counter updates go through a [CounterTracker], which sets their `created` timestamp and detects
+resets (e.g a restarted plugin), counted in the [COUNTER_RESETS_FAMILY] family (owned by
+[OwnerId::HUB]).
+
+# Federation
+
+The metrics of the federated peers (see [crate::federation]) are kept apart from the local ones,
+and are only merged into them on [HubPushMessage::PullFederatedMetrics].
 */
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
 
+use compact_str::CompactString;
 use flume::{Receiver, Sender};
+use uuid::Uuid;
 use xcp_metrics_common::{
     metrics::{MetricFamily, MetricSet},
     protocol::{CreateFamily, RemoveFamily, RemoveMetric, UpdateMetric},
 };
 
+use crate::{
+    alerts::Alerts,
+    counters::{CounterTracker, COUNTER_RESETS_FAMILY},
+    federation,
+    rules::RecordingRule,
+    systemd,
+};
+
+/// Identifier of a metrics provider (e.g a RPC session), used to track metrics ownership.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct OwnerId(u64);
+
+impl OwnerId {
+    /// Owner of the families recorded by the [RecordingRule]s and alerts.
+    pub const RULES: OwnerId = OwnerId(u64::MAX);
+    /// Owner of the families maintained by the hub itself.
+    pub const HUB: OwnerId = OwnerId(u64::MAX - 1);
+
+    /// Allocate a new unique [OwnerId].
+    pub fn allocate() -> Self {
+        static NEXT_OWNER_ID: AtomicU64 = AtomicU64::new(0);
+
+        Self(NEXT_OWNER_ID.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
 /// Fetch metrics, receiving them in a provided [`oneshot::Sender<HubPullResponse>`].
 #[derive(Debug)]
 pub struct PullMetrics(pub Sender<HubPullResponse>);
@@ -24,13 +84,23 @@ pub struct PullMetrics(pub Sender<HubPullResponse>);
 #[derive(Debug)]
 pub enum HubPushMessage {
     // xcp-metrics protocol messages
-    CreateFamily(CreateFamily),
-    RemoveFamily(RemoveFamily),
-    UpdateMetric(UpdateMetric),
-    RemoveMetric(RemoveMetric),
+    CreateFamily(OwnerId, CreateFamily),
+    RemoveFamily(OwnerId, RemoveFamily),
+    UpdateMetric(OwnerId, UpdateMetric),
+    RemoveMetric(OwnerId, RemoveMetric),
 
     // Hub-specific messages
     PullMetrics(PullMetrics),
+    /// Fetch the local metrics merged with the ones of the federated peers.
+    PullFederatedMetrics(PullMetrics),
+    /// Replace the metrics of a federated peer, or forget them ([None]).
+    UpdatePeer(CompactString, Option<Arc<MetricSet>>),
+    /// Remove all families references and metrics of a owner (e.g the plugin died).
+    ReleaseOwner(OwnerId),
+    /// Notify systemd watchdog that the hub is alive.
+    Watchdog,
+    /// Evaluate the recording rules and alerts.
+    EvaluateRules,
 }
 
 /// A hub response.
@@ -43,17 +113,90 @@ pub enum HubPullResponse {
 #[derive(Debug, Clone, Default)]
 pub struct MetricsHub {
     metrics: Arc<MetricSet>,
+
+    /// Owners that hold a reference on each family.
+    family_owners: HashMap<CompactString, HashSet<OwnerId>>,
+    /// Owner of each metric (identified by family name and UUID).
+    metric_owners: HashMap<(CompactString, Uuid), OwnerId>,
+
+    /// State of the counter series, to detect resets.
+    counters: CounterTracker,
+
+    /// Metrics of the federated peers (already tagged with their identity).
+    peers: BTreeMap<CompactString, Arc<MetricSet>>,
+
+    rules: Vec<RecordingRule>,
+    alerts: Alerts,
+}
+
+/// Replace the `name` family with the result of `evaluate`, unless a provider uses this name.
+fn record_family(
+    family_owners: &mut HashMap<CompactString, HashSet<OwnerId>>,
+    metrics: &mut Arc<MetricSet>,
+    name: &CompactString,
+    evaluate: impl FnOnce(&MetricSet) -> MetricFamily,
+) {
+    if family_owners
+        .get(name)
+        .is_some_and(|owners| owners.iter().any(|&owner| owner != OwnerId::RULES))
+    {
+        tracing::debug!("Not recording '{name}' as it is used by a provider");
+        return;
+    }
+
+    let family = evaluate(metrics);
+
+    family_owners.insert(name.clone(), HashSet::from([OwnerId::RULES]));
+    Arc::make_mut(metrics).families.insert(name.clone(), family);
 }
 
 impl MetricsHub {
+    pub fn new(rules: Vec<RecordingRule>, alerts: Alerts) -> Self {
+        Self {
+            rules,
+            alerts,
+            ..Default::default()
+        }
+    }
+
     pub async fn run(mut self, receiver: Receiver<HubPushMessage>) {
+        if let Err(e) = systemd::notify("READY=1") {
+            tracing::warn!("Unable to notify systemd: {e}");
+        }
+
         while let Ok(msg) = receiver.recv_async().await {
             match msg {
-                HubPushMessage::CreateFamily(message) => self.create_family(message).await,
-                HubPushMessage::RemoveFamily(message) => self.remove_family(message).await,
-                HubPushMessage::UpdateMetric(message) => self.update_metric(message).await,
-                HubPushMessage::RemoveMetric(message) => self.remove_metric(message).await,
+                HubPushMessage::CreateFamily(owner, message) => {
+                    self.create_family(owner, message).await
+                }
+                HubPushMessage::RemoveFamily(owner, message) => {
+                    self.remove_family(owner, message).await
+                }
+                HubPushMessage::UpdateMetric(owner, message) => {
+                    self.update_metric(owner, message).await
+                }
+                HubPushMessage::RemoveMetric(owner, message) => {
+                    self.remove_metric(owner, message).await
+                }
                 HubPushMessage::PullMetrics(message) => self.pull_metrics(message).await,
+                HubPushMessage::PullFederatedMetrics(message) => {
+                    self.pull_federated_metrics(message).await
+                }
+                HubPushMessage::UpdatePeer(peer, metrics) => match metrics {
+                    Some(metrics) => {
+                        self.peers.insert(peer, metrics);
+                    }
+                    None => {
+                        self.peers.remove(&peer);
+                    }
+                },
+                HubPushMessage::ReleaseOwner(owner) => self.release_owner(owner).await,
+                HubPushMessage::Watchdog => {
+                    if let Err(e) = systemd::notify("WATCHDOG=1") {
+                        tracing::warn!("Unable to notify systemd watchdog: {e}");
+                    }
+                }
+                HubPushMessage::EvaluateRules => self.evaluate_rules().await,
             }
         }
     }
@@ -61,6 +204,7 @@ impl MetricsHub {
     #[tracing::instrument(skip(self))]
     async fn create_family(
         &mut self,
+        owner: OwnerId,
         CreateFamily {
             name,
             metric_type,
@@ -68,6 +212,27 @@ impl MetricsHub {
             help,
         }: CreateFamily,
     ) {
+        if self
+            .family_owners
+            .get(&name)
+            .is_some_and(|owners| owners.contains(&OwnerId::RULES))
+        {
+            // The provider takes the name over, drop the recorded family.
+            tracing::warn!("{owner:?} is registering '{name}', no longer recording it");
+            self.family_owners.remove(&name);
+            Arc::make_mut(&mut self.metrics).families.remove(&name);
+        }
+
+        if !self
+            .family_owners
+            .entry(name.clone())
+            .or_default()
+            .insert(owner)
+        {
+            tracing::warn!("{owner:?} is registering {name} twice");
+            return;
+        }
+
         let metrics = Arc::make_mut(&mut self.metrics);
 
         if let Some(previous_family) = metrics.families.get_mut(&name) {
@@ -96,7 +261,30 @@ impl MetricsHub {
     }
 
     #[tracing::instrument(skip(self))]
-    async fn remove_family(&mut self, RemoveFamily { name }: RemoveFamily) {
+    async fn remove_family(&mut self, owner: OwnerId, RemoveFamily { name }: RemoveFamily) {
+        if !self
+            .family_owners
+            .get_mut(&name)
+            .is_some_and(|owners| owners.remove(&owner))
+        {
+            tracing::warn!("{owner:?} is trying to remove '{name}' but hasn't registered it");
+            return;
+        }
+
+        if self.family_owners.get(&name).is_some_and(HashSet::is_empty) {
+            self.family_owners.remove(&name);
+        }
+
+        // Drop the metrics the owner still has in this family, as it can no longer update them.
+        let orphans: Vec<Uuid> = self
+            .metric_owners
+            .iter()
+            .filter(|((family_name, _), metric_owner)| {
+                *family_name == name && **metric_owner == owner
+            })
+            .map(|((_, uuid), _)| *uuid)
+            .collect();
+
         let metrics = Arc::make_mut(&mut self.metrics);
 
         let Some(family) = metrics.families.get_mut(&name) else {
@@ -104,6 +292,13 @@ impl MetricsHub {
             return;
         };
 
+        for uuid in orphans {
+            if let Some(metric) = family.metrics.remove(&uuid) {
+                self.counters.forget(&name, &metric);
+            }
+            self.metric_owners.remove(&(name.clone(), uuid));
+        }
+
         family.reference_count -= 1;
 
         if family.reference_count == 0 {
@@ -112,7 +307,29 @@ impl MetricsHub {
     }
 
     #[tracing::instrument(skip(self))]
-    async fn remove_metric(&mut self, RemoveMetric { family_name, uuid }: RemoveMetric) {
+    async fn remove_metric(
+        &mut self,
+        owner: OwnerId,
+        RemoveMetric { family_name, uuid }: RemoveMetric,
+    ) {
+        let key = (family_name.clone(), uuid);
+
+        match self.metric_owners.get(&key) {
+            Some(&metric_owner) if metric_owner == owner => {
+                self.metric_owners.remove(&key);
+            }
+            Some(metric_owner) => {
+                tracing::warn!(
+                    "{owner:?} is trying to remove '{family_name}:{uuid}' owned by {metric_owner:?}"
+                );
+                return;
+            }
+            None => {
+                tracing::warn!("Tried to remove missing metric '{family_name}:{uuid}'");
+                return;
+            }
+        }
+
         let metrics = Arc::make_mut(&mut self.metrics);
 
         let Some(family) = metrics.families.get_mut(&family_name) else {
@@ -120,20 +337,42 @@ impl MetricsHub {
             return;
         };
 
-        if family.metrics.remove(&uuid).is_none() {
-            tracing::warn!("Tried to remove missing metric '{family_name}:{uuid}'");
+        if let Some(metric) = family.metrics.remove(&uuid) {
+            self.counters.forget(&family_name, &metric);
         }
     }
 
     #[tracing::instrument(skip(self))]
     async fn update_metric(
         &mut self,
+        owner: OwnerId,
         UpdateMetric {
             family_name,
-            metric,
+            mut metric,
             uuid,
         }: UpdateMetric,
     ) {
+        if !self
+            .family_owners
+            .get(&family_name)
+            .is_some_and(|owners| owners.contains(&owner))
+        {
+            tracing::warn!("{owner:?} is updating '{family_name}' but hasn't registered it");
+            return;
+        }
+
+        let &mut metric_owner = self
+            .metric_owners
+            .entry((family_name.clone(), uuid))
+            .or_insert(owner);
+
+        if metric_owner != owner {
+            tracing::warn!(
+                "{owner:?} is trying to update '{family_name}:{uuid}' owned by {metric_owner:?}"
+            );
+            return;
+        }
+
         let metrics = Arc::make_mut(&mut self.metrics);
 
         let Some(family) = metrics.families.get_mut(&family_name) else {
@@ -141,7 +380,69 @@ impl MetricsHub {
             return;
         };
 
+        let reset = self.counters.track(&family_name, &mut metric);
         family.metrics.insert(uuid, metric);
+
+        if reset {
+            self.record_counter_resets();
+        }
+    }
+
+    /// Update the [COUNTER_RESETS_FAMILY] family, unless a provider uses this name.
+    fn record_counter_resets(&mut self) {
+        if self
+            .family_owners
+            .get(COUNTER_RESETS_FAMILY)
+            .is_some_and(|owners| owners.iter().any(|&owner| owner != OwnerId::HUB))
+        {
+            tracing::warn!("Not recording '{COUNTER_RESETS_FAMILY}' as it is used by a provider");
+            return;
+        }
+
+        self.family_owners
+            .insert(COUNTER_RESETS_FAMILY.into(), HashSet::from([OwnerId::HUB]));
+        Arc::make_mut(&mut self.metrics)
+            .families
+            .insert(COUNTER_RESETS_FAMILY.into(), self.counters.resets_family());
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn release_owner(&mut self, owner: OwnerId) {
+        // Releasing a family also removes the metrics the owner has in it.
+        let families: Vec<CompactString> = self
+            .family_owners
+            .iter()
+            .filter(|(_, owners)| owners.contains(&owner))
+            .map(|(name, _)| name.clone())
+            .collect();
+
+        for name in families {
+            self.remove_family(owner, RemoveFamily { name }).await;
+        }
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn evaluate_rules(&mut self) {
+        let now = Instant::now();
+
+        // Rules are evaluated in order, so that a rule can use a previously recorded family.
+        for rule in &mut self.rules {
+            record_family(
+                &mut self.family_owners,
+                &mut self.metrics,
+                &rule.record.clone(),
+                |metrics| rule.evaluate(metrics, now),
+            );
+        }
+
+        for alert in &mut self.alerts.rules {
+            record_family(
+                &mut self.family_owners,
+                &mut self.metrics,
+                &alert.alert.clone(),
+                |metrics| alert.evaluate(metrics, now, self.alerts.notifications.as_ref()),
+            );
+        }
     }
 
     #[tracing::instrument(skip(self))]
@@ -153,4 +454,26 @@ impl MetricsHub {
             tracing::error!("Error occured while sending metrics {e:?}");
         }
     }
+
+    #[tracing::instrument(skip(self))]
+    async fn pull_federated_metrics(&mut self, message: PullMetrics) {
+        let sender = message.0;
+        tracing::debug!("Pulling federated metrics");
+
+        let metrics = if self.peers.is_empty() {
+            Arc::clone(&self.metrics)
+        } else {
+            let mut metrics = MetricSet::clone(&self.metrics);
+
+            for (peer, peer_metrics) in &self.peers {
+                federation::merge_metrics(&mut metrics, peer_metrics, peer);
+            }
+
+            Arc::new(metrics)
+        };
+
+        if let Err(e) = sender.send(HubPullResponse::Metrics(metrics)) {
+            tracing::error!("Error occured while sending metrics {e:?}");
+        }
+    }
 }
@@ -0,0 +1,105 @@
+//! Shared part of the ingestion front-ends (e.g [crate::statsd], [crate::textfile]).
+//!
+//! Front-ends build a [MetricSet] of their current metrics, and [HubSync] turns the changes
+//! into [HubPushMessage]s, just like the ones sent by native plugins.
+use flume::Sender;
+use xcp_metrics_common::{
+    metrics::MetricSet,
+    protocol::{CreateFamily, RemoveFamily, RemoveMetric, UpdateMetric},
+    utils::delta::MetricSetModel,
+};
+
+use crate::hub::{HubPushMessage, OwnerId};
+
+/// Keeps the hub in sync with the metrics of a front-end, as a provider.
+pub struct HubSync {
+    owner: OwnerId,
+    model: MetricSetModel,
+    hub: Sender<HubPushMessage>,
+}
+
+impl HubSync {
+    pub fn new(hub: Sender<HubPushMessage>) -> Self {
+        Self {
+            owner: OwnerId::allocate(),
+            model: MetricSetModel::default(),
+            hub,
+        }
+    }
+
+    /// Make the hub metrics of this front-end match `metrics`.
+    pub async fn sync(&mut self, metrics: &MetricSet) -> anyhow::Result<()> {
+        let delta = self.model.compute_delta(metrics);
+        let mut messages = vec![];
+
+        for uuid in &delta.removed_metrics {
+            if let Some(((family_name, _), _)) = self
+                .model
+                .metrics_map
+                .iter()
+                .find(|(_, metric_uuid)| *metric_uuid == uuid)
+            {
+                messages.push(HubPushMessage::RemoveMetric(
+                    self.owner,
+                    RemoveMetric {
+                        family_name: family_name.clone(),
+                        uuid: *uuid,
+                    },
+                ));
+            }
+        }
+
+        for name in &delta.orphaned_families {
+            messages.push(HubPushMessage::RemoveFamily(
+                self.owner,
+                RemoveFamily { name: name.clone() },
+            ));
+        }
+
+        for (name, family) in &delta.added_families {
+            messages.push(HubPushMessage::CreateFamily(
+                self.owner,
+                CreateFamily {
+                    name: (*name).into(),
+                    metric_type: family.metric_type,
+                    unit: family.unit.clone(),
+                    help: family.help.clone(),
+                },
+            ));
+        }
+
+        self.model.apply_delta(&delta);
+
+        // Update all the metrics, using the UUID they were first registered with.
+        for (name, family) in &metrics.families {
+            for metric in family.metrics.values() {
+                if let Some(&uuid) = self
+                    .model
+                    .metrics_map
+                    .get(&(name.clone(), metric.labels.clone()))
+                {
+                    messages.push(HubPushMessage::UpdateMetric(
+                        self.owner,
+                        UpdateMetric {
+                            family_name: name.clone(),
+                            metric: metric.clone(),
+                            uuid,
+                        },
+                    ));
+                }
+            }
+        }
+
+        for message in messages {
+            self.hub.send_async(message).await?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for HubSync {
+    fn drop(&mut self) {
+        self.hub.send(HubPushMessage::ReleaseOwner(self.owner)).ok();
+    }
+}
@@ -0,0 +1,64 @@
+//! InfluxDB line protocol and Graphite plaintext exporters.
+use std::time::{Duration, SystemTime};
+
+use xcp_metrics_common::{
+    metrics::MetricSet,
+    utils::{
+        graphite::{self, GraphiteTemplate},
+        influx,
+    },
+};
+
+use crate::export::{PushExporter, PushTarget};
+
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(10);
+pub const DEFAULT_BATCH_SIZE: usize = 5000;
+
+#[derive(Debug, Clone)]
+pub enum LineFormat {
+    Influx,
+    Graphite(GraphiteTemplate),
+}
+
+impl LineFormat {
+    pub fn name(&self) -> &'static str {
+        match self {
+            LineFormat::Influx => "InfluxDB",
+            LineFormat::Graphite(_) => "Graphite",
+        }
+    }
+
+    pub fn serialize(&self, metrics: &MetricSet, timestamp: SystemTime) -> Vec<String> {
+        match self {
+            LineFormat::Influx => influx::metrics_to_lines(metrics, timestamp),
+            LineFormat::Graphite(template) => {
+                graphite::metrics_to_lines(metrics, template, timestamp)
+            }
+        }
+    }
+}
+
+/// Push metrics as lines, in batches of at most `batch_size` lines.
+#[derive(Debug, Clone)]
+pub struct LineExporter {
+    pub format: LineFormat,
+    pub target: PushTarget,
+    pub batch_size: usize,
+}
+
+impl PushExporter for LineExporter {
+    async fn push(&mut self, metrics: &MetricSet, timestamp: SystemTime) {
+        let lines = self.format.serialize(metrics, timestamp);
+
+        for batch in lines.chunks(self.batch_size.max(1)) {
+            if let Err(e) = self.target.send(batch).await {
+                tracing::warn!(
+                    "Unable to push metrics to {} ({:?}): {e}",
+                    self.format.name(),
+                    self.target
+                );
+                break;
+            }
+        }
+    }
+}
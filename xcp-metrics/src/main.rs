@@ -1,16 +1,57 @@
+pub mod access;
+pub mod alerts;
+pub mod collection;
+pub mod counters;
+pub mod export;
+pub mod federation;
+pub mod host;
+pub mod http;
 pub mod hub;
+pub mod ingest;
+pub mod line_export;
+pub mod otlp;
+pub mod remote_write;
 pub mod rpc;
+pub mod rules;
+pub mod statsd;
+pub mod systemd;
+pub mod textfile;
+
+#[cfg(test)]
+mod test;
 
 use std::{
-    fs,
-    os::unix::net::UnixStream,
+    fs::{self, Permissions},
+    os::{
+        fd::OwnedFd,
+        unix::{
+            fs::PermissionsExt,
+            net::{UnixDatagram, UnixStream},
+        },
+    },
     path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
 };
 
 use argh::FromArgs;
 
-use futures::{select, FutureExt};
-use xcp_metrics_common::protocol;
+use access::{AccessPolicy, PeerMatcher, Role};
+use async_signal::{Signal, Signals};
+use compact_str::CompactString;
+use futures::{
+    future::{self, LocalBoxFuture},
+    select, FutureExt, StreamExt,
+};
+use hub::HubPushMessage;
+use smol::{
+    net::{unix::UnixListener, TcpListener},
+    Timer,
+};
+use xcp_metrics_common::{
+    protocol,
+    utils::{graphite::GraphiteTemplate, relabel::RelabelConfig},
+};
 
 /// xcp-metrics main daemon
 #[derive(FromArgs, Debug)]
@@ -22,32 +63,309 @@ struct Args {
     /// xcp-metrics socket path
     #[argh(option, short = 'd')]
     daemon_path: Option<PathBuf>,
+
+    /// additional socket path that only allows fetching metrics
+    #[argh(option)]
+    read_only_path: Option<PathBuf>,
+
+    /// allow peers to push and fetch metrics (uid:<uid>, gid:<gid>, exe:<path> or any),
+    /// defaults to root and the daemon user (exe: rules are advisory, not a security boundary)
+    #[argh(option)]
+    allow: Vec<PeerMatcher>,
+
+    /// allow peers to fetch metrics (uid:<uid>, gid:<gid>, exe:<path> or any)
+    #[argh(option)]
+    allow_read: Vec<PeerMatcher>,
+
+    /// recording rules file (JSON)
+    #[argh(option)]
+    rules: Option<PathBuf>,
+
+    /// interval between two evaluations of the recording rules and alerts (in seconds)
+    #[argh(option, from_str_fn(parse_interval))]
+    rules_interval: Option<Duration>,
+
+    /// alert rules file (JSON)
+    #[argh(option)]
+    alerts: Option<PathBuf>,
+
+    /// send alert notifications to this webhook (http://host[:port][/path])
+    #[argh(option)]
+    alert_webhook: Option<http::Url>,
+
+    /// write alert notifications in this directory
+    #[argh(option)]
+    alert_spool: Option<PathBuf>,
+
+    /// static host label (name=value), added to the host identity
+    #[argh(option)]
+    host_label: Vec<host::StaticLabel>,
+
+    /// interval between two collection ticks sent to the subscribed plugins (in seconds)
+    #[argh(option, from_str_fn(parse_interval))]
+    collection_interval: Option<Duration>,
+
+    /// trigger a collection round on each fetch, and wait for it before answering
+    #[argh(switch)]
+    collect_on_fetch: bool,
+
+    /// how long a collection round waits for the plugins (in milliseconds)
+    #[argh(option)]
+    collection_deadline_ms: Option<u64>,
+
+    /// how RPC exposes the host identity (none, labels or info)
+    #[argh(option, default = "Default::default()")]
+    rpc_host_labels: host::HostLabelsMode,
+
+    /// relabel configs (JSON) applied to metrics fetched through RPC
+    #[argh(option)]
+    rpc_relabel: Option<PathBuf>,
+
+    /// metrics fetched through RPC (local or federated)
+    #[argh(option, default = "Default::default()")]
+    rpc_view: export::MetricsView,
+
+    /// also accept RPC sessions on this TCP address (host:port), only allowed to fetch the local
+    /// metrics (e.g by a federating daemon), without authentication nor encryption: anyone who can
+    /// reach it can read the metrics
+    #[argh(option)]
+    tcp_listen: Option<String>,
+
+    /// how the TCP listener exposes the host identity (none, labels or info)
+    #[argh(option, default = "host::HostLabelsMode::Info")]
+    tcp_host_labels: host::HostLabelsMode,
+
+    /// relabel configs (JSON) applied to metrics fetched through the TCP listener
+    #[argh(option)]
+    tcp_relabel: Option<PathBuf>,
+
+    /// federate the metrics of this daemon TCP listener (host:port), can be repeated
+    #[argh(option)]
+    federate: Vec<CompactString>,
+
+    /// interval between two fetches of the federated daemons (in seconds)
+    #[argh(option)]
+    federation_interval: Option<u64>,
+
+    /// timeout of the fetches of the federated daemons (in seconds)
+    #[argh(option)]
+    federation_timeout: Option<u64>,
+
+    /// drop the metrics of a federated daemon that couldn't be fetched for this long (in seconds),
+    /// defaults to 3 intervals
+    #[argh(option)]
+    federation_stale_after: Option<u64>,
+
+    /// push metrics to this Prometheus remote-write endpoint (http://host[:port][/path])
+    #[argh(option)]
+    remote_write: Option<http::Url>,
+
+    /// interval between two remote-write pushes (in seconds)
+    #[argh(option)]
+    remote_write_interval: Option<u64>,
+
+    /// maximum number of series per remote-write request
+    #[argh(option, default = "remote_write::DEFAULT_BATCH_SIZE")]
+    remote_write_batch_size: usize,
+
+    /// remote-write WAL directory, keeping the requests that can't be delivered
+    #[argh(option)]
+    remote_write_wal: Option<PathBuf>,
+
+    /// how remote-write exposes the host identity (none, labels or info)
+    #[argh(option, default = "Default::default()")]
+    remote_write_host_labels: host::HostLabelsMode,
+
+    /// relabel configs (JSON) applied to metrics pushed with remote-write
+    #[argh(option)]
+    remote_write_relabel: Option<PathBuf>,
+
+    /// metrics pushed with remote-write (local or federated)
+    #[argh(option, default = "Default::default()")]
+    remote_write_view: export::MetricsView,
+
+    /// push metrics in InfluxDB line protocol (tcp://host:port, udp://host:port or http://host[:port][/path])
+    #[argh(option)]
+    influx: Option<export::PushTarget>,
+
+    /// interval between two InfluxDB pushes (in seconds)
+    #[argh(option)]
+    influx_interval: Option<u64>,
+
+    /// maximum number of lines per InfluxDB batch
+    #[argh(option, default = "line_export::DEFAULT_BATCH_SIZE")]
+    influx_batch_size: usize,
+
+    /// how InfluxDB exposes the host identity (none, labels or info)
+    #[argh(option, default = "Default::default()")]
+    influx_host_labels: host::HostLabelsMode,
+
+    /// relabel configs (JSON) applied to metrics pushed to InfluxDB
+    #[argh(option)]
+    influx_relabel: Option<PathBuf>,
+
+    /// metrics pushed with InfluxDB (local or federated)
+    #[argh(option, default = "Default::default()")]
+    influx_view: export::MetricsView,
+
+    /// push metrics in Graphite plaintext protocol (tcp://host:port, udp://host:port or http://host[:port][/path])
+    #[argh(option)]
+    graphite: Option<export::PushTarget>,
+
+    /// template of Graphite metric paths (e.g xcp.{hostname}.{name}.{labels})
+    #[argh(option, default = "Default::default()")]
+    graphite_template: GraphiteTemplate,
+
+    /// interval between two Graphite pushes (in seconds)
+    #[argh(option)]
+    graphite_interval: Option<u64>,
+
+    /// maximum number of lines per Graphite batch
+    #[argh(option, default = "line_export::DEFAULT_BATCH_SIZE")]
+    graphite_batch_size: usize,
+
+    /// how Graphite exposes the host identity (none, labels or info)
+    #[argh(option, default = "Default::default()")]
+    graphite_host_labels: host::HostLabelsMode,
+
+    /// relabel configs (JSON) applied to metrics pushed to Graphite
+    #[argh(option)]
+    graphite_relabel: Option<PathBuf>,
+
+    /// metrics pushed with Graphite (local or federated)
+    #[argh(option, default = "Default::default()")]
+    graphite_view: export::MetricsView,
+
+    /// push metrics to an OpenTelemetry collector with OTLP/HTTP (e.g http://localhost:4318/v1/metrics)
+    #[argh(option)]
+    otlp: Option<http::Url>,
+
+    /// interval between two OTLP pushes (in seconds)
+    #[argh(option)]
+    otlp_interval: Option<u64>,
+
+    /// how OTLP exposes the host identity in addition to resource attributes (none, labels or info)
+    #[argh(option, default = "Default::default()")]
+    otlp_host_labels: host::HostLabelsMode,
+
+    /// relabel configs (JSON) applied to metrics pushed with OTLP
+    #[argh(option)]
+    otlp_relabel: Option<PathBuf>,
+
+    /// metrics pushed with OTLP (local or federated)
+    #[argh(option, default = "Default::default()")]
+    otlp_view: export::MetricsView,
+
+    /// listen for StatsD metrics (udp://host:port or unix:///path), can be repeated
+    #[argh(option)]
+    statsd: Vec<statsd::StatsdListen>,
+
+    /// interval between two pushes of the StatsD metrics to the hub (in seconds)
+    #[argh(option)]
+    statsd_flush_interval: Option<u64>,
+
+    /// directory of *.prom files to ingest (textfile collector)
+    #[argh(option)]
+    textfile_dir: Option<PathBuf>,
+
+    /// interval between two reads of the textfile directory (in seconds)
+    #[argh(option)]
+    textfile_interval: Option<u64>,
+}
+
+/// Parse an interval (in seconds), which can't be zero.
+fn parse_interval(value: &str) -> Result<Duration, String> {
+    match value.parse::<u64>() {
+        Ok(0) => Err("The interval must be at least 1 second".into()),
+        Ok(seconds) => Ok(Duration::from_secs(seconds)),
+        Err(e) => Err(format!("Invalid interval '{value}': {e}")),
+    }
+}
+
+/// Load relabel configs from an optional JSON file.
+fn load_relabel_configs(path: Option<&Path>) -> Vec<RelabelConfig> {
+    path.map(export::load_relabel_configs)
+        .transpose()
+        .unwrap()
+        .unwrap_or_default()
+}
+
+/// Make the schedule of a push exporter.
+fn push_schedule(
+    interval: Option<u64>,
+    default_interval: Duration,
+    view: export::MetricsView,
+    host: &Arc<host::HostIdentity>,
+    host_labels: host::HostLabelsMode,
+    relabel: Option<&Path>,
+) -> export::PushSchedule {
+    export::PushSchedule {
+        interval: interval.map_or(default_interval, Duration::from_secs),
+        export: export::ExportPipeline {
+            view,
+            host: host.clone(),
+            host_labels,
+            relabel: load_relabel_configs(relabel),
+        },
+    }
 }
 
-/// Check if the Unix socket is active and unlink it if it isn't.
+/// Bind a Unix socket, unlinking the previous inactive one.
+fn bind_unix_socket(socket_path: &Path) -> anyhow::Result<UnixListener> {
+    if check_unix_socket(socket_path, |path| UnixStream::connect(path).map(drop))? {
+        tracing::error!("Unable to start: xcp-metrics socket is active");
+        panic!("Unable to start: is xcp-metrics already running ?");
+    }
+
+    Ok(UnixListener::bind(socket_path)?)
+}
+
+/// Bind a StatsD socket, unlinking the previous inactive Unix one.
+fn bind_statsd_socket(listen: &statsd::StatsdListen) -> anyhow::Result<statsd::StatsdSocket> {
+    if let statsd::StatsdListen::Unix(path) = listen {
+        let active = check_unix_socket(path, |path| UnixDatagram::unbound()?.connect(path))?;
+        anyhow::ensure!(
+            !active,
+            "StatsD socket {} is already in use",
+            path.display()
+        );
+    }
+
+    listen.bind()
+}
+
+/// Make a listener out of a socket passed by systemd.
+fn listener_from_fd(fd: OwnedFd) -> anyhow::Result<UnixListener> {
+    Ok(UnixListener::try_from(
+        std::os::unix::net::UnixListener::from(fd),
+    )?)
+}
+
+/// Check if the Unix socket is active and unlink it if it isn't,
+/// `connect` probing the socket with its type (stream or datagram).
 ///
 /// Returns true if the socket is active.
-fn check_unix_socket(socket_path: &Path) -> anyhow::Result<bool> {
-    if !Path::try_exists(&socket_path)? {
+fn check_unix_socket(
+    socket_path: &Path,
+    connect: impl FnOnce(&Path) -> std::io::Result<()>,
+) -> anyhow::Result<bool> {
+    if !Path::try_exists(socket_path)? {
         // Socket doesn't exist.
         return Ok(false);
     }
 
-    match UnixStream::connect(&socket_path) {
-        Ok(_) => Ok(true),
+    match connect(socket_path) {
+        Ok(()) => Ok(true),
         Err(e) => {
             if matches!(e.kind(), std::io::ErrorKind::ConnectionRefused) {
                 // Unlink socket
-                tracing::warn!(
-                    socket = socket_path.to_str(),
-                    "Unlinking inactive xcp-metrics socket"
-                );
+                tracing::warn!(socket = socket_path.to_str(), "Unlinking inactive socket");
                 fs::remove_file(socket_path)?;
                 Ok(false)
             } else {
                 tracing::error!(
                     socket = socket_path.to_str(),
-                    "Unable to check xcp-metrics socket status: {e}"
+                    "Unable to check socket status: {e}"
                 );
                 Err(e.into())
             }
@@ -70,20 +388,375 @@ fn main() {
         .daemon_path
         .unwrap_or_else(|| protocol::METRICS_SOCKET_PATH.into());
 
-    if check_unix_socket(Path::new(&socket_path)).unwrap() {
-        tracing::error!("Unable to start: xcp-metrics socket is active");
-        panic!("Unable to start: is xcp-metrics already running ?");
-    }
+    // Prefer the sockets passed by systemd (socket activation).
+    let mut activated_sockets = systemd::listen_fds();
+    let read_only_fd = activated_sockets
+        .iter()
+        .position(|(name, _)| name == "read-only")
+        .map(|i| activated_sockets.remove(i).1);
+    let main_fd = activated_sockets.into_iter().next().map(|(_, fd)| fd);
+
+    // Sockets we bound ourselves, to unlink when stopping.
+    let mut bound_paths = vec![];
+
+    let listener = match main_fd {
+        Some(fd) => listener_from_fd(fd).unwrap(),
+        None => {
+            let listener = bind_unix_socket(&socket_path).unwrap();
+            bound_paths.push(socket_path.clone());
+            listener
+        }
+    };
+
+    let read_only_listener = match (read_only_fd, &args.read_only_path) {
+        (Some(fd), _) => Some(listener_from_fd(fd).unwrap()),
+        (None, Some(path)) => {
+            let listener = bind_unix_socket(path).unwrap();
+            // Let anyone connect, the access policy decides who is actually allowed.
+            fs::set_permissions(path, Permissions::from_mode(0o666)).unwrap();
+            bound_paths.push(path.clone());
+            Some(listener)
+        }
+        (None, None) => None,
+    };
+
+    let statsd_sockets: Vec<_> = args
+        .statsd
+        .iter()
+        .map(|listen| {
+            let socket = bind_statsd_socket(listen).unwrap_or_else(|e| {
+                tracing::error!("Unable to listen for StatsD metrics: {e:#}");
+                panic!("Unable to start: {e}");
+            });
+
+            if let statsd::StatsdListen::Unix(path) = listen {
+                bound_paths.push(path.clone());
+            }
+
+            socket
+        })
+        .collect();
+
+    let tcp_listener = args.tcp_listen.as_deref().map(|address| {
+        std::net::TcpListener::bind(address)
+            .and_then(TcpListener::try_from)
+            .unwrap_or_else(|e| {
+                tracing::error!("Unable to listen on {address}: {e}");
+                panic!("Unable to start: {e}");
+            })
+    });
+
+    let policy = Arc::new(AccessPolicy::new(args.allow, args.allow_read));
+
+    let host = Arc::new(host::HostIdentity::load(
+        args.host_label.into_iter().map(|label| label.0).collect(),
+    ));
+    tracing::info!("Host identity: {:?}", host.labels);
+
+    let rpc_export = Arc::new(export::ExportPipeline {
+        view: args.rpc_view,
+        host: host.clone(),
+        host_labels: args.rpc_host_labels,
+        relabel: load_relabel_configs(args.rpc_relabel.as_deref()),
+    });
+
+    // Federating daemons fetch the local metrics, to avoid federation loops.
+    let tcp_export = Arc::new(export::ExportPipeline {
+        view: export::MetricsView::Local,
+        host: host.clone(),
+        host_labels: args.tcp_host_labels,
+        relabel: load_relabel_configs(args.tcp_relabel.as_deref()),
+    });
+
+    let rules = args
+        .rules
+        .as_deref()
+        .map(rules::load_rules)
+        .transpose()
+        .unwrap()
+        .unwrap_or_default();
+    let rules_interval = args.rules_interval.unwrap_or(rules::DEFAULT_RULES_INTERVAL);
+    let alert_rules = args
+        .alerts
+        .as_deref()
+        .map(alerts::load_alerts)
+        .transpose()
+        .unwrap()
+        .unwrap_or_default();
+    let has_rules = !rules.is_empty() || !alert_rules.is_empty();
+
+    let (notification_sender, notification_receiver) = flume::bounded(1024);
+    let notify_alerts = args.alert_webhook.is_some() || args.alert_spool.is_some();
+    let notifier = async {
+        // Without webhook nor spool, the notification sender is dropped.
+        if notify_alerts {
+            alerts::run_notifier(notification_receiver, args.alert_webhook, args.alert_spool).await;
+        }
+
+        future::pending::<()>().await
+    };
 
-    let hub = hub::MetricsHub::default();
+    let hub = hub::MetricsHub::new(
+        rules,
+        alerts::Alerts {
+            rules: alert_rules,
+            notifications: notify_alerts.then_some(notification_sender),
+        },
+    );
     let (hub_sender, hub_receiver) = flume::unbounded();
 
+    let collection_config = collection::CollectionConfig {
+        interval: args
+            .collection_interval
+            .unwrap_or(collection::DEFAULT_INTERVAL),
+        on_demand: args.collect_on_fetch,
+        deadline: args
+            .collection_deadline_ms
+            .map_or(collection::DEFAULT_DEADLINE, Duration::from_millis),
+    };
+    let (collection_sender, collection_receiver) = flume::unbounded();
+
+    // Disconnected to stop the RPC sockets.
+    let (shutdown_sender, shutdown) = flume::bounded::<()>(0);
+    let mut signals = Signals::new([Signal::Term, Signal::Int]).unwrap();
+
+    let stop_signal = async {
+        if let Some(signal) = signals.next().await {
+            tracing::info!("Received {signal:?}, stopping RPC sessions");
+        }
+
+        if let Err(e) = systemd::notify("STOPPING=1") {
+            tracing::warn!("Unable to notify systemd: {e}");
+        }
+
+        drop(shutdown_sender);
+        future::pending::<()>().await
+    };
+
+    let watchdog = async {
+        match systemd::watchdog_interval() {
+            Some(interval) => loop {
+                Timer::after(interval).await;
+                hub_sender.send_async(HubPushMessage::Watchdog).await.ok();
+            },
+            None => future::pending().await,
+        }
+    };
+
+    let rules_timer = async {
+        if !has_rules {
+            return future::pending().await;
+        }
+
+        loop {
+            Timer::after(rules_interval).await;
+            hub_sender
+                .send_async(HubPushMessage::EvaluateRules)
+                .await
+                .ok();
+        }
+    };
+
+    let mut push_exporters: Vec<LocalBoxFuture<'static, ()>> = vec![];
+
+    if let Some(url) = args.remote_write {
+        let writer = remote_write::RemoteWriter::new(remote_write::RemoteWriteConfig {
+            url,
+            batch_size: args.remote_write_batch_size,
+            max_retries: remote_write::DEFAULT_MAX_RETRIES,
+            wal: args.remote_write_wal,
+        });
+        let schedule = push_schedule(
+            args.remote_write_interval,
+            remote_write::DEFAULT_INTERVAL,
+            args.remote_write_view,
+            &host,
+            args.remote_write_host_labels,
+            args.remote_write_relabel.as_deref(),
+        );
+
+        push_exporters
+            .push(export::run_push_exporter(writer, schedule, hub_sender.clone()).boxed_local());
+    }
+
+    if let Some(target) = args.influx {
+        let exporter = line_export::LineExporter {
+            format: line_export::LineFormat::Influx,
+            target,
+            batch_size: args.influx_batch_size,
+        };
+        let schedule = push_schedule(
+            args.influx_interval,
+            line_export::DEFAULT_INTERVAL,
+            args.influx_view,
+            &host,
+            args.influx_host_labels,
+            args.influx_relabel.as_deref(),
+        );
+
+        push_exporters
+            .push(export::run_push_exporter(exporter, schedule, hub_sender.clone()).boxed_local());
+    }
+
+    if let Some(target) = args.graphite {
+        let exporter = line_export::LineExporter {
+            format: line_export::LineFormat::Graphite(args.graphite_template),
+            target,
+            batch_size: args.graphite_batch_size,
+        };
+        let schedule = push_schedule(
+            args.graphite_interval,
+            line_export::DEFAULT_INTERVAL,
+            args.graphite_view,
+            &host,
+            args.graphite_host_labels,
+            args.graphite_relabel.as_deref(),
+        );
+
+        push_exporters
+            .push(export::run_push_exporter(exporter, schedule, hub_sender.clone()).boxed_local());
+    }
+
+    if let Some(url) = args.otlp {
+        let exporter = otlp::OtlpExporter::new(url, &host);
+        let schedule = push_schedule(
+            args.otlp_interval,
+            otlp::DEFAULT_INTERVAL,
+            args.otlp_view,
+            &host,
+            args.otlp_host_labels,
+            args.otlp_relabel.as_deref(),
+        );
+
+        push_exporters
+            .push(export::run_push_exporter(exporter, schedule, hub_sender.clone()).boxed_local());
+    }
+
+    let push_exporters = async {
+        future::join_all(push_exporters).await;
+        future::pending::<()>().await
+    };
+
+    let mut ingestors: Vec<LocalBoxFuture<'_, ()>> = vec![];
+
+    if !statsd_sockets.is_empty() {
+        ingestors.push(
+            statsd::run(
+                statsd_sockets,
+                args.statsd_flush_interval
+                    .map_or(statsd::DEFAULT_FLUSH_INTERVAL, Duration::from_secs),
+                hub_sender.clone(),
+            )
+            .boxed_local(),
+        );
+    }
+
+    if let Some(directory) = &args.textfile_dir {
+        ingestors.push(
+            textfile::run(
+                directory,
+                args.textfile_interval
+                    .map_or(textfile::DEFAULT_INTERVAL, Duration::from_secs),
+                hub_sender.clone(),
+            )
+            .boxed_local(),
+        );
+    }
+
+    if !args.federate.is_empty() {
+        let interval = args
+            .federation_interval
+            .map_or(federation::DEFAULT_INTERVAL, Duration::from_secs);
+
+        ingestors.push(
+            federation::run(
+                federation::FederationConfig {
+                    peers: args.federate,
+                    interval,
+                    timeout: args
+                        .federation_timeout
+                        .map_or(federation::DEFAULT_TIMEOUT, Duration::from_secs),
+                    stale_after: args.federation_stale_after.map_or(
+                        interval * federation::DEFAULT_STALE_INTERVALS,
+                        Duration::from_secs,
+                    ),
+                },
+                hub_sender.clone(),
+            )
+            .boxed_local(),
+        );
+    }
+
+    let ingestors = async {
+        future::join_all(ingestors).await;
+        future::pending::<()>().await
+    };
+
+    let rpc = future::try_join3(
+        rpc::run(
+            listener,
+            hub_sender.clone(),
+            policy.clone(),
+            Role::ReadWrite,
+            rpc_export.clone(),
+            collection_sender.clone(),
+            shutdown.clone(),
+        ),
+        async {
+            match read_only_listener {
+                Some(listener) => {
+                    rpc::run(
+                        listener,
+                        hub_sender.clone(),
+                        policy.clone(),
+                        Role::Read,
+                        rpc_export.clone(),
+                        collection_sender.clone(),
+                        shutdown.clone(),
+                    )
+                    .await
+                }
+                None => Ok(()),
+            }
+        },
+        async {
+            match tcp_listener {
+                Some(listener) => {
+                    rpc::run(
+                        listener,
+                        hub_sender.clone(),
+                        policy.clone(),
+                        Role::Read,
+                        tcp_export.clone(),
+                        collection_sender.clone(),
+                        shutdown.clone(),
+                    )
+                    .await
+                }
+                None => Ok(()),
+            }
+        },
+    );
+
     smol::block_on(async {
         select! {
             res = hub.run(hub_receiver).fuse() => tracing::warn!("Hub returned: {res:?}"),
-            res = rpc::run(&socket_path, hub_sender).fuse() => tracing::warn!("RPC Socket returned: {res:?}"),
+            res = rpc.fuse() => tracing::info!("RPC Sockets returned: {res:?}"),
+            _ = collection::run(collection_config, collection_receiver).fuse() => (),
+            _ = watchdog.fuse() => (),
+            _ = rules_timer.fuse() => (),
+            _ = notifier.fuse() => (),
+            _ = push_exporters.fuse() => (),
+            _ = ingestors.fuse() => (),
+            _ = stop_signal.fuse() => (),
         }
     });
 
+    for path in bound_paths {
+        if let Err(e) = fs::remove_file(&path) {
+            tracing::warn!(socket = path.to_str(), "Unable to unlink socket: {e}");
+        }
+    }
+
     tracing::info!("Stopping");
 }
@@ -0,0 +1,103 @@
+//! OpenTelemetry (OTLP/HTTP) exporter.
+//!
+//! Periodically pushes the hub metrics to a collector (e.g `http://localhost:4318/v1/metrics`),
+//! as protobuf. The host identity is sent as resource attributes.
+//!
+//! Only the HTTP transport is implemented, OTLP/gRPC would need an HTTP/2 client.
+use std::time::{Duration, SystemTime};
+
+use xcp_metrics_common::{
+    metrics::MetricSet,
+    openmetrics::otlp::{self, opentelemetry::KeyValue},
+};
+
+use crate::{
+    export::PushExporter,
+    host::HostIdentity,
+    http::{self, Url},
+};
+
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Default path of collector endpoints.
+pub const DEFAULT_PATH: &str = "/v1/metrics";
+
+/// Default port of the OTLP/gRPC receiver of the collectors.
+pub const GRPC_PORT: u16 = 4317;
+
+/// Make the resource attributes of a host, using the semantic conventions names when they exist.
+pub fn resource_attributes(host: &HostIdentity) -> Vec<KeyValue> {
+    [
+        otlp::key_value("service.name", "xcp-metrics"),
+        otlp::key_value("service.version", env!("CARGO_PKG_VERSION")),
+    ]
+    .into_iter()
+    .chain(host.labels.iter().map(|label| {
+        let key = match label.name.as_str() {
+            "host_uuid" => "host.id",
+            "hostname" => "host.name",
+            name => name,
+        };
+
+        otlp::key_value(key, &label.value)
+    }))
+    .collect()
+}
+
+pub struct OtlpExporter {
+    url: Url,
+    resource: Vec<KeyValue>,
+    /// Start of the cumulative metrics without creation time.
+    start_time: SystemTime,
+}
+
+impl OtlpExporter {
+    pub fn new(mut url: Url, host: &HostIdentity) -> Self {
+        if url.path == "/" {
+            url.path = DEFAULT_PATH.into();
+        }
+
+        if url.port == GRPC_PORT {
+            tracing::warn!(
+                "{url} looks like an OTLP/gRPC receiver, which isn't supported (use OTLP/HTTP)"
+            );
+        }
+
+        Self {
+            url,
+            resource: resource_attributes(host),
+            start_time: SystemTime::now(),
+        }
+    }
+}
+
+impl PushExporter for OtlpExporter {
+    async fn push(&mut self, metrics: &MetricSet, timestamp: SystemTime) {
+        let request = otlp::metrics_to_request(metrics, &self.resource, self.start_time, timestamp);
+        let payload = otlp::encode_request(&request);
+
+        let response =
+            match http::post(&self.url, &[("Content-Type", otlp::CONTENT_TYPE)], &payload).await {
+                Ok(response) => response,
+                Err(e) => {
+                    tracing::warn!("Unable to push metrics to {}: {e}", self.url);
+                    return;
+                }
+            };
+
+        // The collector may only accept a part of the data points.
+        if let Some(partial) = otlp::decode_response(&response)
+            .ok()
+            .and_then(|response| response.partial_success)
+        {
+            if partial.rejected_data_points > 0 {
+                tracing::warn!(
+                    "{} data points rejected by {}: {}",
+                    partial.rejected_data_points,
+                    self.url,
+                    partial.error_message
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,245 @@
+//! Prometheus remote-write exporter.
+//!
+//! Periodically pulls the hub metrics and pushes them to a remote-write endpoint, in batches of
+//! at most `batch_size` series. Failed requests are retried with exponential backoff, then stored
+//! in a write-ahead log (WAL) directory and replayed (oldest first) once the endpoint is back.
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use smol::Timer;
+use xcp_metrics_common::{
+    metrics::MetricSet,
+    openmetrics::remote_write::{self, prometheus},
+};
+
+use crate::{
+    export::PushExporter,
+    http::{self, StatusError, Url},
+};
+
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(15);
+pub const DEFAULT_BATCH_SIZE: usize = 2000;
+pub const DEFAULT_MAX_RETRIES: u32 = 3;
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Maximum number of requests kept in the WAL, the oldest ones are dropped first.
+pub const MAX_WAL_FILES: usize = 1024;
+
+#[derive(Debug, Clone)]
+pub struct RemoteWriteConfig {
+    pub url: Url,
+    /// Maximum number of series per request.
+    pub batch_size: usize,
+    pub max_retries: u32,
+    /// WAL directory, requests that can't be delivered are dropped if [None].
+    pub wal: Option<PathBuf>,
+}
+
+pub struct RemoteWriter {
+    config: RemoteWriteConfig,
+    /// Sequence number of the next WAL file.
+    wal_sequence: u64,
+}
+
+/// Sequence number of a WAL file.
+fn wal_file_sequence(path: &Path) -> Option<u64> {
+    path.file_name()?
+        .to_str()?
+        .strip_suffix(".bin")?
+        .parse()
+        .ok()
+}
+
+/// WAL files, oldest first.
+fn wal_files(wal: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = match fs::read_dir(wal) {
+        Ok(entries) => entries
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .filter(|path| wal_file_sequence(path).is_some())
+            .collect(),
+        Err(e) => {
+            tracing::warn!("Unable to read WAL directory {wal:?}: {e}");
+            vec![]
+        }
+    };
+
+    files.sort_by_key(|path| wal_file_sequence(path));
+    files
+}
+
+impl RemoteWriter {
+    pub fn new(config: RemoteWriteConfig) -> Self {
+        if let Some(wal) = &config.wal {
+            if let Err(e) = fs::create_dir_all(wal) {
+                tracing::error!("Unable to create WAL directory {wal:?}: {e}");
+            }
+        }
+
+        let wal_sequence = config
+            .wal
+            .as_deref()
+            .and_then(|wal| {
+                wal_files(wal)
+                    .last()
+                    .and_then(|path| wal_file_sequence(path))
+            })
+            .map_or(0, |sequence| sequence + 1);
+
+        Self {
+            config,
+            wal_sequence,
+        }
+    }
+
+    /// Make the (encoded) requests of `metrics`, sampled at `timestamp` (in milliseconds).
+    pub fn make_requests(&self, metrics: &MetricSet, timestamp: i64) -> Vec<Vec<u8>> {
+        let series = remote_write::metrics_to_series(metrics, timestamp);
+        let mut metadata = remote_write::metrics_metadata(metrics);
+
+        series
+            .chunks(self.config.batch_size.max(1))
+            .filter_map(|batch| {
+                let request = prometheus::WriteRequest {
+                    timeseries: batch.to_vec(),
+                    // Only send the metadata once.
+                    metadata: std::mem::take(&mut metadata),
+                };
+
+                match remote_write::encode_write_request(&request) {
+                    Ok(payload) => Some(payload),
+                    Err(e) => {
+                        tracing::error!("Unable to encode remote-write request: {e}");
+                        None
+                    }
+                }
+            })
+            .collect()
+    }
+
+    /// Send a request, retrying on failure.
+    ///
+    /// Requests rejected by the endpoint (4xx) are dropped as they would never succeed.
+    async fn send(&self, payload: &[u8]) -> anyhow::Result<()> {
+        let headers = [
+            ("Content-Type", remote_write::CONTENT_TYPE),
+            ("Content-Encoding", "snappy"),
+            ("X-Prometheus-Remote-Write-Version", remote_write::VERSION),
+        ];
+        let mut backoff = INITIAL_BACKOFF;
+        let mut attempt = 0;
+
+        loop {
+            let Err(e) = http::post(&self.config.url, &headers, payload).await else {
+                return Ok(());
+            };
+
+            if let Some(status) = e.downcast_ref::<StatusError>() {
+                if !status.is_retryable() {
+                    tracing::warn!("Remote-write request rejected, dropping it: {e}");
+                    return Ok(());
+                }
+            }
+
+            if attempt >= self.config.max_retries {
+                return Err(e);
+            }
+
+            tracing::debug!("Remote-write request failed ({e}), retrying in {backoff:?}");
+            Timer::after(backoff).await;
+
+            attempt += 1;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+
+    fn store_wal(&mut self, wal: &Path, payload: &[u8]) -> anyhow::Result<()> {
+        let files = wal_files(wal);
+
+        // Make room for the new request.
+        for path in files
+            .iter()
+            .take((files.len() + 1).saturating_sub(MAX_WAL_FILES))
+        {
+            tracing::warn!("WAL is full, dropping {path:?}");
+            fs::remove_file(path)?;
+        }
+
+        let name = format!("{:020}.bin", self.wal_sequence);
+        let temporary = wal.join(format!(".{name}.tmp"));
+        self.wal_sequence += 1;
+
+        fs::write(&temporary, payload)?;
+        fs::rename(&temporary, wal.join(name))?;
+
+        Ok(())
+    }
+
+    /// Send the requests stored in the WAL, returns false if the endpoint is still unreachable.
+    async fn replay_wal(&self, wal: &Path) -> bool {
+        for path in wal_files(wal) {
+            let payload = match fs::read(&path) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    tracing::warn!("Unable to read {path:?}: {e}");
+                    continue;
+                }
+            };
+
+            if let Err(e) = self.send(&payload).await {
+                tracing::debug!("Unable to replay WAL: {e}");
+                return false;
+            }
+
+            if let Err(e) = fs::remove_file(&path) {
+                tracing::warn!("Unable to remove {path:?}: {e}");
+            }
+        }
+
+        true
+    }
+
+    /// Push `metrics` (sampled at `timestamp` in milliseconds) after the pending WAL requests.
+    pub async fn push_at(&mut self, metrics: &MetricSet, timestamp: i64) {
+        let mut available = match self.config.wal.clone() {
+            Some(wal) => self.replay_wal(&wal).await,
+            None => true,
+        };
+
+        for payload in self.make_requests(metrics, timestamp) {
+            if available {
+                match self.send(&payload).await {
+                    Ok(()) => continue,
+                    Err(e) => {
+                        tracing::warn!("Unable to push metrics to {}: {e}", self.config.url);
+                        available = false;
+                    }
+                }
+            }
+
+            match self.config.wal.clone() {
+                Some(wal) => {
+                    if let Err(e) = self.store_wal(&wal, &payload) {
+                        tracing::error!("Unable to store remote-write request in WAL: {e}");
+                    }
+                }
+                None => tracing::warn!("Dropping remote-write request"),
+            }
+        }
+    }
+}
+
+impl PushExporter for RemoteWriter {
+    async fn push(&mut self, metrics: &MetricSet, timestamp: SystemTime) {
+        let timestamp = timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as i64;
+
+        self.push_at(metrics, timestamp).await
+    }
+}
@@ -1,108 +1,255 @@
 //! RPC metrics path.
+//!
+//! Sessions are accepted on Unix sockets (authenticated with [PeerCredentials]), or on TCP
+//! (e.g for [crate::federation]), where peers can't be authenticated and only fetch metrics.
 
-use std::{collections::HashSet, path::Path};
+use std::{future::Future, io, sync::Arc, time::Duration};
 
-use compact_str::CompactString;
-use flume::Sender;
+use compact_str::{format_compact, CompactString, ToCompactString};
+use flume::{Receiver, Sender};
+use futures::{future, pin_mut, select, stream, AsyncRead, AsyncWrite, FutureExt, StreamExt};
+use nix::errno::Errno;
 use smol::{
-    net::unix::{UnixListener, UnixStream},
-    Executor,
+    net::{
+        unix::{UnixListener, UnixStream},
+        TcpListener, TcpStream,
+    },
+    Executor, Timer,
 };
-use uuid::Uuid;
 use xcp_metrics_common::{
     openmetrics::{self, prost::Message},
-    protocol::{FetchMetrics, ProtocolMessage, RemoveFamily, RemoveMetric, XcpMetricsAsyncStream},
+    protocol::{
+        CollectionDone, CollectionTick, FetchMetrics, FetchMetricsStream, ProtocolMessage,
+        SetNamespace, SubscribeTicks, XcpMetricsAsyncStream,
+    },
 };
 
-use crate::hub::{HubPullResponse, HubPushMessage, PullMetrics};
+use crate::{
+    access::{AccessPolicy, PeerCredentials, Role},
+    collection::{self, CollectionMessage},
+    export::{self, ExportPipeline},
+    hub::{HubPushMessage, OwnerId},
+};
+
+/// A listener RPC sessions are accepted on.
+pub trait RpcListener {
+    /// Cloned to read the messages while sending ticks.
+    type Stream: AsyncRead + AsyncWrite + Clone + Unpin + Send + Sync + 'static;
+
+    /// Accept a peer, giving its stream, a description of it and its role ([None] if rejected).
+    fn accept_peer(
+        &self,
+        policy: &AccessPolicy,
+    ) -> impl Future<Output = io::Result<(Self::Stream, CompactString, Option<Role>)>>;
+}
+
+impl RpcListener for UnixListener {
+    type Stream = UnixStream;
+
+    async fn accept_peer(
+        &self,
+        policy: &AccessPolicy,
+    ) -> io::Result<(UnixStream, CompactString, Option<Role>)> {
+        let (stream, _) = self.accept().await?;
 
-struct RpcSessionState {
-    // Keep track of all registered metrics and families to unregister them properly if the plugin dies.
-    families: HashSet<CompactString>,
-    metrics: HashSet<(Uuid, CompactString)>,
+        Ok(match PeerCredentials::from_socket(&stream) {
+            Ok(peer) => {
+                let role = policy.authorize(&peer);
+                (stream, peer.to_compact_string(), role)
+            }
+            Err(e) => {
+                tracing::error!("Unable to get peer credentials: {e}");
+                (stream, "unknown peer".into(), None)
+            }
+        })
+    }
+}
 
+impl RpcListener for TcpListener {
+    type Stream = TcpStream;
+
+    /// TCP peers can't be authenticated, they are only allowed to fetch metrics.
+    ///
+    /// There is no authentication nor encryption: anyone who can reach the listener can read all
+    /// the metrics it exposes.
+    async fn accept_peer(
+        &self,
+        _policy: &AccessPolicy,
+    ) -> io::Result<(TcpStream, CompactString, Option<Role>)> {
+        let (stream, address) = self.accept().await?;
+
+        Ok((stream, format_compact!("tcp={address}"), Some(Role::Read)))
+    }
+}
+
+struct RpcSessionState<S> {
+    // The hub keeps track of all metrics and families registered with this owner, to unregister
+    // them properly if the plugin dies.
+    owner: OwnerId,
+    /// Prefix of all the family names of this session.
+    namespace: Option<CompactString>,
+    /// Whether a family has been registered (the namespace can no longer be changed).
+    registered: bool,
+
+    peer: CompactString,
+    role: Role,
+
+    export: Arc<ExportPipeline>,
     hub: Sender<HubPushMessage>,
-    stream: UnixStream,
+    collection: Sender<CollectionMessage>,
+    /// Collection ticks, once subscribed.
+    ticks: Option<Receiver<CollectionTick>>,
+    stream: S,
+    /// Disconnected when the daemon is stopping.
+    shutdown: Receiver<()>,
 }
 
-impl RpcSessionState {
+impl<S: AsyncRead + AsyncWrite + Clone + Unpin> RpcSessionState<S> {
     pub async fn run(&mut self) -> anyhow::Result<()> {
+        // Messages are read from their own stream, so that sending a tick doesn't interrupt the
+        // reception of a message.
+        let messages = stream::unfold(self.stream.clone(), |mut stream| async {
+            let message = stream.recv_message_async().await;
+            Some((message, stream))
+        })
+        .fuse();
+        pin_mut!(messages);
+
         loop {
-            let message = self.stream.recv_message_async().await?;
+            let tick = async {
+                match &self.ticks {
+                    Some(ticks) => ticks.recv_async().await.ok(),
+                    None => future::pending().await,
+                }
+            };
+
+            // Only stop between messages, to finish processing the current one.
+            // (flume futures are terminated once disconnected, which select! would skip: fuse them)
+            let message = select! {
+                message = messages.next() => message.unwrap_or_else(|| Err(io::ErrorKind::UnexpectedEof.into()))?,
+                tick = tick.fuse() => {
+                    if let Some(tick) = tick {
+                        self.stream.send_message_async(ProtocolMessage::CollectionTick(tick)).await?;
+                    }
+                    continue;
+                }
+                _ = self.shutdown.recv_async().fuse() => return Ok(()),
+            };
 
             tracing::debug!("Received {message:?}");
             self.process_message(message).await?;
         }
     }
 
+    /// Get metrics from hub, in the requested format.
+    async fn fetch_metrics(&self, format: FetchMetrics) -> anyhow::Result<Vec<u8>> {
+        collection::before_fetch(&self.collection).await;
+
+        let metrics_set = export::pull_metrics(&self.hub, self.export.view)
+            .await
+            .ok_or_else(|| anyhow::anyhow!("Hub is stopped"))?;
+        let metrics_set = self.export.apply(metrics_set);
+
+        Ok(match format {
+            FetchMetrics::OpenMetrics1 => {
+                let mut buffer = String::new();
+                openmetrics::text::write_metrics_set_text(&mut buffer, &metrics_set)?;
+
+                buffer.into_bytes()
+            }
+            FetchMetrics::OpenMetrics1Binary => {
+                openmetrics::MetricSet::from((*metrics_set).clone()).encode_to_vec()
+            }
+        })
+    }
+
+    fn namespaced(&self, name: CompactString) -> CompactString {
+        match &self.namespace {
+            Some(prefix) => format_compact!("{prefix}_{name}"),
+            None => name,
+        }
+    }
+
     pub async fn process_message(&mut self, message: ProtocolMessage) -> anyhow::Result<()> {
+        if self.role < Role::ReadWrite
+            && !matches!(
+                message,
+                ProtocolMessage::FetchMetrics(_) | ProtocolMessage::FetchMetricsStream(_)
+            )
+        {
+            anyhow::bail!("{} is not allowed to push metrics", self.peer);
+        }
+
         match message {
-            ProtocolMessage::CreateFamily(create_family) => {
-                if self.families.insert(create_family.name.clone()) {
-                    self.hub
-                        .send_async(HubPushMessage::CreateFamily(create_family))
-                        .await?
-                } else {
-                    tracing::warn!(
-                        "{:?} is registering {} twice",
-                        self.stream,
-                        create_family.name
-                    );
-                }
-            }
-            ProtocolMessage::RemoveFamily(remove_family) => {
-                if self.families.remove(remove_family.name.as_str()) {
-                    self.hub
-                        .send_async(HubPushMessage::RemoveFamily(remove_family))
-                        .await?
-                } else {
-                    tracing::warn!(
-                        "{:?} is trying to remove '{}' but hasn't registered it",
-                        self.stream,
-                        remove_family.name
-                    );
-                }
+            ProtocolMessage::CreateFamily(mut create_family) => {
+                self.registered = true;
+                create_family.name = self.namespaced(create_family.name);
+
+                self.hub
+                    .send_async(HubPushMessage::CreateFamily(self.owner, create_family))
+                    .await?
             }
-            ProtocolMessage::UpdateMetric(update_metric) => {
-                self.metrics
-                    .insert((update_metric.uuid, update_metric.family_name.clone()));
+            ProtocolMessage::RemoveFamily(mut remove_family) => {
+                remove_family.name = self.namespaced(remove_family.name);
+
                 self.hub
-                    .send_async(HubPushMessage::UpdateMetric(update_metric))
+                    .send_async(HubPushMessage::RemoveFamily(self.owner, remove_family))
                     .await?
             }
-            ProtocolMessage::RemoveMetric(remove_metric) => {
-                self.metrics
-                    .remove(&(remove_metric.uuid, remove_metric.family_name.clone()));
+            ProtocolMessage::UpdateMetric(mut update_metric) => {
+                update_metric.family_name = self.namespaced(update_metric.family_name);
+
                 self.hub
-                    .send_async(HubPushMessage::RemoveMetric(remove_metric))
+                    .send_async(HubPushMessage::UpdateMetric(self.owner, update_metric))
                     .await?
             }
+            ProtocolMessage::RemoveMetric(mut remove_metric) => {
+                remove_metric.family_name = self.namespaced(remove_metric.family_name);
 
-            ProtocolMessage::FetchMetrics(fetch_metrics) => {
-                // Get metrics from hub
-                let (sender, receiver) = flume::bounded(0);
                 self.hub
-                    .send_async(HubPushMessage::PullMetrics(PullMetrics(sender)))
-                    .await?;
+                    .send_async(HubPushMessage::RemoveMetric(self.owner, remove_metric))
+                    .await?
+            }
+            ProtocolMessage::SetNamespace(SetNamespace { prefix }) => {
+                if self.registered {
+                    tracing::warn!(
+                        "{} is setting namespace '{prefix}' after registering families",
+                        self.peer
+                    );
+                } else {
+                    self.namespace = Some(prefix);
+                }
+            }
 
-                let HubPullResponse::Metrics(metrics_set) = receiver.recv_async().await?;
+            ProtocolMessage::SubscribeTicks(SubscribeTicks) => {
+                let (sender, receiver) = flume::bounded(1);
+                self.ticks = Some(receiver);
 
-                match fetch_metrics {
-                    FetchMetrics::OpenMetrics1 => {
-                        let mut buffer = String::new();
-                        openmetrics::text::write_metrics_set_text(&mut buffer, &metrics_set)?;
+                self.collection
+                    .send_async(CollectionMessage::Subscribe(self.owner, sender))
+                    .await?
+            }
+            ProtocolMessage::CollectionDone(CollectionDone { sequence }) => {
+                self.collection
+                    .send_async(CollectionMessage::Done(self.owner, sequence))
+                    .await?
+            }
+            ProtocolMessage::CollectionTick(_) => {
+                tracing::warn!("{} sent a collection tick, ignoring it", self.peer)
+            }
 
-                        self.stream
-                            .send_message_raw_async(buffer.as_bytes())
-                            .await?;
-                    }
-                    FetchMetrics::OpenMetrics1Binary => {
-                        let buffer =
-                            openmetrics::MetricSet::from((*metrics_set).clone()).encode_to_vec();
+            ProtocolMessage::FetchMetrics(fetch_metrics) => {
+                let buffer = self.fetch_metrics(fetch_metrics).await?;
 
-                        self.stream.send_message_raw_async(&buffer).await?;
-                    }
-                }
+                self.stream.send_message_raw_async(&buffer).await?;
+            }
+            ProtocolMessage::FetchMetricsStream(FetchMetricsStream {
+                format,
+                compression,
+            }) => {
+                let buffer = compression.compress(&self.fetch_metrics(format).await?)?;
+
+                self.stream.send_message_stream_async(&buffer).await?;
             }
         }
 
@@ -110,49 +257,127 @@ impl RpcSessionState {
     }
 }
 
-async fn rpc_session(stream: UnixStream, hub: Sender<HubPushMessage>) {
+async fn rpc_session<S: AsyncRead + AsyncWrite + Clone + Unpin>(
+    stream: S,
+    peer: CompactString,
+    role: Role,
+    export: Arc<ExportPipeline>,
+    hub: Sender<HubPushMessage>,
+    collection: Sender<CollectionMessage>,
+    shutdown: Receiver<()>,
+) {
     let mut state = RpcSessionState {
-        families: HashSet::new(),
-        metrics: HashSet::new(),
+        owner: OwnerId::allocate(),
+        namespace: None,
+        registered: false,
+        peer,
+        role,
+        export,
         hub,
+        collection,
+        ticks: None,
         stream,
+        shutdown,
     };
 
     if let Err(e) = state.run().await {
         tracing::debug!("RPC session error: {e}")
     }
 
+    state
+        .collection
+        .send(CollectionMessage::Unsubscribe(state.owner))
+        .ok();
+
     // We need to remove all the families/metrics made by the plugin.
-    state.metrics.into_iter().for_each(|(uuid, family_name)| {
-        state
-            .hub
-            .send(HubPushMessage::RemoveMetric(RemoveMetric {
-                family_name,
-                uuid,
-            }))
-            .ok();
-    });
-
-    state.families.into_iter().for_each(|name| {
-        state
-            .hub
-            .send(HubPushMessage::RemoveFamily(RemoveFamily { name }))
-            .ok();
-    });
+    state
+        .hub
+        .send(HubPushMessage::ReleaseOwner(state.owner))
+        .ok();
+}
+
+/// Delay before accepting sessions again after a transient error.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Whether an `accept` error only concerns the connection being accepted, or a temporary lack of
+/// resources, so that the listener can keep going.
+fn is_transient_accept_error(e: &io::Error) -> bool {
+    matches!(
+        e.raw_os_error().map(Errno::from_raw),
+        Some(
+            Errno::ECONNABORTED
+                | Errno::ECONNRESET
+                | Errno::EINTR
+                | Errno::EPROTO
+                | Errno::EPERM
+                | Errno::ETIMEDOUT
+                | Errno::EMFILE
+                | Errno::ENFILE
+                | Errno::ENOBUFS
+                | Errno::ENOMEM
+        )
+    )
 }
 
-pub async fn run(path: &Path, hub: Sender<HubPushMessage>) -> anyhow::Result<()> {
-    let listener = UnixListener::bind(path)?;
+/// Accept RPC sessions on `listener`, peers are given at most `max_role`.
+///
+/// Once `shutdown` is disconnected, stop accepting sessions and wait for the current ones to end.
+pub async fn run(
+    listener: impl RpcListener,
+    hub: Sender<HubPushMessage>,
+    policy: Arc<AccessPolicy>,
+    max_role: Role,
+    export: Arc<ExportPipeline>,
+    collection: Sender<CollectionMessage>,
+    shutdown: Receiver<()>,
+) -> anyhow::Result<()> {
     let executor = Executor::new();
 
     executor
         .run(async {
+            let mut sessions = vec![];
+
             loop {
-                let (stream, _) = listener.accept().await?;
+                let accepted = select! {
+                    accepted = listener.accept_peer(&policy).fuse() => accepted,
+                    _ = shutdown.recv_async().fuse() => break,
+                };
+
+                let (stream, peer, role) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) if is_transient_accept_error(&e) => {
+                        tracing::warn!("Unable to accept RPC session: {e}");
+                        // Don't spin while e.g out of file descriptors.
+                        Timer::after(ACCEPT_RETRY_DELAY).await;
+                        continue;
+                    }
+                    Err(e) => return Err(e.into()),
+                };
+
+                let Some(role) = role.map(|role| role.min(max_role)) else {
+                    tracing::warn!("Rejecting {peer}");
+                    continue;
+                };
+
+                tracing::debug!("Accepted {peer} as {role:?}");
                 let hub = hub.clone();
 
-                executor.spawn(rpc_session(stream, hub)).detach();
+                sessions.retain(|session: &smol::Task<()>| !session.is_finished());
+                sessions.push(executor.spawn(rpc_session(
+                    stream,
+                    peer,
+                    role,
+                    export.clone(),
+                    hub,
+                    collection.clone(),
+                    shutdown.clone(),
+                )));
             }
+
+            tracing::info!("Waiting for {} RPC sessions to end", sessions.len());
+            future::join_all(sessions).await;
+
+            Ok(())
         })
         .await
 }
@@ -0,0 +1,403 @@
+//! Recording rules: families derived from other families of the hub.
+//!
+//! Rules are loaded from a JSON file (`--rules`), e.g
+//! ```json
+//! [
+//!   {
+//!     "record": "xen_memory_usage_guest",
+//!     "expr": { "binary": {
+//!       "op": "sub",
+//!       "left": { "family": "xen_memory_usage_total" },
+//!       "right": { "family": "xen_memory_usage_free" },
+//!       "on": ["domain"]
+//!     } }
+//!   },
+//!   {
+//!     "record": "xen_cpu_time_avg",
+//!     "expr": { "aggregate": { "op": "avg", "by": [], "expr": { "family": "xen_cpu_time" } } }
+//!   }
+//! ]
+//! ```
+//!
+//! The hub periodically evaluates them (in order, so a rule can use the result of a previous one)
+//! and materializes their results as [MetricType::Gauge] families.
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    time::{Duration, Instant, SystemTime},
+};
+
+use compact_str::CompactString;
+use serde::Deserialize;
+use uuid::Uuid;
+use xcp_metrics_common::metrics::{
+    Label, Metric, MetricFamily, MetricSet, MetricType, MetricValue, NumberValue,
+};
+
+/// Default interval between two evaluations of the rules.
+pub const DEFAULT_RULES_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Labels of a series, sorted by name.
+pub type SeriesLabels = Box<[Label]>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+impl BinaryOp {
+    fn apply(self, left: f64, right: f64) -> f64 {
+        match self {
+            BinaryOp::Add => left + right,
+            BinaryOp::Sub => left - right,
+            BinaryOp::Mul => left * right,
+            BinaryOp::Div => left / right,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AggregateOp {
+    Sum,
+    Avg,
+    Min,
+    Max,
+}
+
+/// Expression of a recording rule.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Expr {
+    /// All the numeric series of a family.
+    Family(CompactString),
+    /// A constant.
+    Scalar(f64),
+    /// Arithmetic between two expressions.
+    ///
+    /// Series are joined on the `on` labels (all labels if empty), and keep only these labels.
+    /// Matching is one-to-one, series that share their `on` labels with others are dropped.
+    Binary {
+        op: BinaryOp,
+        left: Box<Expr>,
+        right: Box<Expr>,
+        #[serde(default)]
+        on: Vec<CompactString>,
+    },
+    /// Aggregate series, keeping only the `by` labels.
+    Aggregate {
+        op: AggregateOp,
+        #[serde(default)]
+        by: Vec<CompactString>,
+        expr: Box<Expr>,
+    },
+    /// Per-second rate of a counter, between two evaluations.
+    ///
+    /// Resets are detected from decreases, and from the `created` timestamps of the counters (when
+    /// `expr` is a family).
+    Rate {
+        expr: Box<Expr>,
+        /// Previous value (and `created` timestamp) of each series.
+        #[serde(skip)]
+        previous: HashMap<SeriesLabels, (f64, Instant, Option<SystemTime>)>,
+    },
+}
+
+/// Result of an [Expr].
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Scalar(f64),
+    Vector(Vec<(SeriesLabels, f64)>),
+}
+
+/// Get the numeric value of a metric (if it has one).
+fn numeric_value(value: &MetricValue) -> Option<f64> {
+    let number = match value {
+        MetricValue::Unknown(number) | MetricValue::Gauge(number) => number,
+        MetricValue::Counter { total, .. } => total,
+        _ => return None,
+    };
+
+    match *number {
+        NumberValue::Double(value) => Some(value),
+        NumberValue::Int64(value) => Some(value as f64),
+        NumberValue::Undefined => None,
+    }
+}
+
+/// `created` timestamps of the counters of the `name` family.
+fn counters_created(metrics: &MetricSet, name: &str) -> HashMap<SeriesLabels, SystemTime> {
+    metrics
+        .families
+        .get(name)
+        .map(|family| {
+            family
+                .metrics
+                .values()
+                .filter_map(|metric| match metric.value {
+                    MetricValue::Counter {
+                        created: Some(created),
+                        ..
+                    } => Some((sorted_labels(&metric.labels), created)),
+                    _ => None,
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn sorted_labels(labels: &[Label]) -> SeriesLabels {
+    let mut labels = labels.to_vec();
+    labels.sort_by(|a, b| a.name.cmp(&b.name));
+    labels.into()
+}
+
+/// Only keep the `names` labels (all of them if `names` is empty).
+fn keep_labels(labels: &[Label], names: &[CompactString]) -> SeriesLabels {
+    if names.is_empty() {
+        return labels.into();
+    }
+
+    labels
+        .iter()
+        .filter(|label| names.contains(&label.name))
+        .cloned()
+        .collect()
+}
+
+/// Group series by their `on` labels (in order), [None] for the groups of several series as only
+/// one-to-one matching is allowed (like PromQL).
+fn match_groups(
+    series: Vec<(SeriesLabels, f64)>,
+    on: &[CompactString],
+) -> Vec<(SeriesLabels, Option<f64>)> {
+    let mut groups: Vec<(SeriesLabels, Option<f64>)> = vec![];
+    let mut indices: HashMap<SeriesLabels, usize> = HashMap::new();
+
+    for (labels, value) in series {
+        match indices.entry(keep_labels(&labels, on)) {
+            Entry::Occupied(entry) => groups[*entry.get()].1 = None,
+            Entry::Vacant(entry) => {
+                groups.push((entry.key().clone(), Some(value)));
+                entry.insert(groups.len() - 1);
+            }
+        }
+    }
+
+    groups
+}
+
+impl Expr {
+    pub fn evaluate(&mut self, metrics: &MetricSet, now: Instant) -> Value {
+        match self {
+            Expr::Family(name) => Value::Vector(
+                metrics
+                    .families
+                    .get(name)
+                    .map(|family| {
+                        family
+                            .metrics
+                            .values()
+                            .filter_map(|metric| {
+                                Some((sorted_labels(&metric.labels), numeric_value(&metric.value)?))
+                            })
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+            ),
+            Expr::Scalar(value) => Value::Scalar(*value),
+            Expr::Binary {
+                op,
+                left,
+                right,
+                on,
+            } => {
+                let op = *op;
+
+                match (left.evaluate(metrics, now), right.evaluate(metrics, now)) {
+                    (Value::Scalar(left), Value::Scalar(right)) => {
+                        Value::Scalar(op.apply(left, right))
+                    }
+                    (Value::Vector(left), Value::Scalar(right)) => Value::Vector(
+                        left.into_iter()
+                            .map(|(labels, value)| (labels, op.apply(value, right)))
+                            .collect(),
+                    ),
+                    (Value::Scalar(left), Value::Vector(right)) => Value::Vector(
+                        right
+                            .into_iter()
+                            .map(|(labels, value)| (labels, op.apply(left, value)))
+                            .collect(),
+                    ),
+                    (Value::Vector(left), Value::Vector(right)) => {
+                        let right: HashMap<SeriesLabels, Option<f64>> =
+                            match_groups(right, on).into_iter().collect();
+
+                        Value::Vector(
+                            match_groups(left, on)
+                                .into_iter()
+                                .filter_map(|(labels, value)| match (value, *right.get(&labels)?) {
+                                    (Some(left), Some(right)) => {
+                                        Some((labels, op.apply(left, right)))
+                                    }
+                                    _ => {
+                                        tracing::warn!(
+                                            "Several series match {labels:?}, dropping them"
+                                        );
+                                        None
+                                    }
+                                })
+                                .collect(),
+                        )
+                    }
+                }
+            }
+            Expr::Aggregate { op, by, expr } => {
+                let Value::Vector(series) = expr.evaluate(metrics, now) else {
+                    tracing::warn!("Aggregating a scalar");
+                    return Value::Vector(vec![]);
+                };
+
+                // (value, count) of each group
+                let mut groups: HashMap<SeriesLabels, (f64, usize)> = HashMap::new();
+
+                for (labels, value) in series {
+                    let labels: SeriesLabels = labels
+                        .iter()
+                        .filter(|label| by.contains(&label.name))
+                        .cloned()
+                        .collect();
+
+                    groups
+                        .entry(labels)
+                        .and_modify(|(current, count)| {
+                            *current = match op {
+                                AggregateOp::Sum | AggregateOp::Avg => *current + value,
+                                AggregateOp::Min => current.min(value),
+                                AggregateOp::Max => current.max(value),
+                            };
+                            *count += 1;
+                        })
+                        .or_insert((value, 1));
+                }
+
+                Value::Vector(
+                    groups
+                        .into_iter()
+                        .map(|(labels, (value, count))| match op {
+                            AggregateOp::Avg => (labels, value / count as f64),
+                            _ => (labels, value),
+                        })
+                        .collect(),
+                )
+            }
+            Expr::Rate { expr, previous } => {
+                let Value::Vector(series) = expr.evaluate(metrics, now) else {
+                    tracing::warn!("Computing the rate of a scalar");
+                    return Value::Vector(vec![]);
+                };
+
+                let created = match expr.as_ref() {
+                    Expr::Family(name) => counters_created(metrics, name),
+                    _ => HashMap::new(),
+                };
+
+                let current: HashMap<SeriesLabels, (f64, Instant, Option<SystemTime>)> = series
+                    .into_iter()
+                    .map(|(labels, value)| {
+                        let created = created.get(&labels).copied();
+                        (labels, (value, now, created))
+                    })
+                    .collect();
+
+                let rates = current
+                    .iter()
+                    .filter_map(|(labels, &(value, now, created))| {
+                        let &(previous_value, previous_time, previous_created) =
+                            previous.get(labels)?;
+                        let elapsed = now.duration_since(previous_time).as_secs_f64();
+
+                        if elapsed == 0.0 {
+                            return None;
+                        }
+
+                        // The counter has been reset, assume it restarted from 0.
+                        let recreated = matches!(
+                            (created, previous_created),
+                            (Some(created), Some(previous)) if created > previous
+                        );
+
+                        let increase = if value < previous_value || recreated {
+                            value
+                        } else {
+                            value - previous_value
+                        };
+
+                        Some((labels.clone(), increase / elapsed))
+                    })
+                    .collect();
+
+                *previous = current;
+                Value::Vector(rates)
+            }
+        }
+    }
+}
+
+/// A rule recording the result of `expr` as the `record` family.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RecordingRule {
+    pub record: CompactString,
+    #[serde(default)]
+    pub unit: CompactString,
+    #[serde(default)]
+    pub help: CompactString,
+    pub expr: Expr,
+
+    /// UUID of each recorded series, to keep them stable between evaluations.
+    #[serde(skip)]
+    series: HashMap<SeriesLabels, Uuid>,
+}
+
+impl RecordingRule {
+    /// Evaluate the rule, making the family to record.
+    pub fn evaluate(&mut self, metrics: &MetricSet, now: Instant) -> MetricFamily {
+        let series = match self.expr.evaluate(metrics, now) {
+            Value::Scalar(value) => vec![(SeriesLabels::default(), value)],
+            Value::Vector(series) => series,
+        };
+
+        // Forget series that no longer exist.
+        let mut previous_series = std::mem::take(&mut self.series);
+
+        MetricFamily {
+            reference_count: 1,
+            metric_type: MetricType::Gauge,
+            unit: self.unit.clone(),
+            help: self.help.clone(),
+            metrics: series
+                .into_iter()
+                .map(|(labels, value)| {
+                    let uuid = previous_series.remove(&labels).unwrap_or_else(Uuid::new_v4);
+                    self.series.insert(labels.clone(), uuid);
+
+                    (
+                        uuid,
+                        Metric {
+                            labels,
+                            value: MetricValue::Gauge(NumberValue::Double(value)),
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Load recording rules from a JSON file.
+pub fn load_rules(path: &std::path::Path) -> anyhow::Result<Vec<RecordingRule>> {
+    Ok(serde_json::from_reader(std::fs::File::open(path)?)?)
+}
@@ -0,0 +1,383 @@
+//! StatsD ingestion, for scripts that can't speak the xcp-metrics protocol.
+//!
+//! Listens on UDP and/or Unix datagram sockets for `name:value|type[|@rate][|#tag:value,...]`
+//! lines (DogStatsD tags are used as labels):
+//! - counters (`c`) are summed (scaled by their sample rate) into counter families,
+//! - gauges (`g`) are set, or adjusted if the value has an explicit sign,
+//! - timers (`ms`, converted to seconds) and histograms (`h`, `d`) are aggregated into summaries,
+//!   their quantiles being computed over the samples of each flush interval.
+//!
+//! The metrics are pushed to the hub at each flush interval.
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    io,
+    path::PathBuf,
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+
+use compact_str::CompactString;
+use flume::Sender;
+use futures::future;
+use smol::{
+    net::{unix::UnixDatagram, UdpSocket},
+    Timer,
+};
+use uuid::Uuid;
+use xcp_metrics_common::metrics::{
+    Label, Metric, MetricFamily, MetricSet, MetricType, MetricValue, NumberValue, Quantile,
+};
+
+use crate::{hub::HubPushMessage, ingest::HubSync};
+
+pub const DEFAULT_FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Quantiles computed for timers and histograms.
+pub const QUANTILES: [f64; 3] = [0.5, 0.9, 0.99];
+
+/// Where to listen for StatsD packets (`udp://host:port` or `unix:///path`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StatsdListen {
+    Udp(CompactString),
+    Unix(PathBuf),
+}
+
+impl FromStr for StatsdListen {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(address) = s.strip_prefix("udp://") {
+            Ok(Self::Udp(address.into()))
+        } else if let Some(path) = s.strip_prefix("unix://") {
+            Ok(Self::Unix(path.into()))
+        } else {
+            Err(format!(
+                "Invalid StatsD address '{s}' (expected udp://host:port or unix:///path)"
+            ))
+        }
+    }
+}
+
+pub enum StatsdSocket {
+    Udp(UdpSocket),
+    Unix(UnixDatagram),
+}
+
+impl StatsdListen {
+    pub fn bind(&self) -> anyhow::Result<StatsdSocket> {
+        Ok(match self {
+            StatsdListen::Udp(address) => StatsdSocket::Udp(UdpSocket::try_from(
+                std::net::UdpSocket::bind(address.as_str())?,
+            )?),
+            StatsdListen::Unix(path) => StatsdSocket::Unix(UnixDatagram::try_from(
+                std::os::unix::net::UnixDatagram::bind(path)?,
+            )?),
+        })
+    }
+}
+
+impl StatsdSocket {
+    async fn recv(&self, buffer: &mut [u8]) -> io::Result<usize> {
+        match self {
+            StatsdSocket::Udp(socket) => socket.recv(buffer).await,
+            StatsdSocket::Unix(socket) => socket.recv(buffer).await,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StatsdType {
+    Counter,
+    Gauge,
+    Timer,
+    Histogram,
+}
+
+#[derive(Debug, Clone)]
+enum StatsdValue {
+    Counter(f64),
+    Gauge(f64),
+    Summary {
+        count: u64,
+        sum: f64,
+        /// Samples of the current flush interval.
+        window: Vec<f64>,
+        quantiles: Box<[Quantile]>,
+    },
+}
+
+#[derive(Debug)]
+struct StatsdFamily {
+    kind: StatsdType,
+    metrics: HashMap<Box<[Label]>, StatsdValue>,
+}
+
+/// Make a StatsD name usable as a family name.
+fn sanitize_name(name: &str) -> CompactString {
+    name.chars()
+        .map(|c| match c {
+            'A'..='Z' | 'a'..='z' | '0'..='9' | '_' | ':' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+fn parse_tags(tags: &str) -> Box<[Label]> {
+    let mut labels: Vec<Label> = tags
+        .split(',')
+        .filter_map(|tag| {
+            // Tags without value are ignored.
+            let (name, value) = tag.split_once(':')?;
+
+            Some(Label {
+                name: sanitize_name(name),
+                value: value.into(),
+            })
+        })
+        .collect();
+
+    labels.sort_by(|a, b| a.name.cmp(&b.name));
+    labels.into()
+}
+
+fn quantiles(window: &mut [f64]) -> Box<[Quantile]> {
+    window.sort_by(f64::total_cmp);
+
+    QUANTILES
+        .iter()
+        .map(|&quantile| Quantile {
+            quantile,
+            value: window[((window.len() - 1) as f64 * quantile).round() as usize],
+        })
+        .collect()
+}
+
+/// Aggregated StatsD metrics.
+#[derive(Debug, Default)]
+pub struct StatsdAggregator {
+    families: HashMap<CompactString, StatsdFamily>,
+}
+
+impl StatsdAggregator {
+    /// Ingest a StatsD line.
+    pub fn ingest_line(&mut self, line: &str) -> Result<(), String> {
+        let mut fields = line.split('|');
+
+        let (name, value) = fields
+            .next()
+            .and_then(|sample| sample.rsplit_once(':'))
+            .ok_or_else(|| format!("Invalid StatsD line '{line}'"))?;
+
+        let kind = match fields.next() {
+            Some("c") => StatsdType::Counter,
+            Some("g") => StatsdType::Gauge,
+            Some("ms") => StatsdType::Timer,
+            Some("h" | "d") => StatsdType::Histogram,
+            Some(kind) => return Err(format!("Unsupported StatsD type '{kind}'")),
+            None => return Err(format!("Missing type in '{line}'")),
+        };
+
+        let mut rate = 1.0;
+        let mut labels: Box<[Label]> = [].into();
+
+        for field in fields {
+            if let Some(sample_rate) = field.strip_prefix('@') {
+                rate = sample_rate
+                    .parse()
+                    .ok()
+                    .filter(|&rate: &f64| rate > 0.0 && rate <= 1.0)
+                    .ok_or_else(|| format!("Invalid sample rate '{sample_rate}'"))?;
+            } else if let Some(tags) = field.strip_prefix('#') {
+                labels = parse_tags(tags);
+            }
+        }
+
+        let number: f64 = value
+            .parse()
+            .map_err(|_| format!("Invalid StatsD value '{value}'"))?;
+
+        let family = self
+            .families
+            .entry(sanitize_name(name))
+            .or_insert_with(|| StatsdFamily {
+                kind,
+                metrics: HashMap::new(),
+            });
+
+        if family.kind != kind {
+            return Err(format!("'{name}' is a {:?}, not a {kind:?}", family.kind));
+        }
+
+        // Values of a family are all of the family kind.
+        let metric = family.metrics.entry(labels).or_insert_with(|| match kind {
+            StatsdType::Counter => StatsdValue::Counter(0.0),
+            StatsdType::Gauge => StatsdValue::Gauge(0.0),
+            StatsdType::Timer | StatsdType::Histogram => StatsdValue::Summary {
+                count: 0,
+                sum: 0.0,
+                window: vec![],
+                quantiles: [].into(),
+            },
+        });
+
+        match metric {
+            StatsdValue::Counter(total) => *total += number / rate,
+            // Signed values adjust gauges.
+            StatsdValue::Gauge(gauge) if value.starts_with(['+', '-']) => *gauge += number,
+            StatsdValue::Gauge(gauge) => *gauge = number,
+            StatsdValue::Summary {
+                count, sum, window, ..
+            } => {
+                let number = match kind {
+                    StatsdType::Timer => number / 1000.0,
+                    _ => number,
+                };
+
+                *count += (1.0 / rate).round() as u64;
+                *sum += number / rate;
+                window.push(number);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Ingest a StatsD packet (newline-separated lines).
+    pub fn ingest(&mut self, packet: &str) {
+        for line in packet
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+        {
+            if let Err(e) = self.ingest_line(line) {
+                tracing::debug!("{e}");
+            }
+        }
+    }
+
+    /// Make the current metrics, ending the current flush interval.
+    pub fn flush(&mut self) -> MetricSet {
+        let families = self
+            .families
+            .iter_mut()
+            .map(|(name, family)| {
+                let (metric_type, unit) = match family.kind {
+                    StatsdType::Counter => (MetricType::Counter, ""),
+                    StatsdType::Gauge => (MetricType::Gauge, ""),
+                    StatsdType::Timer => (MetricType::Summary, "seconds"),
+                    StatsdType::Histogram => (MetricType::Summary, ""),
+                };
+
+                let metrics = family
+                    .metrics
+                    .iter_mut()
+                    .map(|(labels, value)| {
+                        let value = match value {
+                            StatsdValue::Counter(total) => MetricValue::Counter {
+                                total: NumberValue::Double(*total),
+                                created: None,
+                                exemplar: None,
+                            },
+                            StatsdValue::Gauge(value) => {
+                                MetricValue::Gauge(NumberValue::Double(*value))
+                            }
+                            StatsdValue::Summary {
+                                count,
+                                sum,
+                                window,
+                                quantiles: previous,
+                            } => {
+                                // Keep the previous quantiles if there was no sample.
+                                if !window.is_empty() {
+                                    *previous = quantiles(window);
+                                    window.clear();
+                                }
+
+                                MetricValue::Summary {
+                                    sum: NumberValue::Double(*sum),
+                                    count: *count,
+                                    created: SystemTime::UNIX_EPOCH,
+                                    quantile: previous.clone(),
+                                }
+                            }
+                        };
+
+                        (
+                            Uuid::new_v4(),
+                            Metric {
+                                labels: labels.clone(),
+                                value,
+                            },
+                        )
+                    })
+                    .collect();
+
+                (
+                    name.clone(),
+                    MetricFamily {
+                        reference_count: 1,
+                        metric_type,
+                        unit: unit.into(),
+                        help: "StatsD metric".into(),
+                        metrics,
+                    },
+                )
+            })
+            .collect();
+
+        MetricSet { families }
+    }
+}
+
+/// Receive StatsD packets on `sockets`, and push the metrics to the hub at each `flush_interval`.
+pub async fn run(
+    sockets: Vec<StatsdSocket>,
+    flush_interval: Duration,
+    hub: Sender<HubPushMessage>,
+) {
+    let aggregator = RefCell::new(StatsdAggregator::default());
+
+    let receive = future::join_all(sockets.iter().map(|socket| {
+        let aggregator = &aggregator;
+
+        async move {
+            let mut buffer = vec![0; u16::MAX as usize];
+
+            loop {
+                match socket.recv(&mut buffer).await {
+                    Ok(size) => aggregator
+                        .borrow_mut()
+                        .ingest(&String::from_utf8_lossy(&buffer[..size])),
+                    Err(e) => {
+                        tracing::error!("Unable to receive StatsD packet: {e}");
+                        return;
+                    }
+                }
+            }
+        }
+    }));
+
+    let flush = async {
+        let mut sync = HubSync::new(hub);
+
+        loop {
+            Timer::after(flush_interval).await;
+
+            let metrics = aggregator.borrow_mut().flush();
+
+            if let Err(e) = sync.sync(&metrics).await {
+                tracing::error!("Unable to push StatsD metrics: {e}");
+                return;
+            }
+        }
+    };
+
+    smol::future::or(
+        async {
+            receive.await;
+        },
+        flush,
+    )
+    .await
+}
@@ -0,0 +1,94 @@
+//! systemd integration: socket activation and service notifications (`sd_notify`).
+//!
+//! All these functions are no-ops when the daemon isn't started by systemd.
+use std::{
+    env,
+    ffi::OsStr,
+    io,
+    os::{
+        fd::{FromRawFd, OwnedFd, RawFd},
+        linux::net::SocketAddrExt,
+        unix::{
+            ffi::OsStrExt,
+            net::{SocketAddr, UnixDatagram},
+        },
+    },
+    process,
+    time::Duration,
+};
+
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+
+/// First file descriptor passed by systemd.
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// Check if a `*_PID` variable targets this process (if defined).
+fn is_target_pid(variable: &str) -> bool {
+    env::var(variable)
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .is_none_or(|pid| pid == process::id())
+}
+
+/// Take the listening sockets passed by systemd (`LISTEN_FDS`), along with their name
+/// (`FileDescriptorName=` of the socket unit).
+pub fn listen_fds() -> Vec<(String, OwnedFd)> {
+    let count: RawFd = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse().ok())
+        .unwrap_or(0);
+    let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+    let is_target = env::var_os("LISTEN_PID").is_some() && is_target_pid("LISTEN_PID");
+
+    // Don't let these variables leak to child processes.
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    if !is_target {
+        return vec![];
+    }
+
+    let mut names = names.split(':');
+
+    (SD_LISTEN_FDS_START..SD_LISTEN_FDS_START + count)
+        .map(|fd| {
+            if let Err(e) = fcntl(fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC)) {
+                tracing::warn!("Unable to set FD_CLOEXEC on passed socket {fd}: {e}");
+            }
+
+            // SAFETY: systemd passed us the ownership of these file descriptors.
+            let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+            (names.next().unwrap_or_default().to_string(), fd)
+        })
+        .collect()
+}
+
+/// Notify systemd of a service state change (e.g `READY=1`).
+pub fn notify(state: &str) -> io::Result<()> {
+    let Some(socket_path) = env::var_os("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+
+    let address = match socket_path.as_bytes().strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(OsStr::new(&socket_path))?,
+    };
+
+    UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &address)?;
+
+    Ok(())
+}
+
+/// Interval at which `WATCHDOG=1` must be sent, if systemd watchdog is enabled.
+pub fn watchdog_interval() -> Option<Duration> {
+    if !is_target_pid("WATCHDOG_PID") {
+        return None;
+    }
+
+    let timeout = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+
+    // Ping twice per timeout period, as recommended by sd_watchdog_enabled(3).
+    Some(Duration::from_micros(timeout) / 2)
+}
@@ -0,0 +1,1547 @@
+//! Metrics hub, access control, recording rules, alerts, export, ingestion and federation tests
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
+
+use compact_str::CompactString;
+use futures::{AsyncReadExt, AsyncWriteExt};
+use nix::unistd::{Gid, Pid, Uid};
+use smol::net::{
+    unix::{UnixListener, UnixStream},
+    TcpListener, UdpSocket,
+};
+use xcp_metrics_common::{
+    metrics::{Label, Metric, MetricFamily, MetricSet, MetricType, MetricValue, NumberValue},
+    openmetrics::remote_write::decode_write_request,
+    protocol::{
+        CreateFamily, ProtocolMessage, RemoveMetric, SetNamespace, UpdateMetric,
+        XcpMetricsAsyncStream,
+    },
+};
+
+use crate::{
+    access::{AccessPolicy, PeerCredentials, PeerMatcher, Role},
+    alerts::{AlertRule, AlertStatus},
+    bind_statsd_socket,
+    collection::{self, CollectionConfig, CollectionMessage},
+    counters::COUNTER_RESETS_FAMILY,
+    export::{ExportPipeline, MetricsView, PushExporter, PushTarget, MAX_DATAGRAM_SIZE},
+    federation,
+    host::{HostIdentity, HostLabelsMode, StaticLabel, HOST_INFO_FAMILY},
+    http::Url,
+    hub::{HubPullResponse, HubPushMessage, MetricsHub, OwnerId, PullMetrics},
+    ingest::HubSync,
+    line_export::{LineExporter, LineFormat},
+    otlp::OtlpExporter,
+    parse_interval,
+    remote_write::{RemoteWriteConfig, RemoteWriter},
+    rpc,
+    rules::RecordingRule,
+    statsd::{StatsdAggregator, StatsdListen},
+    textfile::read_textfiles,
+};
+
+/// Run a hub over `messages`, and return the resulting metrics.
+fn run_hub(messages: Vec<HubPushMessage>) -> MetricSet {
+    let (hub_sender, hub_receiver) = flume::unbounded();
+    let (sender, receiver) = flume::unbounded();
+
+    messages
+        .into_iter()
+        .chain([HubPushMessage::PullMetrics(PullMetrics(sender))])
+        .for_each(|message| hub_sender.send(message).unwrap());
+    drop(hub_sender);
+
+    smol::block_on(MetricsHub::default().run(hub_receiver));
+
+    let HubPullResponse::Metrics(metrics) = receiver.recv().unwrap();
+    (*metrics).clone()
+}
+
+fn create_family(owner: OwnerId) -> HubPushMessage {
+    HubPushMessage::CreateFamily(
+        owner,
+        CreateFamily {
+            name: "test".into(),
+            metric_type: MetricType::Gauge,
+            unit: "".into(),
+            help: "".into(),
+        },
+    )
+}
+
+fn update_metric(owner: OwnerId, uuid: uuid::Uuid, value: i64) -> HubPushMessage {
+    HubPushMessage::UpdateMetric(
+        owner,
+        UpdateMetric {
+            family_name: "test".into(),
+            metric: Metric {
+                labels: vec![].into(),
+                value: MetricValue::Gauge(NumberValue::Int64(value)),
+            },
+            uuid,
+        },
+    )
+}
+
+/// A owner can't overwrite a metric it doesn't own.
+#[test]
+fn hub_reject_foreign_update() {
+    let (owner_a, owner_b) = (OwnerId::allocate(), OwnerId::allocate());
+    let uuid = uuid::Uuid::new_v4();
+
+    let metrics = run_hub(vec![
+        create_family(owner_a),
+        create_family(owner_b),
+        update_metric(owner_a, uuid, 1),
+        update_metric(owner_b, uuid, 2),
+    ]);
+
+    let family = &metrics.families["test"];
+    assert_eq!(family.reference_count, 2);
+    assert_eq!(
+        family.metrics[&uuid].value,
+        MetricValue::Gauge(NumberValue::Int64(1))
+    );
+}
+
+/// A owner can't remove a metric it doesn't own, nor update a family it hasn't registered.
+#[test]
+fn hub_reject_foreign_removal() {
+    let (owner_a, owner_b) = (OwnerId::allocate(), OwnerId::allocate());
+    let uuid = uuid::Uuid::new_v4();
+
+    let metrics = run_hub(vec![
+        create_family(owner_a),
+        update_metric(owner_a, uuid, 1),
+        update_metric(owner_b, uuid::Uuid::new_v4(), 2),
+        HubPushMessage::RemoveMetric(
+            owner_b,
+            RemoveMetric {
+                family_name: "test".into(),
+                uuid,
+            },
+        ),
+    ]);
+
+    let family = &metrics.families["test"];
+    assert_eq!(family.metrics.len(), 1);
+    assert!(family.metrics.contains_key(&uuid));
+}
+
+/// Releasing a owner only removes its own metrics and family references.
+#[test]
+fn hub_release_owner() {
+    let (owner_a, owner_b) = (OwnerId::allocate(), OwnerId::allocate());
+    let (uuid_a, uuid_b) = (uuid::Uuid::new_v4(), uuid::Uuid::new_v4());
+
+    let metrics = run_hub(vec![
+        create_family(owner_a),
+        create_family(owner_b),
+        update_metric(owner_a, uuid_a, 1),
+        update_metric(owner_b, uuid_b, 2),
+        HubPushMessage::ReleaseOwner(owner_a),
+    ]);
+
+    let family = &metrics.families["test"];
+    assert_eq!(family.reference_count, 1);
+    assert_eq!(family.metrics.len(), 1);
+    assert!(family.metrics.contains_key(&uuid_b));
+
+    let metrics = run_hub(vec![
+        create_family(owner_a),
+        update_metric(owner_a, uuid_a, 1),
+        HubPushMessage::ReleaseOwner(owner_a),
+    ]);
+
+    assert!(metrics.families.is_empty());
+}
+
+fn counter_messages(owner: OwnerId, uuid: uuid::Uuid, totals: &[i64]) -> Vec<HubPushMessage> {
+    let create = HubPushMessage::CreateFamily(
+        owner,
+        CreateFamily {
+            name: "counter".into(),
+            metric_type: MetricType::Counter,
+            unit: "".into(),
+            help: "".into(),
+        },
+    );
+
+    [create]
+        .into_iter()
+        .chain(totals.iter().map(|&total| {
+            HubPushMessage::UpdateMetric(
+                owner,
+                UpdateMetric {
+                    family_name: "counter".into(),
+                    metric: Metric {
+                        labels: [label("domain", "0")].into(),
+                        value: MetricValue::Counter {
+                            total: NumberValue::Int64(total),
+                            created: None,
+                            exemplar: None,
+                        },
+                    },
+                    uuid,
+                },
+            )
+        }))
+        .collect()
+}
+
+fn counter_created(metrics: &MetricSet) -> SystemTime {
+    match metrics.families["counter"]
+        .metrics
+        .values()
+        .next()
+        .unwrap()
+        .value
+    {
+        MetricValue::Counter {
+            created: Some(created),
+            ..
+        } => created,
+        ref value => panic!("unexpected value {value:?}"),
+    }
+}
+
+/// The hub sets the `created` timestamp of counters, and detects the resets of restarted plugins.
+#[test]
+fn hub_counter_resets() {
+    let (owner_a, owner_b) = (OwnerId::allocate(), OwnerId::allocate());
+
+    let metrics = run_hub(counter_messages(owner_a, uuid::Uuid::new_v4(), &[10, 20]));
+    assert!(!metrics.families.contains_key(COUNTER_RESETS_FAMILY));
+    counter_created(&metrics);
+
+    let (hub_sender, hub_receiver) = flume::unbounded();
+    let pull = |hub_sender: &flume::Sender<HubPushMessage>| {
+        let (sender, receiver) = flume::unbounded();
+        hub_sender
+            .send(HubPushMessage::PullMetrics(PullMetrics(sender)))
+            .unwrap();
+        receiver
+    };
+
+    // The plugin restarts, with new UUIDs and counters starting over.
+    counter_messages(owner_a, uuid::Uuid::new_v4(), &[100])
+        .into_iter()
+        .for_each(|message| hub_sender.send(message).unwrap());
+    let before = pull(&hub_sender);
+    hub_sender
+        .send(HubPushMessage::ReleaseOwner(owner_a))
+        .unwrap();
+    counter_messages(owner_b, uuid::Uuid::new_v4(), &[5, 8])
+        .into_iter()
+        .for_each(|message| hub_sender.send(message).unwrap());
+    let after = pull(&hub_sender);
+    drop(hub_sender);
+
+    smol::block_on(MetricsHub::default().run(hub_receiver));
+
+    let HubPullResponse::Metrics(before) = before.recv().unwrap();
+    let HubPullResponse::Metrics(after) = after.recv().unwrap();
+    assert!(counter_created(&after) > counter_created(&before));
+
+    let resets = &after.families[COUNTER_RESETS_FAMILY];
+    assert_eq!(resets.metric_type, MetricType::Counter);
+    assert_eq!(
+        resets.metrics.values().next().unwrap(),
+        &Metric {
+            labels: [label("family", "counter")].into(),
+            value: MetricValue::Counter {
+                total: NumberValue::Int64(1),
+                created: None,
+                exemplar: None,
+            },
+        }
+    );
+}
+
+/// Counters without `created` timestamp keep the one assigned by the hub when their plugin
+/// restarts, as long as they don't decrease.
+#[test]
+fn hub_counter_plugin_restart() {
+    let (owner_a, owner_b) = (OwnerId::allocate(), OwnerId::allocate());
+    let (hub_sender, hub_receiver) = flume::unbounded();
+    let pull = |hub_sender: &flume::Sender<HubPushMessage>| {
+        let (sender, receiver) = flume::unbounded();
+        hub_sender
+            .send(HubPushMessage::PullMetrics(PullMetrics(sender)))
+            .unwrap();
+        receiver
+    };
+
+    counter_messages(owner_a, uuid::Uuid::new_v4(), &[10, 20])
+        .into_iter()
+        .for_each(|message| hub_sender.send(message).unwrap());
+    let before = pull(&hub_sender);
+    hub_sender
+        .send(HubPushMessage::ReleaseOwner(owner_a))
+        .unwrap();
+    counter_messages(owner_b, uuid::Uuid::new_v4(), &[25])
+        .into_iter()
+        .for_each(|message| hub_sender.send(message).unwrap());
+    let after = pull(&hub_sender);
+    drop(hub_sender);
+
+    smol::block_on(MetricsHub::default().run(hub_receiver));
+
+    let HubPullResponse::Metrics(before) = before.recv().unwrap();
+    let HubPullResponse::Metrics(after) = after.recv().unwrap();
+    assert_eq!(counter_created(&after), counter_created(&before));
+    assert!(!after.families.contains_key(COUNTER_RESETS_FAMILY));
+}
+
+/// Peers get the highest role of the rules they match.
+#[test]
+fn access_policy_roles() {
+    let policy = AccessPolicy::new(
+        vec!["exe:/usr/bin/plugin".parse().unwrap()],
+        vec!["gid:1000".parse().unwrap()],
+    );
+
+    let mut peer = PeerCredentials {
+        pid: Pid::from_raw(1),
+        uid: Uid::from_raw(1000),
+        gid: Gid::from_raw(1000),
+        exe: None,
+    };
+    assert_eq!(policy.authorize(&peer), Some(Role::Read));
+
+    peer.exe = Some("/usr/bin/plugin".into());
+    assert_eq!(policy.authorize(&peer), Some(Role::ReadWrite));
+
+    peer.exe = None;
+    peer.gid = Gid::from_raw(1001);
+    assert_eq!(policy.authorize(&peer), None);
+
+    assert!("user:root".parse::<PeerMatcher>().is_err());
+}
+
+/// Family names of a session are prefixed by its namespace, which can't change once a family
+/// is registered.
+#[test]
+fn rpc_namespace() {
+    let path = std::env::temp_dir().join(format!("xcp-metrics-rpc-{}", uuid::Uuid::new_v4()));
+    let (hub_sender, hub_receiver) = flume::unbounded();
+    let (shutdown_sender, shutdown) = flume::bounded::<()>(0);
+
+    let family_names = smol::block_on(async {
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = rpc::run(
+            listener,
+            hub_sender,
+            // Allows the user running the tests to push metrics.
+            Arc::new(AccessPolicy::new(vec![], vec![])),
+            Role::ReadWrite,
+            Default::default(),
+            flume::unbounded().0,
+            shutdown,
+        );
+        let client = async {
+            let mut stream = UnixStream::connect(&path).await.unwrap();
+            let namespace = |prefix: &str| {
+                ProtocolMessage::SetNamespace(SetNamespace {
+                    prefix: prefix.into(),
+                })
+            };
+            for message in [
+                namespace("plugin"),
+                ProtocolMessage::CreateFamily(CreateFamily {
+                    name: "test".into(),
+                    metric_type: MetricType::Gauge,
+                    unit: "".into(),
+                    help: "".into(),
+                }),
+                namespace("other"),
+                ProtocolMessage::UpdateMetric(UpdateMetric {
+                    family_name: "test".into(),
+                    metric: Metric {
+                        labels: vec![].into(),
+                        value: MetricValue::Gauge(NumberValue::Int64(1)),
+                    },
+                    uuid: uuid::Uuid::new_v4(),
+                }),
+            ] {
+                stream.send_message_async(message).await.unwrap();
+            }
+            drop(stream);
+
+            // The session ends on disconnection, releasing its metrics.
+            let mut family_names = vec![];
+            loop {
+                match hub_receiver.recv_async().await.unwrap() {
+                    HubPushMessage::CreateFamily(_, family) => family_names.push(family.name),
+                    HubPushMessage::UpdateMetric(_, update) => {
+                        family_names.push(update.family_name)
+                    }
+                    HubPushMessage::ReleaseOwner(_) => break,
+                    _ => (),
+                }
+            }
+            drop(shutdown_sender);
+            family_names
+        };
+
+        let (result, family_names) = futures::join!(server, client);
+        result.unwrap();
+        family_names
+    });
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(family_names, ["plugin_test", "plugin_test"]);
+}
+
+/// Make a gauge family out of `(labels, value)`.
+fn gauge_family(metrics: &[(&[(&str, &str)], f64)]) -> MetricFamily {
+    MetricFamily {
+        reference_count: 1,
+        metric_type: MetricType::Gauge,
+        metrics: metrics
+            .iter()
+            .map(|(labels, value)| {
+                (
+                    uuid::Uuid::new_v4(),
+                    Metric {
+                        labels: labels
+                            .iter()
+                            .map(|(name, value)| Label {
+                                name: (*name).into(),
+                                value: (*value).into(),
+                            })
+                            .collect(),
+                        value: MetricValue::Gauge(NumberValue::Double(*value)),
+                    },
+                )
+            })
+            .collect(),
+        ..Default::default()
+    }
+}
+
+/// Get the recorded values, sorted by value.
+fn recorded_values(family: &MetricFamily) -> Vec<f64> {
+    let mut values: Vec<f64> = family
+        .metrics
+        .values()
+        .map(|metric| match metric.value {
+            MetricValue::Gauge(NumberValue::Double(value)) => value,
+            _ => panic!("Unexpected value {:?}", metric.value),
+        })
+        .collect();
+
+    values.sort_by(f64::total_cmp);
+    values
+}
+
+fn parse_rule(json: &str) -> RecordingRule {
+    serde_json::from_str(json).unwrap()
+}
+
+/// Binary operations join series on labels, and aggregations group them by labels.
+#[test]
+fn rules_binary_and_aggregate() {
+    let mut metrics = MetricSet::default();
+    metrics.families.insert(
+        "total".into(),
+        gauge_family(&[(&[("domain", "0")], 10.0), (&[("domain", "1")], 20.0)]),
+    );
+    metrics.families.insert(
+        "free".into(),
+        gauge_family(&[(&[("domain", "1"), ("type", "ram")], 5.0)]),
+    );
+    metrics.families.insert(
+        "cpu".into(),
+        gauge_family(&[
+            (&[("cpu_id", "0"), ("node", "0")], 1.0),
+            (&[("cpu_id", "1"), ("node", "0")], 3.0),
+            (&[("cpu_id", "2"), ("node", "1")], 8.0),
+        ]),
+    );
+
+    let mut used = parse_rule(
+        r#"{ "record": "used", "expr": { "binary": {
+            "op": "sub", "left": { "family": "total" }, "right": { "family": "free" },
+            "on": ["domain"] } } }"#,
+    );
+    let family = used.evaluate(&metrics, Instant::now());
+    assert_eq!(family.metric_type, MetricType::Gauge);
+    assert_eq!(recorded_values(&family), [15.0]);
+
+    // Matching is one-to-one, ambiguous matches are dropped.
+    metrics.families.insert(
+        "free".into(),
+        gauge_family(&[
+            (&[("domain", "0"), ("type", "ram")], 1.0),
+            (&[("domain", "1"), ("type", "ram")], 5.0),
+            (&[("domain", "1"), ("type", "swap")], 2.0),
+        ]),
+    );
+    assert_eq!(
+        recorded_values(&used.evaluate(&metrics, Instant::now())),
+        [9.0]
+    );
+    let mut reversed = parse_rule(
+        r#"{ "record": "reversed", "expr": { "binary": {
+            "op": "sub", "left": { "family": "free" }, "right": { "family": "total" },
+            "on": ["domain"] } } }"#,
+    );
+    assert_eq!(
+        recorded_values(&reversed.evaluate(&metrics, Instant::now())),
+        [-9.0]
+    );
+
+    let mut scaled = parse_rule(
+        r#"{ "record": "scaled", "expr": { "binary": {
+            "op": "mul", "left": { "family": "total" }, "right": { "scalar": 2 } } } }"#,
+    );
+    assert_eq!(
+        recorded_values(&scaled.evaluate(&metrics, Instant::now())),
+        [20.0, 40.0]
+    );
+
+    let mut average = parse_rule(
+        r#"{ "record": "cpu_avg", "expr": { "aggregate": {
+            "op": "avg", "by": ["node"], "expr": { "family": "cpu" } } } }"#,
+    );
+    let family = average.evaluate(&metrics, Instant::now());
+    assert_eq!(recorded_values(&family), [2.0, 8.0]);
+
+    // Series keep their UUID between evaluations.
+    let uuids: Vec<_> = family.metrics.keys().copied().collect();
+    let family = average.evaluate(&metrics, Instant::now());
+    assert!(uuids.iter().all(|uuid| family.metrics.contains_key(uuid)));
+}
+
+/// Rate of a counter between two evaluations, handling counter resets.
+#[test]
+fn rules_rate() {
+    let mut rule = parse_rule(
+        r#"{ "record": "rate", "expr": { "rate": { "expr": { "family": "counter" } } } }"#,
+    );
+    let start = Instant::now();
+
+    let mut metrics = MetricSet::default();
+    metrics
+        .families
+        .insert("counter".into(), gauge_family(&[(&[], 100.0)]));
+    assert!(rule.evaluate(&metrics, start).metrics.is_empty());
+
+    metrics
+        .families
+        .insert("counter".into(), gauge_family(&[(&[], 150.0)]));
+    let family = rule.evaluate(&metrics, start + Duration::from_secs(5));
+    assert_eq!(recorded_values(&family), [10.0]);
+
+    metrics
+        .families
+        .insert("counter".into(), gauge_family(&[(&[], 20.0)]));
+    let family = rule.evaluate(&metrics, start + Duration::from_secs(10));
+    assert_eq!(recorded_values(&family), [4.0]);
+
+    // A counter recreated between two evaluations is a reset, even if it didn't decrease.
+    let counter = |total: f64, created: SystemTime| MetricFamily {
+        metric_type: MetricType::Counter,
+        metrics: [(
+            uuid::Uuid::new_v4(),
+            Metric {
+                labels: vec![].into(),
+                value: MetricValue::Counter {
+                    total: NumberValue::Double(total),
+                    created: Some(created),
+                    exemplar: None,
+                },
+            },
+        )]
+        .into(),
+        ..Default::default()
+    };
+    let created = SystemTime::now();
+
+    metrics
+        .families
+        .insert("counter".into(), counter(30.0, created));
+    rule.evaluate(&metrics, start + Duration::from_secs(15));
+
+    metrics.families.insert(
+        "counter".into(),
+        counter(40.0, created + Duration::from_secs(1)),
+    );
+    let family = rule.evaluate(&metrics, start + Duration::from_secs(20));
+    assert_eq!(recorded_values(&family), [8.0]);
+}
+
+/// The hub materializes recorded families, unless a provider uses the same name.
+#[test]
+fn hub_recording_rules() {
+    let owner = OwnerId::allocate();
+    let uuid = uuid::Uuid::new_v4();
+    let rules = vec![
+        parse_rule(
+            r#"{ "record": "test_double", "expr": { "binary": {
+                "op": "mul", "left": { "family": "test" }, "right": { "scalar": 2 } } } }"#,
+        ),
+        parse_rule(r#"{ "record": "test", "expr": { "scalar": 0 } }"#),
+    ];
+
+    let (hub_sender, hub_receiver) = flume::unbounded();
+    let (sender, receiver) = flume::unbounded();
+
+    [
+        // Recorded until a provider registers the family.
+        HubPushMessage::EvaluateRules,
+        create_family(owner),
+        update_metric(owner, uuid, 21),
+        HubPushMessage::EvaluateRules,
+        HubPushMessage::PullMetrics(PullMetrics(sender)),
+    ]
+    .into_iter()
+    .for_each(|message| hub_sender.send(message).unwrap());
+    drop(hub_sender);
+
+    smol::block_on(MetricsHub::new(rules, Default::default()).run(hub_receiver));
+    let HubPullResponse::Metrics(metrics) = receiver.recv().unwrap();
+
+    assert_eq!(recorded_values(&metrics.families["test_double"]), [42.0]);
+    assert_eq!(metrics.families["test"].metrics.len(), 1);
+    assert_eq!(
+        metrics.families["test"].metrics[&uuid].value,
+        MetricValue::Gauge(NumberValue::Int64(21))
+    );
+}
+
+/// Get the enabled state of each alert series.
+fn alert_states(family: &MetricFamily) -> Vec<CompactString> {
+    family
+        .metrics
+        .values()
+        .map(|metric| match &metric.value {
+            MetricValue::StateSet(states) => states
+                .iter()
+                .find(|state| state.enabled)
+                .unwrap()
+                .name
+                .clone(),
+            value => panic!("Unexpected value {value:?}"),
+        })
+        .collect()
+}
+
+/// Alerts go through pending, firing and resolved states, notifying firing and resolution.
+#[test]
+fn alert_lifecycle() {
+    let mut alert: AlertRule = serde_json::from_str(
+        r#"{ "alert": "high", "expr": { "family": "value" },
+            "condition": { "above": 0.95 }, "for": 60 }"#,
+    )
+    .unwrap();
+    let (sender, receiver) = flume::unbounded();
+    let start = Instant::now();
+
+    let mut metrics = MetricSet::default();
+    let mut evaluate = |value: f64, elapsed: u64| {
+        metrics
+            .families
+            .insert("value".into(), gauge_family(&[(&[("domain", "1")], value)]));
+        alert_states(&alert.evaluate(
+            &metrics,
+            start + Duration::from_secs(elapsed),
+            Some(&sender),
+        ))
+    };
+
+    assert!(evaluate(0.5, 0).is_empty());
+    assert_eq!(evaluate(0.99, 10), ["pending"]);
+    assert_eq!(evaluate(0.99, 30), ["pending"]);
+    assert!(receiver.is_empty());
+
+    assert_eq!(evaluate(0.99, 70), ["firing"]);
+    let notification = receiver.try_recv().unwrap();
+    assert_eq!(notification.status, AlertStatus::Firing);
+    assert_eq!(notification.labels["domain"], "1");
+
+    assert_eq!(evaluate(0.5, 80), ["resolved"]);
+    assert_eq!(receiver.try_recv().unwrap().status, AlertStatus::Resolved);
+
+    // Resolved alerts are published once, then forgotten.
+    assert!(evaluate(0.5, 85).is_empty());
+    assert!(receiver.is_empty());
+
+    // A pending alert that never fired is forgotten.
+    assert_eq!(evaluate(0.99, 90), ["pending"]);
+    assert!(evaluate(0.5, 100).is_empty());
+    assert!(receiver.is_empty());
+}
+
+/// Absent alerts fire when the expression has no series.
+#[test]
+fn alert_absent() {
+    let mut alert: AlertRule = serde_json::from_str(
+        r#"{ "alert": "stale", "expr": { "family": "value" }, "condition": "absent" }"#,
+    )
+    .unwrap();
+
+    let mut metrics = MetricSet::default();
+    assert_eq!(
+        alert_states(&alert.evaluate(&metrics, Instant::now(), None)),
+        ["firing"]
+    );
+
+    metrics
+        .families
+        .insert("value".into(), gauge_family(&[(&[], 1.0)]));
+    assert_eq!(
+        alert_states(&alert.evaluate(&metrics, Instant::now(), None)),
+        ["resolved"]
+    );
+}
+
+#[test]
+fn interval_arguments() {
+    assert_eq!(parse_interval("30"), Ok(Duration::from_secs(30)));
+    // A zero interval would make the timers spin.
+    assert!(parse_interval("0").is_err());
+    assert!(parse_interval("-1").is_err());
+}
+
+#[test]
+fn http_url() {
+    assert_eq!(
+        "http://localhost:8080/alerts".parse(),
+        Ok(Url {
+            host: "localhost".into(),
+            port: 8080,
+            path: "/alerts".into()
+        })
+    );
+    assert_eq!(
+        "http://10.0.0.1".parse(),
+        Ok(Url {
+            host: "10.0.0.1".into(),
+            port: 80,
+            path: "/".into()
+        })
+    );
+    assert!("https://localhost".parse::<Url>().is_err());
+    assert!("http://:80/".parse::<Url>().is_err());
+}
+
+fn label(name: &str, value: &str) -> Label {
+    Label {
+        name: name.into(),
+        value: value.into(),
+    }
+}
+
+#[test]
+fn host_identity() {
+    let inventory =
+        "PRIMARY_DISK='/dev/sda'\nINSTALLATION_UUID='1d4bd11f-5b2a-4f8f-9f0c-8d1f0b1f3a55'\n";
+    let static_label: StaticLabel = "datacenter=paris".parse().unwrap();
+
+    let host = HostIdentity::new(
+        Some(inventory),
+        Some("slave:10.0.0.1\n"),
+        Some("xcp-host-1"),
+        vec![static_label.0, label("hostname", "overridden")],
+    );
+
+    assert_eq!(
+        host.labels,
+        [
+            label("datacenter", "paris"),
+            label("hostname", "overridden"),
+            label("host_uuid", "1d4bd11f-5b2a-4f8f-9f0c-8d1f0b1f3a55"),
+            label("pool_role", "slave"),
+            label("pool_master", "10.0.0.1"),
+        ]
+    );
+
+    assert!("=value".parse::<StaticLabel>().is_err());
+}
+
+#[test]
+fn host_labels_modes() {
+    let host = HostIdentity {
+        labels: vec![label("host_uuid", "1234"), label("domain", "host")],
+    };
+
+    let mut metrics = MetricSet::default();
+    metrics
+        .families
+        .insert("test".into(), gauge_family(&[(&[("domain", "1")], 1.0)]));
+
+    let mut labelled = metrics.clone();
+    HostLabelsMode::Labels.apply(&mut labelled, &host);
+    let metric = labelled.families["test"].metrics.values().next().unwrap();
+    assert_eq!(
+        *metric.labels,
+        [label("domain", "1"), label("host_uuid", "1234")]
+    );
+
+    HostLabelsMode::Info.apply(&mut metrics, &host);
+    let info = metrics.families[HOST_INFO_FAMILY]
+        .metrics
+        .values()
+        .next()
+        .unwrap();
+    assert_eq!(info.value, MetricValue::Info(host.labels.clone().into()));
+}
+
+/// Stand-in HTTP receiver: answer `status` to `count` requests, and return their bodies.
+async fn http_receiver(listener: &TcpListener, status: u16, count: usize) -> Vec<Vec<u8>> {
+    let mut bodies = vec![];
+
+    for _ in 0..count {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = vec![];
+        let mut buffer = [0; 4096];
+
+        // Read the headers, then the body.
+        let body_start = loop {
+            let read = stream.read(&mut buffer).await.unwrap();
+            request.extend_from_slice(&buffer[..read]);
+
+            if let Some(i) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                break i + 4;
+            }
+        };
+
+        let headers = String::from_utf8_lossy(&request[..body_start]).to_lowercase();
+        let length: usize = headers
+            .lines()
+            .find_map(|line| line.strip_prefix("content-length: "))
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap();
+
+        while request.len() < body_start + length {
+            let read = stream.read(&mut buffer).await.unwrap();
+            request.extend_from_slice(&buffer[..read]);
+        }
+
+        stream
+            .write_all(format!("HTTP/1.1 {status} Status\r\nContent-Length: 0\r\n\r\n").as_bytes())
+            .await
+            .unwrap();
+
+        bodies.push(request.split_off(body_start));
+    }
+
+    bodies
+}
+
+/// Remote-write requests are batched, stored in the WAL while the endpoint fails, then replayed.
+#[test]
+fn remote_write_wal() {
+    // The WAL directory is created by the writer.
+    let wal = std::env::temp_dir().join(format!("xcp-metrics-wal-{}", uuid::Uuid::new_v4()));
+
+    let listener = smol::block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+    let url: Url = format!("http://{}/write", listener.local_addr().unwrap())
+        .parse()
+        .unwrap();
+
+    let mut writer = RemoteWriter::new(RemoteWriteConfig {
+        url,
+        batch_size: 2,
+        max_retries: 0,
+        wal: Some(wal.clone()),
+    });
+
+    let mut metrics = MetricSet::default();
+    metrics.families.insert(
+        "test".into(),
+        gauge_family(&[
+            (&[("domain", "0")], 1.0),
+            (&[("domain", "1")], 2.0),
+            (&[("domain", "2")], 3.0),
+        ]),
+    );
+
+    // The first batch fails, both are stored in the WAL.
+    let (_, bodies) = smol::block_on(futures::future::join(
+        writer.push_at(&metrics, 1000),
+        http_receiver(&listener, 503, 1),
+    ));
+    assert_eq!(bodies.len(), 1);
+    assert_eq!(std::fs::read_dir(&wal).unwrap().count(), 2);
+
+    // WAL is replayed first, then the new batches are sent.
+    let (_, bodies) = smol::block_on(futures::future::join(
+        writer.push_at(&metrics, 2000),
+        http_receiver(&listener, 200, 4),
+    ));
+    assert_eq!(std::fs::read_dir(&wal).unwrap().count(), 0);
+
+    let requests: Vec<_> = bodies
+        .iter()
+        .map(|body| decode_write_request(body).unwrap())
+        .collect();
+    let timestamps: Vec<i64> = requests
+        .iter()
+        .flat_map(|request| &request.timeseries)
+        .map(|series| series.samples[0].timestamp)
+        .collect();
+    assert_eq!(timestamps, [1000, 1000, 1000, 2000, 2000, 2000]);
+
+    let series = &requests[0].timeseries[0];
+    assert_eq!(series.labels[0].name, "__name__");
+    assert_eq!(series.labels[0].value, "test");
+    assert_eq!(series.labels[1].name, "domain");
+    assert_eq!(requests[0].metadata.len(), 1);
+    assert!(requests[1].metadata.is_empty());
+
+    std::fs::remove_dir(&wal).unwrap();
+}
+
+#[test]
+fn line_exporter() {
+    assert_eq!(
+        "tcp://localhost:2003".parse(),
+        Ok(PushTarget::Tcp("localhost:2003".into()))
+    );
+    assert_eq!(
+        "udp://localhost:8089".parse(),
+        Ok(PushTarget::Udp("localhost:8089".into()))
+    );
+    assert!(matches!(
+        "http://localhost:8086/write".parse(),
+        Ok(PushTarget::Http(_))
+    ));
+    assert!("localhost:2003".parse::<PushTarget>().is_err());
+
+    let mut metrics = MetricSet::default();
+    metrics.families.insert(
+        "test".into(),
+        gauge_family(&[(&[("domain", "0")], 1.0), (&[("domain", "1")], 2.0)]),
+    );
+    let timestamp = SystemTime::UNIX_EPOCH + Duration::from_secs(1700000000);
+
+    // Graphite over TCP, one connection per batch.
+    let listener = smol::block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+    let mut exporter = LineExporter {
+        format: LineFormat::Graphite(Default::default()),
+        target: PushTarget::Tcp(listener.local_addr().unwrap().to_string().into()),
+        batch_size: 1,
+    };
+
+    let (_, received) = smol::block_on(futures::future::join(
+        exporter.push(&metrics, timestamp),
+        async {
+            let mut received = vec![];
+
+            for _ in 0..2 {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut lines = String::new();
+                stream.read_to_string(&mut lines).await.unwrap();
+                received.push(lines);
+            }
+
+            received.sort();
+            received
+        },
+    ));
+    assert_eq!(received, ["test.0 1 1700000000\n", "test.1 2 1700000000\n"]);
+
+    // InfluxDB over UDP, lines are packed in a datagram.
+    let socket = smol::block_on(UdpSocket::bind("127.0.0.1:0")).unwrap();
+    let mut exporter = LineExporter {
+        format: LineFormat::Influx,
+        target: PushTarget::Udp(socket.local_addr().unwrap().to_string().into()),
+        batch_size: 10,
+    };
+
+    smol::block_on(exporter.push(&metrics, timestamp));
+
+    let mut datagram = [0; MAX_DATAGRAM_SIZE];
+    let size = smol::block_on(socket.recv(&mut datagram)).unwrap();
+    let mut lines: Vec<&str> = std::str::from_utf8(&datagram[..size])
+        .unwrap()
+        .lines()
+        .collect();
+    lines.sort();
+    assert_eq!(
+        lines,
+        [
+            "test,domain=0 value=1 1700000000000000000",
+            "test,domain=1 value=2 1700000000000000000"
+        ]
+    );
+
+    // IPv6 targets (when the host supports IPv6).
+    if let Ok(socket) = smol::block_on(UdpSocket::bind("[::1]:0")) {
+        exporter.target = PushTarget::Udp(socket.local_addr().unwrap().to_string().into());
+        smol::block_on(exporter.push(&metrics, timestamp));
+        let received = smol::block_on(smol::future::or(
+            async { socket.recv(&mut datagram).await.ok() },
+            async {
+                smol::Timer::after(Duration::from_secs(5)).await;
+                None
+            },
+        ));
+        assert!(received.is_some_and(|size| size > 0));
+    }
+}
+
+#[test]
+fn otlp_exporter() {
+    use xcp_metrics_common::{
+        metrics::{Bucket, State},
+        openmetrics::otlp::{
+            decode_request,
+            opentelemetry::{any_value, metric::Data, number_data_point, KeyValue},
+        },
+    };
+
+    let family = |metric_type, unit: &str, value| MetricFamily {
+        reference_count: 1,
+        metric_type,
+        unit: unit.into(),
+        help: "Some help".into(),
+        metrics: [(
+            uuid::Uuid::new_v4(),
+            Metric {
+                labels: [label("domain", "0")].into(),
+                value,
+            },
+        )]
+        .into(),
+    };
+    let mut metrics = MetricSet::default();
+    metrics.families.insert(
+        "memory".into(),
+        family(
+            MetricType::Gauge,
+            "bytes",
+            MetricValue::Gauge(NumberValue::Int64(1024)),
+        ),
+    );
+    metrics.families.insert(
+        "cpu_time".into(),
+        family(
+            MetricType::Counter,
+            "seconds",
+            MetricValue::Counter {
+                total: NumberValue::Double(12.5),
+                created: None,
+                exemplar: None,
+            },
+        ),
+    );
+    metrics.families.insert(
+        "latency".into(),
+        family(
+            MetricType::Histogram,
+            "seconds",
+            MetricValue::Histogram {
+                sum: NumberValue::Double(3.0),
+                count: 5,
+                created: SystemTime::UNIX_EPOCH,
+                buckets: [
+                    Bucket {
+                        count: 2,
+                        upper_bound: 0.1,
+                        exemplar: None,
+                    },
+                    Bucket {
+                        count: 4,
+                        upper_bound: 1.0,
+                        exemplar: None,
+                    },
+                    Bucket {
+                        count: 5,
+                        upper_bound: f64::INFINITY,
+                        exemplar: None,
+                    },
+                ]
+                .into(),
+            },
+        ),
+    );
+    metrics.families.insert(
+        "power_state".into(),
+        family(
+            MetricType::StateSet,
+            "",
+            MetricValue::StateSet(
+                [
+                    State {
+                        enabled: true,
+                        name: "running".into(),
+                    },
+                    State {
+                        enabled: false,
+                        name: "paused".into(),
+                    },
+                ]
+                .into(),
+            ),
+        ),
+    );
+    metrics.families.insert(
+        "version".into(),
+        family(
+            MetricType::Info,
+            "",
+            MetricValue::Info([label("xen", "4.17")].into()),
+        ),
+    );
+
+    let host = HostIdentity {
+        labels: vec![label("host_uuid", "1234"), label("pool_role", "master")],
+    };
+    let listener = smol::block_on(TcpListener::bind("127.0.0.1:0")).unwrap();
+    let url: Url = format!("http://{}", listener.local_addr().unwrap())
+        .parse()
+        .unwrap();
+    let mut exporter = OtlpExporter::new(url, &host);
+    let timestamp = SystemTime::UNIX_EPOCH + Duration::from_secs(1700000000);
+
+    let (_, bodies) = smol::block_on(futures::future::join(
+        exporter.push(&metrics, timestamp),
+        http_receiver(&listener, 200, 1),
+    ));
+    let request = decode_request(&bodies[0]).unwrap();
+
+    let string = |attribute: &KeyValue| match &attribute.value.as_ref().unwrap().value {
+        Some(any_value::Value::StringValue(value)) => (attribute.key.clone(), value.clone()),
+        _ => panic!("Not a string attribute"),
+    };
+    let resource = &request.resource_metrics[0];
+    let attributes: Vec<_> = resource
+        .resource
+        .as_ref()
+        .unwrap()
+        .attributes
+        .iter()
+        .map(string)
+        .collect();
+    assert!(attributes.contains(&("host.id".into(), "1234".into())));
+    assert!(attributes.contains(&("pool_role".into(), "master".into())));
+    assert!(attributes.contains(&("service.name".into(), "xcp-metrics".into())));
+
+    let metric = |name: &str| {
+        resource.scope_metrics[0]
+            .metrics
+            .iter()
+            .find(|metric| metric.name == name)
+            .unwrap()
+    };
+
+    let memory = metric("memory");
+    assert_eq!(memory.unit, "bytes");
+    assert_eq!(memory.description, "Some help");
+    let Some(Data::Gauge(gauge)) = &memory.data else {
+        panic!("memory is not a gauge");
+    };
+    assert_eq!(
+        gauge.data_points[0].value,
+        Some(number_data_point::Value::AsInt(1024))
+    );
+    assert_eq!(
+        gauge.data_points[0].time_unix_nano,
+        1700000000 * 1_000_000_000
+    );
+    assert_eq!(
+        string(&gauge.data_points[0].attributes[0]),
+        ("domain".into(), "0".into())
+    );
+
+    let Some(Data::Sum(sum)) = &metric("cpu_time").data else {
+        panic!("cpu_time is not a sum");
+    };
+    assert!(sum.is_monotonic);
+    assert_eq!(sum.aggregation_temporality, 2);
+    assert_ne!(sum.data_points[0].start_time_unix_nano, 0);
+
+    let Some(Data::Histogram(histogram)) = &metric("latency").data else {
+        panic!("latency is not a histogram");
+    };
+    assert_eq!(histogram.data_points[0].explicit_bounds, [0.1, 1.0]);
+    assert_eq!(histogram.data_points[0].bucket_counts, [2, 2, 1]);
+    assert_eq!(histogram.data_points[0].count, 5);
+
+    let Some(Data::Sum(sum)) = &metric("power_state").data else {
+        panic!("power_state is not a sum");
+    };
+    assert!(!sum.is_monotonic);
+    let states: Vec<_> = sum
+        .data_points
+        .iter()
+        .map(|point| (string(&point.attributes[1]), point.value))
+        .collect();
+    assert_eq!(
+        states,
+        [
+            (
+                ("power_state".into(), "running".into()),
+                Some(number_data_point::Value::AsInt(1))
+            ),
+            (
+                ("power_state".into(), "paused".into()),
+                Some(number_data_point::Value::AsInt(0))
+            ),
+        ]
+    );
+
+    let Some(Data::Sum(sum)) = &metric("version").data else {
+        panic!("version is not a sum");
+    };
+    assert!(!sum.is_monotonic);
+    assert_eq!(
+        string(&sum.data_points[0].attributes[1]),
+        ("xen".into(), "4.17".into())
+    );
+}
+
+/// Run a [HubSync] over successive metric sets, returning the hub metrics.
+fn sync_hub(sets: &[MetricSet]) -> MetricSet {
+    let (sender, receiver) = flume::unbounded();
+    let mut sync = HubSync::new(sender);
+
+    for metrics in sets {
+        smol::block_on(sync.sync(metrics)).unwrap();
+    }
+
+    run_hub(receiver.drain().collect())
+}
+
+#[test]
+fn statsd_aggregation() {
+    let mut aggregator = StatsdAggregator::default();
+    aggregator.ingest(
+        "backup.runs:1|c|#status:ok\n\
+         backup.runs:2|c|@0.5|#status:ok\n\
+         backup.size:100|g\n\
+         backup.size:-30|g\n\
+         backup.time:100|ms\n\
+         backup.time:300|ms\n\
+         backup.time:200|ms\n\
+         backup.runs:1|g\n\
+         invalid line",
+    );
+    assert!(aggregator.ingest_line("backup.set:1|s").is_err());
+    assert!(aggregator.ingest_line("backup.runs:1|g").is_err());
+
+    let metrics = aggregator.flush();
+    let value = |name: &str| {
+        metrics.families[name]
+            .metrics
+            .values()
+            .next()
+            .unwrap()
+            .clone()
+    };
+
+    let runs = value("backup_runs");
+    assert_eq!(*runs.labels, [label("status", "ok")]);
+    assert!(matches!(
+        runs.value,
+        MetricValue::Counter {
+            total: NumberValue::Double(total),
+            ..
+        } if total == 5.0
+    ));
+    assert_eq!(
+        value("backup_size").value,
+        MetricValue::Gauge(NumberValue::Double(70.0))
+    );
+
+    assert_eq!(metrics.families["backup_time"].unit, "seconds");
+    let MetricValue::Summary {
+        count, quantile, ..
+    } = value("backup_time").value
+    else {
+        panic!("backup_time is not a summary");
+    };
+    assert_eq!(count, 3);
+    assert_eq!(quantile[0].value, 0.2);
+    assert_eq!(quantile[2].value, 0.3);
+
+    // Quantiles are kept when there is no new sample.
+    let metrics = aggregator.flush();
+    let MetricValue::Summary { quantile, .. } = &metrics.families["backup_time"]
+        .metrics
+        .values()
+        .next()
+        .unwrap()
+        .value
+    else {
+        panic!("backup_time is not a summary");
+    };
+    assert_eq!(quantile[0].value, 0.2);
+
+    // Metrics are pushed to the hub.
+    let hub_metrics = sync_hub(&[metrics]);
+    assert_eq!(hub_metrics.families.len(), 3);
+    assert_eq!(
+        hub_metrics.families["backup_runs"].metric_type,
+        MetricType::Counter
+    );
+
+    assert_eq!(
+        "udp://127.0.0.1:8125".parse(),
+        Ok(StatsdListen::Udp("127.0.0.1:8125".into()))
+    );
+    assert!("127.0.0.1:8125".parse::<StatsdListen>().is_err());
+}
+
+#[test]
+fn statsd_unix_socket() {
+    let path = std::env::temp_dir().join(format!("xcp-metrics-statsd-{}", uuid::Uuid::new_v4()));
+    let listen = StatsdListen::Unix(path.clone());
+
+    // A socket still in use is kept.
+    let socket = bind_statsd_socket(&listen).unwrap();
+    assert!(bind_statsd_socket(&listen).is_err());
+    assert!(path.exists());
+
+    // The socket of a stopped daemon is replaced.
+    drop(socket);
+    bind_statsd_socket(&listen).unwrap();
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn textfile_ingestion() {
+    let directory =
+        std::env::temp_dir().join(format!("xcp-metrics-textfile-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir(&directory).unwrap();
+
+    std::fs::write(
+        directory.join("backup.prom"),
+        "# TYPE backup_last_success gauge\nbackup_last_success{vm=\"a\"} 1\nbackup_last_success{vm=\"b\"} 0\n",
+    )
+    .unwrap();
+    std::fs::write(directory.join("ignored.txt"), "ignored 1\n").unwrap();
+    std::fs::write(directory.join("broken.prom"), "broken{ 1\n").unwrap();
+
+    let first = read_textfiles(&directory);
+    assert_eq!(first.families["backup_last_success"].metrics.len(), 2);
+    assert!(!first.families.contains_key("ignored"));
+    assert_eq!(first.families["textfile_mtime_seconds"].metrics.len(), 1);
+    assert_eq!(
+        first.families["textfile_scrape_error"]
+            .metrics
+            .values()
+            .next()
+            .unwrap()
+            .value,
+        MetricValue::Gauge(NumberValue::Int64(1))
+    );
+
+    // Metrics that disappear from the files are removed from the hub.
+    std::fs::write(
+        directory.join("backup.prom"),
+        "# TYPE backup_last_success gauge\nbackup_last_success{vm=\"a\"} 0\n",
+    )
+    .unwrap();
+    std::fs::remove_file(directory.join("broken.prom")).unwrap();
+    let second = read_textfiles(&directory);
+
+    let hub_metrics = sync_hub(&[first, second]);
+    let family = &hub_metrics.families["backup_last_success"];
+    assert_eq!(family.metrics.len(), 1);
+    let metric = family.metrics.values().next().unwrap();
+    assert_eq!(*metric.labels, [label("vm", "a")]);
+    assert_eq!(metric.value, MetricValue::Gauge(NumberValue::Int64(0)));
+
+    std::fs::remove_dir_all(&directory).unwrap();
+}
+
+/// Fetch a peer through the TCP listener, and merge its metrics into the federated view.
+#[test]
+fn federation() {
+    let peer_host = HostIdentity {
+        labels: vec![label("host_uuid", "peer"), label("hostname", "peer-host")],
+    };
+    let owner = OwnerId::allocate();
+
+    // Peer daemon: a hub with a TCP listener.
+    let (peer_sender, peer_receiver) = flume::unbounded();
+    peer_sender.send(create_family(owner)).unwrap();
+    peer_sender
+        .send(update_metric(owner, uuid::Uuid::new_v4(), 1))
+        .unwrap();
+    let (shutdown_sender, shutdown) = flume::bounded::<()>(0);
+
+    let (address, mut peer_metrics) = smol::block_on(async {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let export = Arc::new(ExportPipeline {
+            host: Arc::new(peer_host),
+            host_labels: HostLabelsMode::Info,
+            ..Default::default()
+        });
+
+        let server = rpc::run(
+            listener,
+            peer_sender.clone(),
+            Arc::new(AccessPolicy::new(vec![], vec![])),
+            Role::Read,
+            export,
+            // No collection rounds.
+            flume::unbounded().0,
+            shutdown,
+        );
+        let client = async {
+            let metrics = federation::fetch_peer(&address).await.unwrap();
+            drop((shutdown_sender, peer_sender));
+            metrics
+        };
+
+        let (_, result, metrics) =
+            futures::join!(MetricsHub::default().run(peer_receiver), server, client);
+        result.unwrap();
+
+        (address, metrics)
+    });
+
+    federation::tag_peer_metrics(&mut peer_metrics, &address);
+    assert!(!peer_metrics.families.contains_key(HOST_INFO_FAMILY));
+    let metric = peer_metrics.families["test"]
+        .metrics
+        .values()
+        .next()
+        .unwrap();
+    assert_eq!(
+        *metric.labels,
+        [
+            label("host_uuid", "peer"),
+            label("hostname", "peer-host"),
+            label("instance", &address)
+        ]
+    );
+
+    // Families of conflicting types are skipped.
+    let mut conflicting = MetricSet::default();
+    conflicting.families.insert(
+        "test".into(),
+        MetricFamily {
+            metric_type: MetricType::Counter,
+            ..gauge_family(&[(&[], 1.0)])
+        },
+    );
+
+    let (hub_sender, hub_receiver) = flume::unbounded();
+    let (local_sender, local_receiver) = flume::unbounded();
+    let (federated_sender, federated_receiver) = flume::unbounded();
+    [
+        create_family(owner),
+        update_metric(owner, uuid::Uuid::new_v4(), 2),
+        HubPushMessage::UpdatePeer(address.as_str().into(), Some(Arc::new(peer_metrics))),
+        HubPushMessage::UpdatePeer("conflicting".into(), Some(Arc::new(conflicting))),
+        HubPushMessage::PullMetrics(PullMetrics(local_sender)),
+        HubPushMessage::PullFederatedMetrics(PullMetrics(federated_sender)),
+    ]
+    .into_iter()
+    .for_each(|message| hub_sender.send(message).unwrap());
+    drop(hub_sender);
+    smol::block_on(MetricsHub::default().run(hub_receiver));
+
+    let HubPullResponse::Metrics(local) = local_receiver.recv().unwrap();
+    assert_eq!(local.families["test"].metrics.len(), 1);
+
+    let HubPullResponse::Metrics(federated) = federated_receiver.recv().unwrap();
+    let export = ExportPipeline {
+        view: MetricsView::Federated,
+        host: Arc::new(HostIdentity {
+            labels: vec![label("host_uuid", "local"), label("pool_role", "master")],
+        }),
+        host_labels: HostLabelsMode::Labels,
+        ..Default::default()
+    };
+    let federated = export.apply(federated);
+
+    let mut hosts: Vec<_> = federated.families["test"]
+        .metrics
+        .values()
+        .map(|metric| metric.labels.to_vec())
+        .collect();
+    hosts.sort_by(|a, b| a[0].value.cmp(&b[0].value));
+    // The peer series keep the identity of their host only.
+    assert_eq!(
+        hosts,
+        [
+            vec![label("host_uuid", "local"), label("pool_role", "master")],
+            vec![
+                label("host_uuid", "peer"),
+                label("hostname", "peer-host"),
+                label("instance", &address)
+            ]
+        ]
+    );
+}
+
+#[test]
+fn collection_rounds() {
+    let (delay, timestamp) = collection::next_tick(
+        SystemTime::UNIX_EPOCH + Duration::from_millis(12_300),
+        Duration::from_secs(5),
+    );
+    assert_eq!(delay, Duration::from_millis(2_700));
+    assert_eq!(timestamp, SystemTime::UNIX_EPOCH + Duration::from_secs(15));
+
+    let config = CollectionConfig {
+        interval: Duration::from_secs(3600),
+        on_demand: true,
+        deadline: Duration::from_millis(50),
+    };
+    let (sender, receiver) = flume::unbounded();
+    let owner = OwnerId::allocate();
+    let (tick_sender, ticks) = flume::bounded(1);
+
+    smol::block_on(async {
+        let rounds = async {
+            sender
+                .send_async(CollectionMessage::Subscribe(owner, tick_sender))
+                .await
+                .unwrap();
+
+            // A fetch waits for the subscribers to be done.
+            let (waiter, done) = flume::bounded(1);
+            sender
+                .send_async(CollectionMessage::Fetch(waiter))
+                .await
+                .unwrap();
+            let tick = ticks.recv_async().await.unwrap();
+            assert!(done.try_recv().is_err());
+
+            sender
+                .send_async(CollectionMessage::Done(owner, tick.sequence))
+                .await
+                .unwrap();
+            done.recv_async().await.unwrap();
+
+            // Or for the deadline.
+            let start = Instant::now();
+            collection::before_fetch(&sender).await;
+            assert!(start.elapsed() >= config.deadline);
+            assert_eq!(
+                ticks.recv_async().await.unwrap().sequence,
+                tick.sequence + 1
+            );
+
+            // Subscribers still busy with their previous tick skip the round, and aren't waited
+            // for.
+            collection::before_fetch(&sender).await;
+            let start = Instant::now();
+            collection::before_fetch(&sender).await;
+            assert!(start.elapsed() < config.deadline);
+            assert_eq!(
+                ticks.recv_async().await.unwrap().sequence,
+                tick.sequence + 2
+            );
+
+            drop(sender);
+        };
+
+        futures::join!(collection::run(config, receiver), rounds);
+    });
+}
@@ -0,0 +1,153 @@
+//! Textfile collector, ingesting the `*.prom` files of a directory (like node_exporter).
+//!
+//! Files are in the OpenMetrics (or Prometheus) text format, and should be written atomically
+//! (e.g written to a temporary file, then renamed). The directory is read at each interval, and
+//! the hub is updated with the changes.
+//!
+//! Two families describe the files themselves: `textfile_mtime_seconds` (modification time of
+//! each file) and `textfile_scrape_error` (1 if a file couldn't be read or parsed).
+use std::{
+    fs,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use flume::Sender;
+use smol::Timer;
+use uuid::Uuid;
+use xcp_metrics_common::{
+    metrics::{Label, Metric, MetricFamily, MetricSet, MetricType, MetricValue, NumberValue},
+    openmetrics::parse::parse_metrics_set_text,
+};
+
+use crate::{hub::HubPushMessage, ingest::HubSync};
+
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(15);
+
+fn gauge_family(help: &str, metrics: impl IntoIterator<Item = (Label, f64)>) -> MetricFamily {
+    MetricFamily {
+        reference_count: 1,
+        metric_type: MetricType::Gauge,
+        help: help.into(),
+        metrics: metrics
+            .into_iter()
+            .map(|(label, value)| {
+                (
+                    Uuid::new_v4(),
+                    Metric {
+                        labels: [label].into(),
+                        value: MetricValue::Gauge(NumberValue::Double(value)),
+                    },
+                )
+            })
+            .collect(),
+        ..Default::default()
+    }
+}
+
+fn read_textfile(path: &Path) -> anyhow::Result<(MetricSet, SystemTime)> {
+    let modified = fs::metadata(path)?.modified()?;
+    let metrics = parse_metrics_set_text(&fs::read_to_string(path)?)?;
+
+    Ok((metrics, modified))
+}
+
+/// Read and merge the `*.prom` files of `directory`.
+pub fn read_textfiles(directory: &Path) -> MetricSet {
+    let mut metrics = MetricSet::default();
+    let mut mtimes = vec![];
+    let mut scrape_error = false;
+
+    let mut paths: Vec<_> = match fs::read_dir(directory) {
+        Ok(entries) => entries
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .filter(|path| {
+                path.extension()
+                    .is_some_and(|extension| extension == "prom")
+            })
+            .collect(),
+        Err(e) => {
+            tracing::warn!("Unable to read textfile directory {directory:?}: {e}");
+            scrape_error = true;
+            vec![]
+        }
+    };
+    paths.sort();
+
+    for path in paths {
+        let (file_metrics, modified) = match read_textfile(&path) {
+            Ok(result) => result,
+            Err(e) => {
+                tracing::warn!("Unable to read {path:?}: {e:#}");
+                scrape_error = true;
+                continue;
+            }
+        };
+
+        let file = Label {
+            name: "file".into(),
+            value: path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .into(),
+        };
+        mtimes.push((
+            file,
+            modified
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64(),
+        ));
+
+        for (name, family) in file_metrics.families {
+            match metrics.families.get_mut(&name) {
+                Some(existing) if existing.metric_type != family.metric_type => {
+                    tracing::warn!(
+                        "Family '{name}' of {path:?} has a conflicting type, ignoring it"
+                    );
+                    scrape_error = true;
+                }
+                Some(existing) => existing.metrics.extend(family.metrics),
+                None => {
+                    metrics.families.insert(name, family);
+                }
+            }
+        }
+    }
+
+    metrics.families.insert(
+        "textfile_mtime_seconds".into(),
+        gauge_family("Modification time of the textfiles", mtimes),
+    );
+
+    let mut scrape_error_family = gauge_family("1 if there was an error reading the textfiles", []);
+    scrape_error_family.metrics.insert(
+        Uuid::new_v4(),
+        Metric {
+            labels: [].into(),
+            value: MetricValue::Gauge(NumberValue::Int64(scrape_error.into())),
+        },
+    );
+    metrics
+        .families
+        .insert("textfile_scrape_error".into(), scrape_error_family);
+
+    metrics
+}
+
+/// Ingest the textfiles of `directory` at each `interval`.
+pub async fn run(directory: &Path, interval: Duration, hub: Sender<HubPushMessage>) {
+    let mut sync = HubSync::new(hub);
+
+    loop {
+        let metrics = read_textfiles(directory);
+
+        if let Err(e) = sync.sync(&metrics).await {
+            tracing::error!("Unable to push textfile metrics: {e}");
+            return;
+        }
+
+        Timer::after(interval).await;
+    }
+}
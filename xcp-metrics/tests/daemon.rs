@@ -0,0 +1,49 @@
+//! Tests of the daemon executable.
+
+use std::{
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use nix::{
+    sys::signal::{kill, Signal},
+    unistd::Pid,
+};
+
+/// With the default arguments, the daemon keeps running until it is asked to stop.
+#[test]
+fn daemon_default_arguments() {
+    let socket =
+        std::env::temp_dir().join(format!("xcp-metrics-daemon-{}.sock", std::process::id()));
+
+    let mut daemon = Command::new(env!("CARGO_BIN_EXE_xcp-metrics"))
+        .arg("--daemon-path")
+        .arg(&socket)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    thread::sleep(Duration::from_secs(1));
+    let running = daemon.try_wait().unwrap().is_none();
+    kill(Pid::from_raw(daemon.id() as i32), Signal::SIGTERM).ok();
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let status = loop {
+        if let Some(status) = daemon.try_wait().unwrap() {
+            break status;
+        }
+
+        if Instant::now() > deadline {
+            daemon.kill().ok();
+            panic!("Daemon didn't stop on SIGTERM");
+        }
+
+        thread::sleep(Duration::from_millis(50));
+    };
+
+    assert!(running, "Daemon stopped on its own ({status})");
+    assert!(status.success());
+    assert!(!socket.exists());
+}